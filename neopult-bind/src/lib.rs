@@ -0,0 +1,87 @@
+//! Binding of the listeners of neopult and neopult-lighthouse, so that both servers bind them the
+//! same way.
+
+use log::warn;
+use std::{
+    io,
+    net::{SocketAddr, TcpListener},
+};
+use tokio::time::{self, Duration};
+
+const BIND_ATTEMPTS: u32 = 5;
+const BIND_INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+/// Binds the listener, retrying with exponential backoff while the address is in use. This happens
+/// when restarting quickly, because the socket of the old process may still be in TIME_WAIT.
+pub async fn bind_with_backoff(addr: SocketAddr) -> io::Result<TcpListener> {
+    retry_with_backoff(BIND_ATTEMPTS, BIND_INITIAL_BACKOFF, || {
+        TcpListener::bind(addr)
+    })
+    .await
+}
+
+async fn retry_with_backoff<T>(
+    attempts: u32,
+    initial_backoff: Duration,
+    mut op: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut backoff = initial_backoff;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && attempt < attempts => {
+                warn!(
+                    "address in use (attempt {}/{}), retrying in {}ms",
+                    attempt,
+                    attempts,
+                    backoff.as_millis()
+                );
+                time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_retry_with_backoff() {
+        let addr_in_use = || io::Error::from(io::ErrorKind::AddrInUse);
+
+        let mut calls = 0;
+        let result = retry_with_backoff(3, Duration::from_millis(1), || {
+            calls += 1;
+            if calls == 1 {
+                Err(addr_in_use())
+            } else {
+                Ok(calls)
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2);
+
+        let mut calls = 0;
+        let result: io::Result<()> = retry_with_backoff(3, Duration::from_millis(1), || {
+            calls += 1;
+            Err(addr_in_use())
+        })
+        .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(calls, 3);
+
+        // Other errors aren't transient, so they are returned right away
+        let mut calls = 0;
+        let result: io::Result<()> = retry_with_backoff(3, Duration::from_millis(1), || {
+            calls += 1;
+            Err(io::Error::from(io::ErrorKind::PermissionDenied))
+        })
+        .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(calls, 1);
+    }
+}
@@ -1,33 +1,94 @@
 use askama::Template;
 use axum::{
-    http::StatusCode,
+    body::{Body, BoxBody},
+    http::{Request, Response, StatusCode},
     response::{Html, IntoResponse},
     routing::{get, get_service},
     Extension, Router,
 };
 use clap::Parser;
 use env_logger::Env;
-use log::{debug, error};
-use std::{fs, io, net::SocketAddr, process, sync::Arc};
+use log::{debug, error, info};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs, io,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    process,
+    sync::Arc,
+};
 use tokio::{
+    net::TcpStream,
     sync::RwLock,
     time::{self, Duration},
 };
-use tower_http::services::ServeDir;
+use tower_http::{services::ServeDir, trace::TraceLayer};
+use tracing::Span;
+
+use neopult_bind::bind_with_backoff;
 
 const IS_DEV: bool = cfg!(debug_assertions);
 
+/// Log target of the access log, which is enabled on info level by `--access-log`
+const ACCESS_LOG_TARGET: &str = "lighthouse::access";
+
 const STATIC_ROOT: &str = if IS_DEV {
     "neopult-lighthouse/static"
 } else {
     "/usr/local/share/neopult/lighthouse/static"
 };
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ChannelStatus {
+    Up,
+    Down,
+}
+
+impl fmt::Display for ChannelStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelStatus::Up => write!(f, "up"),
+            ChannelStatus::Down => write!(f, "down"),
+        }
+    }
+}
+
+/// Value of noVNC's `resize` query parameter
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum NovncResizeMode {
+    /// noVNC scales the framebuffer to fit the browser window
+    Scale,
+    /// noVNC would ask the VNC server to change its resolution to the size of the browser window,
+    /// but it doesn't for view-only connections. The framebuffer is shown unscaled instead.
+    Remote,
+}
+
+impl fmt::Display for NovncResizeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NovncResizeMode::Scale => write!(f, "scale"),
+            NovncResizeMode::Remote => write!(f, "remote"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 struct ChannelInfo {
     number: u8,
     novnc_url: String,
     neopult_url: String,
+    /// `None` when channels aren't probed
+    status: Option<ChannelStatus>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+struct ChannelGroup {
+    /// `None` when channels aren't grouped
+    title: Option<String>,
+    channels: Vec<ChannelInfo>,
 }
 
 /// Neopult channel overview page that guides you to your channel
@@ -42,6 +103,15 @@ struct Args {
     #[clap(short = 'p', long, value_name = "PORT", default_value = "4199")]
     port: u16,
 
+    /// IP address on which lighthouse should listen, e.g. 0.0.0.0 to listen on all IPv4
+    /// interfaces.
+    #[clap(long, value_name = "ADDRESS", default_value = "127.0.0.1")]
+    bind_address: IpAddr,
+
+    /// Log every request with its status and duration.
+    #[clap(long)]
+    access_log: bool,
+
     /// Show channels even when they are hidden via a `lighthouse_hide` file.
     #[clap(long)]
     show_hidden_channels: bool,
@@ -69,6 +139,15 @@ struct Args {
     )]
     novnc_base_url: String,
 
+    /// How noVNC fits the remote screen into the browser window. `scale` scales the framebuffer
+    /// on the client and leaves the resolution of the X server alone. The generated links are
+    /// view-only, so that viewers can't control the channel, and noVNC never asks the VNC server
+    /// to resize for view-only connections. With `remote`, the framebuffer is therefore shown at
+    /// its actual size without scaling, and the screen size stays under the control of neopult's
+    /// window manager.
+    #[clap(long, arg_enum, value_name = "MODE", default_value = "scale")]
+    novnc_resize_mode: NovncResizeMode,
+
     /// Host on which websockify can be reached by the noVNC client. If not given, noVNC will use
     /// the same host, on which it is hosted.
     #[clap(short = 'w', long, value_name = "HOST")]
@@ -84,19 +163,49 @@ struct Args {
     /// Defaults to 6080 + channel_number.
     #[clap(short = 's', long, value_name = "PORT")]
     websockify_port: Option<u16>,
+
+    /// Splits the channel list into groups of `N` channels with a header each. If not given, all
+    /// channels are shown in one list.
+    #[clap(short = 'g', long, value_name = "N")]
+    group_by: Option<usize>,
+
+    /// Check whether the neopult server of each channel is reachable when rerendering and mark
+    /// the channels as up or down.
+    #[clap(long)]
+    probe: bool,
+
+    /// Channels whose neopult server doesn't accept a connection within `MS` milliseconds are
+    /// marked as down.
+    #[clap(long, value_name = "MS", default_value = "1000")]
+    probe_timeout_ms: u64,
+
+    /// Directory containing a custom `channel-overview.html`, which is used instead of the
+    /// embedded template. The template uses Jinja syntax and gets the same `groups` as the
+    /// embedded one. If it can't be loaded or rendered, the embedded template is used. The
+    /// template is read again whenever the page is rerendered, which only happens when the
+    /// channels or their statuses change.
+    #[clap(long, value_name = "DIR")]
+    template_dir: Option<String>,
 }
 
 #[derive(Debug)]
 struct Config {
     rerender_interval_ms: Duration,
     port: u16,
+    bind_address: IpAddr,
+    access_log: bool,
     show_hidden_channels: bool,
     neopult_home: String,
     neopult_url_template: String,
     novnc_base_url: String,
+    novnc_resize_mode: NovncResizeMode,
     websockify_host: Option<String>,
     websockify_base_path: Option<String>,
     websockify_port: Option<u16>,
+    group_by: Option<usize>,
+    /// `None` when channels aren't probed
+    probe_timeout: Option<Duration>,
+    template_dir: Option<String>,
 }
 
 impl From<Args> for Config {
@@ -104,28 +213,45 @@ impl From<Args> for Config {
         Config {
             rerender_interval_ms: Duration::from_millis(args.rerender_interval_ms),
             port: args.port,
+            bind_address: args.bind_address,
+            access_log: args.access_log,
             show_hidden_channels: args.show_hidden_channels,
             neopult_home: args.neopult_home,
             neopult_url_template: args.neopult_url_template,
             novnc_base_url: args.novnc_base_url,
+            novnc_resize_mode: args.novnc_resize_mode,
             websockify_host: args.websockify_host,
             websockify_base_path: args.websockify_base_path,
             websockify_port: args.websockify_port,
+            group_by: args.group_by.filter(|&n| n > 0),
+            probe_timeout: args
+                .probe
+                .then(|| Duration::from_millis(args.probe_timeout_ms)),
+            template_dir: args.template_dir,
         }
     }
 }
 
+const CHANNEL_OVERVIEW_TEMPLATE_NAME: &str = "channel-overview.html";
+
 #[derive(Template)]
 #[template(path = "channel-overview.html")]
 struct ChannelOverviewTemplate<'a> {
-    channels: &'a [ChannelInfo],
+    groups: &'a [ChannelGroup],
 }
 
 struct State {
     channel_overview_html: Arc<RwLock<String>>,
 }
 
-async fn rerender_loop(config: Config, mut channels: Vec<u8>, state: Arc<State>) {
+type ChannelStatuses = HashMap<u8, ChannelStatus>;
+
+async fn rerender_loop(
+    config: Config,
+    mut channels: Vec<u8>,
+    mut statuses: ChannelStatuses,
+    state: Arc<State>,
+) {
     // Leak config so it can be passed to the blocking task. This is no problem since this function
     // will run until program termination anyways, thus the config effettively has a static
     // lifetime.
@@ -136,11 +262,13 @@ async fn rerender_loop(config: Config, mut channels: Vec<u8>, state: Arc<State>)
         let result = tokio::task::spawn_blocking(|| read_channels(config)).await;
         match result {
             Ok(Ok(new_channels)) => {
-                if new_channels != channels {
+                let new_statuses = probe_configured_channels(config, &new_channels).await;
+                if new_channels != channels || new_statuses != statuses {
                     debug!("channels changed -- rerendering");
-                    match generate_channel_overview_html(config, &new_channels) {
+                    match generate_channel_overview_html(config, &new_channels, &new_statuses) {
                         Ok(html) => {
                             channels = new_channels;
+                            statuses = new_statuses;
                             *state.channel_overview_html.write().await = html;
                         }
                         Err(e) => error!("Failed to render channel overview template: {}", e),
@@ -157,15 +285,73 @@ async fn rerender_loop(config: Config, mut channels: Vec<u8>, state: Arc<State>)
     }
 }
 
-fn generate_channel_overview_html(config: &Config, channels: &[u8]) -> askama::Result<String> {
+fn neopult_port(channel: u8) -> u16 {
+    4200 + (channel as u16)
+}
+
+/// Probes the neopult servers of the channels on this host if probing is enabled.
+async fn probe_configured_channels(config: &Config, channels: &[u8]) -> ChannelStatuses {
+    match config.probe_timeout {
+        Some(timeout) => {
+            let addrs = channels.iter().map(|&channel| {
+                (
+                    channel,
+                    SocketAddr::from(([127, 0, 0, 1], neopult_port(channel))),
+                )
+            });
+            probe_channels(addrs, timeout).await
+        }
+        None => ChannelStatuses::new(),
+    }
+}
+
+/// Tries to connect to all addresses concurrently. A channel is up if the connection is
+/// established within `timeout`.
+async fn probe_channels(
+    addrs: impl IntoIterator<Item = (u8, SocketAddr)>,
+    timeout: Duration,
+) -> ChannelStatuses {
+    let probes = addrs
+        .into_iter()
+        .map(|(channel, addr)| {
+            let probe = tokio::spawn(async move {
+                match time::timeout(timeout, TcpStream::connect(addr)).await {
+                    Ok(Ok(_)) => ChannelStatus::Up,
+                    Ok(Err(e)) => {
+                        debug!("channel {} is down: {}", channel, e);
+                        ChannelStatus::Down
+                    }
+                    Err(_) => {
+                        debug!("channel {} is down: probe timed out", channel);
+                        ChannelStatus::Down
+                    }
+                }
+            });
+            (channel, probe)
+        })
+        .collect::<Vec<_>>();
+
+    let mut statuses = ChannelStatuses::new();
+    for (channel, probe) in probes {
+        let status = probe.await.unwrap_or(ChannelStatus::Down);
+        statuses.insert(channel, status);
+    }
+    statuses
+}
+
+fn generate_channel_overview_html(
+    config: &Config,
+    channels: &[u8],
+    statuses: &ChannelStatuses,
+) -> askama::Result<String> {
     let channel_info = channels
         .iter()
         .map(|&channel| {
             let websockify_port = config.websockify_port.unwrap_or(6080 + (channel as u16));
             let janus_room = 1000 + (channel as u16);
             let mut novnc_url = format!(
-                "{}?view_only=1&reconnect=1&bell=0&resize=scale&port={}&room={}",
-                config.novnc_base_url, websockify_port, janus_room
+                "{}?view_only=1&reconnect=1&bell=0&resize={}&port={}&room={}",
+                config.novnc_base_url, config.novnc_resize_mode, websockify_port, janus_room
             );
             if let Some(ref websockify_host) = config.websockify_host {
                 novnc_url = format!("{}&host={}", novnc_url, websockify_host);
@@ -175,22 +361,74 @@ fn generate_channel_overview_html(config: &Config, channels: &[u8]) -> askama::R
             }
 
             let mut neopult_url = config.neopult_url_template.clone();
-            neopult_url = neopult_url.replace("{{PORT}}", &(4200 + (channel as u16)).to_string());
+            neopult_url = neopult_url.replace("{{PORT}}", &neopult_port(channel).to_string());
             neopult_url = neopult_url.replace("{{CHANNEL}}", &channel.to_string());
 
             ChannelInfo {
                 number: channel,
                 novnc_url,
                 neopult_url,
+                status: statuses.get(&channel).copied(),
             }
         })
         .collect::<Vec<_>>();
-    let template = ChannelOverviewTemplate {
-        channels: &channel_info,
-    };
+    let groups = group_channels(channel_info, config.group_by);
+    if let Some(ref template_dir) = config.template_dir {
+        match render_runtime_template(Path::new(template_dir), &groups) {
+            Ok(html) => return Ok(html),
+            Err(e) => error!(
+                "Failed to render template from {}, falling back to the embedded template: {}",
+                template_dir, e
+            ),
+        }
+    }
+    let template = ChannelOverviewTemplate { groups: &groups };
     template.render()
 }
 
+/// Reads the template on every render, so that changes to it show up once the channels or their
+/// statuses change and the page is rerendered.
+fn render_runtime_template(
+    template_dir: &Path,
+    groups: &[ChannelGroup],
+) -> Result<String, Box<dyn Error>> {
+    let source = fs::read_to_string(template_dir.join(CHANNEL_OVERVIEW_TEMPLATE_NAME))?;
+    let mut env = minijinja::Environment::new();
+    env.add_template(CHANNEL_OVERVIEW_TEMPLATE_NAME, &source)?;
+    let html = env
+        .get_template(CHANNEL_OVERVIEW_TEMPLATE_NAME)?
+        .render(minijinja::context! { groups => groups })?;
+    Ok(html)
+}
+
+fn group_channels(channels: Vec<ChannelInfo>, group_by: Option<usize>) -> Vec<ChannelGroup> {
+    if channels.is_empty() {
+        return vec![];
+    }
+    match group_by {
+        Some(group_size) => channels
+            .chunks(group_size)
+            .map(|chunk| {
+                let first = chunk.first().unwrap().number;
+                let last = chunk.last().unwrap().number;
+                let title = if first == last {
+                    format!("Channel {}", first)
+                } else {
+                    format!("Channels {}–{}", first, last)
+                };
+                ChannelGroup {
+                    title: Some(title),
+                    channels: chunk.to_vec(),
+                }
+            })
+            .collect(),
+        None => vec![ChannelGroup {
+            title: None,
+            channels,
+        }],
+    }
+}
+
 fn read_channels(config: &Config) -> io::Result<Vec<u8>> {
     let channel_entries = fs::read_dir(&config.neopult_home)?;
     let mut channels = channel_entries
@@ -227,9 +465,13 @@ fn read_channels(config: &Config) -> io::Result<Vec<u8>> {
 
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
-
     let args = Args::parse();
+    let default_filter = if args.access_log {
+        format!("warn,{}=info", ACCESS_LOG_TARGET)
+    } else {
+        "warn".to_string()
+    };
+    env_logger::Builder::from_env(Env::default().default_filter_or(default_filter)).init();
     debug!("Got args: {:?}", args);
     let config = Config::from(args);
     debug!("Got config: {:?}", config);
@@ -242,7 +484,8 @@ async fn main() {
         }
     };
 
-    let html = match generate_channel_overview_html(&config, &channels) {
+    let statuses = probe_configured_channels(&config, &channels).await;
+    let html = match generate_channel_overview_html(&config, &channels, &statuses) {
         Ok(html) => html,
         Err(e) => {
             eprintln!("Failed to render channel overview template: {}", e);
@@ -254,10 +497,41 @@ async fn main() {
         channel_overview_html: Arc::new(RwLock::new(html)),
     });
 
-    let port = config.port;
+    let addr = bind_addr(&config);
+    let access_log = config.access_log.then(|| Arc::new(log_access) as AccessLog);
+    let app = router(state.clone(), access_log);
+
+    tokio::spawn(rerender_loop(config, channels, statuses, state));
+
+    debug!("Listening on {}", addr);
+    let listener = match bind_with_backoff(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind to {}: {}", addr, e);
+            process::exit(1);
+        }
+    };
+    axum::Server::from_tcp(listener)
+        .unwrap()
+        .serve(app.into_make_service())
+        .await
+        .unwrap();
+}
+
+fn bind_addr(config: &Config) -> SocketAddr {
+    SocketAddr::new(config.bind_address, config.port)
+}
 
-    tokio::spawn(rerender_loop(config, channels, state.clone()));
+/// Receives the lines of the access log, see `router`
+type AccessLog = Arc<dyn Fn(String) + Send + Sync>;
 
+/// The access log of the `--access-log` flag
+fn log_access(line: String) {
+    info!(target: ACCESS_LOG_TARGET, "{}", line);
+}
+
+/// With `access_log`, every request and its response are passed to it
+fn router(state: Arc<State>, access_log: Option<AccessLog>) -> Router {
     let app = Router::new()
         .route("/", get(channel_overview))
         .nest(
@@ -265,13 +539,26 @@ async fn main() {
             get_service(ServeDir::new(STATIC_ROOT)).handle_error(handle_error),
         )
         .layer(Extension(state));
-
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
-    debug!("Listening on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await
-        .unwrap();
+    let access_log = match access_log {
+        Some(access_log) => access_log,
+        None => return app,
+    };
+    let response_log = access_log.clone();
+    app.layer(
+        TraceLayer::new_for_http()
+            .on_request(move |request: &Request<Body>, _span: &Span| {
+                access_log(format!("{} {}", request.method(), request.uri()));
+            })
+            .on_response(
+                move |response: &Response<BoxBody>, latency: Duration, _span: &Span| {
+                    response_log(format!(
+                        "-> {} in {}ms",
+                        response.status(),
+                        latency.as_millis()
+                    ));
+                },
+            ),
+    )
 }
 
 async fn handle_error(err: io::Error) -> impl IntoResponse {
@@ -292,21 +579,86 @@ mod tests {
         Config {
             neopult_home: "irrelevant".to_string(),
             port: 4199,
+            bind_address: IpAddr::from([127, 0, 0, 1]),
+            access_log: false,
             show_hidden_channels: false,
             neopult_url_template: "https://neopult.my-domain.com/{{CHANNEL}}".to_string(),
             novnc_base_url: "https://my-domain.com".to_string(),
+            novnc_resize_mode: NovncResizeMode::Scale,
             rerender_interval_ms: Duration::from_millis(30000),
             websockify_base_path: None,
             websockify_port: None,
             websockify_host: None,
+            group_by: None,
+            probe_timeout: None,
+            template_dir: None,
         }
     }
 
+    #[test]
+    fn test_bind_address_flag() {
+        let config = Config::from(Args::parse_from(["neopult-lighthouse"]));
+        assert_eq!(bind_addr(&config), "127.0.0.1:4199".parse().unwrap());
+
+        let args = Args::parse_from([
+            "neopult-lighthouse",
+            "--bind-address",
+            "0.0.0.0",
+            "--port",
+            "8080",
+        ]);
+        assert_eq!(
+            bind_addr(&Config::from(args)),
+            "0.0.0.0:8080".parse().unwrap()
+        );
+        let args = Args::parse_from(["neopult-lighthouse", "--bind-address", "::1"]);
+        assert_eq!(
+            bind_addr(&Config::from(args)),
+            "[::1]:4199".parse().unwrap()
+        );
+
+        assert!(
+            Args::try_parse_from(["neopult-lighthouse", "--bind-address", "localhost"]).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_access_log_flag() {
+        use tower::ServiceExt;
+
+        let messages = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let access_log: AccessLog = Arc::new({
+            let messages = messages.clone();
+            move |line| messages.lock().unwrap().push(line)
+        });
+        let state = Arc::new(State {
+            channel_overview_html: Arc::new(RwLock::new("overview".to_string())),
+        });
+        let request = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        let response = router(state.clone(), None)
+            .oneshot(request("/?quiet"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(messages.lock().unwrap().is_empty());
+
+        let response = router(state, Some(access_log))
+            .oneshot(request("/?logged"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let messages = messages.lock().unwrap().clone();
+        assert_eq!(messages.len(), 2, "{:?}", messages);
+        assert_eq!(messages[0], "GET /?logged");
+        assert!(messages[1].starts_with("-> 200 OK in "), "{}", messages[1]);
+    }
+
     #[test]
     fn test_generate_channel_overview_html() {
         let config = default_test_config();
         let channels = [1, 3, 4];
-        let html = generate_channel_overview_html(&config, &channels).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
         assert!(html.contains("Channel 1"));
         assert!(!html.contains("Channel 2"));
         assert!(html.contains("Channel 3"));
@@ -324,7 +676,7 @@ mod tests {
             "https://neopult.my-domain.com",
         ]);
         let config = Config::from(args);
-        let html = generate_channel_overview_html(&config, &channels).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
         assert!(html.contains(r#"href="https://neopult.my-domain.com""#));
 
         let args = Args::parse_from([
@@ -333,7 +685,7 @@ mod tests {
             "https://neopult.my-domain.com:{{PORT}}",
         ]);
         let config = Config::from(args);
-        let html = generate_channel_overview_html(&config, &channels).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
         assert!(html.contains(r#"href="https://neopult.my-domain.com:4206""#));
         assert!(html.contains(r#"href="https://neopult.my-domain.com:4222""#));
         assert!(html.contains(r#"href="https://neopult.my-domain.com:4237""#));
@@ -344,7 +696,7 @@ mod tests {
             "https://my-domain.com/neopult/{{CHANNEL}}/admin",
         ]);
         let config = Config::from(args);
-        let html = generate_channel_overview_html(&config, &channels).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
         assert!(html.contains(r#"href="https://my-domain.com/neopult/6/admin""#));
         assert!(html.contains(r#"href="https://my-domain.com/neopult/22/admin""#));
         assert!(html.contains(r#"href="https://my-domain.com/neopult/37/admin""#));
@@ -356,12 +708,12 @@ mod tests {
 
         let args = Args::parse_from(["neopult-lighthouse"]);
         let config = Config::from(args);
-        let html = generate_channel_overview_html(&config, &channels).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
         assert!(!html.contains("&amp;host="));
 
         let args = Args::parse_from(["neopult-lighthouse", "--websockify-host", "my-domain.com"]);
         let config = Config::from(args);
-        let html = generate_channel_overview_html(&config, &channels).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
         assert!(html.contains("&amp;host=my-domain.com"));
     }
 
@@ -371,12 +723,12 @@ mod tests {
 
         let args = Args::parse_from(["neopult-lighthouse"]);
         let config = Config::from(args);
-        let html = generate_channel_overview_html(&config, &channels).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
         assert!(!html.contains("&amp;path="));
 
         let args = Args::parse_from(["neopult-lighthouse", "--websockify-base-path", "/channel/"]);
         let config = Config::from(args);
-        let html = generate_channel_overview_html(&config, &channels).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
         assert!(html.contains("&amp;path=/channel/5"));
         assert!(html.contains("&amp;path=/channel/18"));
         assert!(html.contains("&amp;path=/channel/37"));
@@ -388,17 +740,125 @@ mod tests {
 
         let args = Args::parse_from(["neopult-lighthouse"]);
         let config = Config::from(args);
-        let html = generate_channel_overview_html(&config, &channels).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
         assert!(html.contains("&amp;port=6085"));
         assert!(html.contains("&amp;port=6088"));
         assert!(html.contains("&amp;port=6093"));
 
         let args = Args::parse_from(["neopult-lighthouse", "--websockify-port", "443"]);
         let config = Config::from(args);
-        let html = generate_channel_overview_html(&config, &channels).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
         assert!(html.contains("&amp;port=443"));
         assert!(!html.contains("&amp;port=6085"));
         assert!(!html.contains("&amp;port=6088"));
         assert!(!html.contains("&amp;port=6093"));
     }
+
+    #[test]
+    fn test_novnc_resize_mode_flag() {
+        let channels = [2, 9];
+
+        let args = Args::parse_from(["neopult-lighthouse"]);
+        let config = Config::from(args);
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
+        assert_eq!(html.matches("&amp;resize=scale&amp;").count(), 2);
+        assert!(!html.contains("resize=remote"));
+
+        let args = Args::parse_from(["neopult-lighthouse", "--novnc-resize-mode", "remote"]);
+        let config = Config::from(args);
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
+        assert_eq!(html.matches("&amp;resize=remote&amp;").count(), 2);
+        assert!(!html.contains("resize=scale"));
+
+        assert!(
+            Args::try_parse_from(["neopult-lighthouse", "--novnc-resize-mode", "off"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_group_by_flag() {
+        let channels = [1, 2, 3, 5, 8, 13, 21];
+
+        let args = Args::parse_from(["neopult-lighthouse"]);
+        let config = Config::from(args);
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
+        assert!(!html.contains("channel-group__header"));
+
+        let args = Args::parse_from(["neopult-lighthouse", "--group-by", "3"]);
+        let config = Config::from(args);
+        let html = generate_channel_overview_html(&config, &channels, &HashMap::new()).unwrap();
+        assert_eq!(html.matches("channel-group__header").count(), 3);
+        assert!(html.contains("Channels 1–3"));
+        assert!(html.contains("Channels 5–13"));
+        assert!(html.contains(">Channel 21<"));
+        assert!(html.find("Channels 1–3").unwrap() < html.find("Channel 2").unwrap());
+        assert!(html.find("Channel 3").unwrap() < html.find("Channels 5–13").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_probe_channels() {
+        let reachable = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        // Nothing listens on the port after the listener is dropped
+        let unreachable_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let addrs = [(3, reachable.local_addr().unwrap()), (7, unreachable_addr)];
+        let statuses = probe_channels(addrs, Duration::from_millis(1000)).await;
+        assert_eq!(statuses[&3], ChannelStatus::Up);
+        assert_eq!(statuses[&7], ChannelStatus::Down);
+
+        let config = default_test_config();
+        let html = generate_channel_overview_html(&config, &[3, 7], &statuses).unwrap();
+        let up = html.find("channel-item__status--up").unwrap();
+        let down = html.find("channel-item__status--down").unwrap();
+        assert!(html.find("Channel 3").unwrap() < up);
+        assert!(up < html.find("Channel 7").unwrap());
+        assert!(html.find("Channel 7").unwrap() < down);
+
+        let html = generate_channel_overview_html(&config, &[3, 7], &HashMap::new()).unwrap();
+        assert!(!html.contains("channel-item__status"));
+    }
+
+    #[test]
+    fn test_template_dir_flag() {
+        let template_dir =
+            std::env::temp_dir().join(format!("neopult-lighthouse-template-{}", process::id()));
+        fs::create_dir_all(&template_dir).unwrap();
+        fs::write(
+            template_dir.join(CHANNEL_OVERVIEW_TEMPLATE_NAME),
+            r#"<h1>Custom Lighthouse</h1>
+{% for group in groups %}{% for channel in group.channels %}
+<li>Channel {{ channel.number }}: {{ channel.status or "unknown" }}</li>
+{% endfor %}{% endfor %}"#,
+        )
+        .unwrap();
+        let channels = [2, 9];
+        let statuses = HashMap::from([(9, ChannelStatus::Down)]);
+
+        let args = Args::parse_from([
+            "neopult-lighthouse",
+            "--template-dir",
+            template_dir.to_str().unwrap(),
+        ]);
+        let config = Config::from(args);
+        let html = generate_channel_overview_html(&config, &channels, &statuses).unwrap();
+        assert!(html.contains("<h1>Custom Lighthouse</h1>"));
+        assert!(html.contains("<li>Channel 2: unknown</li>"));
+        assert!(html.contains("<li>Channel 9: down</li>"));
+        assert!(!html.contains("Guiding you to your lecture"));
+
+        // Broken templates fall back to the embedded one
+        fs::write(
+            template_dir.join(CHANNEL_OVERVIEW_TEMPLATE_NAME),
+            "{% for group in groups %}",
+        )
+        .unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &statuses).unwrap();
+        assert!(html.contains("Guiding you to your lecture"));
+
+        fs::remove_dir_all(&template_dir).unwrap();
+        let html = generate_channel_overview_html(&config, &channels, &statuses).unwrap();
+        assert!(html.contains("Guiding you to your lecture"));
+    }
 }
@@ -18,6 +18,16 @@ PluginInstanceHandle = {}
 ---  Keys:
 ---  - display_name?: string
 ---    Name that should be displayed in the interface.
+---  - icon?: string
+---    Initial icon of the module, see `ModuleHandle:set_icon`.
+---  - actions?: table[]
+---    Actions that are registered in order right away. Each entry is a table
+---    with the `name` and `callback` of the action and the options of
+---    `ModuleHandle:register_action`. More actions can still be registered
+---    with `ModuleHandle:register_action` later on.
+---  - requires?: string[]
+---    Requirements that are checked against the active config, see
+---    `neopult.api.register_plugin_instance`.
 --- @return ModuleHandle|nil #module handle or nil if an error occurred
 function PluginInstanceHandle:register_module(name, opts) end
 
@@ -32,7 +42,47 @@ function PluginInstanceHandle:register_module(name, opts) end
 ---  Keys:
 ---  - on_output?: function(line: string)
 ---    called for each line (line ending excluded) of the process output
+---  - merge_stderr?: boolean DEFAULT: false
+---    redirects stderr to the same pipe as stdout, so that `on_output` gets
+---    the lines of both in the order in which they were written; otherwise
+---    lines of stdout and stderr may arrive out of order
+---  - buffer_lines?: integer
+---    keeps the last `buffer_lines` lines of the output (stdout and stderr),
+---    independently of `on_output`, see `ProcessHandle:get_recent_output`
+---  - stdin_from?: string path of a file (relative to the channel home) whose
+---    contents are written to the stdin of the process when it is spawned;
+---    afterwards stdin is closed, unless `keep_stdin_open` is true
+---  - keep_stdin_open?: boolean DEFAULT: false
+---  - restart_on_exit?: boolean DEFAULT: false
+---    spawns the process again when it exits without being killed; the
+---    returned process handle controls the restarted process
+---  - max_restarts?: integer DEFAULT: 5
+---    how often the process is restarted after crashing in quick succession;
+---    afterwards it isn't restarted anymore and the status of `module` is set
+---    to "error"; processes that ran for a minute reset this count
+---  - restart_delay_ms?: integer DEFAULT: 1000
+---    delay before the first restart, which doubles with every further restart
+---  - module?: ModuleHandle module whose status is set to "error" when the
+---    process isn't restarted anymore
+---  - max_lifetime_ms?: integer
+---    kills the process when it runs longer than this, first with SIGINT and
+---    after a grace period with SIGKILL; such processes are not restarted
+---  - on_exit?: fun(exit: { reason: "exited"|"killed"|"timeout", code: integer|nil })
+---    called whenever the process ends; `reason` is "timeout" when the
+---    process was killed due to `max_lifetime_ms` and "killed" when it was
+---    killed via `ProcessHandle:kill`; `code` is the exit code of processes
+---    that exited on their own
+---  - nice?: integer
+---    nice value of the process between -20 and 19; higher values give the
+---    process less CPU time when neopult or other processes need it, e.g. for
+---    encoding helpers; values below neopult's own nice value need privileges;
+---    inherited from neopult if not given
+---  - ionice?: "idle"|"best_effort"
+---    I/O scheduling class of the process; "idle" only gets disk time when no
+---    other process needs it, "best_effort" uses the lowest priority level of
+---    the default class; inherited from neopult if not given
 --- @return ProcessHandle|nil #process handle or nil if an error occurred
+--- @return string|nil #reason why the process was refused, e.g. because max_processes_per_plugin processes are running
 function PluginInstanceHandle:spawn_process(cmd, opts) end
 
 -- Claims a window that is not already managed and whose class (WM_CLASS atom)
@@ -41,6 +91,13 @@ function PluginInstanceHandle:spawn_process(cmd, opts) end
 --
 -- This function should be used to claim a window of a process that was spawned
 -- before.
+--
+-- When no window could be claimed, the second return value tells why:
+-- `{ reason = "timeout" }` if there is no window with the class and
+-- `{ reason = "already_managed", owner = <plugin instance>|nil }` if all
+-- windows with the class are managed already. `owner` is nil when the window
+-- is managed by another neopult instance. A conflict is reported right away
+-- instead of waiting for the timeout.
 --- @param class string substring of window's class
 --- @param opts? table options
 ---  Keys:
@@ -51,10 +108,26 @@ function PluginInstanceHandle:spawn_process(cmd, opts) end
 ---    the string should have the format
 ---    "<width>x<height><'+'|'-'><x_offset><'+'|'-'><y_offset>". Positive
 ---    x_offset and y_offset define the offset from the top and left, negative
----    x_offset and y_offset define the offset from the bottom and right.
+---    x_offset and y_offset define the offset from the bottom and right. See
+---    `WindowHandle:set_min_geometry` for the other accepted values.
+---  - allow_steal?: boolean (DEFAULT: false)
+---    claim the window even if it is managed already, releasing it from its
+---    current owner first; unmanaged windows are still preferred. Windows of
+---    other neopult instances can't be stolen.
 --- @return WindowHandle|nil #window handle or nil if an error occurred
+--- @return { reason: "timeout"|"already_managed", owner: string|nil }|nil #why no window could be claimed
 function PluginInstanceHandle:claim_window(class, opts) end
 
+-- Claims a window like `PluginInstanceHandle:claim_window`, but raises an
+-- error naming the class and the timeout or the owner of the window when no
+-- window could be claimed. This is meant for windows that have to exist, e.g.
+-- of a process that the plugin spawned right before, where a missing window
+-- means that the setup is broken.
+--- @param class string substring of window's class
+--- @param opts? table same options as `PluginInstanceHandle:claim_window`
+--- @return WindowHandle #window handle
+function PluginInstanceHandle:assert_window(class, opts) end
+
 -- Creates a virtual window -- a window that is not shown on the screen but
 -- managed by the window manager. This puts the window to min mode
 -- automatically.
@@ -66,14 +139,47 @@ function PluginInstanceHandle:claim_window(class, opts) end
 --- @param name string name of the virtual window, this makes debugging easier
 --- @param opts table options
 ---  Keys:
----  - set_geometry fun(x_offset: integer, y_offset: integer, width: integer, height: integer, alignment: "lt"|"rt"|"rb"|"lb", z: integer) function that will be called when the window manager sets the geometry of the window
+---  - set_geometry fun(x_offset: integer, y_offset: integer, width: integer, height: integer, alignment: "lt"|"rt"|"rb"|"lb", z: integer, frontend: { id: string|nil, metadata: any }) function that will be called when the window manager sets the geometry of the window; `frontend` holds the `frontend_id` and `metadata` of the window
 ---  - map fun() function that will be called when the window manager maps (shows) the window
 ---  - unmap fun() function that will be called when the window manager unmaps (hides) the window
 ---  - primary_demotion_action "do_nothing"|"make_min"|"hide"? (DEFAULT: "do_nothing") defines what should be done when another window becomes the primary window while this window is the primary window
 ---  - min_geometry string? same as in `PluginInstanceHandle:claim_window`
+---  - frontend_id string? stable identifier that lets frontends associate the content they render with the window
+---  - metadata any? arbitrary value that is passed to `set_geometry` alongside the geometry, e.g. to be forwarded to the frontend
 --- @return WindowHandle|nil #window handle or nil if an error occurred
 function PluginInstanceHandle:create_virtual_window(name, opts) end
 
+-- Calls `callback` once after `delay_ms` milliseconds. Like all callbacks, it
+-- is called from the event loop.
+--- @param delay_ms integer
+--- @param callback function
+--- @return integer #id of the timer
+function PluginInstanceHandle:set_timeout(delay_ms, callback) end
+
+-- Calls `callback` every `interval_ms` milliseconds until the timer is
+-- cleared.
+--- @param interval_ms integer
+--- @param callback function
+--- @return integer #id of the timer
+function PluginInstanceHandle:set_interval(interval_ms, callback) end
+
+-- Cancels the timeout or interval with the given id.
+--- @param id integer id returned by `set_timeout` or `set_interval`
+--- @return boolean #whether the timer was still active
+function PluginInstanceHandle:clear_timer(id) end
+
+-- Cancels all timeouts and intervals of the plugin instance, e.g. when
+-- switching modes. Timers are also cleared when the plugin instance is
+-- unregistered.
+--- @return integer #number of cancelled timers
+function PluginInstanceHandle:clear_all_timers() end
+
+-- Returns the active timeouts and intervals of the plugin instance, ordered by
+-- id. Each timer is a table with the keys `id`, `remaining_ms` (time until the
+-- timer fires next) and `repeat` (true for intervals).
+--- @return table[]
+function PluginInstanceHandle:list_timers() end
+
 -- Like `neopult.log.debug`, but scoped to the plugin instance.
 --- @param msg string message to log
 function PluginInstanceHandle:debug(msg) end
@@ -95,16 +201,39 @@ function PluginInstanceHandle:error(msg) end
 ModuleHandle = {}
 
 -- Registers an action with the given `name` for the module. The name must be
--- unique across all actions of the module. When the action is called,
--- `callback` will be executed.
+-- unique across all actions of the module unless `replace` is set. When the
+-- action is called, `callback` will be executed. Actions that are registered
+-- after the module was registered are broadcast to clients.
 --- @param name string name of the action
 --- @param opts? table options
 ---  Keys:
 ---  - display_name?: string
 ---    Name that should be displayed in the interface.
---- @param callback function function to be executed when the action is called
+---  - tags?: string[]
+---    Tags that interfaces can use to group or search actions.
+---  - confirm?: boolean (DEFAULT: false)
+---    Whether the interface should ask for confirmation before calling the
+---    action.
+---  - cooldown_ms?: integer
+---    Calls of the action are rejected for this many milliseconds after the
+---    last successful call.
+---  - priority?: "normal"|"high" (DEFAULT: "normal")
+---    Calls of high priority actions from clients and the terminal are handled
+---    before other queued events, e.g. so that cutting to black isn't delayed
+---    when many other actions are called at once.
+---  - replace?: boolean (DEFAULT: false)
+---    Replace the callback and options of an existing action with the same
+---    name instead of rejecting the registration. The action keeps its
+---    position.
+--- @param callback fun(args: any, caller: { role: "admin"|"viewer", source: "websocket"|"local"|"plugin", token_id: integer|nil }) function to be executed when the action is called; `args` is only set when the action is called via `neopult.api.call_action`; `caller` describes who called the action, `token_id` is the id of the access token the client authenticated with, if any; calls from the terminal get the admin role; calls from plugins get the role of the caller of the action that is running, or the admin role outside of actions
 function ModuleHandle:register_action(name, callback, opts) end
 
+-- Removes the action with the given `name` from the module and broadcasts the
+-- updated action list.
+--- @param name string name of the action
+--- @return boolean removed false if the module has no action with this name
+function ModuleHandle:unregister_action(name) end
+
 -- Sets the status of the module.
 --- @param status string|nil the new status; nil to clear the status
 function ModuleHandle:set_status(status) end
@@ -123,11 +252,24 @@ function ModuleHandle:get_status() end
 --- @param status string|nil the new status; nil to clear the status
 function ModuleHandle:set_message(status) end
 
+-- Sets the icon of the module, e.g. to reflect whether a stream is muted. The
+-- icon is a name or URL that is interpreted by the interface.
+--- @param icon string|nil the new icon; nil to remove the icon
+function ModuleHandle:set_icon(icon) end
+
 -- Sets the active actions of the module. The default web interface will style
 -- active actions differently.
 --- @param actions string[] names (not display names!) of the actions to be set to active
 function ModuleHandle:set_active_actions(actions) end
 
+-- Attaches a window to the module. From then on, the status and the active
+-- actions of the module mirror the mode of the window: The status is set to
+-- "max", "min" or "hidden" and the active actions to the matching "max", "min"
+-- or "hide" action. When the window is unclaimed, it is detached and the
+-- active actions are cleared. A module can only have one attached window.
+--- @param window_handle WindowHandle window to attach
+function ModuleHandle:attach_window(window_handle) end
+
 -- Like `neopult.log.debug`, but scoped to the module.
 --- @param msg string message to log
 function ModuleHandle:debug(msg) end
@@ -160,6 +302,34 @@ function ProcessHandle:writeln(line) end
 -- process handle that refers to a dead process.
 function ProcessHandle:kill() end
 
+-- Sends a signal to the process. The signal can be given as a name with or
+-- without the "SIG" prefix (e.g. "SIGHUP", "usr1") or as a number. Raises an
+-- error listing the accepted names if the signal is unknown. Returns false if
+-- the signal couldn't be sent, e.g. because the process is gone.
+--- @param sig string|integer signal name or number
+--- @return boolean #whether the signal was sent
+function ProcessHandle:signal(sig) end
+
+-- Adds a listener that is called for each subsequent line of the process
+-- output, in addition to the `on_output` callback of `spawn_process`. Listeners
+-- are called in the order in which they were added and stay attached when the
+-- process is restarted.
+--- @param callback fun(line: string) called for each line (line ending excluded)
+--- @return integer #id of the listener, see `ProcessHandle:remove_output_listener`
+function ProcessHandle:add_output_listener(callback) end
+
+-- Removes the output listener with the given `id`, so that it isn't called for
+-- later lines anymore.
+--- @param id integer id returned by `ProcessHandle:add_output_listener`
+--- @return boolean #whether the listener was attached
+function ProcessHandle:remove_output_listener(id) end
+
+-- Returns the most recent lines of the process output, oldest first. This
+-- requires the `buffer_lines` option of `PluginInstanceHandle:spawn_process`,
+-- otherwise nil is returned. Lines of restarted processes are kept.
+--- @return string[]|nil #recent lines or nil if no output is buffered
+function ProcessHandle:get_recent_output() end
+
 
 --- @class WindowHandle
 WindowHandle = {}
@@ -186,6 +356,24 @@ function WindowHandle:min() end
 -- Puts the window to hide mode, which hides it.
 function WindowHandle:hide() end
 
+-- Puts the window to min mode with the given size, centered on the screen.
+-- This replaces the window's `min_geometry`, so later calls of
+-- `WindowHandle:min` keep the window centered, even when the screen size
+-- changes.
+--- @param width integer
+--- @param height integer
+function WindowHandle:center(width, height) end
+
+-- Replaces the window's `min_geometry` and moves the window to the new
+-- position if it is currently in min mode.
+--- @param min_geometry string|table|function geometry string in the format of
+---   `PluginInstanceHandle:claim_window`, a table with the keys `width`,
+---   `height`, `x_offset?`, `y_offset?` and `alignment?` ("lt"|"rt"|"rb"|"lb",
+---   DEFAULT: "lt") or a function that returns a geometry string whenever the
+---   window is minimized
+--- @return boolean #whether the geometry was set
+function WindowHandle:set_min_geometry(min_geometry) end
+
 -- Unclaims the window. This means that the window manager won't manage it
 -- anymore. This should generally only be done with window handles of
 -- terminated processes.
@@ -198,6 +386,34 @@ function WindowHandle:unclaim() end
 --- @return boolean
 function WindowHandle:is_primary_window() end
 
+-- Draws a bright border around the window for a moment, which helps finding
+-- it on a crowded screen. Afterwards, the border from before the flash is
+-- restored, which is the border of a flash that is still running or else the
+-- original border. Virtual windows have no border and can't be flashed.
+--- @param opts? table options
+---  Keys:
+---  - duration_ms integer? (DEFAULT: 1000) how long the border is shown
+---  - color string? (DEFAULT: "#ff0000") color of the border in the format `#rrggbb`
+---  - border_width integer? (DEFAULT: 8) width of the border in pixels
+--- @return boolean #whether the window was flashed
+function WindowHandle:flash(opts) end
+
+-- Reads the X property with the name `atom_name` of the window, e.g. to drive
+-- the remote control protocol of an application. Only properties of the types
+-- STRING, UTF8_STRING and CARDINAL are supported. Virtual windows have no
+-- properties.
+--- @param atom_name string name of the property
+--- @return string|integer[]|nil #string for string properties, list of integers for cardinal properties; nil if the property isn't set or an error occurred
+function WindowHandle:get_property(atom_name) end
+
+-- Sets the X property with the name `atom_name` of the window, replacing its
+-- previous value.
+--- @param atom_name string name of the property
+--- @param type "string"|"utf8_string"|"cardinal" type of the property
+--- @param value string|integer|integer[] string for string properties, integer or list of integers for cardinal properties
+--- @return boolean #whether the property was set
+function WindowHandle:set_property(atom_name, type, value) end
+
 
 --- @class StoreSubscription
 StoreSubscription = {}
@@ -235,14 +451,29 @@ neopult.api = {}
 
 -- Registers a plugin instance with the given `name`. The name must be unique
 -- across all plugin instances.
--- Returns nil if an error occurs (e.g. name is already taken).
+-- Returns nil if an error occurs (e.g. name is already taken or a requirement
+-- is not satisfied).
 --- @param name string name of the plugin instance
 --- @param opts? table options
 ---  Keys:
 ---  - on_cleanup? function cleanup function that is called when the plugin system shuts down correctly; this function should not rely on any processes to still be alive
+---  - on_idle? function function that is called when no client has been connected for `neopult.config.idle_timeout_ms`; this can be used to pause expensive work like previews
+---  - on_resume? function function that is called when a client connects after `on_idle` was called
+---  - on_screen_resolution_change? fun(width: integer, height: integer) function that is called after the window manager changed the screen resolution, e.g. to reposition overlays
+---  - on_windows_lost? fun(wids: integer[]) function that is called with the managed wids of the windows of the plugin instance that were released, because the connection to the X server was lost; the windows have to be claimed again, e.g. after restarting their processes
+---  - self_test? fun(): boolean, string|nil function that checks whether the plugin instance works; it is exposed as the action `<name>::__meta::self_test`, which fails with the returned message when the function doesn't return true
+---  - requires? string[] requirements that are checked against the active config, so that a misconfiguration fails at registration instead of at runtime; `spawn:<cmd>` requires `cmd` to be in `neopult.config.allowed_commands` and unknown requirements are never satisfied
 --- @return PluginInstanceHandle|nil #plugin instance handle or nil if an error occurred
 neopult.api.register_plugin_instance = function(name, opts) end
 
+-- Unregisters the plugin instance with the given `name`. Its `on_cleanup`
+-- callback is called and all windows it claimed or created are released, so
+-- that another window becomes the primary window if needed. Clients only see
+-- that the plugin instance is gone after reconnecting.
+--- @param name string name of the plugin instance
+--- @return boolean #whether the plugin instance was registered
+neopult.api.unregister_plugin_instance = function(name) end
+
 -- Generates a random token consisting of `num_chars` alphanumeric characters
 -- ([a-zA-Z0-9]). This token is generated in a way that makes it safe to use in
 -- secure contexts, such as passwords.
@@ -250,6 +481,15 @@ neopult.api.register_plugin_instance = function(name, opts) end
 --- @return string #random alphanumeric token
 neopult.api.generate_token = function(num_chars) end
 
+-- Creates a token that clients can authenticate with like with an admin
+-- password until it expires, e.g. for one-click or QR code links. This avoids
+-- exposing the password itself. Raises an error if `ttl_ms` is zero or longer
+-- than a day.
+--- @param ttl_ms integer how many milliseconds the token is valid
+--- @return string #access token
+--- @return integer #id of the token, which is passed to actions called by clients that authenticated with it as `caller.token_id`
+neopult.api.create_access_token = function(ttl_ms) end
+
 -- Returns the channel number of the current neopult instance.
 --- @return integer
 neopult.api.get_channel = function() end
@@ -258,6 +498,32 @@ neopult.api.get_channel = function() end
 --- @return string
 neopult.api.get_channel_home = function() end
 
+-- Returns the environment of the current neopult instance in one table, e.g.
+-- to debug which channel and paths a plugin sees.
+--- @return { channel: integer, neopult_home: string, channel_home: string }
+neopult.api.env_config = function() end
+
+-- Calls `callback` from the event loop whenever the status of any module
+-- changes, e.g. for a plugin that summarizes the state of the other plugins.
+-- Changes that an observer makes to statuses are observed as well, so
+-- observers must not set statuses unconditionally. The observer is removed
+-- when `plugin_instance` is unregistered.
+--- @param plugin_instance PluginInstanceHandle plugin instance that owns the observer
+--- @param callback fun(module: { plugin_instance: string, module: string }, status: string|nil)
+neopult.api.on_status_change = function(plugin_instance, callback) end
+
+-- Returns the path to the channel home like `neopult.api.get_channel_home`,
+-- but first creates the subdirectories ".neopult-data" and ".neopult-state"
+-- in it if they don't exist yet. Plugins should keep their files in these
+-- directories. Returns nil if the directories couldn't be created.
+--- @return string|nil
+neopult.api.get_channel_config_path = function() end
+
+-- Returns whether neopult is a debug build, as used during development. This
+-- can be used to enable verbose diagnostics only while developing plugins.
+--- @return boolean
+neopult.api.is_dev = function() end
+
 -- Creates a store for communication between plugins. A store holds one value
 -- of any type at a time. A store handle can be used to register subscriptions
 -- in form of a callback. All callbacks will be called with the new value every
@@ -278,6 +544,54 @@ neopult.api.create_store = function(initial_value) end
 -- noop.
 neopult.api.reposition_windows = function() end
 
+-- Fills the parts of the screen that aren't covered by any window with the
+-- given color. The screen is cleared again after the resolution changes.
+-- Returns whether the color was set.
+--- @param color string color in the format `#rrggbb`
+--- @return boolean
+neopult.api.set_background_color = function(color) end
+
+-- Returns all RandR outputs of the X server. Each output is a table with the
+-- keys `name` (string), `connected` (boolean), `current_mode` (table with
+-- `width` and `height`, nil if the output is disabled) and `modes` (list of
+-- tables with `width` and `height` of every mode the output supports).
+-- Returns nil if an error occurs.
+--- @return table[]|nil
+neopult.api.get_display_info = function() end
+
+-- Lists the top level windows that are not managed by any neopult instance and
+-- could therefore be claimed with `PluginInstanceHandle:claim_window`. Only
+-- windows whose class (WM_CLASS atom) contains `class_filter` are listed. Each
+-- window is a table with the keys `id` (X window id), `class` (instance and
+-- class name separated by a space) and `name` (WM_NAME atom). Returns nil if
+-- an error occurs.
+--- @param class_filter? string DEFAULT: "" (all windows)
+--- @return table[]|nil
+neopult.api.list_claimable_windows = function(class_filter) end
+
+-- Returns the owner of the current primary window as a table with the keys
+-- `plugin_instance` (name of the plugin instance that claimed or created the
+-- window) and `module` (name of the module the window is attached to, nil if
+-- it isn't attached). Returns nil if there is no primary window.
+--- @return table|nil
+neopult.api.get_primary_window_owner = function() end
+
+-- Lists all windows managed by the window manager, ordered by their managed
+-- wid. Each window is a table with the keys `id` (managed wid), `name` (name
+-- of virtual windows, nil for X windows), `mode` ("max", "min" or "hidden"),
+-- `primary` (boolean), `plugin_instance` (name of the owning plugin instance)
+-- and `module` (name of the module the window is attached to, nil if it isn't
+-- attached). Returns nil if an error occurs.
+--- @return table[]|nil
+neopult.api.list_windows = function() end
+
+-- Returns all actions of all plugin instances as a list of tables with the keys
+-- `plugin_instance`, `module`, `action` and `display_name` (nil if the action
+-- has no display name). The identifiers can be passed to
+-- `neopult.api.call_action`.
+--- @return table[]
+neopult.api.get_action_list = function() end
+
 -- Runs the function at a later point in time. Currently this is in the event
 -- loop of the plugin system, before processing new events. This makes sure,
 -- that those tasks don't interfere with other events. This can be useful when
@@ -285,6 +599,82 @@ neopult.api.reposition_windows = function() end
 --- @param task function
 neopult.api.run_later = function(task) end
 
+-- Runs the task once the event loop is quiet, i.e. after a full tick in which
+-- no event was pending and no `run_later` task ran. Unlike `run_later`, this
+-- waits until a burst of events is handled, which makes it useful for
+-- batching expensive work. Tasks that are deferred from inside an idle task
+-- wait for the next event, but at most 50 ms.
+--- @param task function
+neopult.api.defer_until_idle = function(task) end
+
+-- Runs the callback once the given point in time is reached. Timestamps that
+-- aren't in the future are rejected.
+--- @param unix_timestamp number seconds since the unix epoch, may have a fraction
+--- @param callback function
+--- @return boolean|nil #whether the callback was scheduled, nil if the timestamp isn't a valid point in time
+neopult.api.schedule_at = function(unix_timestamp, callback) end
+
+-- Runs the callback whenever the local time matches the cron expression,
+-- e.g. "0 15 * * 1-5" for 15:00 on weekdays. The fields are minute (0-59),
+-- hour (0-23), day of month (1-31), month (1-12) and day of week (0-7, 0 and
+-- 7 are sunday). Each field supports `*`, values, ranges (`a-b`), steps
+-- (`*/n`, `a-b/n`) and comma separated lists. Names of months and days aren't
+-- supported. Like in cron, a day matches if the day of month or the day of
+-- week matches when both are restricted. The schedule runs for the lifetime of
+-- the plugin system and can't be cancelled.
+--- @param expression string cron expression
+--- @param callback function
+--- @return boolean #whether the expression was valid
+neopult.api.schedule_cron = function(expression, callback) end
+
+-- Runs `callback` only the first time that `key` is passed to this function
+-- during the lifetime of the plugin system. This can be used for one-time
+-- initialization in modules that might be required multiple times.
+--- @param key string key identifying the initialization
+--- @param callback function
+--- @return boolean #whether the callback ran
+neopult.api.once = function(key, callback) end
+
+-- Runs `callback` while holding the lock `name`, so that the critical section
+-- isn't entered again while it runs, e.g. when an action calls another action
+-- that uses the same lock. If the lock is already held, the callback is
+-- queued with `neopult.api.run_later` and runs once the lock is free.
+--- @param name string name of the lock
+--- @param callback function
+--- @return boolean #whether the callback ran immediately
+neopult.api.with_lock = function(name, callback) end
+
+-- Returns a function that calls `callback` at most once every `interval_ms`
+-- milliseconds. Calls made in between are dropped, but the last one of them
+-- is made once the interval has passed, so the most recent arguments are
+-- never lost. This is useful for callbacks of chatty processes, e.g. to limit
+-- how often a module status is updated.
+--- @param interval_ms integer minimum time between two calls of `callback`
+--- @param callback function
+--- @return function #throttled function receiving the arguments for `callback`
+neopult.api.throttle = function(interval_ms, callback) end
+
+-- Calls the action of another (or the same) plugin instance, like a client
+-- would, and passes `args` to its callback. Actions may call further actions
+-- this way, but only up to a nesting depth of 8, so that cycles are aborted.
+--- @param plugin_instance string name of the plugin instance
+--- @param module string name of the module
+--- @param action string name of the action
+--- @param args? any value that is passed to the action's callback
+--- @return boolean ok #whether the action was called successfully
+--- @return string|nil err #error message if the call failed
+neopult.api.call_action = function(plugin_instance, module, action, args) end
+
+-- Registers a command for the terminal interface. When a line starts with
+-- `name`, `callback` is called with the remaining words of the line as
+-- arguments and the returned string is printed as the reply. Built-in
+-- commands (actions, statuses, system-info, call) can't be overridden.
+-- Registering the same name again replaces the previous callback.
+--- @param name string single word that starts the command
+--- @param callback fun(...: string): string|nil
+--- @return boolean #whether the command was registered
+neopult.api.register_cli_command = function(name, callback) end
+
 -- Escapes the given html string so it can be safely inserted into the browser
 -- DOM. Untrusted user input should always be escaped to avoid cross-site
 -- scripting (XSS) attacks.
@@ -292,6 +682,76 @@ neopult.api.run_later = function(task) end
 --- @return string #escaped html
 neopult.api.escape_html = function(html) end
 
+-- Escapes `text` and wraps it in a span with the class `severity-<severity>`,
+-- which the web interface styles accordingly. The result can be used as a
+-- module message. Unknown severities fall back to "info".
+--- @param text string unescaped text
+--- @param severity? "info"|"success"|"warning"|"error" DEFAULT: "info"
+--- @return string #html snippet
+neopult.api.format_status_html = function(text, severity) end
+
+-- Returns the current UTC time as an ISO-8601 string with second precision,
+-- e.g. "2022-05-14T18:03:09Z".
+--- @return string
+neopult.api.now_iso8601 = function() end
+
+-- Formats a unix timestamp (e.g. from `os.time()`) with a strftime-like
+-- format string, e.g. "%Y-%m-%d_%H-%M-%S" for file names. The local timezone
+-- is used unless `opts.utc` is set.
+--- @param unix integer seconds since the unix epoch
+--- @param fmt string format string, see https://docs.rs/chrono/latest/chrono/format/strftime/
+--- @param opts? table options
+---  Keys:
+---  - utc?: boolean
+---    Format in UTC instead of local time. Defaults to false.
+--- @return string|nil #formatted timestamp; nil if the format string or timestamp is invalid
+neopult.api.format_timestamp = function(unix, fmt, opts) end
+
+-- Hashes `data` with the given algorithm and returns the digest as a lowercase
+-- hex string. This can be used to detect whether content changed without
+-- keeping the content around. Returns nil for unsupported algorithms.
+--- @param algorithm "sha256"|"sha512"
+--- @param data string data to be hashed (may contain arbitrary bytes)
+--- @return string|nil #hex encoded digest or nil if an error occurred
+neopult.api.hash = function(algorithm, data) end
+
+-- Replaces `{{KEY}}` placeholders in `template` with the values of `vars`,
+-- like the URL templates of the lighthouse. Placeholders without a value in
+-- `vars` are left intact and a warning is logged.
+--- @param template string template containing `{{KEY}}` placeholders
+--- @param vars table<string, string|number> values of the placeholders
+--- @return string #interpolated template
+neopult.api.interpolate = function(template, vars) end
+
+-- Creates a virtual window (see `PluginInstanceHandle:create_virtual_window`)
+-- whose content is the image at `image_path`. Instead of taking callbacks,
+-- the window sends an `image_window_update` notification with the image path,
+-- the visibility and the geometry to the frontends whenever the window
+-- manager changes them, so the frontends can render the image. The window is
+-- invisible until the window manager shows it. Clients also get the current
+-- image windows in the system info, and a last notification marking the
+-- window as invisible when it is unclaimed. The frontends load the image from
+-- the `/images/<image_path>` route of the server, which only serves the images
+-- (png, jpg, gif, webp, svg and bmp) of existing image windows inside the
+-- channel home.
+--- @param plugin_instance PluginInstanceHandle plugin instance that owns the window
+--- @param name string name of the window, identifies the window in the notifications
+--- @param image_path string path of the image relative to the channel home
+--- @param opts? table same options as `PluginInstanceHandle:create_virtual_window` without the callbacks
+--- @return WindowHandle|nil #window handle or nil if an error occurred
+neopult.api.create_image_window = function(plugin_instance, name, image_path, opts) end
+
+-- Returns a new table with `override` recursively merged into `base`, e.g. to
+-- combine the default config of a plugin with the options of the user. Maps
+-- are merged key by key, while arrays (tables with only the keys `1..n`) and
+-- all other values of `override` replace the ones of `base`. A nil argument is
+-- treated like an empty table. Neither argument is modified. Returns nil if an
+-- argument is not a table or the tables are cyclic.
+--- @param base table|nil table with the default values
+--- @param override table|nil table with the values that take precedence
+--- @return table|nil #merged table or nil if an error occurred
+neopult.api.deep_merge = function(base, override) end
+
 
 -- Log functions
 neopult.log = {}
@@ -314,5 +774,87 @@ neopult.log.error = function(msg) end
 
 
 -- Config values
---- @type { websocket_password?: string }
+--
+-- Most values can also be set in the file "neopult.toml" in the channel home,
+-- with the same keys, e.g. `idle_timeout_ms = 5000`. This allows configuring
+-- channels without editing lua. Values that are set in `neopult.config`
+-- take precedence over the file. `allowed_commands` and
+-- `max_processes_per_plugin` can only be set in lua. A file that can't be
+-- read, has invalid syntax, unknown keys or invalid values stops neopult from
+-- starting.
+--
+-- `data_dir` can only be set in "neopult.toml", because the lua search path is
+-- set up before `init.lua` runs. It replaces "/usr/local/share/neopult" in the
+-- search path for plugins, e.g. to test plugins from another location.
+-- Relative paths are relative to the channel home. The environment variable
+-- `NEOPULT_DATA_DIR` takes precedence over it.
+--
+-- `cors_allowed_origins` lists origins (e.g. "https://admin.example.com")
+-- that may access the server from a different origin, e.g. from an admin
+-- interface that is hosted elsewhere. By default, only same-origin access is
+-- allowed. This also applies to websocket connections, which are refused when
+-- the browser's origin is neither listed nor the host that the connection was
+-- made to. That host is taken from the X-Forwarded-Host header if it is set
+-- and from the Host header otherwise. Reverse proxies therefore have to set
+-- X-Forwarded-Host or forward the Host header. Otherwise, the public origin of
+-- the server (e.g. "https://neopult.example.com") has to be listed here.
+--
+-- `reanchor` controls how the offsets of min window geometries are adapted
+-- when the screen size changes. With "absolute" (DEFAULT), windows keep the
+-- same distance in pixels to the corner they are aligned to. With
+-- "proportional", the offsets are scaled with the screen size, relative to the
+-- screen size at startup.
+--
+-- When the primary window needs a resolution that the output has no mode for,
+-- a new mode is created. Some X servers (e.g. some VNC servers) don't support
+-- that. With `mode_fallback` "nearest" (DEFAULT), the largest existing mode
+-- that fits into the requested resolution is used instead. With "error", the
+-- resolution change fails.
+--
+-- When `allowed_commands` is set, plugins can only spawn processes whose
+-- command is exactly one of the strings in this list, e.g. "/usr/bin/ffmpeg"
+-- or "ffmpeg". Bare names are looked up in the PATH of neopult. Processes
+-- can't set PATH, LD_PRELOAD or LD_LIBRARY_PATH via `envs` then. For other
+-- commands, `PluginInstanceHandle:spawn_process` returns nil and the reason.
+-- By default, every command is allowed.
+--
+-- Each plugin instance can have at most `max_processes_per_plugin` (DEFAULT:
+-- 64) spawned processes running at the same time. Further calls of
+-- `PluginInstanceHandle:spawn_process` fail until one of them exits.
+--
+-- `allowed_commands` and `max_processes_per_plugin` are read once, when the
+-- first plugin instance is registered, so they have to be set before any
+-- plugin is loaded. Later changes to them have no effect.
+--
+-- When `notification_coalesce_ms` is set, status and message updates of a
+-- module that are made within that many milliseconds are collapsed into one
+-- notification carrying the latest value. This reduces the traffic to clients
+-- when plugins update their modules in a tight loop, but delays updates by up
+-- to the configured time. By default, every update is sent immediately.
+--
+-- Websocket connections of clients that send messages larger than
+-- `max_message_bytes` (DEFAULT: 65536) are closed without reading the whole
+-- message. Clients don't reconnect after such a close.
+--
+-- When `idle_disconnect_ms` is set, websocket connections of admins that
+-- didn't send a request (e.g. calling an action) for that many milliseconds
+-- are closed, so that forgotten admin sessions don't stay open. Heartbeats
+-- don't count as requests. Viewer connections and, by default, all idle
+-- connections stay open.
+--
+-- `websocket_password` may be a list of passwords. Clients can authenticate
+-- with any of them, which allows rotating the password without locking out
+-- clients that still use the old one.
+--
+-- Clients that authenticate with a `viewer_websocket_password` get the viewer
+-- role, clients that use a `websocket_password` get the admin role. Viewers
+-- can call actions, but admin tooling like setting module messages is
+-- reserved for admins. By default, there are no viewer passwords.
+--
+-- Actions called by clients are recorded in an audit log at `audit_log_path`
+-- (DEFAULT: "audit.log", relative to the channel home). Once the log exceeds
+-- `audit_log_max_bytes` (DEFAULT: 1048576), it is moved to
+-- "<audit_log_path>.1" and a new log is started. Each entry names the role and
+-- source of the caller and the id of its access token, if it used one.
+--- @type { websocket_password?: string|string[], viewer_websocket_password?: string|string[], idle_timeout_ms?: integer, idle_disconnect_ms?: integer, slow_event_threshold_ms?: integer, audit_log_path?: string, audit_log_max_bytes?: integer, cors_allowed_origins?: string[], max_message_bytes?: integer, notification_coalesce_ms?: integer, allowed_commands?: string[], max_processes_per_plugin?: integer, reanchor?: "absolute"|"proportional", mode_fallback?: "nearest"|"error" }
 neopult.config = {}
@@ -0,0 +1,74 @@
+use anyhow::bail;
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const TOKEN_CHARS: usize = 32;
+/// Tokens are meant for short-lived links, a longer ttl is most likely a mistake
+pub const MAX_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Short-lived tokens that clients may authenticate with instead of the admin password, e.g. for
+/// one-click links. Only hashes of the tokens are stored.
+#[derive(Debug, Default)]
+pub struct AccessTokens {
+    tokens: Mutex<HashMap<Vec<u8>, AccessToken>>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug)]
+struct AccessToken {
+    /// Identifies the token to plugins without revealing it
+    id: u64,
+    expiry: Instant,
+}
+
+impl AccessTokens {
+    /// Returns a new token that is valid for `ttl`, which must be positive and at most `MAX_TTL`,
+    /// together with its id
+    pub fn create(&self, ttl: Duration) -> anyhow::Result<(String, u64)> {
+        if ttl.is_zero() {
+            bail!("the ttl of an access token must be positive");
+        }
+        if ttl > MAX_TTL {
+            bail!(
+                "the ttl of an access token must be at most {}ms, got {}ms",
+                MAX_TTL.as_millis(),
+                ttl.as_millis()
+            );
+        }
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_CHARS);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        // Expired tokens are dropped here, so that they don't pile up when nobody authenticates
+        tokens.retain(|_, token| token.expiry > now);
+        tokens.insert(
+            hash_token(&token),
+            AccessToken {
+                id,
+                expiry: now + ttl,
+            },
+        );
+        Ok((token, id))
+    }
+
+    /// Returns the id of the token if it is valid
+    pub fn validate(&self, token: &str) -> Option<u64> {
+        let now = Instant::now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, token| token.expiry > now);
+        // Looking up the hash instead of the token prevents timing attacks
+        tokens.get(&hash_token(token)).map(|token| token.id)
+    }
+}
+
+fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
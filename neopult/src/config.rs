@@ -1,5 +1,11 @@
-use log::{debug, error};
-use std::{env, path::PathBuf};
+use crate::{access_tokens::AccessTokens, plugin_system};
+use log::{debug, error, warn};
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 
 pub const GLOBAL_DATA_DIR: &str = "/usr/local/share/neopult";
 
@@ -9,9 +15,21 @@ pub const WEB_ROOT: &str = if cfg!(debug_assertions) {
     "/usr/local/share/neopult/web"
 };
 
+/// Admin password that is used when neither neopult.toml nor neopult.config sets one
+pub const DEFAULT_WEBSOCKET_PASSWORD: &str = "admin";
 const CHANNEL_ENV_KEY: &str = "NEOPULT_CHANNEL";
 const CHANNEL_DEFAULT: u8 = 0;
 const CHANNEL_MAX: u8 = 99;
+const PID_DIR_BASE_ENV_KEY: &str = "NEOPULT_PID_DIR_BASE";
+const PID_DIR_BASE_DEFAULT: &str = "/tmp";
+const DATA_DIR_ENV_KEY: &str = "NEOPULT_DATA_DIR";
+const NOTIFICATION_CAPACITY_ENV_KEY: &str = "NEOPULT_NOTIFICATION_CAPACITY";
+const NOTIFICATION_CAPACITY_DEFAULT: usize = 64;
+/// The broadcast channel allocates every slot up front, so huge capacities would exhaust memory
+const NOTIFICATION_CAPACITY_MAX: usize = 65536;
+/// Subdirectories of the channel home in which plugins keep their files
+const CHANNEL_DATA_DIR_NAME: &str = ".neopult-data";
+const CHANNEL_STATE_DIR_NAME: &str = ".neopult-state";
 // In debug mode we do not want to overwrite HOME or cargo won't work. In production, neopult will
 // run under its own user so it is fine to inherit the HOME.
 const NEOPULT_HOME_ENV_KEY: &str = if cfg!(debug_assertions) {
@@ -25,41 +43,154 @@ pub struct EnvConfig {
     pub channel: u8,
     pub neopult_home: PathBuf,
     pub channel_home: PathBuf,
+    /// Directory in which the PID directories of all channels are created
+    pub pid_dir_base: PathBuf,
+    /// Replaces `GLOBAL_DATA_DIR` in the lua search path, e.g. to test plugins from another
+    /// location. Set by `NEOPULT_DATA_DIR` or by `data_dir` in neopult.toml, in that order.
+    pub data_dir: PathBuf,
+    /// Number of notifications that the broadcast channel to clients buffers. Clients that fall
+    /// further behind skip notifications. Every buffered notification is kept in memory until
+    /// all clients received it, so a larger capacity trades memory for fewer skipped
+    /// notifications during bursts.
+    pub notification_capacity: usize,
+}
+
+impl EnvConfig {
+    /// Holds the PID files of the processes that were spawned by plugins
+    pub fn pid_dir_path(&self) -> PathBuf {
+        self.pid_dir_base
+            .join(format!("neopult-channel-{}", self.channel))
+    }
+
+    /// Holds files in which neopult keeps state across restarts
+    pub fn state_dir(&self) -> PathBuf {
+        self.channel_home.join(CHANNEL_STATE_DIR_NAME)
+    }
+
+    /// Returns the channel home after creating the subdirectories for data and state of plugins,
+    /// if they don't exist yet
+    pub fn ensure_channel_dirs(&self) -> io::Result<&Path> {
+        for dir_name in [CHANNEL_DATA_DIR_NAME, CHANNEL_STATE_DIR_NAME] {
+            fs::create_dir_all(self.channel_home.join(dir_name))?;
+        }
+        Ok(&self.channel_home)
+    }
 }
 
 #[derive(Debug)]
 pub struct Config {
     pub channel: u8,
-    pub neopult_home: PathBuf,
     pub channel_home: PathBuf,
-    pub websocket_password: String,
+    /// Clients may authenticate with any of these passwords, which allows rotating passwords
+    /// without rejecting clients that still use the old one
+    pub websocket_passwords: Vec<String>,
+    /// Clients that authenticate with one of these passwords get the viewer role instead of the
+    /// admin role
+    pub viewer_websocket_passwords: Vec<String>,
+    /// Time without any connected clients after which the plugin system is notified that it is
+    /// idle
+    pub idle_timeout: Option<Duration>,
+    /// Time without requests after which the connection of an admin is closed
+    pub idle_disconnect: Option<Duration>,
+    /// Origins that may access the server from other origins via CORS
+    pub cors_allowed_origins: Vec<String>,
+    /// Larger websocket messages from clients are rejected by closing the connection
+    pub max_message_bytes: usize,
+    /// Tokens created via `neopult.api.create_access_token`, which are accepted like admin
+    /// passwords until they expire
+    pub access_tokens: Arc<AccessTokens>,
+    /// Set once the plugins are loaded, which may happen after the server was started
+    pub plugins_loaded: Arc<AtomicBool>,
+}
+
+impl Config {
+    pub fn uses_default_websocket_password(&self) -> bool {
+        self.websocket_passwords == [DEFAULT_WEBSOCKET_PASSWORD]
+    }
+}
+
+/// `source` names where the channel came from and `fallback` what is used instead of an invalid
+/// channel, so that the error tells which value is ignored in favor of which.
+fn validate_channel(channel: u8, source: &str, fallback: &str) -> Option<u8> {
+    if channel <= CHANNEL_MAX {
+        Some(channel)
+    } else {
+        error!(
+            "channel {} of {} must be at most {} -- using {}",
+            channel, source, CHANNEL_MAX, fallback
+        );
+        None
+    }
 }
 
-pub fn get_env_config() -> anyhow::Result<EnvConfig> {
-    let channel_option = match env::var(CHANNEL_ENV_KEY) {
-        Ok(channel_str) => {
+/// Selects the channel from the `--channel` flag, falling back to the value of the channel
+/// environment variable and then to the default channel.
+fn select_channel(channel_flag: Option<u8>, channel_env: Option<String>) -> u8 {
+    let flag_fallback = if channel_env.is_some() {
+        CHANNEL_ENV_KEY
+    } else {
+        "default"
+    };
+    if let Some(channel) =
+        channel_flag.and_then(|channel| validate_channel(channel, "--channel", flag_fallback))
+    {
+        return channel;
+    }
+
+    let channel_option = match channel_env {
+        Some(channel_str) => {
             debug!(
                 "got {} environment variable with value {}",
                 CHANNEL_ENV_KEY, channel_str
             );
             match channel_str.parse() {
-                Ok(channel) => {
-                    if channel <= CHANNEL_MAX {
-                        Some(channel)
-                    } else {
-                        error!("channel must be at most {}  -- using default", CHANNEL_MAX);
-                        None
-                    }
-                }
+                Ok(channel) => validate_channel(channel, CHANNEL_ENV_KEY, "default"),
                 Err(e) => {
                     error!("could not parse channel: {} -- using default", e);
                     None
                 }
             }
         }
-        Err(_) => None,
+        None => None,
     };
-    let channel = channel_option.unwrap_or(CHANNEL_DEFAULT);
+    channel_option.unwrap_or(CHANNEL_DEFAULT)
+}
+
+/// Falls back to the default for values that can't be parsed and for zero, which the broadcast
+/// channel doesn't support. Values above the maximum are clamped.
+fn parse_notification_capacity(capacity_env: Option<String>) -> usize {
+    match capacity_env.map(|capacity_str| capacity_str.parse::<usize>()) {
+        Some(Ok(capacity)) if capacity > NOTIFICATION_CAPACITY_MAX => {
+            warn!(
+                "{} must be at most {} -- using maximum",
+                NOTIFICATION_CAPACITY_ENV_KEY, NOTIFICATION_CAPACITY_MAX
+            );
+            NOTIFICATION_CAPACITY_MAX
+        }
+        Some(Ok(capacity)) if capacity > 0 => capacity,
+        Some(_) => {
+            warn!(
+                "{} has to be a positive integer -- using default",
+                NOTIFICATION_CAPACITY_ENV_KEY
+            );
+            NOTIFICATION_CAPACITY_DEFAULT
+        }
+        None => NOTIFICATION_CAPACITY_DEFAULT,
+    }
+}
+
+/// The data dir of the environment variable takes precedence over the one of neopult.toml, so that
+/// plugins from another location can be tried without editing the file
+fn select_data_dir(env_data_dir: Option<String>, file_data_dir: Option<PathBuf>) -> PathBuf {
+    env_data_dir
+        .map(PathBuf::from)
+        .or(file_data_dir)
+        .unwrap_or_else(|| PathBuf::from(GLOBAL_DATA_DIR))
+}
+
+/// `channel_flag` takes precedence over the channel environment variable
+pub fn get_env_config(channel_flag: Option<u8>) -> anyhow::Result<EnvConfig> {
+    let channel = select_channel(channel_flag, env::var(CHANNEL_ENV_KEY).ok());
     debug!("using channel {}", channel);
 
     debug!(
@@ -91,10 +222,158 @@ pub fn get_env_config() -> anyhow::Result<EnvConfig> {
         anyhow::bail!("channel home directory does not exist");
     }
 
+    let pid_dir_base = env::var(PID_DIR_BASE_ENV_KEY)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(PID_DIR_BASE_DEFAULT));
+    debug!("using PID directory base {:?}", pid_dir_base);
+
+    let data_dir = select_data_dir(
+        env::var(DATA_DIR_ENV_KEY).ok(),
+        plugin_system::get_data_dir(&channel_home)?,
+    );
+    debug!("using global data directory {:?}", data_dir);
+
+    let notification_capacity =
+        parse_notification_capacity(env::var(NOTIFICATION_CAPACITY_ENV_KEY).ok());
+    debug!("using notification capacity {}", notification_capacity);
+
     let config = EnvConfig {
         channel,
         neopult_home,
         channel_home,
+        pid_dir_base,
+        data_dir,
+        notification_capacity,
     };
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{capture_logs, captured_logs, temp_dir};
+
+    #[test]
+    fn test_select_channel() {
+        assert_eq!(select_channel(Some(3), Some("5".to_string())), 3);
+        assert_eq!(select_channel(None, Some("5".to_string())), 5);
+        assert_eq!(select_channel(None, None), CHANNEL_DEFAULT);
+        assert_eq!(select_channel(Some(CHANNEL_MAX), None), CHANNEL_MAX);
+        assert_eq!(
+            select_channel(Some(CHANNEL_MAX + 1), Some("5".to_string())),
+            5
+        );
+        assert_eq!(select_channel(Some(CHANNEL_MAX + 1), None), CHANNEL_DEFAULT);
+        assert_eq!(
+            select_channel(None, Some((CHANNEL_MAX + 1).to_string())),
+            CHANNEL_DEFAULT
+        );
+    }
+
+    #[test]
+    fn test_select_channel_names_fallback() {
+        capture_logs();
+        // Channels that no other test uses, since the logs of all tests are captured together
+        assert_eq!(select_channel(Some(200), Some("5".to_string())), 5);
+        assert_eq!(select_channel(Some(201), None), CHANNEL_DEFAULT);
+        assert_eq!(
+            select_channel(Some(202), Some("203".to_string())),
+            CHANNEL_DEFAULT
+        );
+
+        let logs = captured_logs();
+        for expected in [
+            "ERROR channel 200 of --channel must be at most 99 -- using NEOPULT_CHANNEL",
+            "ERROR channel 201 of --channel must be at most 99 -- using default",
+            "ERROR channel 202 of --channel must be at most 99 -- using NEOPULT_CHANNEL",
+            "ERROR channel 203 of NEOPULT_CHANNEL must be at most 99 -- using default",
+        ] {
+            assert!(logs.iter().any(|log| log == expected), "{}", expected);
+        }
+    }
+
+    #[test]
+    fn test_ensure_channel_dirs() {
+        let neopult_home = temp_dir("channel-dirs");
+        let channel_home = neopult_home.join("channel-4");
+        fs::create_dir_all(&channel_home).unwrap();
+        let env_config = EnvConfig {
+            channel: 4,
+            neopult_home: neopult_home.clone(),
+            channel_home: channel_home.clone(),
+            pid_dir_base: PathBuf::from(PID_DIR_BASE_DEFAULT),
+            data_dir: PathBuf::from(GLOBAL_DATA_DIR),
+            notification_capacity: NOTIFICATION_CAPACITY_DEFAULT,
+        };
+
+        assert_eq!(env_config.ensure_channel_dirs().unwrap(), channel_home);
+        assert!(channel_home.join(CHANNEL_DATA_DIR_NAME).is_dir());
+        assert!(channel_home.join(CHANNEL_STATE_DIR_NAME).is_dir());
+        // Existing directories are fine
+        assert!(env_config.ensure_channel_dirs().is_ok());
+
+        fs::remove_dir_all(&neopult_home).unwrap();
+    }
+
+    #[test]
+    fn test_data_dir() {
+        let channel_home = temp_dir("data-dir");
+        assert_eq!(plugin_system::get_data_dir(&channel_home).unwrap(), None);
+        assert_eq!(select_data_dir(None, None), PathBuf::from(GLOBAL_DATA_DIR));
+
+        fs::write(
+            channel_home.join("neopult.toml"),
+            "data_dir = \"dev-plugins\"\n",
+        )
+        .unwrap();
+        let file_data_dir = plugin_system::get_data_dir(&channel_home).unwrap();
+        assert_eq!(file_data_dir, Some(channel_home.join("dev-plugins")));
+        assert_eq!(
+            select_data_dir(None, file_data_dir.clone()),
+            channel_home.join("dev-plugins")
+        );
+        // The environment variable takes precedence
+        assert_eq!(
+            select_data_dir(Some("/opt/neopult".to_string()), file_data_dir),
+            PathBuf::from("/opt/neopult")
+        );
+
+        fs::remove_dir_all(&channel_home).unwrap();
+    }
+
+    #[test]
+    fn test_notification_capacity() {
+        assert_eq!(
+            parse_notification_capacity(None),
+            NOTIFICATION_CAPACITY_DEFAULT
+        );
+        assert_eq!(parse_notification_capacity(Some("256".to_string())), 256);
+        assert_eq!(
+            parse_notification_capacity(Some("0".to_string())),
+            NOTIFICATION_CAPACITY_DEFAULT
+        );
+        assert_eq!(
+            parse_notification_capacity(Some("many".to_string())),
+            NOTIFICATION_CAPACITY_DEFAULT
+        );
+        assert_eq!(
+            parse_notification_capacity(Some("65537".to_string())),
+            NOTIFICATION_CAPACITY_MAX
+        );
+        // Would make the broadcast channel panic
+        assert_eq!(
+            parse_notification_capacity(Some(usize::MAX.to_string())),
+            NOTIFICATION_CAPACITY_MAX
+        );
+
+        // A burst up to the capacity reaches a subscriber without lagging
+        let capacity = parse_notification_capacity(Some("256".to_string()));
+        let (tx, mut rx) = tokio::sync::broadcast::channel(capacity);
+        for i in 0..capacity {
+            tx.send(i).unwrap();
+        }
+        for i in 0..capacity {
+            assert_eq!(rx.try_recv().unwrap(), i);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+use env_logger::Logger;
+use log::{Log, Metadata, Record, SetLoggerError};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Number of log entries that are kept for clients. Older entries are dropped, so the retention
+/// depends on how much is logged.
+pub const LOG_BUFFER_CAPACITY: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LogEntry {
+    /// Unix timestamp in milliseconds
+    pub timestamp_ms: u64,
+    /// Lowercase name of the level, e.g. "warn"
+    pub level: String,
+    pub target: String,
+    pub message: String,
+}
+
+/// The most recent log entries, which clients can fetch via the websocket
+#[derive(Debug)]
+pub struct LogBuffer {
+    capacity: usize,
+    entries: Mutex<VecDeque<LogEntry>>,
+}
+
+impl Default for LogBuffer {
+    fn default() -> Self {
+        Self::with_capacity(LOG_BUFFER_CAPACITY)
+    }
+}
+
+impl LogBuffer {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub fn push(&self, entry: LogEntry) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Entries that were logged at or after `since_ms` with one of the `levels` (case-insensitive),
+    /// oldest first. Every level matches if `levels` is `None`.
+    pub fn query(&self, since_ms: u64, levels: Option<&[String]>) -> Vec<LogEntry> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| entry.timestamp_ms >= since_ms)
+            .filter(|entry| match levels {
+                Some(levels) => levels
+                    .iter()
+                    .any(|level| level.eq_ignore_ascii_case(&entry.level)),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Logs like `env_logger` and additionally keeps the entries that pass its filter (`RUST_LOG`) in
+/// a `LogBuffer`.
+pub struct BufferingLogger {
+    inner: Logger,
+    buffer: Arc<LogBuffer>,
+}
+
+impl BufferingLogger {
+    pub fn new(inner: Logger, buffer: Arc<LogBuffer>) -> Self {
+        Self { inner, buffer }
+    }
+
+    /// Installs the logger as the global logger
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        let max_level = self.inner.filter();
+        log::set_boxed_logger(Box::new(self))?;
+        log::set_max_level(max_level);
+        Ok(())
+    }
+}
+
+impl Log for BufferingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.inner.matches(record) {
+            return;
+        }
+        self.inner.log(record);
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+        self.buffer.push(LogEntry {
+            timestamp_ms,
+            level: record.level().as_str().to_lowercase(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        });
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use env_logger::Builder;
+    use log::{Level, LevelFilter};
+
+    fn entry(timestamp_ms: u64, level: &str, message: &str) -> LogEntry {
+        LogEntry {
+            timestamp_ms,
+            level: level.to_string(),
+            target: "neopult".to_string(),
+            message: message.to_string(),
+        }
+    }
+
+    fn messages(entries: Vec<LogEntry>) -> Vec<String> {
+        entries.into_iter().map(|entry| entry.message).collect()
+    }
+
+    #[test]
+    fn test_query_log_buffer() {
+        let buffer = LogBuffer::with_capacity(4);
+        buffer.push(entry(100, "info", "dropped"));
+        buffer.push(entry(200, "error", "old error"));
+        buffer.push(entry(300, "warn", "warning"));
+        buffer.push(entry(400, "debug", "details"));
+        buffer.push(entry(500, "error", "new error"));
+
+        assert_eq!(
+            messages(buffer.query(0, None)),
+            ["old error", "warning", "details", "new error"]
+        );
+        assert_eq!(
+            messages(buffer.query(300, None)),
+            ["warning", "details", "new error"]
+        );
+        let levels = ["ERROR".to_string(), "warn".to_string()];
+        assert_eq!(
+            messages(buffer.query(0, Some(&levels))),
+            ["old error", "warning", "new error"]
+        );
+        assert_eq!(
+            messages(buffer.query(250, Some(&levels))),
+            ["warning", "new error"]
+        );
+        assert!(buffer.query(600, None).is_empty());
+    }
+
+    #[test]
+    fn test_buffering_logger_respects_filter() {
+        let buffer = Arc::new(LogBuffer::default());
+        let inner = Builder::new()
+            .filter_level(LevelFilter::Info)
+            .is_test(true)
+            .build();
+        let logger = BufferingLogger::new(inner, buffer.clone());
+
+        for (level, message) in [
+            (Level::Error, "broken"),
+            (Level::Info, "started"),
+            (Level::Debug, "hidden"),
+        ] {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target("neopult::test")
+                    .args(format_args!("{}", message))
+                    .build(),
+            );
+        }
+
+        let entries = buffer.query(0, None);
+        assert_eq!(messages(entries.clone()), ["broken", "started"]);
+        assert_eq!(entries[0].level, "error");
+        assert_eq!(entries[0].target, "neopult::test");
+    }
+}
@@ -1,21 +1,67 @@
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use clap::Parser;
 use env_logger::Env;
-use log::debug;
-use std::{process, sync::Arc, time::Instant};
+use log::{debug, warn};
+use std::{ops::ControlFlow, process, sync::Arc, time::Instant};
 use tokio::{
     io::{self, AsyncBufReadExt, BufReader},
     signal,
-    sync::{broadcast, mpsc, oneshot},
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, oneshot,
+    },
+    task::JoinHandle,
 };
 
+mod access_tokens;
 mod config;
+mod log_buffer;
 mod plugin_system;
+mod readiness;
 mod server;
+#[cfg(test)]
+mod test_support;
 mod window_manager;
 
-use plugin_system::{Event, Notification, PluginSystem};
+use config::Config;
+use log_buffer::{BufferingLogger, LogBuffer};
+use plugin_system::{Event, ImageWindowInfo, Notification, PluginSystem, PluginSystemError};
+use readiness::ReadySignal;
 use window_manager::WindowManager;
 
+/// Neopult channel with a plugin system and a web interface to control it
+#[derive(Parser, Debug)]
+#[clap(name = "Neopult", author, version, about, long_about=None)]
+struct Args {
+    /// Channel to run. Overrides the NEOPULT_CHANNEL environment variable.
+    #[clap(short = 'c', long, value_name = "N")]
+    channel: Option<u8>,
+
+    /// Reads the output of spawned processes on a dedicated runtime with `N` worker threads
+    /// instead of the main runtime. This helps when many processes produce a lot of output. Lua
+    /// callbacks are still only called from the plugin event loop.
+    #[clap(long, value_name = "N", value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    process_io_threads: Option<usize>,
+
+    /// Prints the plugins that can be loaded from the channel home and the global data directory
+    /// without loading them, then exits.
+    #[clap(long)]
+    list_plugins: bool,
+
+    /// Signals readiness once the plugins are loaded and the server accepts connections.
+    /// `systemd` notifies the socket in NOTIFY_SOCKET (for `Type=notify` units), `stdout` prints
+    /// a `READY` line and `file:<PATH>` writes the PID to the file.
+    #[clap(long, value_name = "TARGET")]
+    ready_signal: Option<ReadySignal>,
+
+    /// Starts the server before loading the plugins, so that clients can connect while init.lua
+    /// does slow work. Actions can only be called once loading is done. Settings of the server
+    /// are only read from neopult.toml, since neopult.config isn't set yet. That's why neopult
+    /// refuses to start if neopult.toml doesn't set websocket_password.
+    #[clap(long)]
+    defer_plugin_loading: bool,
+}
+
 #[derive(Debug, Clone)]
 pub struct ShutdownChannels {
     pub shutdown_sender: broadcast::Sender<()>,
@@ -56,38 +102,109 @@ async fn terminal_client(
                 }
             },
             notification_result = plugin_notification_rx.recv() => {
-                match notification_result {
-                    Ok(notification) => {
-                        let json = serde_json::to_string(&notification).expect("serialization");
-                        match notification {
-                            Notification::ModuleStatusUpdate { module_identifier, new_status } =>
-                                println!("new module status for {}: '{:?}'", module_identifier, new_status),
-                            Notification::ModuleMessageUpdate { module_identifier, new_message: Some(msg) } =>
-                                println!("new module message for {}: '{}'", module_identifier, msg),
-                            Notification::ModuleMessageUpdate { module_identifier, new_message: None } =>
-                                println!("cleared message for module {}", module_identifier),
-                            Notification::ModuleActiveActionsUpdate { module_identifier, new_active_actions } =>
-                                println!("new active actions for {}: '{:?}'", module_identifier, new_active_actions),
-                        }
-                        println!("  json: {}", json);
-                    }
-                    Err(e) => {
-                        eprintln!("error when receiving notification: {}", e);
-                    }
+                if print_notification(notification_result).is_break() {
+                    break;
                 }
             },
         );
     }
 }
 
+fn print_notification(notification_result: Result<Notification, RecvError>) -> ControlFlow<()> {
+    match notification_result {
+        Ok(notification) => {
+            let json = serde_json::to_string(&notification).expect("serialization");
+            match notification {
+                Notification::ModuleStatusUpdate {
+                    module_identifier,
+                    new_status,
+                } => println!(
+                    "new module status for {}: '{:?}'",
+                    module_identifier, new_status
+                ),
+                Notification::ModuleMessageUpdate {
+                    module_identifier,
+                    new_message: Some(msg),
+                } => println!("new module message for {}: '{}'", module_identifier, msg),
+                Notification::ModuleMessageUpdate {
+                    module_identifier,
+                    new_message: None,
+                } => println!("cleared message for module {}", module_identifier),
+                Notification::ModuleActiveActionsUpdate {
+                    module_identifier,
+                    new_active_actions,
+                } => println!(
+                    "new active actions for {}: '{:?}'",
+                    module_identifier, new_active_actions
+                ),
+                Notification::ModuleOrderUpdate {
+                    module_identifier,
+                    new_order,
+                } => println!("new order for {}: {}", module_identifier, new_order),
+                Notification::ModuleIconUpdate {
+                    module_identifier,
+                    new_icon,
+                } => println!("new icon for {}: '{:?}'", module_identifier, new_icon),
+                Notification::ModuleActionsUpdate {
+                    module_identifier,
+                    new_actions,
+                } => println!("new actions for {}: {:?}", module_identifier, new_actions),
+                Notification::ScreenResolutionChanged { width, height } => {
+                    println!("new screen resolution: {}x{}", width, height)
+                }
+                Notification::PluginsLoaded => println!("plugins loaded"),
+                Notification::ImageWindowUpdate(ImageWindowInfo {
+                    plugin_instance,
+                    name,
+                    image_path,
+                    visible,
+                    geometry,
+                }) => println!(
+                    "image window {} of {} ({}) updated: visible={}, geometry={:?}",
+                    name, plugin_instance, image_path, visible, geometry
+                ),
+            }
+            println!("  json: {}", json);
+        }
+        Err(RecvError::Lagged(skipped)) => {
+            eprintln!(
+                "terminal client lagged and skipped {} notifications",
+                skipped
+            );
+        }
+        Err(RecvError::Closed) => {
+            eprintln!("notification channel closed");
+            return ControlFlow::Break(());
+        }
+    }
+    ControlFlow::Continue(())
+}
+
 fn main() -> Result<()> {
     let startup_time = Instant::now();
-    env_logger::Builder::from_env(Env::default().default_filter_or("warn")).init();
+    let log_buffer = Arc::new(LogBuffer::default());
+    let logger = env_logger::Builder::from_env(Env::default().default_filter_or("warn")).build();
+    BufferingLogger::new(logger, log_buffer.clone())
+        .init()
+        .expect("couldn't set logger");
+
+    let args = Args::parse();
+    let env_config = config::get_env_config(args.channel)?;
+
+    if args.list_plugins {
+        for (name, path) in plugin_system::list_plugins(&env_config) {
+            println!("{} -- {}", name, path.display());
+        }
+        return Ok(());
+    }
 
-    let env_config = config::get_env_config()?;
+    if let Some(ref ready_signal) = args.ready_signal {
+        ready_signal.reset();
+    }
+    let channel = env_config.channel;
 
     let (plugin_event_tx, plugin_event_rx) = mpsc::channel(64);
-    let (plugin_notification_tx, _) = broadcast::channel(64);
+    let (plugin_notification_tx, _) = broadcast::channel(env_config.notification_capacity);
 
     let (shutdown_wait_tx, mut shutdown_wait_rx) = mpsc::channel::<()>(1);
     let (shutdown_tx, _) = broadcast::channel(1);
@@ -126,6 +243,7 @@ fn main() -> Result<()> {
                 let plugin_system = match PluginSystem::init(
                     runtime_handle,
                     env_config,
+                    args.process_io_threads,
                     shutdown_channels.clone(),
                     plugin_event_tx.clone(),
                     plugin_event_rx,
@@ -133,14 +251,35 @@ fn main() -> Result<()> {
                     wm,
                 ) {
                     Ok(plugin_system) => plugin_system,
-                    Err(e) => {
-                        eprintln!("Error when initializing the plugin system: {:?}", e);
-                        process::exit(1);
+                    Err(e) => exit_with_plugin_system_error("initializing the plugin system", e),
+                };
+                let load_plugins = |plugin_system: &PluginSystem| {
+                    if let Err(e) = plugin_system.load_plugins() {
+                        exit_with_plugin_system_error("loading plugins", e);
                     }
                 };
 
-                let config = Arc::new(plugin_system.get_config().expect("couldn't read config from lua"));
-                config_tx.send(config).unwrap();
+                if !args.defer_plugin_loading {
+                    load_plugins(&plugin_system);
+                }
+                let config = match plugin_system.get_config() {
+                    Ok(config) => Arc::new(config),
+                    Err(e) => exit_with_plugin_system_error("reading the config", e),
+                };
+                if args.defer_plugin_loading {
+                    if let Err(e) = check_deferred_config(&config) {
+                        exit_with_plugin_system_error("checking the config", e);
+                    }
+                }
+                // Fails only if startup was aborted already
+                let _ = config_tx.send(config.clone());
+                if args.defer_plugin_loading {
+                    load_plugins(&plugin_system);
+                    match plugin_system.get_config() {
+                        Ok(loaded_config) => warn_ignored_server_settings(&config, &loaded_config),
+                        Err(e) => warn!("couldn't read config after loading plugins: {}", e),
+                    }
+                }
 
                 let time_until_event_loop = startup_time.elapsed();
                 debug!("Time until event loop start: {}ms", time_until_event_loop.as_millis());
@@ -149,12 +288,19 @@ fn main() -> Result<()> {
             }
         });
 
-        let config = config_rx.await?;
+        let config = receive_config(config_rx, &mut plugin_system_handle).await?;
 
+        let (server_bound_tx, server_bound_rx) = oneshot::channel();
+        if let Some(ready_signal) = args.ready_signal {
+            tokio::spawn(readiness::signal_when_bound(ready_signal, channel, server_bound_rx));
+        }
         let mut server_handle = tokio::spawn(server::start(
             config,
             plugin_event_tx.clone(),
             plugin_notification_tx.clone(),
+            shutdown_channels.shutdown_sender.clone(),
+            log_buffer,
+            server_bound_tx,
         ));
         let terminal_client_handle =
             tokio::spawn(async {
@@ -170,7 +316,7 @@ fn main() -> Result<()> {
                 eprint!("error: plugin system exited ");
                 match join_result {
                     Ok(Ok(_)) => eprintln!("without error"),
-                    Ok(Err(e)) => eprintln!("with system error: {:?}", e),
+                    Ok(Err(e)) => eprintln!("with system error: {}", e),
                     Err(e) => eprintln!("with join error: {}", e),
                 };
                 server_handle.abort();
@@ -199,3 +345,194 @@ fn main() -> Result<()> {
         Ok(())
     })
 }
+
+/// Exit codes of sysexits.h, so that scripts that start neopult can tell a broken channel setup,
+/// which needs a fix before restarting, from a failing system
+fn plugin_system_exit_code(e: &PluginSystemError) -> i32 {
+    match e {
+        // EX_CONFIG
+        PluginSystemError::Config(_) | PluginSystemError::ConfigFile { .. } => 78,
+        // EX_DATAERR, the lua code of the channel or a plugin is broken
+        PluginSystemError::Lua { .. } => 65,
+        // EX_IOERR
+        PluginSystemError::Io { .. } => 74,
+    }
+}
+
+fn exit_with_plugin_system_error(action: &str, e: PluginSystemError) -> ! {
+    eprintln!("Error when {}: {}", action, e);
+    match e {
+        PluginSystemError::Config(_) | PluginSystemError::ConfigFile { .. } => {
+            eprintln!("Check neopult.toml and init.lua in the channel home")
+        }
+        PluginSystemError::Lua { .. } => {
+            eprintln!("The error is in init.lua of the channel or in one of its plugins")
+        }
+        PluginSystemError::Io { .. } => {}
+    }
+    process::exit(plugin_system_exit_code(&e));
+}
+
+/// The server is started with the config that was read before init.lua ran, so the admin password
+/// can only come from neopult.toml. Starting with the default password would let anyone in.
+fn check_deferred_config(config: &Config) -> Result<(), PluginSystemError> {
+    if config.uses_default_websocket_password() {
+        return Err(PluginSystemError::Config(
+            "--defer-plugin-loading needs websocket_password to be set in neopult.toml".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Server settings from neopult.config come too late when plugin loading is deferred.
+fn warn_ignored_server_settings(server_config: &Config, loaded_config: &Config) {
+    if server_config.websocket_passwords != loaded_config.websocket_passwords
+        || server_config.viewer_websocket_passwords != loaded_config.viewer_websocket_passwords
+        || server_config.cors_allowed_origins != loaded_config.cors_allowed_origins
+        || server_config.max_message_bytes != loaded_config.max_message_bytes
+        || server_config.idle_disconnect != loaded_config.idle_disconnect
+    {
+        warn!(
+            "server settings of neopult.config are ignored with --defer-plugin-loading, set them in neopult.toml instead"
+        );
+    }
+}
+
+/// Waits for the config, which the plugin system sends once it is initialized. If the plugin system
+/// exits before that, e.g. because it panicked, its error is returned instead of a bare
+/// channel-closed error.
+async fn receive_config<C>(
+    config_rx: oneshot::Receiver<C>,
+    plugin_system_handle: &mut JoinHandle<Result<(), PluginSystemError>>,
+) -> Result<C> {
+    if let Ok(config) = config_rx.await {
+        return Ok(config);
+    }
+    let error = match plugin_system_handle.await {
+        Ok(Ok(())) => anyhow!("plugin system exited without error"),
+        Ok(Err(e)) => anyhow!(e),
+        Err(e) if e.is_panic() => {
+            let panic = e.into_panic();
+            let msg = panic
+                .downcast_ref::<&str>()
+                .map(|msg| msg.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            anyhow!("plugin system panicked: {}", msg)
+        }
+        Err(e) => anyhow!(e),
+    };
+    Err(error).context("plugin system failed before sending its config")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plugin_system::ModuleIdentifier;
+
+    fn message_update(message: &str) -> Notification {
+        Notification::ModuleMessageUpdate {
+            module_identifier: ModuleIdentifier {
+                plugin_instance: "vnc".to_string(),
+                module: "viewer".to_string(),
+            },
+            new_message: Some(message.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_process_io_threads_must_be_positive() {
+        let args = Args::try_parse_from(["neopult", "--process-io-threads", "4"]).unwrap();
+        assert_eq!(args.process_io_threads, Some(4));
+        assert!(Args::try_parse_from(["neopult", "--process-io-threads", "0"]).is_err());
+        assert!(Args::try_parse_from(["neopult", "--process-io-threads", "-1"]).is_err());
+    }
+
+    #[test]
+    fn test_plugin_system_exit_code() {
+        let config_error = PluginSystemError::Config("no init.lua found".to_string());
+        let lua_error = PluginSystemError::Lua {
+            context: "error when loading plugins",
+            source: mlua::Error::RuntimeError("boom".to_string()),
+        };
+        let io_error = PluginSystemError::Io {
+            context: "couldn't build plugin runtime",
+            source: std::io::Error::other("no threads"),
+        };
+        assert_eq!(plugin_system_exit_code(&config_error), 78);
+        assert_eq!(plugin_system_exit_code(&lua_error), 65);
+        assert_eq!(plugin_system_exit_code(&io_error), 74);
+    }
+
+    #[test]
+    fn test_check_deferred_config() {
+        let mut config = Config {
+            channel: 0,
+            channel_home: "neopult_home/channel-0".into(),
+            websocket_passwords: vec![config::DEFAULT_WEBSOCKET_PASSWORD.to_string()],
+            viewer_websocket_passwords: vec![],
+            idle_timeout: None,
+            idle_disconnect: None,
+            cors_allowed_origins: vec![],
+            max_message_bytes: 1024,
+            access_tokens: Default::default(),
+            plugins_loaded: Default::default(),
+        };
+        let e = check_deferred_config(&config).unwrap_err();
+        assert!(e.to_string().contains("websocket_password"), "{}", e);
+
+        config.websocket_passwords = vec!["secret".to_string()];
+        assert!(check_deferred_config(&config).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_receive_config_reports_init_failure() {
+        let (config_tx, config_rx) = oneshot::channel::<u8>();
+        let mut handle = tokio::task::spawn_blocking(move || {
+            let _config_tx = config_tx;
+            panic!("error in init.lua: attempt to index a nil value");
+        });
+        let err = receive_config(config_rx, &mut handle).await.unwrap_err();
+        let msg = format!("{:#}", err);
+        assert!(msg.contains("before sending its config"), "{}", msg);
+        assert!(
+            msg.contains("panicked: error in init.lua: attempt to index a nil value"),
+            "{}",
+            msg
+        );
+
+        let (config_tx, config_rx) = oneshot::channel::<u8>();
+        let mut handle = tokio::task::spawn_blocking(move || {
+            drop(config_tx);
+            Err(PluginSystemError::Config(
+                "invalid websocket_password".to_string(),
+            ))
+        });
+        let err = receive_config(config_rx, &mut handle).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("invalid websocket_password"));
+
+        let (config_tx, config_rx) = oneshot::channel();
+        let mut handle = tokio::task::spawn_blocking(move || {
+            config_tx.send(42).unwrap();
+            Ok(())
+        });
+        assert_eq!(receive_config(config_rx, &mut handle).await.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_print_notification_tolerates_lag() {
+        let (tx, mut rx) = broadcast::channel(1);
+        tx.send(message_update("first")).unwrap();
+        tx.send(message_update("second")).unwrap();
+
+        let lagged = rx.recv().await;
+        assert!(matches!(lagged, Err(RecvError::Lagged(1))));
+        assert!(print_notification(lagged).is_continue());
+        assert!(print_notification(rx.recv().await).is_continue());
+
+        drop(tx);
+        let closed = rx.recv().await;
+        assert!(matches!(closed, Err(RecvError::Closed)));
+        assert!(print_notification(closed).is_break());
+    }
+}
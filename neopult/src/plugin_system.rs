@@ -1,53 +1,151 @@
 use crate::{
-    config::{Config, EnvConfig, GLOBAL_DATA_DIR},
-    window_manager::WindowManager,
+    access_tokens::AccessTokens,
+    config::{Config, EnvConfig},
+    window_manager::{ManagedWid, Mode, Reanchor, WindowManager},
     ShutdownChannels,
 };
 use ::log::{debug, error, info, warn};
 use anyhow::Context;
-use mlua::{FromLuaMulti, Function, Lua, RegistryKey, Table, ToLuaMulti, Value};
+use mlua::{FromLuaMulti, Function, Lua, MultiValue, RegistryKey, Table, ToLua, ToLuaMulti, Value};
 use nix::{
     sys::signal::{self, Signal},
     unistd::Pid,
 };
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt::{self, Display, Formatter},
     fs::{self, ReadDir},
-    io, panic,
-    path::PathBuf,
-    sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak},
+    io, mem, panic,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+    },
     thread,
     time::{Duration, Instant},
 };
 use tokio::sync::{broadcast, mpsc, oneshot};
 
 mod api;
+mod audit_log;
+mod coalescer;
 mod config;
+mod error;
 mod log;
+mod module_orders;
+mod schedule;
+#[cfg(test)]
+mod test_support;
+mod timers;
+
+use audit_log::AuditLog;
+use coalescer::{NotificationCoalescer, UpdateKind};
+pub use config::get_data_dir;
+pub use error::PluginSystemError;
+use module_orders::ModuleOrders;
+use timers::{TimerId, Timers};
 
 const SEPARATOR: &str = "::";
+/// Terminal commands that can't be overridden by plugins
+const BUILTIN_CLI_COMMANDS: &[&str] = &["actions", "statuses", "system-info", "call"];
 const OLD_PROCESS_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_millis(2500);
 const OLD_PROCESS_SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Events taken from the channel ahead of time to look for high priority calls. The rest stays in
+/// the bounded channel, so that senders still notice when the event loop falls behind.
+const MAX_PENDING_EVENTS: usize = 16;
+/// Idle tasks that defer themselves again wait this long for an event before they run again
+const IDLE_TASK_RETRY_DELAY: Duration = Duration::from_millis(50);
 
 #[derive(Debug)]
 struct LuaContext {
     env_config: Arc<EnvConfig>,
     main_runtime_handle: tokio::runtime::Handle,
     plugin_runtime: tokio::runtime::Runtime,
+    /// Dedicated runtime for reading the output of spawned processes. Its tasks only forward
+    /// output to the event loop via `event_sender`, so lua is still only called from the event
+    /// loop thread. Without it, process I/O runs on the main runtime.
+    process_io_runtime: Option<tokio::runtime::Runtime>,
     plugin_instances: RwLock<Vec<Arc<PluginInstance>>>,
     event_sender: Arc<mpsc::Sender<Event>>,
     notification_sender: Arc<broadcast::Sender<Notification>>,
+    /// Only set when `notification_coalesce_ms` is configured
+    notification_coalescer: RwLock<Option<Arc<NotificationCoalescer>>>,
     window_manager: RwLock<WindowManager>,
     shutdown_sender: broadcast::Sender<()>,
     plugin_shutdown_wait_sender: Weak<mpsc::Sender<()>>,
     run_later_tasks: Mutex<VecDeque<RegistryKey>>,
+    /// Functions of `neopult.api.defer_until_idle` that wait for a quiet tick
+    idle_tasks: Mutex<VecDeque<RegistryKey>>,
+    /// Keys of `neopult.api.once` calls whose callback already ran
+    once_keys: Mutex<HashSet<String>>,
+    /// Terminal commands registered via `neopult.api.register_cli_command`
+    cli_commands: Mutex<HashMap<String, RegistryKey>>,
+    /// Names of the locks of `neopult.api.with_lock` whose callback is currently running
+    held_locks: Arc<Mutex<HashSet<String>>>,
+    /// Shared with the server, which accepts the tokens when clients authenticate
+    access_tokens: Arc<AccessTokens>,
+    /// Callers of the actions that are currently running, outermost first. Actions that are
+    /// called via `neopult.api.call_action` get the role of the outermost caller.
+    action_callers: Mutex<Vec<Caller>>,
+    /// Callbacks of `neopult.api.on_status_change`
+    status_observers: Mutex<StatusObservers>,
+    /// Copied from `neopult.config` when the first plugin instance is registered, at the latest
+    /// when the config is read before the event loop. Plugins can change `neopult.config`, so it
+    /// is never read again afterwards.
+    spawn_limits: Mutex<Option<Arc<config::SpawnLimits>>>,
     pid_dir_path: PathBuf,
+    /// Orders that clients set for modules, which are applied when the modules are registered
+    module_orders: ModuleOrders,
 }
 
 impl LuaContext {
-    fn read_window_manager(&self) -> Option<RwLockReadGuard<WindowManager>> {
+    fn process_io_handle(&self) -> tokio::runtime::Handle {
+        match &self.process_io_runtime {
+            Some(runtime) => runtime.handle().clone(),
+            None => self.main_runtime_handle.clone(),
+        }
+    }
+
+    /// Notifies clients about a changed status or message of the module, which has to be set
+    /// before.
+    fn notify_module_update(&self, module: &Arc<Module>, kind: UpdateKind) {
+        if let Some(coalescer) = self.notification_coalescer.read().unwrap().as_ref() {
+            coalescer.update(module, kind, &self.main_runtime_handle);
+            return;
+        }
+
+        let notification = match kind {
+            UpdateKind::Status => Notification::ModuleStatusUpdate {
+                module_identifier: module.identifier(),
+                new_status: module.status.read().unwrap().clone(),
+            },
+            UpdateKind::Message => Notification::ModuleMessageUpdate {
+                module_identifier: module.identifier(),
+                new_message: module.message.read().unwrap().clone(),
+            },
+        };
+        let _ = self.notification_sender.send(notification);
+    }
+
+    fn spawn_limits(&self, lua: &Lua) -> Arc<config::SpawnLimits> {
+        self.spawn_limits
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| {
+                Arc::new(config::get_spawn_limits(lua).unwrap_or_else(|e| {
+                    error!("couldn't read spawn limits, no command is allowed: {:?}", e);
+                    config::SpawnLimits {
+                        allowed_commands: Some(Vec::new()),
+                        ..Default::default()
+                    }
+                }))
+            })
+            .clone()
+    }
+
+    fn read_window_manager(&self) -> Option<RwLockReadGuard<'_, WindowManager>> {
         let wm = &self.window_manager;
         match panic::catch_unwind(|| wm.read()) {
             Ok(lock_result) => Some(lock_result.unwrap()),
@@ -58,7 +156,7 @@ impl LuaContext {
         }
     }
 
-    fn write_window_manager(&self) -> Option<RwLockWriteGuard<WindowManager>> {
+    fn write_window_manager(&self) -> Option<RwLockWriteGuard<'_, WindowManager>> {
         let wm = &self.window_manager;
         match panic::catch_unwind(move || wm.write()) {
             Ok(lock_result) => Some(lock_result.unwrap()),
@@ -68,6 +166,46 @@ impl LuaContext {
             }
         }
     }
+
+    /// Mirrors the current mode of attached windows into the state of their modules. Has to be
+    /// called after every change to the window layout.
+    fn sync_attached_windows(&self) {
+        let (resolution_change, lost_windows) = match self.write_window_manager() {
+            Some(mut wm) => (wm.take_resolution_change(), wm.take_lost_windows()),
+            None => return,
+        };
+        if let Some(size) = resolution_change {
+            announce_resolution_change(
+                size,
+                &self.notification_sender,
+                self.event_sender.as_ref().clone(),
+                &self.main_runtime_handle,
+            );
+        }
+
+        let wm = match self.read_window_manager() {
+            Some(wm) => wm,
+            None => return,
+        };
+        sync_attached_windows(
+            &self.plugin_instances.read().unwrap(),
+            |id| wm.window_mode(id),
+            &self.notification_sender,
+        );
+        prune_image_windows(
+            &self.plugin_instances.read().unwrap(),
+            |id| wm.window_mode(id).is_some(),
+            &self.notification_sender,
+        );
+        if !lost_windows.is_empty() {
+            announce_lost_windows(
+                &lost_windows,
+                &self.plugin_instances.read().unwrap(),
+                self.event_sender.as_ref(),
+                &self.main_runtime_handle,
+            );
+        }
+    }
 }
 
 trait LogWithPrefix {
@@ -93,12 +231,32 @@ trait LogWithPrefix {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SystemInfo {
     plugin_instances: Vec<PluginInstanceInfo>,
+    /// Set while plugins are loaded after the server was started, see `--defer-plugin-loading`
+    loading: bool,
+}
+
+impl SystemInfo {
+    pub fn loading() -> Self {
+        SystemInfo {
+            plugin_instances: vec![],
+            loading: true,
+        }
+    }
+
+    /// Paths of the images of all image windows, relative to the channel home
+    pub fn image_paths(&self) -> impl Iterator<Item = &str> {
+        self.plugin_instances
+            .iter()
+            .flat_map(|plugin_instance| &plugin_instance.image_windows)
+            .map(|image_window| image_window.image_path.as_str())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PluginInstanceInfo {
     name: String,
     modules: Vec<ModuleInfo>,
+    image_windows: Vec<ImageWindowInfo>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -109,29 +267,193 @@ pub struct ModuleInfo {
     active_actions: HashSet<String>,
     status: Option<ModuleStatus>,
     message: Option<ModuleMessage>,
+    icon: Option<ModuleIcon>,
+    order: ModuleOrder,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ActionInfo {
     name: String,
     display_name: Option<String>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ActionCatalogEntry {
+    #[serde(flatten)]
+    identifier: ActionIdentifier,
+    display_name: Option<String>,
+    tags: Vec<String>,
+    confirm: bool,
+}
+
+/// Role of an authenticated client, which depends on the password it used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Viewer,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Viewer => "viewer",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallerSource {
+    Websocket,
+    /// The terminal interface
+    Local,
+    /// Another action via `neopult.api.call_action`
+    Plugin,
+}
+
+impl CallerSource {
+    fn as_str(&self) -> &'static str {
+        match self {
+            CallerSource::Websocket => "websocket",
+            CallerSource::Local => "local",
+            CallerSource::Plugin => "plugin",
+        }
+    }
+}
+
+/// Who called an action. It is passed to the action callback, so that actions can behave
+/// differently depending on the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Caller {
+    pub role: Role,
+    pub source: CallerSource,
+    /// Id of the access token the client authenticated with, see `AccessTokens`
+    pub token_id: Option<u64>,
+}
+
+impl Caller {
+    /// Terminal commands can only be sent by whoever runs neopult, so they are trusted like admins
+    const LOCAL: Caller = Caller {
+        role: Role::Admin,
+        source: CallerSource::Local,
+        token_id: None,
+    };
+    /// Plugins are trusted, so actions they call get the admin role
+    const PLUGIN: Caller = Caller {
+        role: Role::Admin,
+        source: CallerSource::Plugin,
+        token_id: None,
+    };
+}
+
+impl<'lua> ToLua<'lua> for Caller {
+    fn to_lua(self, lua: &'lua Lua) -> mlua::Result<Value<'lua>> {
+        let table = lua.create_table()?;
+        table.set("role", self.role.as_str())?;
+        table.set("source", self.source.as_str())?;
+        table.set("token_id", self.token_id)?;
+        Ok(Value::Table(table))
+    }
+}
+
 #[derive(Debug)]
 pub enum ClientCommand {
     CallAction {
         identifier: ActionIdentifier,
+        caller: Caller,
+        error_sender: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetModuleOrder {
+        orders: Vec<(ModuleIdentifier, ModuleOrder)>,
+        error_sender: oneshot::Sender<anyhow::Result<()>>,
+    },
+    SetModuleMessage {
+        module_identifier: ModuleIdentifier,
+        message: Option<String>,
+        severity: Option<String>,
         error_sender: oneshot::Sender<anyhow::Result<()>>,
     },
 }
 
+/// Callbacks that are called with every line of a process output. The `on_output` callback of
+/// `spawn_process` is the first listener, more are added and removed via the process handle.
+#[derive(Debug, Default)]
+pub struct OutputListeners {
+    inner: Mutex<OutputListenersInner>,
+}
+
+#[derive(Debug, Default)]
+struct OutputListenersInner {
+    next_id: u64,
+    listeners: Vec<(u64, Arc<RegistryKey>)>,
+}
+
+impl OutputListeners {
+    /// Returns the id that removes the listener again
+    fn add(&self, callback_key: Arc<RegistryKey>) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let id = inner.next_id;
+        inner.listeners.push((id, callback_key));
+        id
+    }
+
+    /// Removes the callback from the registry right away, unless the listener is being called.
+    /// Then its key is expired by the event loop afterwards.
+    fn remove(&self, lua: &Lua, id: u64) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let position = match inner
+            .listeners
+            .iter()
+            .position(|(listener_id, _)| *listener_id == id)
+        {
+            Some(position) => position,
+            None => return false,
+        };
+        let (_, callback_key) = inner.listeners.remove(position);
+        drop(inner);
+        if let Ok(callback_key) = Arc::try_unwrap(callback_key) {
+            let _ = lua.remove_registry_value(callback_key);
+        }
+        true
+    }
+
+    fn is_empty(&self) -> bool {
+        self.inner.lock().unwrap().listeners.is_empty()
+    }
+
+    /// Calls the listeners in the order in which they were added. They are called outside of the
+    /// lock, so that listeners can add and remove listeners.
+    fn call(&self, lua: &Lua, line: String, process_name: &str, plugin_instance: &PluginInstance) {
+        let callback_keys: Vec<Arc<RegistryKey>> = self
+            .inner
+            .lock()
+            .unwrap()
+            .listeners
+            .iter()
+            .map(|(_, callback_key)| callback_key.clone())
+            .collect();
+        for callback_key in callback_keys {
+            if let Ok(callback) = lua.registry_value::<Function>(&callback_key) {
+                if let Err(e) = callback.call::<_, Value>(line.clone()) {
+                    plugin_instance.error(format!(
+                        "error when handling callback for process {}: {:?}",
+                        process_name, e
+                    ));
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Event {
     ProcessOutput {
         line: String,
         process_name: String,
         plugin_instance: Arc<PluginInstance>,
-        callback_key: Arc<RegistryKey>,
+        listeners: Arc<OutputListeners>,
     },
     CliCommand {
         command: String,
@@ -140,7 +462,256 @@ pub enum Event {
     FetchSystemInfo {
         reply_sender: oneshot::Sender<SystemInfo>,
     },
+    FetchActionCatalog {
+        reply_sender: oneshot::Sender<Vec<ActionCatalogEntry>>,
+    },
     ClientCommand(ClientCommand),
+    /// No client has been connected for the configured idle timeout
+    Idle,
+    /// A client connected after the plugin system went idle
+    Resume,
+    /// A timer that was scheduled by the plugin system elapsed
+    Timer {
+        callback_key: Arc<RegistryKey>,
+    },
+    /// A timeout or interval of a plugin instance elapsed
+    PluginTimer {
+        plugin_instance: Arc<PluginInstance>,
+        timer_id: TimerId,
+    },
+    /// The window manager changed the screen resolution
+    ScreenResolutionChanged {
+        width: u16,
+        height: u16,
+    },
+    /// X windows of the plugin instance were released, because the connection to the X server
+    /// was lost
+    WindowsLost {
+        plugin_instance: Arc<PluginInstance>,
+        wids: Vec<ManagedWid>,
+    },
+    /// The status of a module changed while status observers are registered
+    ModuleStatusChange {
+        module_identifier: ModuleIdentifier,
+        new_status: Option<ModuleStatus>,
+    },
+    /// A process that was spawned with an `on_exit` callback ended
+    ProcessExit {
+        process_name: String,
+        plugin_instance: Arc<PluginInstance>,
+        callback_key: Arc<RegistryKey>,
+        exit: ProcessExit,
+    },
+}
+
+impl Event {
+    fn kind(&self) -> &'static str {
+        match self {
+            Event::ProcessOutput { .. } => "ProcessOutput",
+            Event::CliCommand { .. } => "CliCommand",
+            Event::FetchSystemInfo { .. } => "FetchSystemInfo",
+            Event::FetchActionCatalog { .. } => "FetchActionCatalog",
+            Event::ClientCommand(ClientCommand::CallAction { .. }) => "ClientCommand::CallAction",
+            Event::ClientCommand(ClientCommand::SetModuleOrder { .. }) => {
+                "ClientCommand::SetModuleOrder"
+            }
+            Event::ClientCommand(ClientCommand::SetModuleMessage { .. }) => {
+                "ClientCommand::SetModuleMessage"
+            }
+            Event::Idle => "Idle",
+            Event::Resume => "Resume",
+            Event::Timer { .. } => "Timer",
+            Event::PluginTimer { .. } => "PluginTimer",
+            Event::ScreenResolutionChanged { .. } => "ScreenResolutionChanged",
+            Event::WindowsLost { .. } => "WindowsLost",
+            Event::ModuleStatusChange { .. } => "ModuleStatusChange",
+            Event::ProcessExit { .. } => "ProcessExit",
+        }
+    }
+}
+
+/// Forwards the status updates that are broadcast to clients to the event loop, which calls the
+/// observers of `neopult.api.on_status_change`. Coalesced updates are forwarded once they are sent.
+async fn forward_status_updates(
+    mut notification_receiver: broadcast::Receiver<Notification>,
+    event_sender: Arc<mpsc::Sender<Event>>,
+) {
+    loop {
+        match notification_receiver.recv().await {
+            Ok(Notification::ModuleStatusUpdate {
+                module_identifier,
+                new_status,
+            }) => {
+                let event = Event::ModuleStatusChange {
+                    module_identifier,
+                    new_status,
+                };
+                if event_sender.send(event).await.is_err() {
+                    return;
+                }
+            }
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!(
+                    "status observers lagged and skipped {} notifications",
+                    skipped
+                );
+            }
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct StatusObserver {
+    /// Name of the plugin instance that registered the observer
+    plugin_instance: String,
+    callback_key: Arc<RegistryKey>,
+}
+
+/// Observers of `neopult.api.on_status_change` and the task that forwards status updates to the
+/// event loop, which only runs while there are observers
+#[derive(Debug, Default)]
+struct StatusObservers {
+    observers: Vec<StatusObserver>,
+    forwarder: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl StatusObservers {
+    /// Removes the observers of the plugin instance and stops forwarding status updates when no
+    /// observers are left
+    fn remove_plugin_instance(&mut self, lua: &Lua, plugin_instance: &str) {
+        let (removed, kept) = mem::take(&mut self.observers)
+            .into_iter()
+            .partition(|observer| observer.plugin_instance == plugin_instance);
+        self.observers = kept;
+        for observer in removed {
+            // Keys that are still in use by a running observer are expired once it is done
+            if let Ok(callback_key) = Arc::try_unwrap(observer.callback_key) {
+                let _ = lua.remove_registry_value(callback_key);
+            }
+        }
+        if self.observers.is_empty() {
+            if let Some(forwarder) = self.forwarder.take() {
+                forwarder.abort();
+            }
+        }
+    }
+}
+
+/// Calls every observer with the identifier (`{ plugin_instance, module }`) and the new status of
+/// the module. The observers are called outside of the lock, so that observers can register more
+/// observers.
+fn call_status_observers(
+    lua: &Lua,
+    status_observers: &Mutex<StatusObservers>,
+    module_identifier: &ModuleIdentifier,
+    new_status: Option<&str>,
+) {
+    let callback_keys: Vec<_> = status_observers
+        .lock()
+        .unwrap()
+        .observers
+        .iter()
+        .map(|observer| observer.callback_key.clone())
+        .collect();
+    for callback_key in callback_keys {
+        let result = lua
+            .registry_value::<Function>(&callback_key)
+            .and_then(|callback| {
+                let identifier = lua.create_table()?;
+                identifier.set(
+                    "plugin_instance",
+                    module_identifier.plugin_instance.as_str(),
+                )?;
+                identifier.set("module", module_identifier.module.as_str())?;
+                callback.call::<_, Value>((identifier, new_status))
+            });
+        if let Err(e) = result {
+            error!(
+                "error when calling status observer for module {}: {:?}",
+                module_identifier, e
+            );
+        }
+    }
+}
+
+/// How a spawned process ended, which is passed to its `on_exit` callback
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProcessExit {
+    /// The process exited on its own. `code` is `None` when a signal terminated it.
+    Exited { code: Option<i32> },
+    /// The process was killed via `ProcessHandle:kill`
+    Killed,
+    /// The process was killed, because it ran longer than its `max_lifetime_ms`
+    Timeout,
+}
+
+impl ProcessExit {
+    fn to_lua_table<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Table<'lua>> {
+        let table = lua.create_table()?;
+        match self {
+            ProcessExit::Exited { code } => {
+                table.set("reason", "exited")?;
+                table.set("code", *code)?;
+            }
+            ProcessExit::Killed => table.set("reason", "killed")?,
+            ProcessExit::Timeout => table.set("reason", "timeout")?,
+        }
+        Ok(table)
+    }
+}
+
+/// Measures how long events take to be handled and warns about slow handlers, since every event
+/// blocks the event loop while it is handled.
+#[derive(Debug)]
+struct EventWatchdog {
+    threshold: Duration,
+}
+
+impl EventWatchdog {
+    fn new(threshold: Duration) -> Self {
+        Self { threshold }
+    }
+
+    fn watch<T>(&self, event_kind: &str, handler: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = handler();
+        let elapsed = start.elapsed();
+        if elapsed > self.threshold {
+            warn!(
+                "handling {} event took {}ms, which blocked the event loop (threshold is {}ms)",
+                event_kind,
+                elapsed.as_millis(),
+                self.threshold.as_millis()
+            );
+        }
+        result
+    }
+}
+
+/// Geometry of an image window as passed to the `set_geometry` callback of virtual windows
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ImageWindowGeometry {
+    pub x_offset: u16,
+    pub y_offset: u16,
+    pub width: u16,
+    pub height: u16,
+    /// One of "lt", "rt", "rb" and "lb"
+    pub alignment: String,
+    pub z: u16,
+}
+
+/// State of a window created with `neopult.api.create_image_window`. The frontend is expected to
+/// render the image while the window is visible.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ImageWindowInfo {
+    pub plugin_instance: String,
+    pub name: String,
+    pub image_path: String,
+    pub visible: bool,
+    /// `None` until the window manager set the geometry for the first time
+    pub geometry: Option<ImageWindowGeometry>,
 }
 
 #[allow(clippy::enum_variant_names)]
@@ -162,25 +733,82 @@ pub enum Notification {
         module_identifier: ModuleIdentifier,
         new_active_actions: HashSet<String>,
     },
+    ModuleOrderUpdate {
+        #[serde(flatten)]
+        module_identifier: ModuleIdentifier,
+        new_order: ModuleOrder,
+    },
+    ModuleIconUpdate {
+        #[serde(flatten)]
+        module_identifier: ModuleIdentifier,
+        new_icon: Option<ModuleIcon>,
+    },
+    ModuleActionsUpdate {
+        #[serde(flatten)]
+        module_identifier: ModuleIdentifier,
+        new_actions: Vec<ActionInfo>,
+    },
+    ScreenResolutionChanged {
+        width: u16,
+        height: u16,
+    },
+    /// Plugins were loaded after the server was started, see `--defer-plugin-loading`
+    PluginsLoaded,
+    /// The window manager changed the geometry or visibility of a window created with
+    /// `neopult.api.create_image_window`. Released image windows are sent one last time as
+    /// invisible.
+    ImageWindowUpdate(ImageWindowInfo),
 }
 
 #[derive(Debug)]
 pub struct PluginInstance {
     name: String,
     modules: RwLock<Vec<Arc<Module>>>,
-    on_cleanup: Option<RegistryKey>,
+    callbacks: PluginInstanceCallbacks,
+    timers: Timers,
+    /// Number of spawned processes that haven't exited yet
+    running_processes: Arc<AtomicUsize>,
+    /// Windows created via `neopult.api.create_image_window` by their managed wid
+    image_windows: Mutex<HashMap<ManagedWid, Arc<Mutex<ImageWindowInfo>>>>,
 }
 
 impl PluginInstance {
-    fn new(name: String, on_cleanup: Option<RegistryKey>) -> Self {
+    fn new(name: String, callbacks: PluginInstanceCallbacks) -> Self {
         Self {
             name,
             modules: RwLock::new(Vec::new()),
-            on_cleanup,
+            callbacks,
+            timers: Timers::default(),
+            running_processes: Arc::new(AtomicUsize::new(0)),
+            image_windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn call_callback(&self, lua: &Lua, callback_key: &Option<RegistryKey>, kind: &str) {
+        if let Some(ref callback_key) = callback_key {
+            match lua.registry_value::<Function>(callback_key) {
+                Ok(callback) => {
+                    if let Err(e) = callback.call::<_, Value>(()) {
+                        self.error(format!("error when calling {} callback: {:?}", kind, e));
+                    }
+                }
+                Err(e) => {
+                    self.error(format!("error when retreiving {} callback. {:?}", kind, e));
+                }
+            }
         }
     }
 }
 
+#[derive(Debug, Default)]
+struct PluginInstanceCallbacks {
+    on_cleanup: Option<RegistryKey>,
+    on_idle: Option<RegistryKey>,
+    on_resume: Option<RegistryKey>,
+    on_screen_resolution_change: Option<RegistryKey>,
+    on_windows_lost: Option<RegistryKey>,
+}
+
 impl LogWithPrefix for PluginInstance {
     fn prefix_msg(&self, msg: String) -> String {
         format!("[{}] {}", self.name, msg)
@@ -189,6 +817,10 @@ impl LogWithPrefix for PluginInstance {
 
 type ModuleStatus = String;
 type ModuleMessage = String;
+/// Name or URL of an icon, which is interpreted by the interface
+type ModuleIcon = String;
+/// Position of a module relative to the other modules; interfaces display lower orders first
+pub type ModuleOrder = i32;
 
 #[derive(Debug)]
 struct Module {
@@ -199,6 +831,10 @@ struct Module {
     active_actions: RwLock<HashSet<String>>,
     status: RwLock<Option<ModuleStatus>>,
     message: RwLock<Option<ModuleMessage>>,
+    icon: RwLock<Option<ModuleIcon>>,
+    /// Window whose mode is mirrored into the status and active actions of the module
+    attached_window: RwLock<Option<ManagedWid>>,
+    order: RwLock<ModuleOrder>,
 }
 
 impl Module {
@@ -211,8 +847,83 @@ impl Module {
             active_actions: RwLock::new(HashSet::new()),
             status: RwLock::new(None),
             message: RwLock::new(None),
+            icon: RwLock::new(None),
+            attached_window: RwLock::new(None),
+            order: RwLock::new(0),
         }
     }
+
+    fn identifier(&self) -> ModuleIdentifier {
+        ModuleIdentifier {
+            plugin_instance: self.plugin_instance_name.clone(),
+            module: self.name.clone(),
+        }
+    }
+
+    fn set_status(
+        &self,
+        status: Option<ModuleStatus>,
+        notification_sender: &broadcast::Sender<Notification>,
+    ) {
+        self.debug(format!("setting module status to '{:?}'", status));
+        *self.status.write().unwrap() = status.clone();
+
+        let _ = notification_sender.send(Notification::ModuleStatusUpdate {
+            module_identifier: self.identifier(),
+            new_status: status,
+        });
+    }
+
+    fn set_icon(
+        &self,
+        icon: Option<ModuleIcon>,
+        notification_sender: &broadcast::Sender<Notification>,
+    ) {
+        self.debug(format!("setting module icon to '{:?}'", icon));
+        *self.icon.write().unwrap() = icon.clone();
+
+        let _ = notification_sender.send(Notification::ModuleIconUpdate {
+            module_identifier: self.identifier(),
+            new_icon: icon,
+        });
+    }
+
+    fn action_infos(&self) -> Vec<ActionInfo> {
+        self.actions
+            .read()
+            .unwrap()
+            .iter()
+            .map(|action| ActionInfo {
+                name: action.name.clone(),
+                display_name: action.display_name.clone(),
+            })
+            .collect()
+    }
+
+    /// Broadcasts the current action list, so that clients pick up actions that were registered,
+    /// replaced or unregistered after the module was registered.
+    fn notify_actions_update(&self, notification_sender: &broadcast::Sender<Notification>) {
+        let _ = notification_sender.send(Notification::ModuleActionsUpdate {
+            module_identifier: self.identifier(),
+            new_actions: self.action_infos(),
+        });
+    }
+
+    fn set_active_actions(
+        &self,
+        actions: Vec<String>,
+        notification_sender: &broadcast::Sender<Notification>,
+    ) {
+        self.debug(format!("setting active actions to '{:?}'", actions));
+        let mut active_actions = self.active_actions.write().unwrap();
+        active_actions.clear();
+        active_actions.extend(actions);
+
+        let _ = notification_sender.send(Notification::ModuleActiveActionsUpdate {
+            module_identifier: self.identifier(),
+            new_active_actions: active_actions.clone(),
+        });
+    }
 }
 
 impl LogWithPrefix for Module {
@@ -224,10 +935,40 @@ impl LogWithPrefix for Module {
     }
 }
 
+/// Calls of high priority actions are handled before other queued events, so that e.g. cutting to
+/// black isn't delayed by a flood of other calls
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum ActionPriority {
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for ActionPriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "normal" => Ok(ActionPriority::Normal),
+            "high" => Ok(ActionPriority::High),
+            _ => anyhow::bail!("priority must be \"normal\" or \"high\", got {}", s),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Action {
     name: String,
     display_name: Option<String>,
+    tags: Vec<String>,
+    /// Whether the interface should ask for confirmation before calling the action
+    confirm: bool,
+    /// Minimum time between two successful calls of the action
+    cooldown: Option<Duration>,
+    priority: ActionPriority,
+    /// Shared, so that it can be updated after the callback ran without holding the lock of the
+    /// actions, which the callback may change
+    last_call: Arc<Mutex<Option<Instant>>>,
     key: RegistryKey,
 }
 
@@ -260,99 +1001,476 @@ impl Display for ActionIdentifier {
     }
 }
 
-fn list_actions(ctx: &LuaContext) -> Vec<String> {
-    let mut action_identifiers = vec![];
-    for plugin_instance in ctx.plugin_instances.read().unwrap().iter() {
+fn action_catalog(plugin_instances: &[Arc<PluginInstance>]) -> Vec<ActionCatalogEntry> {
+    let mut catalog = vec![];
+    for plugin_instance in plugin_instances.iter() {
         for module in plugin_instance.modules.read().unwrap().iter() {
             for action in module.actions.read().unwrap().iter() {
-                let action_identifier = format!(
-                    "{}{}{}{}{}",
-                    plugin_instance.name, SEPARATOR, module.name, SEPARATOR, action.name
-                );
-                action_identifiers.push(action_identifier);
+                catalog.push(ActionCatalogEntry {
+                    identifier: ActionIdentifier {
+                        plugin_instance: plugin_instance.name.clone(),
+                        module: module.name.clone(),
+                        action: action.name.clone(),
+                    },
+                    display_name: action.display_name.clone(),
+                    tags: action.tags.clone(),
+                    confirm: action.confirm,
+                });
             }
         }
     }
-    action_identifiers
+    catalog
 }
 
-fn list_statuses(ctx: &LuaContext) -> Vec<String> {
-    let mut status_lines = vec![];
-    for plugin_instance in ctx.plugin_instances.read().unwrap().iter() {
-        for module in plugin_instance.modules.read().unwrap().iter() {
-            let status = module.status.read().unwrap();
-            let status_line = format!(
-                "{}{}{} -- {}",
-                plugin_instance.name,
-                SEPARATOR,
-                module.name,
-                status.as_ref().unwrap_or(&"unknown".to_string())
-            );
-            status_lines.push(status_line)
-        }
+/// Returns the module status and the active action that correspond to a window mode.
+fn window_mode_state(mode: Mode) -> (&'static str, &'static str) {
+    match mode {
+        Mode::Max { .. } => ("max", "max"),
+        Mode::Min => ("min", "min"),
+        Mode::Hidden => ("hidden", "hide"),
     }
-    status_lines
 }
 
-fn call_action_string(lua: &Lua, ctx: &LuaContext, action_string: &str) -> anyhow::Result<()> {
-    let tokens = action_string.split(SEPARATOR).collect::<Vec<_>>();
-    if tokens.len() != 3 {
-        anyhow::bail!("malformed action identifier: \"{}\"", action_string);
-    }
-    let identifier = ActionIdentifier {
-        plugin_instance: tokens[0].to_string(),
-        module: tokens[1].to_string(),
-        action: tokens[2].to_string(),
-    };
+/// Notifies clients right away and plugins via an event, since this is called while lua is busy
+fn announce_resolution_change(
+    (width, height): (u16, u16),
+    notification_sender: &broadcast::Sender<Notification>,
+    event_sender: mpsc::Sender<Event>,
+    runtime_handle: &tokio::runtime::Handle,
+) {
+    debug!("screen resolution changed to {}x{}", width, height);
+    let _ = notification_sender.send(Notification::ScreenResolutionChanged { width, height });
+    runtime_handle.spawn(async move {
+        let _ = event_sender
+            .send(Event::ScreenResolutionChanged { width, height })
+            .await;
+    });
+}
 
-    call_action(lua, ctx, identifier)
+/// Tells the owners of lost X windows via an event, so that they can claim their windows again.
+/// Modules that had them attached are detached by `sync_attached_windows` already.
+fn announce_lost_windows(
+    lost_windows: &[(String, ManagedWid)],
+    plugin_instances: &[Arc<PluginInstance>],
+    event_sender: &mpsc::Sender<Event>,
+    runtime_handle: &tokio::runtime::Handle,
+) {
+    for plugin_instance in plugin_instances.iter() {
+        let wids: Vec<ManagedWid> = lost_windows
+            .iter()
+            .filter(|(owner, _)| *owner == plugin_instance.name)
+            .map(|(_, id)| *id)
+            .collect();
+        if wids.is_empty() {
+            continue;
+        }
+        plugin_instance.warn(format!(
+            "lost windows with managed wids {:?} after reconnecting to the x server",
+            wids
+        ));
+        let event = Event::WindowsLost {
+            plugin_instance: plugin_instance.clone(),
+            wids,
+        };
+        let event_sender = event_sender.clone();
+        runtime_handle.spawn(async move {
+            let _ = event_sender.send(event).await;
+        });
+    }
 }
 
-fn call_action(lua: &Lua, ctx: &LuaContext, identifier: ActionIdentifier) -> anyhow::Result<()> {
-    let plugin_instances = ctx.plugin_instances.read().unwrap();
-    let plugin_instance = match plugin_instances
-        .iter()
-        .find(|p| p.name == identifier.plugin_instance)
-    {
-        None => anyhow::bail!(
-            "no plugin instance with name {}",
-            identifier.plugin_instance
-        ),
-        Some(p) => p,
+fn sync_attached_windows(
+    plugin_instances: &[Arc<PluginInstance>],
+    window_mode: impl Fn(ManagedWid) -> Option<Mode>,
+    notification_sender: &broadcast::Sender<Notification>,
+) {
+    for plugin_instance in plugin_instances.iter() {
+        for module in plugin_instance.modules.read().unwrap().iter() {
+            let wid = match *module.attached_window.read().unwrap() {
+                Some(wid) => wid,
+                None => continue,
+            };
+
+            match window_mode(wid) {
+                Some(mode) => {
+                    let (status, action) = window_mode_state(mode);
+                    if module.status.read().unwrap().as_deref() != Some(status) {
+                        module.set_status(Some(status.to_string()), notification_sender);
+                    }
+                    let active_actions_match = {
+                        let active_actions = module.active_actions.read().unwrap();
+                        active_actions.len() == 1 && active_actions.contains(action)
+                    };
+                    if !active_actions_match {
+                        module.set_active_actions(vec![action.to_string()], notification_sender);
+                    }
+                }
+                None => {
+                    // The window was unclaimed, so there is nothing left to mirror
+                    module.debug(format!(
+                        "detaching unmanaged window with managed wid {}",
+                        wid
+                    ));
+                    *module.attached_window.write().unwrap() = None;
+                    if !module.active_actions.read().unwrap().is_empty() {
+                        module.set_active_actions(Vec::new(), notification_sender);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Forgets image windows that were released and tells the frontends to stop rendering them
+fn prune_image_windows(
+    plugin_instances: &[Arc<PluginInstance>],
+    is_managed: impl Fn(ManagedWid) -> bool,
+    notification_sender: &broadcast::Sender<Notification>,
+) {
+    for plugin_instance in plugin_instances.iter() {
+        plugin_instance
+            .image_windows
+            .lock()
+            .unwrap()
+            .retain(|&wid, image_window| {
+                if is_managed(wid) {
+                    return true;
+                }
+                let mut image_window = image_window.lock().unwrap();
+                plugin_instance.debug(format!(
+                    "forgetting released image window with name {}",
+                    image_window.name
+                ));
+                image_window.visible = false;
+                let _ =
+                    notification_sender.send(Notification::ImageWindowUpdate(image_window.clone()));
+                false
+            });
+    }
+}
+
+/// Applies the orders to the referenced modules and saves them, so that they survive restarts. Fails
+/// without changing any module when one of the modules doesn't exist.
+fn set_module_order(
+    plugin_instances: &[Arc<PluginInstance>],
+    orders: Vec<(ModuleIdentifier, ModuleOrder)>,
+    module_orders: &ModuleOrders,
+    notification_sender: &broadcast::Sender<Notification>,
+) -> anyhow::Result<()> {
+    let mut modules = Vec::with_capacity(orders.len());
+    for (module_identifier, order) in &orders {
+        let module = plugin_instances
+            .iter()
+            .find(|p| p.name == module_identifier.plugin_instance)
+            .and_then(|p| {
+                p.modules
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .find(|m| m.name == module_identifier.module)
+                    .cloned()
+            });
+        match module {
+            Some(module) => modules.push((module, *order)),
+            None => anyhow::bail!("module {} does not exist", module_identifier),
+        }
+    }
+    module_orders.set(&orders);
+
+    for (module, order) in modules {
+        module.debug(format!("setting module order to {}", order));
+        *module.order.write().unwrap() = order;
+        let _ = notification_sender.send(Notification::ModuleOrderUpdate {
+            module_identifier: module.identifier(),
+            new_order: order,
+        });
+    }
+
+    Ok(())
+}
+
+/// Sets the message of a module on behalf of a client. The text is escaped and formatted like
+/// `neopult.api.format_status_html` does, since clients render messages as HTML. Returns the module,
+/// whose update the caller has to notify.
+fn set_module_message(
+    plugin_instances: &[Arc<PluginInstance>],
+    module_identifier: ModuleIdentifier,
+    message: Option<String>,
+    severity: Option<String>,
+) -> anyhow::Result<Arc<Module>> {
+    let module = plugin_instances
+        .iter()
+        .find(|p| p.name == module_identifier.plugin_instance)
+        .and_then(|p| {
+            p.modules
+                .read()
+                .unwrap()
+                .iter()
+                .find(|m| m.name == module_identifier.module)
+                .cloned()
+        })
+        .with_context(|| format!("module {} does not exist", module_identifier))?;
+    let message = match message {
+        Some(text) => Some(api::format_status_html(text, severity)?),
+        None => None,
     };
 
-    let modules = plugin_instance.modules.read().unwrap();
-    let module = match modules.iter().find(|m| m.name == identifier.module) {
-        None => anyhow::bail!("no module with name {}", identifier.module),
-        Some(m) => m,
+    module.debug(format!("client set module message to '{:?}'", message));
+    *module.message.write().unwrap() = message;
+    Ok(module)
+}
+
+/// Handles system info requests and client commands that set module messages like the event loop
+/// does, so that the server can be tested without lua. Knows the module `vnc::viewer` and the
+/// image window `vnc::slides`, which shows `slides/title.png`.
+#[cfg(test)]
+pub(crate) async fn run_fake_event_loop(
+    mut event_receiver: mpsc::Receiver<Event>,
+    notification_sender: broadcast::Sender<Notification>,
+) {
+    let (plugin_instance, _) = test_support::vnc_viewer();
+    plugin_instance.image_windows.lock().unwrap().insert(
+        1,
+        Arc::new(Mutex::new(ImageWindowInfo {
+            plugin_instance: "vnc".to_string(),
+            name: "slides".to_string(),
+            image_path: "slides/title.png".to_string(),
+            visible: true,
+            geometry: None,
+        })),
+    );
+    let plugin_instances = [plugin_instance];
+
+    while let Some(event) = event_receiver.recv().await {
+        match event {
+            Event::FetchSystemInfo { reply_sender } => {
+                let _ = reply_sender.send(system_info(&plugin_instances));
+            }
+            Event::ClientCommand(ClientCommand::SetModuleMessage {
+                module_identifier,
+                message,
+                severity,
+                error_sender,
+            }) => {
+                let result =
+                    set_module_message(&plugin_instances, module_identifier, message, severity)
+                        .map(|module| {
+                            let _ = notification_sender.send(Notification::ModuleMessageUpdate {
+                                module_identifier: module.identifier(),
+                                new_message: module.message.read().unwrap().clone(),
+                            });
+                        });
+                let _ = error_sender.send(result);
+            }
+            event => panic!("unexpected event {}", event.kind()),
+        }
+    }
+}
+
+fn list_actions(ctx: &LuaContext) -> Vec<String> {
+    action_catalog(&ctx.plugin_instances.read().unwrap())
+        .into_iter()
+        .map(|entry| entry.identifier.to_string())
+        .collect()
+}
+
+/// Calls the plugin command that matches the first token of `command` with the remaining tokens
+/// as arguments. Returns `None` if no plugin registered the command.
+fn call_cli_command(
+    lua: &Lua,
+    cli_commands: &Mutex<HashMap<String, RegistryKey>>,
+    command: &str,
+) -> Option<String> {
+    let mut tokens = command.split_whitespace();
+    let name = tokens.next()?;
+    // The lock must not be held while calling the callback, so that it can register commands
+    let callback = match cli_commands.lock().unwrap().get(name) {
+        Some(key) => lua.registry_value::<Function>(key),
+        None => return None,
     };
+    let reply = callback.and_then(|callback| {
+        let args = tokens.map(str::to_string).collect::<Vec<_>>();
+        callback.call::<_, Option<String>>(MultiValue::from_vec(
+            args.into_iter()
+                .map(|arg| lua.create_string(&arg).map(Value::String))
+                .collect::<mlua::Result<_>>()?,
+        ))
+    });
+    match reply {
+        Ok(reply) => Some(reply.unwrap_or_default()),
+        Err(e) => {
+            error!("error when calling cli command {}: {:?}", name, e);
+            Some(format!("error when calling command: {}", e))
+        }
+    }
+}
+
+/// Pretty-printed JSON of the same system info that clients receive when they connect
+fn system_info_json(plugin_instances: &[Arc<PluginInstance>]) -> String {
+    serde_json::to_string_pretty(&system_info(plugin_instances)).expect("serialization failed")
+}
+
+fn list_statuses(ctx: &LuaContext) -> Vec<String> {
+    let mut status_lines = vec![];
+    for plugin_instance in ctx.plugin_instances.read().unwrap().iter() {
+        for module in plugin_instance.modules.read().unwrap().iter() {
+            let status = module.status.read().unwrap();
+            let status_line = format!(
+                "{}{}{} -- {}",
+                plugin_instance.name,
+                SEPARATOR,
+                module.name,
+                status.as_ref().unwrap_or(&"unknown".to_string())
+            );
+            status_lines.push(status_line)
+        }
+    }
+    status_lines
+}
+
+#[derive(Debug, Serialize)]
+struct StatusInfo {
+    identifier: String,
+    status: Option<ModuleStatus>,
+    message: Option<ModuleMessage>,
+    active_actions: Vec<String>,
+}
+
+/// Pretty-printed JSON of the status of every module, for `statuses --json`
+fn statuses_json(plugin_instances: &[Arc<PluginInstance>]) -> String {
+    let statuses = system_info(plugin_instances)
+        .plugin_instances
+        .into_iter()
+        .flat_map(|plugin_instance| {
+            let plugin_instance_name = plugin_instance.name;
+            plugin_instance.modules.into_iter().map(move |module| {
+                let mut active_actions = module.active_actions.into_iter().collect::<Vec<_>>();
+                active_actions.sort();
+                StatusInfo {
+                    identifier: format!("{}{}{}", plugin_instance_name, SEPARATOR, module.name),
+                    status: module.status,
+                    message: module.message,
+                    active_actions,
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_string_pretty(&statuses).expect("serialization failed")
+}
+
+/// Parses `<plugin instance>::<module>::<action>`
+fn parse_action_identifier(action_string: &str) -> Option<ActionIdentifier> {
+    let tokens = action_string.split(SEPARATOR).collect::<Vec<_>>();
+    if tokens.len() != 3 {
+        return None;
+    }
+    Some(ActionIdentifier {
+        plugin_instance: tokens[0].to_string(),
+        module: tokens[1].to_string(),
+        action: tokens[2].to_string(),
+    })
+}
+
+fn call_action_string(lua: &Lua, ctx: &LuaContext, action_string: &str) -> anyhow::Result<()> {
+    let identifier = parse_action_identifier(action_string)
+        .with_context(|| format!("malformed action identifier: \"{}\"", action_string))?;
+
+    // Cloning the list, so that the lock isn't held while the action runs
+    let plugin_instances = ctx.plugin_instances.read().unwrap().clone();
+    call_tracked_action(
+        lua,
+        &plugin_instances,
+        &ctx.action_callers,
+        identifier,
+        Value::Nil,
+        Caller::LOCAL,
+    )
+}
 
-    let actions = module.actions.read().unwrap();
-    let action = match actions.iter().find(|a| a.name == identifier.action) {
-        None => anyhow::bail!("no action with name {}", identifier.action),
-        Some(a) => a,
+/// Calls the action and remembers its caller while it runs, see `LuaContext::action_callers`
+fn call_tracked_action(
+    lua: &Lua,
+    plugin_instances: &[Arc<PluginInstance>],
+    action_callers: &Mutex<Vec<Caller>>,
+    identifier: ActionIdentifier,
+    args: Value,
+    caller: Caller,
+) -> anyhow::Result<()> {
+    action_callers.lock().unwrap().push(caller);
+    let result = call_action(lua, plugin_instances, identifier, args, caller);
+    action_callers.lock().unwrap().pop();
+    result
+}
+
+fn call_action(
+    lua: &Lua,
+    plugin_instances: &[Arc<PluginInstance>],
+    identifier: ActionIdentifier,
+    args: Value,
+    caller: Caller,
+) -> anyhow::Result<()> {
+    let plugin_instance = match plugin_instances
+        .iter()
+        .find(|p| p.name == identifier.plugin_instance)
+    {
+        None => anyhow::bail!(
+            "no plugin instance with name {}",
+            identifier.plugin_instance
+        ),
+        Some(p) => p,
     };
 
-    let callback = lua
-        .registry_value::<Function>(&action.key)
-        .context("action key has no corresponding callback in lua registry")?;
+    // The locks are released before calling the callback, since it may register or unregister
+    // modules and actions
+    let (callback, last_call) = {
+        let modules = plugin_instance.modules.read().unwrap();
+        let module = match modules.iter().find(|m| m.name == identifier.module) {
+            None => anyhow::bail!("no module with name {}", identifier.module),
+            Some(m) => m,
+        };
+
+        let actions = module.actions.read().unwrap();
+        let action = match actions.iter().find(|a| a.name == identifier.action) {
+            None => anyhow::bail!("no action with name {}", identifier.action),
+            Some(a) => a,
+        };
+
+        if let Some(cooldown) = action.cooldown {
+            if let Some(last_call) = *action.last_call.lock().unwrap() {
+                let elapsed = last_call.elapsed();
+                if elapsed < cooldown {
+                    anyhow::bail!(
+                        "action {} is cooling down, try again in {}ms",
+                        identifier,
+                        (cooldown - elapsed).as_millis()
+                    );
+                }
+            }
+        }
+
+        let callback = lua
+            .registry_value::<Function>(&action.key)
+            .context("action key has no corresponding callback in lua registry")?;
+        (callback, action.last_call.clone())
+    };
 
     callback
-        .call::<_, ()>(())
+        .call::<_, ()>((args, caller))
         .context("action callback failed")?;
 
+    *last_call.lock().unwrap() = Some(Instant::now());
+
     Ok(())
 }
 
-fn system_info(ctx: &LuaContext) -> SystemInfo {
-    let plugin_instances = ctx
-        .plugin_instances
-        .read()
-        .unwrap()
+/// Plugin instances are sorted by name and modules by their order and then by name, so that
+/// interfaces get the same layout regardless of the registration order.
+fn system_info(plugin_instances: &[Arc<PluginInstance>]) -> SystemInfo {
+    let mut plugin_instances: Vec<PluginInstanceInfo> = plugin_instances
         .iter()
         .map(|plugin_instance| {
             let name = plugin_instance.name.clone();
-            let modules = plugin_instance
+            let mut modules: Vec<ModuleInfo> = plugin_instance
                 .modules
                 .read()
                 .unwrap()
@@ -360,19 +1478,12 @@ fn system_info(ctx: &LuaContext) -> SystemInfo {
                 .map(|module| {
                     let name = module.name.clone();
                     let display_name = module.display_name.clone();
-                    let actions = module
-                        .actions
-                        .read()
-                        .unwrap()
-                        .iter()
-                        .map(|action| ActionInfo {
-                            name: action.name.clone(),
-                            display_name: action.display_name.clone(),
-                        })
-                        .collect();
+                    let actions = module.action_infos();
                     let active_actions = module.active_actions.read().unwrap().clone();
                     let status = module.status.read().unwrap().clone();
                     let message = module.message.read().unwrap().clone();
+                    let icon = module.icon.read().unwrap().clone();
+                    let order = *module.order.read().unwrap();
 
                     ModuleInfo {
                         name,
@@ -381,15 +1492,35 @@ fn system_info(ctx: &LuaContext) -> SystemInfo {
                         active_actions,
                         status,
                         message,
+                        icon,
+                        order,
                     }
                 })
                 .collect();
+            modules.sort_by(|a, b| (a.order, &a.name).cmp(&(b.order, &b.name)));
 
-            PluginInstanceInfo { name, modules }
+            let mut image_windows: Vec<ImageWindowInfo> = plugin_instance
+                .image_windows
+                .lock()
+                .unwrap()
+                .values()
+                .map(|image_window| image_window.lock().unwrap().clone())
+                .collect();
+            image_windows.sort_by(|a, b| a.name.cmp(&b.name));
+
+            PluginInstanceInfo {
+                name,
+                modules,
+                image_windows,
+            }
         })
         .collect();
+    plugin_instances.sort_by(|a, b| a.name.cmp(&b.name));
 
-    SystemInfo { plugin_instances }
+    SystemInfo {
+        plugin_instances,
+        loading: false,
+    }
 }
 
 /// Wraps the `mlua::create_function` call and passes the `ctx` as the third argument to `func`.
@@ -409,12 +1540,68 @@ where
     lua.create_function(move |lua, lua_args| func(lua, lua_args, ctx.clone()))
 }
 
-fn inject_plugin_api(lua: &Lua, neopult: &Table, ctx: Arc<LuaContext>) -> anyhow::Result<()> {
-    log::inject_log_functions(lua, neopult).context("error when injecting log functions")?;
-    api::inject_api_functions(lua, neopult, ctx).context("error when injecting api functions")?;
+fn inject_plugin_api(lua: &Lua, neopult: &Table, ctx: Arc<LuaContext>) -> error::Result<()> {
+    log::inject_log_functions(lua, neopult)
+        .map_err(PluginSystemError::lua("error when injecting log functions"))?;
+    api::inject_api_functions(lua, neopult, ctx)
+        .map_err(PluginSystemError::lua("error when injecting api functions"))?;
     Ok(())
 }
 
+/// Cleans up processes of a previous run or creates the PID directory if it doesn't exist yet.
+fn prepare_pid_dir(pid_dir_path: &Path) {
+    match fs::read_dir(pid_dir_path) {
+        Ok(items) => {
+            clean_old_processes(items);
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            if let Err(e) = fs::create_dir_all(pid_dir_path) {
+                error!(
+                    "couldn't create PID directory {}: {}",
+                    pid_dir_path.display(),
+                    e
+                );
+            }
+        }
+        Err(e) => {
+            error!(
+                "couldn't read PID directory {}: {}",
+                pid_dir_path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Start time of the process in clock ticks after boot (field 22 of `/proc/<pid>/stat`). Together
+/// with the PID, it identifies a process, since PIDs can be reused by unrelated processes.
+fn process_start_time(pid: i32) -> Option<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name in parentheses may contain spaces, so the fields are counted after it
+    let after_comm = &stat[stat.rfind(')')? + 1..];
+    after_comm.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// The PID file contains the start time of the process on the first line and the command on the
+/// second line, so that the process can be recognized when cleaning up after a restart.
+fn create_pid_file(pid_dir_path: &Path, pid: u32, cmd: &str) -> io::Result<PathBuf> {
+    let pid_file_path = pid_dir_path.join(format!("{}.pid", pid));
+    let start_time = process_start_time(pid as i32)
+        .map(|start_time| start_time.to_string())
+        .unwrap_or_default();
+    fs::write(&pid_file_path, format!("{}\n{}\n", start_time, cmd))?;
+    Ok(pid_file_path)
+}
+
+/// Whether the process with the PID is still the one that the PID file was created for.
+fn is_same_process(pid: i32, pid_file_contents: &str) -> bool {
+    let recorded_start_time = match pid_file_contents.lines().next().map(str::parse::<u64>) {
+        Some(Ok(start_time)) => start_time,
+        _ => return false,
+    };
+    process_start_time(pid) == Some(recorded_start_time)
+}
+
 fn clean_old_processes(pid_items: ReadDir) {
     // The performance isn't ideal, because processes are killed one after another, with each
     // process having a grace period to shut down after a SIGINT. But ideally needing to clean old
@@ -425,27 +1612,35 @@ fn clean_old_processes(pid_items: ReadDir) {
         let filename = filename_os.to_string_lossy();
         if filename.ends_with(".pid") {
             match filename.trim_end_matches(".pid").parse::<i32>() {
-                Ok(pid) if pid > 0 => {
-                    debug!("found old process with pid {}", pid);
-                    let pid = Pid::from_raw(pid);
+                Ok(raw_pid) if raw_pid > 0 => {
+                    debug!("found old process with pid {}", raw_pid);
+                    let contents = fs::read_to_string(&path).unwrap_or_default();
+                    let pid = Pid::from_raw(raw_pid);
                     if signal::kill(pid, None).is_err() {
                         debug!("old process with pid {} is already dead", pid);
+                    } else if !is_same_process(raw_pid, &contents) {
+                        warn!(
+                            "not killing process with pid {}, because it isn't the old process \
+                            (the pid was probably reused)",
+                            pid
+                        );
                     } else {
                         debug!("old process with pid {} is still alive", pid);
                         kill_old_process(pid);
                     }
-
-                    if let Err(e) = fs::remove_file(path) {
-                        warn!("error removing old process pid file: {}", e);
-                    }
                 }
                 Ok(pid) => {
                     warn!("found old process with invalid pid {}, somebody might have messed with the pid directory", pid);
                 }
                 Err(e) => {
-                    error!("error parsing pid filename: {}", e);
+                    warn!("found pid file {} without a pid: {}", filename, e);
                 }
             }
+
+            // Files without a valid pid can't be cleaned up later either
+            if let Err(e) = fs::remove_file(path) {
+                warn!("error removing old process pid file: {}", e);
+            }
         }
     }
 }
@@ -485,96 +1680,95 @@ pub struct PluginSystem {
     shutdown_wait_sender: mpsc::Sender<()>,
     plugin_shutdown_wait_receiver: mpsc::Receiver<()>,
     plugin_shutdown_wait_sender: Arc<mpsc::Sender<()>>,
+    plugins_loaded: Arc<AtomicBool>,
+    /// Config that `get_config` read last, so that the event loop doesn't read it again and
+    /// report invalid values a second time
+    lua_config: Mutex<Option<config::LuaConfig>>,
 }
 
 impl PluginSystem {
+    #[allow(clippy::too_many_arguments)]
     pub fn init(
         main_runtime_handle: tokio::runtime::Handle,
         env_config: EnvConfig,
+        process_io_threads: Option<usize>,
         shutdown_channels: ShutdownChannels,
         event_tx: mpsc::Sender<Event>,
         event_rx: mpsc::Receiver<Event>,
         notification_tx: broadcast::Sender<Notification>,
         window_manager: WindowManager,
-    ) -> anyhow::Result<PluginSystem> {
+    ) -> error::Result<PluginSystem> {
         let lua = Lua::new();
 
         let (plugin_shutdown_wait_sender, plugin_shutdown_wait_receiver) = mpsc::channel::<()>(1);
         let plugin_shutdown_wait_sender = Arc::new(plugin_shutdown_wait_sender);
 
-        let runtime = tokio::runtime::Builder::new_current_thread().build()?;
-
-        {
-            let globals = lua.globals();
-
-            // Look for lua modules in the specified paths first
-            let package_table = globals.get::<_, Table>("package")?;
-            let lua_path: String = package_table.get("path")?;
-            let channel_path = env_config.channel_home.display().to_string();
-            let mut neopult_lua_path = String::new();
-            for path in [&channel_path, GLOBAL_DATA_DIR] {
-                neopult_lua_path = format!(
-                    "{}{}/?.lua;{}/plugins/?.lua;{}/plugins/?/init.lua;",
-                    neopult_lua_path, path, path, path
-                );
-            }
-            package_table.set("path", neopult_lua_path + &lua_path)?;
-        }
-
-        let pid_dir_path = PathBuf::from(format!("/tmp/neopult-channel-{}", env_config.channel));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .map_err(PluginSystemError::io("couldn't build plugin runtime"))?;
+        let process_io_runtime = match process_io_threads {
+            Some(threads) => Some(build_process_io_runtime(threads).map_err(
+                PluginSystemError::io("couldn't build runtime for process I/O"),
+            )?),
+            None => None,
+        };
 
-        match fs::read_dir(&pid_dir_path) {
-            Ok(items) => {
-                clean_old_processes(items);
-            }
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                if let Err(e) = fs::create_dir_all(&pid_dir_path) {
-                    error!(
-                        "couldn't create PID directory {}: {}",
-                        pid_dir_path.display(),
-                        e
-                    );
-                }
-            }
-            Err(e) => {
-                error!(
-                    "couldn't read PID directory {}: {}",
-                    pid_dir_path.display(),
-                    e
-                );
-            }
-        }
+        let search_dirs = lua_search_dirs(&env_config);
+        // Look for lua modules in the specified paths first
+        lua.globals()
+            .get::<_, Table>("package")
+            .and_then(|package_table| {
+                let lua_path: String = package_table.get("path")?;
+                package_table.set("path", neopult_lua_path(&search_dirs) + &lua_path)
+            })
+            .map_err(PluginSystemError::lua(
+                "error when setting the lua package path",
+            ))?;
+
+        let pid_dir_path = env_config.pid_dir_path();
+        prepare_pid_dir(&pid_dir_path);
+        let module_orders = ModuleOrders::load(
+            env_config
+                .state_dir()
+                .join(module_orders::MODULE_ORDERS_FILE_NAME),
+        );
 
         let ctx = Arc::new(LuaContext {
             env_config: Arc::new(env_config),
             main_runtime_handle,
             plugin_runtime: runtime,
+            process_io_runtime,
             plugin_instances: RwLock::new(Vec::new()),
             event_sender: Arc::new(event_tx),
             window_manager: RwLock::new(window_manager),
             notification_sender: Arc::new(notification_tx),
+            notification_coalescer: RwLock::new(None),
             shutdown_sender: shutdown_channels.shutdown_sender,
             // The context must not own the plugin shutdown wait sender because we won't be able to drop
             // every context reference on shutdown.
             plugin_shutdown_wait_sender: Arc::downgrade(&plugin_shutdown_wait_sender),
             run_later_tasks: Mutex::new(VecDeque::new()),
+            idle_tasks: Mutex::new(VecDeque::new()),
+            once_keys: Mutex::new(HashSet::new()),
+            cli_commands: Mutex::new(HashMap::new()),
+            held_locks: Arc::new(Mutex::new(HashSet::new())),
+            access_tokens: Arc::new(AccessTokens::default()),
+            action_callers: Mutex::new(Vec::new()),
+            status_observers: Mutex::new(StatusObservers::default()),
+            spawn_limits: Mutex::new(None),
             pid_dir_path,
+            module_orders,
         });
 
-        let neopult = lua.create_table()?;
-        inject_plugin_api(&lua, &neopult, ctx.clone())
-            .context("error when injecting plugin api")?;
-        config::inject_config_table(&lua, &neopult).context("error when injecting config table")?;
-        lua.globals().set("neopult", neopult)?;
-
-        info!("loading plugins");
-
-        lua.load(r#"require("init")"#)
-            .set_name("init.lua")?
-            .exec()
-            .context("error when loading plugins")?;
-
-        info!("plugins loaded");
+        let neopult = lua
+            .create_table()
+            .map_err(PluginSystemError::lua("error when creating neopult table"))?;
+        inject_plugin_api(&lua, &neopult, ctx.clone())?;
+        config::inject_config_table(&lua, &neopult)
+            .map_err(PluginSystemError::lua("error when injecting config table"))?;
+        lua.globals()
+            .set("neopult", neopult)
+            .map_err(PluginSystemError::lua("error when setting neopult table"))?;
 
         let plugin_system = PluginSystem {
             lua,
@@ -583,43 +1777,114 @@ impl PluginSystem {
             shutdown_wait_sender: shutdown_channels.shutdown_wait_sender,
             plugin_shutdown_wait_receiver,
             plugin_shutdown_wait_sender,
+            plugins_loaded: Arc::new(AtomicBool::new(false)),
+            lua_config: Mutex::new(None),
         };
         Ok(plugin_system)
     }
 
-    pub fn get_config(&self) -> anyhow::Result<Config> {
-        let lua_config = config::get_config(&self.lua)?;
+    /// Runs `init.lua`, which loads the plugins. Afterwards, clients that connected in the
+    /// meantime are notified.
+    pub fn load_plugins(&self) -> error::Result<()> {
+        info!("loading plugins");
+        load_init(&self.lua, &lua_search_dirs(&self.ctx.env_config))?;
+        info!("plugins loaded");
+
+        self.plugins_loaded.store(true, Ordering::SeqCst);
+        let _ = self
+            .ctx
+            .notification_sender
+            .send(Notification::PluginsLoaded);
+        Ok(())
+    }
+
+    pub fn get_config(&self) -> error::Result<Config> {
+        let lua_config = config::get_config(&self.lua, &self.ctx.env_config.channel_home)?;
+        // With deferred plugin loading, init.lua didn't run yet
+        if self.plugins_loaded.load(Ordering::SeqCst) {
+            self.ctx.spawn_limits(&self.lua);
+        }
 
         let config = Config {
             channel: self.ctx.env_config.channel,
-            neopult_home: self.ctx.env_config.neopult_home.clone(),
             channel_home: self.ctx.env_config.channel_home.clone(),
-            websocket_password: lua_config.websocket_password,
+            websocket_passwords: lua_config.websocket_passwords.clone(),
+            viewer_websocket_passwords: lua_config.viewer_websocket_passwords.clone(),
+            idle_timeout: lua_config.idle_timeout_ms.map(Duration::from_millis),
+            idle_disconnect: lua_config.idle_disconnect_ms.map(Duration::from_millis),
+            cors_allowed_origins: lua_config.cors_allowed_origins.clone(),
+            max_message_bytes: lua_config.max_message_bytes as usize,
+            access_tokens: self.ctx.access_tokens.clone(),
+            plugins_loaded: self.plugins_loaded.clone(),
         };
+        *self.lua_config.lock().unwrap() = Some(lua_config);
 
         Ok(config)
     }
 
-    pub fn event_loop(self) -> io::Result<()> {
+    pub fn event_loop(self) -> error::Result<()> {
         let lua = self.lua;
         let ctx = self.ctx;
-        let mut event_receiver = self.event_receiver;
+        let mut event_queue = EventQueue::new(self.event_receiver);
         let shutdown_wait_sender = self.shutdown_wait_sender;
         let mut plugin_shutdown_wait_receiver = self.plugin_shutdown_wait_receiver;
         let plugin_shutdown_wait_sender = self.plugin_shutdown_wait_sender;
 
         let mut shutdown_receiver = ctx.shutdown_sender.subscribe();
 
+        let lua_config = match self.lua_config.into_inner().unwrap() {
+            Some(lua_config) => lua_config,
+            None => config::get_config(&lua, &ctx.env_config.channel_home).unwrap_or_else(|e| {
+                error!(
+                    "couldn't read config for the event loop (using defaults): {:?}",
+                    e
+                );
+                Default::default()
+            }),
+        };
+        let watchdog =
+            EventWatchdog::new(Duration::from_millis(lua_config.slow_event_threshold_ms));
+        let audit_log_path = ctx.env_config.channel_home.join(
+            lua_config
+                .audit_log_path
+                .unwrap_or_else(|| PathBuf::from(audit_log::DEFAULT_AUDIT_LOG_FILE_NAME)),
+        );
+        debug!("using audit log {}", audit_log_path.display());
+        let audit_log = AuditLog::new(audit_log_path, lua_config.audit_log_max_bytes);
+        if let Some(mut wm) = ctx.write_window_manager() {
+            wm.set_mode_fallback(lua_config.mode_fallback);
+        }
+        if lua_config.reanchor != Reanchor::default() {
+            if let Some(mut wm) = ctx.write_window_manager() {
+                if let Err(e) = wm.set_reanchor(&lua, lua_config.reanchor) {
+                    error!(
+                        "error when repositioning windows after setting reanchor: {:?}",
+                        e
+                    );
+                }
+            }
+            ctx.sync_attached_windows();
+        }
+        *ctx.notification_coalescer.write().unwrap() =
+            lua_config.notification_coalesce_ms.map(|window_ms| {
+                Arc::new(NotificationCoalescer::new(
+                    Duration::from_millis(window_ms),
+                    ctx.notification_sender.clone(),
+                ))
+            });
+
         info!("starting event loop");
 
         let mut event_loop_counter = 0;
         loop {
+            let mut ran_run_later_tasks = false;
             // Inner block is necessary to drop the mutex guard, so that `run_later` can be called
             // from inside `run_later` tasks.
             while let Some(func_key) = {
                 let mut run_later_tasks = ctx.run_later_tasks.lock().unwrap();
                 run_later_tasks.pop_front()
             } {
+                ran_run_later_tasks = true;
                 if let Ok(func) = lua.registry_value::<Function>(&func_key) {
                     if let Err(e) = func.call::<_, Value>(()) {
                         error!("error when calling run_later function: {:?}", e);
@@ -628,21 +1893,63 @@ impl PluginSystem {
                 let _ = lua.remove_registry_value(func_key);
             }
 
-            let event_option = ctx.plugin_runtime.block_on({
-                async {
-                    tokio::select!(
-                        event_option = event_receiver.recv() => event_option,
-                        _ = shutdown_receiver.recv() => {
-                            None
+            let has_idle_tasks = !ctx.idle_tasks.lock().unwrap().is_empty();
+            let step = next_loop_step(
+                &mut event_queue,
+                &ctx.plugin_instances.read().unwrap(),
+                ran_run_later_tasks,
+                has_idle_tasks,
+            );
+            let event_option = match step {
+                // Queued events don't block, so shutdown has to be checked separately
+                LoopStep::Handle(event) => match shutdown_receiver.try_recv() {
+                    Ok(_) => None,
+                    Err(_) => Some(event),
+                },
+                LoopStep::NextTick => continue,
+                LoopStep::RunIdleTasks => {
+                    let deferred_again = run_idle_tasks(&lua, &ctx.idle_tasks);
+                    // Otherwise tasks that keep deferring themselves would busy loop without
+                    // ever checking for shutdown
+                    let timeout = if deferred_again {
+                        IDLE_TASK_RETRY_DELAY
+                    } else {
+                        Duration::ZERO
+                    };
+                    let woken = ctx.plugin_runtime.block_on(async {
+                        tokio::select!(
+                            biased;
+                            _ = shutdown_receiver.recv() => None,
+                            event_option = event_queue.wait_for_event(timeout) => Some(event_option),
+                        )
+                    });
+                    match woken {
+                        Some(Some(event)) => {
+                            event_queue.push(event, &ctx.plugin_instances.read().unwrap())
                         }
-                    )
+                        Some(None) => {}
+                        None => break,
+                    }
+                    continue;
                 }
-            });
+                LoopStep::Wait => ctx.plugin_runtime.block_on({
+                    async {
+                        tokio::select!(
+                            event_option = event_queue.recv() => event_option,
+                            _ = shutdown_receiver.recv() => {
+                                None
+                            }
+                        )
+                    }
+                }),
+            };
 
             // Handling the event must happen outside of the async runtime, so that non-async rust
             // functions that are called from lua can call `block_on` on the runtime.
             match event_option {
-                Some(event) => handle_event(&lua, &ctx, event),
+                Some(event) => {
+                    watchdog.watch(event.kind(), || handle_event(&lua, &ctx, &audit_log, event));
+                }
                 None => break,
             };
 
@@ -661,20 +1968,11 @@ impl PluginSystem {
             .unwrap()
             .iter()
             .for_each(|plugin_instance| {
-                if let Some(ref callback_key) = plugin_instance.on_cleanup {
-                    match lua.registry_value::<Function>(callback_key) {
-                        Ok(callback) => {
-                            if let Err(e) = callback.call::<_, Value>(()) {
-                                plugin_instance
-                                    .error(format!("error when calling cleanup callback: {:?}", e));
-                            }
-                        }
-                        Err(e) => {
-                            plugin_instance
-                                .error(format!("error when retreiving cleanup callback. {:?}", e));
-                        }
-                    }
-                }
+                plugin_instance.call_callback(
+                    &lua,
+                    &plugin_instance.callbacks.on_cleanup,
+                    "cleanup",
+                );
             });
 
         ctx.plugin_runtime.block_on(async {
@@ -691,7 +1989,139 @@ impl PluginSystem {
     }
 }
 
-fn handle_event(lua: &Lua, ctx: &LuaContext, event: Event) {
+#[derive(Debug)]
+enum LoopStep {
+    Handle(Event),
+    /// Starts another tick to find out whether the event loop is quiet
+    NextTick,
+    /// Nothing happened during the last tick
+    RunIdleTasks,
+    /// Blocks until the next event arrives
+    Wait,
+}
+
+/// Events of the event loop. Calls of high priority actions jump ahead of the events that are
+/// already pending, other events are handled in order.
+struct EventQueue {
+    receiver: mpsc::Receiver<Event>,
+    /// Pending calls of high priority actions
+    high: VecDeque<Event>,
+    /// Other pending events
+    normal: VecDeque<Event>,
+}
+
+impl EventQueue {
+    fn new(receiver: mpsc::Receiver<Event>) -> Self {
+        Self {
+            receiver,
+            high: VecDeque::new(),
+            normal: VecDeque::new(),
+        }
+    }
+
+    /// Returns the next event without waiting. Takes events from the channel until
+    /// `MAX_PENDING_EVENTS` normal events are pending, so that high priority calls can skip those
+    /// while the channel still applies backpressure.
+    fn try_next(&mut self, plugin_instances: &[Arc<PluginInstance>]) -> Option<Event> {
+        while self.normal.len() < MAX_PENDING_EVENTS {
+            match self.receiver.try_recv() {
+                Ok(event) => self.push(event, plugin_instances),
+                Err(_) => break,
+            }
+        }
+        self.high.pop_front().or_else(|| self.normal.pop_front())
+    }
+
+    /// Adds an event that was received from the channel. The priority is only looked up once.
+    fn push(&mut self, event: Event, plugin_instances: &[Arc<PluginInstance>]) {
+        match event_priority(&event, plugin_instances) {
+            ActionPriority::High => self.high.push_back(event),
+            ActionPriority::Normal => self.normal.push_back(event),
+        }
+    }
+
+    /// Waits for the next event to arrive. Only called when no events are pending.
+    async fn recv(&mut self) -> Option<Event> {
+        self.receiver.recv().await
+    }
+
+    /// Waits up to `timeout` for the next event, which has to be `push`ed afterwards
+    async fn wait_for_event(&mut self, timeout: Duration) -> Option<Event> {
+        tokio::time::timeout(timeout, self.receiver.recv())
+            .await
+            .ok()
+            .flatten()
+    }
+}
+
+/// Calls of actions get the priority of the action, no matter whether a client or the terminal
+/// calls them
+fn event_priority(event: &Event, plugin_instances: &[Arc<PluginInstance>]) -> ActionPriority {
+    match event {
+        Event::ClientCommand(ClientCommand::CallAction { identifier, .. }) => {
+            action_priority(identifier, plugin_instances)
+        }
+        Event::CliCommand { command, .. } => command
+            .strip_prefix("call ")
+            .and_then(parse_action_identifier)
+            .map(|identifier| action_priority(&identifier, plugin_instances))
+            .unwrap_or_default(),
+        _ => ActionPriority::Normal,
+    }
+}
+
+fn action_priority(
+    identifier: &ActionIdentifier,
+    plugin_instances: &[Arc<PluginInstance>],
+) -> ActionPriority {
+    plugin_instances
+        .iter()
+        .find(|p| p.name == identifier.plugin_instance)
+        .and_then(|plugin_instance| {
+            let modules = plugin_instance.modules.read().unwrap();
+            let module = modules.iter().find(|m| m.name == identifier.module)?;
+            let actions = module.actions.read().unwrap();
+            let action = actions.iter().find(|a| a.name == identifier.action)?;
+            Some(action.priority)
+        })
+        .unwrap_or_default()
+}
+
+/// Idle tasks only run after a tick in which neither an event was pending nor a `run_later` task
+/// ran. Without idle tasks, the event loop just waits for the next event.
+fn next_loop_step(
+    event_queue: &mut EventQueue,
+    plugin_instances: &[Arc<PluginInstance>],
+    ran_run_later_tasks: bool,
+    has_idle_tasks: bool,
+) -> LoopStep {
+    if let Some(event) = event_queue.try_next(plugin_instances) {
+        LoopStep::Handle(event)
+    } else if !has_idle_tasks {
+        LoopStep::Wait
+    } else if ran_run_later_tasks {
+        LoopStep::NextTick
+    } else {
+        LoopStep::RunIdleTasks
+    }
+}
+
+/// Only runs the tasks that were queued before. Tasks that are deferred while the idle tasks run
+/// have to wait for the next quiet tick. Returns whether there are such tasks.
+fn run_idle_tasks(lua: &Lua, idle_tasks: &Mutex<VecDeque<RegistryKey>>) -> bool {
+    let func_keys = std::mem::take(&mut *idle_tasks.lock().unwrap());
+    for func_key in func_keys {
+        if let Ok(func) = lua.registry_value::<Function>(&func_key) {
+            if let Err(e) = func.call::<_, Value>(()) {
+                error!("error when calling defer_until_idle function: {:?}", e);
+            }
+        }
+        let _ = lua.remove_registry_value(func_key);
+    }
+    !idle_tasks.lock().unwrap().is_empty()
+}
+
+fn handle_event(lua: &Lua, ctx: &LuaContext, audit_log: &AuditLog, event: Event) {
     match event {
         Event::CliCommand {
             command,
@@ -705,6 +2135,12 @@ fn handle_event(lua: &Lua, ctx: &LuaContext, event: Event) {
                 let statuses = list_statuses(ctx);
                 let reply = statuses.join("\n");
                 let _ = reply_sender.send(reply);
+            } else if command == "statuses --json" {
+                let reply = statuses_json(&ctx.plugin_instances.read().unwrap());
+                let _ = reply_sender.send(reply);
+            } else if command == "system-info" {
+                let reply = system_info_json(&ctx.plugin_instances.read().unwrap());
+                let _ = reply_sender.send(reply);
             } else if let Some(identifier) = command.strip_prefix("call ") {
                 match call_action_string(lua, ctx, identifier) {
                     Ok(_) => {
@@ -714,6 +2150,8 @@ fn handle_event(lua: &Lua, ctx: &LuaContext, event: Event) {
                         let _ = reply_sender.send(format!("error when calling action: {:?}", e));
                     }
                 }
+            } else if let Some(reply) = call_cli_command(lua, &ctx.cli_commands, &command) {
+                let _ = reply_sender.send(reply);
             } else {
                 let _ = reply_sender.send(format!("unknown command: {}", command));
             }
@@ -722,31 +2160,1370 @@ fn handle_event(lua: &Lua, ctx: &LuaContext, event: Event) {
             line,
             process_name,
             plugin_instance,
-            callback_key,
+            listeners,
         } => {
-            if let Ok(callback) = lua.registry_value::<Function>(&callback_key) {
-                if let Err(e) = callback.call::<_, Value>(line) {
-                    plugin_instance.error(format!(
-                        "error when handling callback for process {}: {:?}",
-                        process_name, e
-                    ));
-                }
-            }
+            listeners.call(lua, line, &process_name, &plugin_instance);
         }
         Event::FetchSystemInfo { reply_sender } => {
-            let system_info = system_info(ctx);
+            let system_info = system_info(&ctx.plugin_instances.read().unwrap());
             if reply_sender.send(system_info).is_err() {
                 warn!("fetch system info: reply receiver was closed");
             }
         }
+        Event::FetchActionCatalog { reply_sender } => {
+            let catalog = action_catalog(&ctx.plugin_instances.read().unwrap());
+            if reply_sender.send(catalog).is_err() {
+                warn!("fetch action catalog: reply receiver was closed");
+            }
+        }
+        Event::Idle => {
+            info!("no clients connected, plugin system is idle");
+            for plugin_instance in ctx.plugin_instances.read().unwrap().iter() {
+                plugin_instance.call_callback(lua, &plugin_instance.callbacks.on_idle, "idle");
+            }
+        }
+        Event::Resume => {
+            info!("client connected, plugin system resumes");
+            for plugin_instance in ctx.plugin_instances.read().unwrap().iter() {
+                plugin_instance.call_callback(lua, &plugin_instance.callbacks.on_resume, "resume");
+            }
+        }
+        Event::ScreenResolutionChanged { width, height } => {
+            for plugin_instance in ctx.plugin_instances.read().unwrap().iter() {
+                let callback_key = match plugin_instance.callbacks.on_screen_resolution_change {
+                    Some(ref callback_key) => callback_key,
+                    None => continue,
+                };
+                let result = lua
+                    .registry_value::<Function>(callback_key)
+                    .and_then(|callback| callback.call::<_, Value>((width, height)));
+                if let Err(e) = result {
+                    plugin_instance.error(format!(
+                        "error when calling screen resolution change callback: {:?}",
+                        e
+                    ));
+                }
+            }
+        }
+        Event::WindowsLost {
+            plugin_instance,
+            wids,
+        } => {
+            if let Some(ref callback_key) = plugin_instance.callbacks.on_windows_lost {
+                let result = lua
+                    .registry_value::<Function>(callback_key)
+                    .and_then(|callback| callback.call::<_, Value>(wids));
+                if let Err(e) = result {
+                    plugin_instance
+                        .error(format!("error when calling windows lost callback: {:?}", e));
+                }
+            }
+        }
+        Event::Timer { callback_key } => match lua.registry_value::<Function>(&callback_key) {
+            Ok(callback) => {
+                if let Err(e) = callback.call::<_, Value>(()) {
+                    error!("error when calling timer callback: {:?}", e);
+                }
+            }
+            Err(e) => error!("couldn't get timer callback from lua registry: {:?}", e),
+        },
+        Event::ModuleStatusChange {
+            module_identifier,
+            new_status,
+        } => {
+            call_status_observers(
+                lua,
+                &ctx.status_observers,
+                &module_identifier,
+                new_status.as_deref(),
+            );
+        }
+        Event::ProcessExit {
+            process_name,
+            plugin_instance,
+            callback_key,
+            exit,
+        } => {
+            let result = lua
+                .registry_value::<Function>(&callback_key)
+                .and_then(|callback| callback.call::<_, Value>(exit.to_lua_table(lua)?));
+            if let Err(e) = result {
+                plugin_instance.error(format!(
+                    "error when calling on_exit callback of process {}: {:?}",
+                    process_name, e
+                ));
+            }
+        }
+        Event::PluginTimer {
+            plugin_instance,
+            timer_id,
+        } => {
+            // Timers that were cleared after the event was sent don't fire anymore
+            if let Some(callback_key) = plugin_instance.timers.fire(timer_id) {
+                match lua.registry_value::<Function>(&callback_key) {
+                    Ok(callback) => {
+                        if let Err(e) = callback.call::<_, Value>(()) {
+                            plugin_instance.error(format!(
+                                "error when calling callback of timer {}: {:?}",
+                                timer_id, e
+                            ));
+                        }
+                    }
+                    Err(e) => plugin_instance.error(format!(
+                        "couldn't get callback of timer {} from lua registry: {:?}",
+                        timer_id, e
+                    )),
+                }
+            }
+        }
         Event::ClientCommand(cmd) => match cmd {
             ClientCommand::CallAction {
                 identifier,
+                caller,
                 error_sender,
             } => {
-                let call_result = call_action(lua, ctx, identifier);
+                let plugin_instances = ctx.plugin_instances.read().unwrap().clone();
+                let call_result = call_tracked_action(
+                    lua,
+                    &plugin_instances,
+                    &ctx.action_callers,
+                    identifier.clone(),
+                    Value::Nil,
+                    caller,
+                );
+                audit_log.record_call_action(&identifier, caller, &call_result);
                 let _ = error_sender.send(call_result);
             }
+            ClientCommand::SetModuleOrder {
+                orders,
+                error_sender,
+            } => {
+                let result = set_module_order(
+                    &ctx.plugin_instances.read().unwrap(),
+                    orders,
+                    &ctx.module_orders,
+                    &ctx.notification_sender,
+                );
+                let _ = error_sender.send(result);
+            }
+            ClientCommand::SetModuleMessage {
+                module_identifier,
+                message,
+                severity,
+                error_sender,
+            } => {
+                let result = set_module_message(
+                    &ctx.plugin_instances.read().unwrap(),
+                    module_identifier,
+                    message,
+                    severity,
+                )
+                .map(|module| ctx.notify_module_update(&module, UpdateKind::Message));
+                let _ = error_sender.send(result);
+            }
         },
     }
 }
+
+fn build_process_io_runtime(worker_threads: usize) -> io::Result<tokio::runtime::Runtime> {
+    // Tokio panics instead of returning an error for zero worker threads
+    if worker_threads == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "at least one worker thread is needed",
+        ));
+    }
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(worker_threads)
+        .thread_name("neopult-process-io")
+        .enable_all()
+        .build()
+}
+
+/// The channel home comes first, so that channels can override plugins of the global data directory
+fn lua_search_dirs(env_config: &EnvConfig) -> [String; 2] {
+    [
+        env_config.channel_home.display().to_string(),
+        env_config.data_dir.display().to_string(),
+    ]
+}
+
+/// Plugins that `require` would find, without loading them, see `find_plugins`
+pub fn list_plugins(env_config: &EnvConfig) -> Vec<(String, PathBuf)> {
+    find_plugins(&lua_search_dirs(env_config))
+}
+
+/// Finds `plugins/<name>.lua` files and `plugins/<name>/init.lua` directories in the search
+/// directories, sorted by name. Like with the lua path, plugins in earlier search directories
+/// shadow plugins with the same name in later ones.
+fn find_plugins(search_dirs: &[String]) -> Vec<(String, PathBuf)> {
+    let mut plugins: Vec<(String, PathBuf)> = Vec::new();
+    for dir in search_dirs {
+        let plugin_entries = match fs::read_dir(Path::new(dir).join("plugins")) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        let mut dir_plugins = plugin_entries
+            .into_iter()
+            .flatten()
+            .flat_map(|entry| {
+                let path = entry.path();
+                if path.is_dir() {
+                    let init_path = path.join("init.lua");
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    init_path.is_file().then_some((name, init_path))
+                } else if path.extension() == Some("lua".as_ref()) {
+                    let name = path.file_stem()?.to_string_lossy().to_string();
+                    Some((name, path))
+                } else {
+                    None
+                }
+            })
+            .filter(|(name, _)| !plugins.iter().any(|(found, _)| found == name))
+            .collect::<Vec<_>>();
+        plugins.append(&mut dir_plugins);
+    }
+    plugins.sort();
+    plugins
+}
+
+fn neopult_lua_path(search_dirs: &[String]) -> String {
+    search_dirs
+        .iter()
+        .map(|dir| {
+            format!(
+                "{}/?.lua;{}/plugins/?.lua;{}/plugins/?/init.lua;",
+                dir, dir, dir
+            )
+        })
+        .collect()
+}
+
+/// Loads the `init.lua` of the channel. Reports a missing `init.lua` separately, so that it is not
+/// confused with errors inside of the file.
+fn load_init(lua: &Lua, search_dirs: &[String]) -> error::Result<()> {
+    let init_path: Option<String> = lua
+        .load(r#"return package.searchpath("init", package.path)"#)
+        .eval()
+        .map_err(PluginSystemError::lua("error when searching for init.lua"))?;
+    if init_path.is_none() {
+        return Err(PluginSystemError::Config(format!(
+            "no init.lua found, create one in the channel home or global data directory (searched in {})",
+            search_dirs.join(", ")
+        )));
+    }
+
+    lua.load(r#"require("init")"#)
+        .set_name("init.lua")
+        .and_then(|chunk| chunk.exec())
+        .map_err(PluginSystemError::lua("error when loading plugins"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GLOBAL_DATA_DIR;
+    use crate::test_support::{capture_logs, captured_logs, temp_dir};
+    use crate::window_manager::fake_backend::FakeRequest;
+    use std::process;
+    use test_support::TestPluginSystem;
+
+    fn register_test_action(lua: &Lua, module: &Module, name: &str, tags: &[&str], confirm: bool) {
+        let callback = lua.create_function(|_, ()| Ok(())).unwrap();
+        module.actions.write().unwrap().push(Action {
+            name: name.to_string(),
+            display_name: Some(name.to_uppercase()),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            confirm,
+            cooldown: None,
+            priority: ActionPriority::Normal,
+            last_call: Default::default(),
+            key: lua.create_registry_value(callback).unwrap(),
+        });
+    }
+
+    #[test]
+    fn test_build_process_io_runtime_without_threads() {
+        let err = build_process_io_runtime(0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_status_observers() {
+        let system = TestPluginSystem::new("status-observers");
+        system.exec(
+            r#"
+            changes = {}
+            function take_changes()
+                local taken = changes
+                changes = {}
+                return taken
+            end
+            local function observer(name)
+                return function(module, status)
+                    table.insert(changes, name .. ":" .. module.plugin_instance .. "::" .. module.module .. "=" .. tostring(status))
+                end
+            end
+            dashboard = neopult.api.register_plugin_instance("dashboard")
+            summary = neopult.api.register_plugin_instance("summary")
+            neopult.api.on_status_change(dashboard, observer("dashboard"))
+            neopult.api.on_status_change(summary, observer("summary"))
+            "#,
+        );
+        let change_status = |new_status: Option<&str>| {
+            system.handle_event(Event::ModuleStatusChange {
+                module_identifier: ModuleIdentifier {
+                    plugin_instance: "vnc".to_string(),
+                    module: "viewer".to_string(),
+                },
+                new_status: new_status.map(str::to_string),
+            })
+        };
+        let take_changes = || -> Vec<String> { system.eval("take_changes()") };
+        let notification_receivers = || system.ctx().notification_sender.receiver_count();
+
+        change_status(Some("active"));
+        change_status(None);
+        assert_eq!(
+            take_changes(),
+            [
+                "dashboard:vnc::viewer=active",
+                "summary:vnc::viewer=active",
+                "dashboard:vnc::viewer=nil",
+                "summary:vnc::viewer=nil",
+            ]
+        );
+
+        // The observers of unregistered plugin instances are dropped
+        system.exec(r#"neopult.api.unregister_plugin_instance("dashboard")"#);
+        change_status(Some("active"));
+        assert_eq!(take_changes(), ["summary:vnc::viewer=active"]);
+        assert_eq!(notification_receivers(), 2);
+
+        // Without observers, status updates aren't forwarded anymore
+        system.exec(r#"neopult.api.unregister_plugin_instance("summary")"#);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while notification_receivers() != 1 {
+            assert!(
+                Instant::now() < deadline,
+                "status updates are still forwarded"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(system
+            .ctx()
+            .status_observers
+            .lock()
+            .unwrap()
+            .observers
+            .is_empty());
+    }
+
+    #[test]
+    fn test_remove_output_listener() {
+        let lua = Lua::new();
+        let plugin_instance = PluginInstance::new("camera".to_string(), Default::default());
+        lua.load("first, second = {}, {}").exec().unwrap();
+        let listener = |name: &str| {
+            let callback: Function = lua
+                .load(&format!("function(line) table.insert({}, line) end", name))
+                .eval()
+                .unwrap();
+            Arc::new(lua.create_registry_value(callback).unwrap())
+        };
+
+        let listeners = OutputListeners::default();
+        assert!(listeners.is_empty());
+        let first = listeners.add(listener("first"));
+        listeners.add(listener("second"));
+        listeners.call(&lua, "one".to_string(), "ffmpeg", &plugin_instance);
+        assert!(listeners.remove(&lua, first));
+        assert!(!listeners.remove(&lua, first));
+        listeners.call(&lua, "two".to_string(), "ffmpeg", &plugin_instance);
+
+        let received = |name: &str| -> Vec<String> { lua.globals().get(name).unwrap() };
+        assert_eq!(received("first"), vec!["one"]);
+        assert_eq!(received("second"), vec!["one", "two"]);
+    }
+
+    #[test]
+    fn test_action_catalog() {
+        let lua = Lua::new();
+        let (plugin_instance, module) = test_support::vnc_viewer();
+        register_test_action(&lua, &module, "max", &["layout"], false);
+        register_test_action(&lua, &module, "stop", &[], true);
+
+        let catalog = action_catalog(&[plugin_instance]);
+        assert_eq!(catalog.len(), 2);
+        assert_eq!(catalog[0].identifier.to_string(), "vnc::viewer::max");
+        assert_eq!(catalog[0].display_name.as_deref(), Some("MAX"));
+        assert_eq!(catalog[0].tags, vec!["layout".to_string()]);
+        assert!(!catalog[0].confirm);
+        assert_eq!(catalog[1].identifier.to_string(), "vnc::viewer::stop");
+        assert!(catalog[1].tags.is_empty());
+        assert!(catalog[1].confirm);
+    }
+
+    #[test]
+    fn test_action_cooldown() {
+        let lua = Lua::new();
+        lua.load("calls = 0").exec().unwrap();
+        let callback: Function = lua.load("function() calls = calls + 1 end").eval().unwrap();
+
+        let plugin_instance =
+            Arc::new(PluginInstance::new("scene".to_string(), Default::default()));
+        let module = Arc::new(Module::new("stream".to_string(), "scene".to_string(), None));
+        module.actions.write().unwrap().push(Action {
+            name: "restart".to_string(),
+            display_name: None,
+            tags: vec![],
+            confirm: false,
+            cooldown: Some(Duration::from_millis(50)),
+            priority: ActionPriority::Normal,
+            last_call: Default::default(),
+            key: lua.create_registry_value(callback).unwrap(),
+        });
+        plugin_instance.modules.write().unwrap().push(module);
+        let plugin_instances = [plugin_instance];
+
+        let identifier = ActionIdentifier {
+            plugin_instance: "scene".to_string(),
+            module: "stream".to_string(),
+            action: "restart".to_string(),
+        };
+        assert!(call_action(
+            &lua,
+            &plugin_instances,
+            identifier.clone(),
+            Value::Nil,
+            Caller::LOCAL
+        )
+        .is_ok());
+        let err = call_action(
+            &lua,
+            &plugin_instances,
+            identifier.clone(),
+            Value::Nil,
+            Caller::LOCAL,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("cooling down"));
+        assert_eq!(lua.globals().get::<_, u32>("calls").unwrap(), 1);
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(call_action(
+            &lua,
+            &plugin_instances,
+            identifier,
+            Value::Nil,
+            Caller::LOCAL
+        )
+        .is_ok());
+        assert_eq!(lua.globals().get::<_, u32>("calls").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_action_unregisters_itself() {
+        let lua = Lua::new();
+        let (notification_tx, mut notification_rx) = broadcast::channel(16);
+        let (plugin_instance, module) = test_support::vnc_viewer();
+        let callback = lua
+            .create_function({
+                let module = module.clone();
+                move |lua, _: MultiValue| {
+                    // Like ModuleHandle:unregister_action
+                    if api::remove_action(lua, &module, "once") {
+                        module.notify_actions_update(&notification_tx);
+                    }
+                    Ok(())
+                }
+            })
+            .unwrap();
+        register_test_action(&lua, &module, "stay", &[], false);
+        module.actions.write().unwrap().push(Action {
+            name: "once".to_string(),
+            display_name: None,
+            tags: vec![],
+            confirm: false,
+            cooldown: Some(Duration::from_secs(60)),
+            priority: ActionPriority::Normal,
+            last_call: Default::default(),
+            key: lua.create_registry_value(callback).unwrap(),
+        });
+
+        call_action(
+            &lua,
+            &[plugin_instance],
+            ActionIdentifier {
+                plugin_instance: "vnc".to_string(),
+                module: "viewer".to_string(),
+                action: "once".to_string(),
+            },
+            Value::Nil,
+            Caller::LOCAL,
+        )
+        .unwrap();
+
+        match notification_rx.try_recv() {
+            Ok(Notification::ModuleActionsUpdate { new_actions, .. }) => {
+                let names: Vec<_> = new_actions.iter().map(|a| a.name.as_str()).collect();
+                assert_eq!(names, ["stay"]);
+            }
+            notification => panic!("expected actions update, got {:?}", notification),
+        }
+        let actions = module.actions.read().unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].name, "stay");
+    }
+
+    #[test]
+    fn test_action_receives_caller() {
+        let lua = Lua::new();
+        let callback: Function = lua
+            .load(
+                "function(args, caller) role, source, token_id = caller.role, caller.source, caller.token_id end",
+            )
+            .eval()
+            .unwrap();
+        let (plugin_instance, module) = test_support::vnc_viewer();
+        module.actions.write().unwrap().push(Action {
+            name: "max".to_string(),
+            display_name: None,
+            tags: vec![],
+            confirm: false,
+            cooldown: None,
+            priority: ActionPriority::Normal,
+            last_call: Default::default(),
+            key: lua.create_registry_value(callback).unwrap(),
+        });
+        let plugin_instances = [plugin_instance];
+        let identifier = ActionIdentifier {
+            plugin_instance: "vnc".to_string(),
+            module: "viewer".to_string(),
+            action: "max".to_string(),
+        };
+        let caller_of_last_call = || {
+            let globals = lua.globals();
+            (
+                globals.get::<_, String>("role").unwrap(),
+                globals.get::<_, String>("source").unwrap(),
+                globals.get::<_, Option<u64>>("token_id").unwrap(),
+            )
+        };
+
+        let viewer = Caller {
+            role: Role::Viewer,
+            source: CallerSource::Websocket,
+            token_id: None,
+        };
+        call_action(
+            &lua,
+            &plugin_instances,
+            identifier.clone(),
+            Value::Nil,
+            viewer,
+        )
+        .unwrap();
+        assert_eq!(
+            caller_of_last_call(),
+            ("viewer".to_string(), "websocket".to_string(), None)
+        );
+
+        let token_holder = Caller {
+            role: Role::Admin,
+            source: CallerSource::Websocket,
+            token_id: Some(3),
+        };
+        call_action(
+            &lua,
+            &plugin_instances,
+            identifier.clone(),
+            Value::Nil,
+            token_holder,
+        )
+        .unwrap();
+        assert_eq!(
+            caller_of_last_call(),
+            ("admin".to_string(), "websocket".to_string(), Some(3))
+        );
+
+        call_action(
+            &lua,
+            &plugin_instances,
+            identifier,
+            Value::Nil,
+            Caller::LOCAL,
+        )
+        .unwrap();
+        assert_eq!(
+            caller_of_last_call(),
+            ("admin".to_string(), "local".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn test_resolution_change_notification() {
+        let mut system = TestPluginSystem::new("resolution-change");
+        system.fake_backend(|backend| backend.add_top_level_window(20, "vnc"));
+        system.exec(
+            r#"
+            plugin_instance = neopult.api.register_plugin_instance("vnc")
+            window = plugin_instance:claim_window("vnc", { timeout_ms = 1000 })
+            "#,
+        );
+        let resolution_changes = |system: &mut TestPluginSystem| {
+            system
+                .take_notifications()
+                .into_iter()
+                .filter_map(|notification| match notification {
+                    Notification::ScreenResolutionChanged { width, height } => {
+                        Some((width, height))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        resolution_changes(&mut system);
+
+        system.exec("window:max({ 1920, 1080 })");
+        assert_eq!(resolution_changes(&mut system), [(1920, 1080)]);
+        system.fake_backend(|backend| {
+            assert!(backend
+                .take_requests()
+                .contains(&FakeRequest::SetScreenSize((1920, 1080))));
+        });
+
+        // Maximizing to the current size doesn't change the resolution
+        system.exec("window:min()");
+        system.exec("window:max({ 1920, 1080 })");
+        assert!(resolution_changes(&mut system).is_empty());
+    }
+
+    #[test]
+    fn test_sync_attached_windows() {
+        let (notification_sender, mut notification_receiver) = broadcast::channel(16);
+        let (plugin_instance, module) = test_support::vnc_viewer();
+        *module.attached_window.write().unwrap() = Some(3);
+        let plugin_instances = [plugin_instance];
+
+        let max_mode = Mode::Max {
+            width: 1920,
+            height: 1080,
+            priority: u32::MAX,
+            margin: Default::default(),
+        };
+        sync_attached_windows(&plugin_instances, |_| Some(max_mode), &notification_sender);
+        assert_eq!(module.status.read().unwrap().as_deref(), Some("max"));
+        assert_eq!(
+            *module.active_actions.read().unwrap(),
+            HashSet::from(["max".to_string()])
+        );
+        assert!(matches!(
+            notification_receiver.try_recv(),
+            Ok(Notification::ModuleStatusUpdate { .. })
+        ));
+        assert!(matches!(
+            notification_receiver.try_recv(),
+            Ok(Notification::ModuleActiveActionsUpdate { .. })
+        ));
+
+        // Unchanged modes don't produce further notifications
+        sync_attached_windows(&plugin_instances, |_| Some(max_mode), &notification_sender);
+        assert!(notification_receiver.try_recv().is_err());
+
+        sync_attached_windows(
+            &plugin_instances,
+            |_| Some(Mode::Hidden),
+            &notification_sender,
+        );
+        assert_eq!(module.status.read().unwrap().as_deref(), Some("hidden"));
+        assert!(module.active_actions.read().unwrap().contains("hide"));
+
+        sync_attached_windows(&plugin_instances, |_| None, &notification_sender);
+        assert!(module.attached_window.read().unwrap().is_none());
+        assert!(module.active_actions.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_set_module_icon() {
+        let (notification_sender, mut notification_receiver) = broadcast::channel(16);
+        let (plugin_instance, module) = test_support::vnc_viewer();
+        *module.icon.write().unwrap() = Some("unmuted".to_string());
+        let plugin_instances = [plugin_instance];
+        let icon = || {
+            system_info(&plugin_instances).plugin_instances[0].modules[0]
+                .icon
+                .clone()
+        };
+        assert_eq!(icon().as_deref(), Some("unmuted"));
+
+        module.set_icon(Some("muted".to_string()), &notification_sender);
+        assert_eq!(icon().as_deref(), Some("muted"));
+        match notification_receiver.try_recv().unwrap() {
+            Notification::ModuleIconUpdate {
+                module_identifier,
+                new_icon,
+            } => {
+                assert_eq!(module_identifier.to_string(), "vnc::viewer");
+                assert_eq!(new_icon.as_deref(), Some("muted"));
+            }
+            n => panic!("unexpected notification {:?}", n),
+        }
+
+        module.set_icon(None, &notification_sender);
+        assert_eq!(icon(), None);
+        assert!(notification_receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_set_module_message() {
+        let mut system = TestPluginSystem::new("set-module-message");
+        system.exec(
+            r#"
+            plugin_instance = neopult.api.register_plugin_instance("vnc")
+            plugin_instance:register_module("viewer")
+            "#,
+        );
+        let set_message = |system: &TestPluginSystem, module: &str, message: Option<&str>| {
+            let (error_sender, mut error_receiver) = oneshot::channel();
+            system.handle_event(Event::ClientCommand(ClientCommand::SetModuleMessage {
+                module_identifier: ModuleIdentifier {
+                    plugin_instance: "vnc".to_string(),
+                    module: module.to_string(),
+                },
+                message: message.map(str::to_string),
+                severity: Some("warning".to_string()),
+                error_sender,
+            }));
+            error_receiver.try_recv().unwrap()
+        };
+        let message = |system: &TestPluginSystem| {
+            system_info(&system.ctx().plugin_instances.read().unwrap()).plugin_instances[0].modules
+                [0]
+            .message
+            .clone()
+        };
+        let notified_messages = |system: &mut TestPluginSystem| -> Vec<Option<String>> {
+            system
+                .take_notifications()
+                .into_iter()
+                .map(|notification| match notification {
+                    Notification::ModuleMessageUpdate {
+                        module_identifier,
+                        new_message,
+                    } => {
+                        assert_eq!(module_identifier.to_string(), "vnc::viewer");
+                        new_message
+                    }
+                    n => panic!("unexpected notification {:?}", n),
+                })
+                .collect()
+        };
+        system.take_notifications();
+
+        set_message(&system, "viewer", Some("Break <5 min>")).unwrap();
+        let expected = "<span class=\"severity-warning\">Break &lt;5 min&gt;</span>";
+        assert_eq!(message(&system).as_deref(), Some(expected));
+        assert_eq!(
+            notified_messages(&mut system),
+            vec![Some(expected.to_string())]
+        );
+
+        assert!(set_message(&system, "unknown", None).is_err());
+        assert!(system.take_notifications().is_empty());
+
+        // Client messages are coalesced like the ones that plugins set
+        *system.ctx().notification_coalescer.write().unwrap() =
+            Some(Arc::new(NotificationCoalescer::new(
+                Duration::from_millis(50),
+                system.ctx().notification_sender.clone(),
+            )));
+        set_message(&system, "viewer", Some("first")).unwrap();
+        set_message(&system, "viewer", None).unwrap();
+        assert!(message(&system).is_none());
+        assert!(system.take_notifications().is_empty());
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(notified_messages(&mut system), vec![None]);
+    }
+
+    #[test]
+    fn test_set_module_order() {
+        let mut system = TestPluginSystem::new("set-module-order");
+        let register_modules = r#"
+            plugin_instance = neopult.api.register_plugin_instance("vnc")
+            plugin_instance:register_module("viewer")
+            plugin_instance:register_module("banner")
+        "#;
+        system.exec(register_modules);
+        let identifier = |module: &str| ModuleIdentifier {
+            plugin_instance: "vnc".to_string(),
+            module: module.to_string(),
+        };
+        let set_order = |system: &TestPluginSystem, orders| {
+            let (error_sender, mut error_receiver) = oneshot::channel();
+            system.handle_event(Event::ClientCommand(ClientCommand::SetModuleOrder {
+                orders,
+                error_sender,
+            }));
+            error_receiver.try_recv().unwrap()
+        };
+        let orders = |system: &TestPluginSystem| -> Vec<(String, ModuleOrder)> {
+            system_info(&system.ctx().plugin_instances.read().unwrap()).plugin_instances[0]
+                .modules
+                .iter()
+                .map(|m| (m.name.clone(), m.order))
+                .collect()
+        };
+        system.take_notifications();
+
+        set_order(
+            &system,
+            vec![(identifier("viewer"), 2), (identifier("banner"), 1)],
+        )
+        .unwrap();
+        assert_eq!(
+            orders(&system),
+            vec![("banner".to_string(), 1), ("viewer".to_string(), 2)]
+        );
+        let notifications = system.take_notifications();
+        match &notifications[0] {
+            Notification::ModuleOrderUpdate {
+                module_identifier,
+                new_order,
+            } => {
+                assert_eq!(module_identifier.to_string(), "vnc::viewer");
+                assert_eq!(*new_order, 2);
+            }
+            n => panic!("unexpected notification {:?}", n),
+        }
+        assert_eq!(notifications.len(), 2);
+
+        // Unknown modules reject the whole request
+        let result = set_order(
+            &system,
+            vec![(identifier("banner"), 5), (identifier("missing"), 3)],
+        );
+        assert!(result.is_err());
+        assert_eq!(
+            orders(&system),
+            vec![("banner".to_string(), 1), ("viewer".to_string(), 2)]
+        );
+        assert!(system.take_notifications().is_empty());
+
+        // The orders are applied again when the modules are registered after a restart
+        let restarted = TestPluginSystem::with_channel_home(system.channel_home.clone());
+        restarted.exec(register_modules);
+        assert_eq!(
+            orders(&restarted),
+            vec![("banner".to_string(), 1), ("viewer".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_idle_tasks_run_after_queue_drains() {
+        let lua = Lua::new();
+        let (event_tx, event_rx) = mpsc::channel(8);
+        let mut event_queue = EventQueue::new(event_rx);
+        for _ in 0..3 {
+            event_tx.try_send(Event::Resume).unwrap();
+        }
+        let idle_tasks = Mutex::new(VecDeque::new());
+        let deferred = lua
+            .load("function() idle_ran = true end")
+            .eval::<Function>()
+            .unwrap();
+        idle_tasks
+            .lock()
+            .unwrap()
+            .push_back(lua.create_registry_value(deferred).unwrap());
+
+        // A tick with run_later tasks isn't quiet, even if no event is pending
+        assert!(matches!(
+            next_loop_step(&mut event_queue, &[], true, true),
+            LoopStep::Handle(_)
+        ));
+
+        let mut handled_events = 1;
+        loop {
+            let has_idle_tasks = !idle_tasks.lock().unwrap().is_empty();
+            match next_loop_step(&mut event_queue, &[], false, has_idle_tasks) {
+                LoopStep::Handle(_) => {
+                    assert_eq!(
+                        lua.globals().get::<_, Option<bool>>("idle_ran").unwrap(),
+                        None
+                    );
+                    handled_events += 1;
+                }
+                LoopStep::RunIdleTasks => assert!(!run_idle_tasks(&lua, &idle_tasks)),
+                LoopStep::NextTick => unreachable!(),
+                LoopStep::Wait => break,
+            }
+        }
+        assert_eq!(handled_events, 3);
+        assert_eq!(
+            lua.globals().get::<_, Option<bool>>("idle_ran").unwrap(),
+            Some(true)
+        );
+
+        assert!(matches!(
+            next_loop_step(&mut event_queue, &[], true, true),
+            LoopStep::NextTick
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_idle_task_deferring_itself_waits() {
+        let lua = Lua::new();
+        let idle_tasks = Arc::new(Mutex::new(VecDeque::new()));
+        let defer = {
+            let idle_tasks = idle_tasks.clone();
+            lua.create_function(move |lua, func: Function| {
+                let func_key = lua.create_registry_value(func)?;
+                idle_tasks.lock().unwrap().push_back(func_key);
+                Ok(())
+            })
+            .unwrap()
+        };
+        lua.globals().set("defer", defer).unwrap();
+        let task = lua
+            .load(
+                r#"
+                runs = 0
+                function task()
+                    runs = runs + 1
+                    defer(task)
+                end
+                return task
+                "#,
+            )
+            .eval::<Function>()
+            .unwrap();
+        idle_tasks
+            .lock()
+            .unwrap()
+            .push_back(lua.create_registry_value(task).unwrap());
+
+        assert!(run_idle_tasks(&lua, &idle_tasks));
+        assert_eq!(lua.globals().get::<_, u32>("runs").unwrap(), 1);
+        assert_eq!(idle_tasks.lock().unwrap().len(), 1);
+
+        let (event_tx, event_rx) = mpsc::channel(8);
+        let mut event_queue = EventQueue::new(event_rx);
+        let started = Instant::now();
+        assert!(event_queue
+            .wait_for_event(IDLE_TASK_RETRY_DELAY)
+            .await
+            .is_none());
+        assert!(started.elapsed() >= IDLE_TASK_RETRY_DELAY);
+        assert!(event_queue.try_next(&[]).is_none());
+
+        // Events end the wait early and are handled before the idle tasks run again
+        event_tx.try_send(Event::Resume).unwrap();
+        let started = Instant::now();
+        let event = event_queue.wait_for_event(Duration::from_secs(10)).await;
+        assert!(started.elapsed() < Duration::from_secs(10));
+        event_queue.push(event.unwrap(), &[]);
+        assert!(matches!(
+            next_loop_step(&mut event_queue, &[], false, true),
+            LoopStep::Handle(Event::Resume)
+        ));
+    }
+
+    #[test]
+    fn test_high_priority_action_runs_first() {
+        let lua = Lua::new();
+        let plugin_instance =
+            Arc::new(PluginInstance::new("scene".to_string(), Default::default()));
+        let module = Arc::new(Module::new("output".to_string(), "scene".to_string(), None));
+        register_test_action(&lua, &module, "slideshow", &[], false);
+        register_test_action(&lua, &module, "black", &[], false);
+        module.actions.write().unwrap()[1].priority = ActionPriority::High;
+        plugin_instance.modules.write().unwrap().push(module);
+        let plugin_instances = [plugin_instance];
+
+        let call = |action: &str| {
+            Event::ClientCommand(ClientCommand::CallAction {
+                identifier: ActionIdentifier {
+                    plugin_instance: "scene".to_string(),
+                    module: "output".to_string(),
+                    action: action.to_string(),
+                },
+                caller: Caller::LOCAL,
+                error_sender: oneshot::channel().0,
+            })
+        };
+        let (event_tx, event_rx) = mpsc::channel(8);
+        let mut event_queue = EventQueue::new(event_rx);
+        event_tx.try_send(Event::Resume).unwrap();
+        event_tx.try_send(call("slideshow")).unwrap();
+        event_tx.try_send(call("black")).unwrap();
+
+        let mut order = vec![];
+        while let Some(event) = event_queue.try_next(&plugin_instances) {
+            order.push(match event {
+                Event::ClientCommand(ClientCommand::CallAction { identifier, .. }) => {
+                    identifier.action
+                }
+                event => event.kind().to_string(),
+            });
+        }
+        assert_eq!(order, ["black", "Resume", "slideshow"]);
+
+        // Calls from the terminal are prioritized as well
+        let (event_tx, event_rx) = mpsc::channel(8);
+        let mut event_queue = EventQueue::new(event_rx);
+        event_tx.try_send(call("slideshow")).unwrap();
+        event_tx
+            .try_send(Event::CliCommand {
+                command: "call scene::output::black".to_string(),
+                reply_sender: oneshot::channel().0,
+            })
+            .unwrap();
+        assert!(matches!(
+            event_queue.try_next(&plugin_instances),
+            Some(Event::CliCommand { .. })
+        ));
+        assert_eq!(event_queue.normal.len(), 1);
+
+        // Events beyond the pending limit stay in the channel, so that it applies backpressure
+        let (event_tx, event_rx) = mpsc::channel(MAX_PENDING_EVENTS * 2);
+        let mut event_queue = EventQueue::new(event_rx);
+        for _ in 0..MAX_PENDING_EVENTS * 2 {
+            event_tx.try_send(Event::Resume).unwrap();
+        }
+        assert!(event_queue.try_next(&plugin_instances).is_some());
+        assert_eq!(event_queue.normal.len(), MAX_PENDING_EVENTS - 1);
+        assert_eq!(event_tx.capacity(), MAX_PENDING_EVENTS);
+        assert_eq!(
+            "high".parse::<ActionPriority>().unwrap(),
+            ActionPriority::High
+        );
+        assert!("urgent".parse::<ActionPriority>().is_err());
+    }
+
+    #[test]
+    fn test_event_watchdog() {
+        capture_logs();
+        let system = TestPluginSystem::new("event-watchdog");
+        system.exec(
+            r#"
+            neopult.config.slow_event_threshold_ms = 20
+            local plugin_instance = neopult.api.register_plugin_instance("watchdog")
+            local module = plugin_instance:register_module("busy", {})
+            module:register_action("fast", function() end)
+            module:register_action("slow", function()
+                local start = os.clock()
+                while os.clock() - start < 0.05 do end
+            end)
+            "#,
+        );
+        let event_loop = system.spawn_event_loop();
+        let call = |action: &str| {
+            let (reply_sender, reply_receiver) = oneshot::channel();
+            event_loop
+                .event_sender
+                .blocking_send(Event::CliCommand {
+                    command: format!("call watchdog::busy::{}", action),
+                    reply_sender,
+                })
+                .unwrap();
+            reply_receiver.blocking_recv().unwrap();
+        };
+        let slow_event_warnings = || {
+            captured_logs()
+                .into_iter()
+                .filter(|log| log.starts_with("WARN handling CliCommand event took"))
+                .count()
+        };
+
+        let warnings_before = slow_event_warnings();
+        call("fast");
+        call("slow");
+        // The warning is logged after the reply was sent
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while slow_event_warnings() == warnings_before {
+            assert!(Instant::now() < deadline, "no warning about the slow event");
+            thread::sleep(Duration::from_millis(10));
+        }
+        event_loop.stop();
+        assert_eq!(slow_event_warnings(), warnings_before + 1);
+    }
+
+    #[test]
+    fn test_load_plugins_errors() {
+        let system = TestPluginSystem::new("load-plugins-errors");
+        // The default lua path contains ./?.lua, which finds the init.lua of the working directory
+        let search_dirs = lua_search_dirs(&system.ctx().env_config);
+        system
+            .lua()
+            .globals()
+            .get::<_, Table>("package")
+            .unwrap()
+            .set("path", neopult_lua_path(&search_dirs))
+            .unwrap();
+        let e = system.plugin_system.load_plugins().unwrap_err();
+        assert!(matches!(e, PluginSystemError::Config(_)), "{:?}", e);
+        let msg = e.to_string();
+        assert!(msg.contains("no init.lua found"), "{}", msg);
+        assert!(
+            msg.contains(&system.channel_home.display().to_string()),
+            "{}",
+            msg
+        );
+        assert!(
+            msg.contains(&system.channel_home.join("data").display().to_string()),
+            "{}",
+            msg
+        );
+
+        fs::write(system.channel_home.join("init.lua"), "this is not lua").unwrap();
+        let e = system.plugin_system.load_plugins().unwrap_err();
+        assert!(
+            matches!(
+                e,
+                PluginSystemError::Lua {
+                    context: "error when loading plugins",
+                    ..
+                }
+            ),
+            "{:?}",
+            e
+        );
+        assert!(e.to_string().contains("'=' expected near 'is'"), "{}", e);
+
+        // The parse error of neopult.toml is kept as the source
+        fs::write(system.channel_home.join("init.lua"), "").unwrap();
+        system.plugin_system.load_plugins().unwrap();
+        fs::write(system.channel_home.join("neopult.toml"), "reanchor = ").unwrap();
+        let e = system.plugin_system.get_config().unwrap_err();
+        assert!(matches!(e, PluginSystemError::ConfigFile { .. }), "{:?}", e);
+        assert!(std::error::Error::source(&e).is_some());
+    }
+
+    #[test]
+    fn test_data_dir_override_in_package_path() {
+        let env_config = EnvConfig {
+            channel: 3,
+            neopult_home: PathBuf::from("/nonexistent"),
+            channel_home: PathBuf::from("/nonexistent/channel-3"),
+            pid_dir_base: PathBuf::from("/tmp"),
+            data_dir: PathBuf::from("/home/dev/neopult-plugins"),
+            notification_capacity: 64,
+        };
+        let lua = Lua::new();
+        let package_table = lua.globals().get::<_, Table>("package").unwrap();
+        package_table
+            .set("path", neopult_lua_path(&lua_search_dirs(&env_config)))
+            .unwrap();
+
+        let package_path: String = package_table.get("path").unwrap();
+        assert!(package_path.starts_with("/nonexistent/channel-3/?.lua;"));
+        assert!(package_path.contains("/home/dev/neopult-plugins/plugins/?.lua;"));
+        assert!(package_path.contains("/home/dev/neopult-plugins/plugins/?/init.lua;"));
+        assert!(!package_path.contains(GLOBAL_DATA_DIR));
+    }
+
+    #[test]
+    fn test_find_plugins() {
+        let base = temp_dir("find-plugins");
+        let channel_plugins = base.join("channel-0").join("plugins");
+        let global_plugins = base.join("global").join("plugins");
+        fs::create_dir_all(channel_plugins.join("vnc")).unwrap();
+        fs::create_dir_all(global_plugins.join("no-init")).unwrap();
+        fs::write(channel_plugins.join("vnc").join("init.lua"), "").unwrap();
+        fs::write(channel_plugins.join("camera.lua"), "").unwrap();
+        fs::write(channel_plugins.join("README.md"), "").unwrap();
+        fs::write(global_plugins.join("camera.lua"), "").unwrap();
+        fs::write(global_plugins.join("jitsi.lua"), "").unwrap();
+        let search_dirs = [
+            base.join("channel-0").display().to_string(),
+            base.join("global").display().to_string(),
+            base.join("missing").display().to_string(),
+        ];
+
+        let plugins = find_plugins(&search_dirs);
+        assert_eq!(
+            plugins,
+            vec![
+                ("camera".to_string(), channel_plugins.join("camera.lua")),
+                ("jitsi".to_string(), global_plugins.join("jitsi.lua")),
+                (
+                    "vnc".to_string(),
+                    channel_plugins.join("vnc").join("init.lua")
+                ),
+            ]
+        );
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_custom_pid_dir_base() {
+        let pid_dir_base = temp_dir("pid-base");
+        let env_config = EnvConfig {
+            channel: 7,
+            neopult_home: PathBuf::from("/nonexistent"),
+            channel_home: PathBuf::from("/nonexistent/channel-7"),
+            pid_dir_base: pid_dir_base.clone(),
+            data_dir: PathBuf::from(GLOBAL_DATA_DIR),
+            notification_capacity: 64,
+        };
+        let pid_dir_path = env_config.pid_dir_path();
+        assert_eq!(pid_dir_path, pid_dir_base.join("neopult-channel-7"));
+
+        prepare_pid_dir(&pid_dir_path);
+        assert!(pid_dir_path.is_dir());
+        let pid_file_path = create_pid_file(&pid_dir_path, 4242, "ffmpeg").unwrap();
+        assert_eq!(pid_file_path, pid_dir_path.join("4242.pid"));
+        let contents = fs::read_to_string(&pid_file_path).unwrap();
+        assert_eq!(contents.lines().nth(1), Some("ffmpeg"));
+
+        fs::remove_dir_all(&pid_dir_base).unwrap();
+    }
+
+    #[test]
+    fn test_prepare_pid_dir_removes_files_without_pid() {
+        let pid_dir_path = temp_dir("pid-without-pid");
+        for filename in [".pid", "ffmpeg.pid", "0.pid", "-3.pid"] {
+            fs::write(pid_dir_path.join(filename), "").unwrap();
+        }
+        // Legacy PID files have no start time, so init is not killed
+        fs::write(pid_dir_path.join("1.pid"), "").unwrap();
+        fs::write(pid_dir_path.join("notes.txt"), "").unwrap();
+
+        prepare_pid_dir(&pid_dir_path);
+        let remaining: Vec<_> = fs::read_dir(&pid_dir_path)
+            .unwrap()
+            .flatten()
+            .map(|item| item.file_name())
+            .collect();
+        assert_eq!(remaining, ["notes.txt"]);
+
+        fs::remove_dir_all(&pid_dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_reused_pid_is_not_killed() {
+        let pid_dir_path = temp_dir("pid-reuse");
+
+        // The test process stands in for an unrelated process that reused the PID
+        let pid = process::id();
+        let start_time = process_start_time(pid as i32).unwrap();
+        let pid_file_path = create_pid_file(&pid_dir_path, pid, "ffmpeg").unwrap();
+        let contents = fs::read_to_string(&pid_file_path).unwrap();
+        assert_eq!(contents, format!("{}\nffmpeg\n", start_time));
+        assert!(is_same_process(pid as i32, &contents));
+
+        let stale_contents = format!("{}\nffmpeg\n", start_time + 1);
+        assert!(!is_same_process(pid as i32, &stale_contents));
+        assert!(!is_same_process(pid as i32, ""));
+        fs::write(&pid_file_path, stale_contents).unwrap();
+
+        clean_old_processes(fs::read_dir(&pid_dir_path).unwrap());
+        // Still alive, since killing would have ended the test run
+        assert!(signal::kill(Pid::from_raw(pid as i32), None).is_ok());
+        assert!(!pid_file_path.exists());
+
+        fs::remove_dir_all(&pid_dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_system_info_json() {
+        let lua = Lua::new();
+        let (plugin_instance, module) = test_support::vnc_viewer();
+        register_test_action(&lua, &module, "max", &[], false);
+
+        let json = system_info_json(&[plugin_instance]);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let plugin_instances = value["plugin_instances"].as_array().unwrap();
+        assert_eq!(plugin_instances.len(), 1);
+        assert_eq!(plugin_instances[0]["name"], "vnc");
+        assert_eq!(plugin_instances[0]["modules"][0]["name"], "viewer");
+        assert_eq!(
+            plugin_instances[0]["modules"][0]["actions"][0]["name"],
+            "max"
+        );
+        assert!(json.contains('\n'));
+    }
+
+    #[test]
+    fn test_system_info_order() {
+        let plugin_instances: Vec<Arc<PluginInstance>> = ["vnc", "camera", "banner"]
+            .into_iter()
+            .map(|name| Arc::new(PluginInstance::new(name.to_string(), Default::default())))
+            .collect();
+        for (name, order) in [
+            ("viewer", 0),
+            ("preview", 1),
+            ("controls", -1),
+            ("audio", 0),
+        ] {
+            let module = Module::new(name.to_string(), "camera".to_string(), None);
+            *module.order.write().unwrap() = order;
+            plugin_instances[1]
+                .modules
+                .write()
+                .unwrap()
+                .push(Arc::new(module));
+        }
+
+        let value = serde_json::to_value(system_info(&plugin_instances)).unwrap();
+        let names = |value: &serde_json::Value| -> Vec<String> {
+            value
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| entry["name"].as_str().unwrap().to_string())
+                .collect()
+        };
+        assert_eq!(
+            names(&value["plugin_instances"]),
+            ["banner", "camera", "vnc"]
+        );
+        assert_eq!(
+            names(&value["plugin_instances"][1]["modules"]),
+            ["controls", "audio", "viewer", "preview"]
+        );
+    }
+
+    #[test]
+    fn test_statuses_json() {
+        let lua = Lua::new();
+        let (plugin_instance, module) = test_support::vnc_viewer();
+        register_test_action(&lua, &module, "max", &[], false);
+        register_test_action(&lua, &module, "min", &[], false);
+        *module.status.write().unwrap() = Some("active".to_string());
+        *module.message.write().unwrap() = Some("<b>connected</b>".to_string());
+        module
+            .active_actions
+            .write()
+            .unwrap()
+            .extend(["min".to_string(), "max".to_string()]);
+        plugin_instance
+            .modules
+            .write()
+            .unwrap()
+            .push(Arc::new(Module::new(
+                "idle".to_string(),
+                "vnc".to_string(),
+                None,
+            )));
+
+        let json = statuses_json(&[plugin_instance]);
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        let statuses = value.as_array().unwrap();
+        assert_eq!(statuses.len(), 2);
+        // Modules with the same order are sorted by name
+        assert_eq!(statuses[0]["identifier"], "vnc::idle");
+        assert!(statuses[0]["status"].is_null());
+        assert!(statuses[0]["message"].is_null());
+        assert_eq!(statuses[0]["active_actions"], serde_json::json!([]));
+        assert_eq!(statuses[1]["identifier"], "vnc::viewer");
+        assert_eq!(statuses[1]["status"], "active");
+        assert_eq!(statuses[1]["message"], "<b>connected</b>");
+        assert_eq!(
+            statuses[1]["active_actions"],
+            serde_json::json!(["max", "min"])
+        );
+    }
+}
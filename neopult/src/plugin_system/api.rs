@@ -1,27 +1,77 @@
 use crate::{
+    config::EnvConfig,
     plugin_system::{
-        create_context_function, Action, Event, LogWithPrefix, LuaContext, Module,
-        ModuleIdentifier, ModuleMessage, ModuleStatus, Notification, PluginInstance,
+        action_catalog, call_tracked_action,
+        coalescer::UpdateKind,
+        config, create_context_function, create_pid_file, forward_status_updates,
+        schedule::{delay_until, CronSchedule},
+        window_mode_state, Action, ActionIdentifier, ActionPriority, Caller, Event,
+        ImageWindowGeometry, ImageWindowInfo, LogWithPrefix, LuaContext, Module, ModuleMessage,
+        ModuleStatus, Notification, OutputListeners, PluginInstance, PluginInstanceCallbacks,
+        ProcessExit, StatusObserver, TimerId, BUILTIN_CLI_COMMANDS,
+        OLD_PROCESS_SHUTDOWN_GRACE_PERIOD,
     },
     window_manager::{
-        ManagedWid, Margin, MinGeometry, PrimaryDemotionAction, VirtualWindowCallbacks,
+        ClaimCandidate, Color, DisplayMode, FrontendHint, Highlight, ManagedWid, Margin,
+        MinGeometry, PrimaryDemotionAction, PropertyType, PropertyValue, VirtualWindowCallbacks,
     },
 };
-use ::log::{debug, error};
-use mlua::{AnyUserData, Function, Lua, RegistryKey, Table, UserData, UserDataMethods, Value};
+use ::log::{debug, error, warn};
+use anyhow::Context;
+use chrono::{
+    format::{Item, StrftimeItems},
+    DateTime, Local, SecondsFormat, TimeZone, Utc,
+};
+use mlua::{
+    AnyUserData, Function, Lua, MetaMethod, MultiValue, RegistryKey, Table, UserData,
+    UserDataMethods, Value,
+};
+use nix::{
+    sys::signal::{self, Signal},
+    unistd::Pid,
+};
 use rand::distributions::{Alphanumeric, DistString};
+use sha2::{Digest, Sha256, Sha512};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryFrom,
+    fmt,
+    fs::File,
+    future::Future,
+    io::Read,
+    os::fd::{AsRawFd, OwnedFd},
+    path::{Path, PathBuf},
+    pin::Pin,
     process::Stdio,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context as TaskContext, Poll},
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
-    process::{ChildStdin, Command},
-    sync::{mpsc, oneshot},
+    io::{
+        self, unix::AsyncFd, AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt,
+        BufReader, ReadBuf,
+    },
+    process::{Child, ChildStdin, Command},
+    sync::{broadcast, mpsc, oneshot},
+    task::JoinHandle,
 };
+use xcb::x;
+
+/// Limits how deeply actions may call other actions via `neopult.api.call_action`
+const MAX_ACTION_CALL_DEPTH: usize = 8;
+/// Module that holds the actions which are registered automatically for plugin instances
+const META_MODULE_NAME: &str = "__meta";
+
+const DEFAULT_CLAIM_TIMEOUT_MS: u64 = 250;
+
+const DEFAULT_FLASH_DURATION_MS: u64 = 1000;
+const DEFAULT_FLASH_COLOR: &str = "#ff0000";
+const DEFAULT_FLASH_BORDER_WIDTH: u16 = 8;
 
 #[derive(Debug)]
 struct PluginInstanceHandle {
@@ -35,37 +85,18 @@ impl PluginInstanceHandle {
         lua: &'lua Lua,
         (name, opts): (String, Value),
     ) -> mlua::Result<Value<'lua>> {
-        let mut modules = self.plugin_instance.modules.write().unwrap();
-
-        if modules.iter().any(|m| m.name == name) {
-            self.plugin_instance.error(format!(
-                "tried registering module with duplicate name {}",
-                name
-            ));
-            Ok(Value::Nil)
-        } else {
-            self.plugin_instance
-                .debug(format!("registering module {}", name));
-
-            let mut display_name = None;
-            if let Value::Table(opts_table) = opts {
-                if let Ok(display_name_arg) = opts_table.get::<_, String>("display_name") {
-                    display_name = Some(display_name_arg)
+        let spawn_limits = self.ctx.spawn_limits(lua);
+        match add_module(lua, &spawn_limits, &self.plugin_instance, name, opts)? {
+            Some(module) => {
+                if let Some(order) = self.ctx.module_orders.get(&module.identifier()) {
+                    *module.order.write().unwrap() = order;
                 }
+                lua.pack(ModuleHandle {
+                    module,
+                    ctx: self.ctx.clone(),
+                })
             }
-
-            let module = Arc::new(Module::new(
-                name,
-                self.plugin_instance.name.clone(),
-                display_name,
-            ));
-            let module_handle = ModuleHandle {
-                module: module.clone(),
-                ctx: self.ctx.clone(),
-            };
-            modules.push(module);
-            let val = lua.pack(module_handle)?;
-            Ok(val)
+            None => Ok(Value::Nil),
         }
     }
 
@@ -73,16 +104,28 @@ impl PluginInstanceHandle {
         &self,
         lua: &'lua Lua,
         (cmd, opts): (String, Value),
-    ) -> mlua::Result<Value<'lua>> {
-        let _enter_guard = self.ctx.main_runtime_handle.enter();
+    ) -> mlua::Result<(Value<'lua>, Option<String>)> {
+        // Process I/O must happen on a runtime with an I/O driver, the plugin runtime doesn't
+        // have one.
+        let process_io_handle = self.ctx.process_io_handle();
+        let _enter_guard = process_io_handle.enter();
 
         let mut args = Vec::<String>::new();
         let mut envs = HashMap::<String, String>::new();
-        let mut on_output_key = None;
+        let output_listeners = Arc::new(OutputListeners::default());
+        let mut stdin_from = None;
+        let mut keep_stdin_open = false;
+        let mut merge_stderr = false;
+        let mut recent_output = None;
+        let mut restart_policy = None;
+        let mut status_module = None;
+        let mut max_lifetime = None;
+        let mut on_exit_key = None;
+        let mut priority = ProcessPriority::default();
 
         if let Value::Table(ref opts_table) = opts {
             if let Ok(on_output) = opts_table.get::<_, Function>("on_output") {
-                on_output_key = Some(lua.create_registry_value(on_output)?);
+                output_listeners.add(Arc::new(lua.create_registry_value(on_output)?));
             }
             if let Ok(args_table) = opts_table.get::<_, Table>("args") {
                 args = args_table
@@ -94,121 +137,142 @@ impl PluginInstanceHandle {
             if let Ok(env_table) = opts_table.get::<_, Table>("envs") {
                 envs = env_table.pairs::<String, String>().flatten().collect();
             }
-        }
-
-        let child_result = Command::new(&cmd)
-            .args(&args)
-            .envs(&envs)
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn();
-
-        let pid;
-        let mut child = match child_result {
-            Err(e) => {
-                self.plugin_instance.error(format!(
-                    "couldn't spawn process {} with args {:?} and envs {:?}: {}",
-                    cmd, args, envs, e
-                ));
-                return Ok(Value::Nil);
+            if let Ok(path) = opts_table.get::<_, String>("stdin_from") {
+                stdin_from = Some(path);
+            }
+            if let Ok(keep_open) = opts_table.get::<_, bool>("keep_stdin_open") {
+                keep_stdin_open = keep_open;
             }
-            Ok(c) => {
-                pid = c.id().unwrap();
-                self.plugin_instance.debug(format!(
-                    "spawned process {} with args {:?} and envs {:?} (PID {})",
-                    cmd, args, envs, pid,
+            if let Ok(merge) = opts_table.get::<_, bool>("merge_stderr") {
+                merge_stderr = merge;
+            }
+            if let Ok(buffer_lines) = opts_table.get::<_, usize>("buffer_lines") {
+                if buffer_lines > 0 {
+                    recent_output = Some(Arc::new(RecentOutput::new(buffer_lines)));
+                }
+            }
+            if let Ok(true) = opts_table.get::<_, bool>("restart_on_exit") {
+                let max_restarts = opts_table
+                    .get::<_, u32>("max_restarts")
+                    .unwrap_or(DEFAULT_MAX_RESTARTS);
+                let restart_delay_ms = opts_table
+                    .get::<_, u64>("restart_delay_ms")
+                    .unwrap_or(DEFAULT_RESTART_DELAY_MS);
+                restart_policy = Some(RestartPolicy::new(
+                    max_restarts,
+                    Duration::from_millis(restart_delay_ms),
                 ));
-                c
             }
-        };
-
-        async fn read_lines(
-            source: impl AsyncReadExt + Unpin,
-            event_sender: Arc<mpsc::Sender<Event>>,
-            process_name: String,
-            plugin_instance: Arc<PluginInstance>,
-            callback_key: Option<Arc<RegistryKey>>,
-            pid: u32,
-            kind: &str,
-        ) {
-            let mut lines = BufReader::new(source).lines();
-            loop {
-                match lines.next_line().await {
-                    Ok(Some(line)) => {
-                        plugin_instance.debug(format!(
-                            "process {} (PID {}) {} line: {}",
-                            process_name, pid, kind, line
-                        ));
-                        if let Some(key) = callback_key.as_ref() {
-                            let event = Event::ProcessOutput {
-                                line,
-                                process_name: process_name.clone(),
-                                plugin_instance: plugin_instance.clone(),
-                                callback_key: key.clone(),
-                            };
-                            if event_sender.send(event).await.is_err() {
-                                plugin_instance.warn(format!(
-                                    "event receiver was dropped, couldn't send process output ({})",
-                                    kind
-                                ));
-                                break;
-                            };
-                        }
-                    }
-                    Ok(None) => {
-                        plugin_instance.debug(format!(
-                            "{} of process {} (PID {}) closed",
-                            kind, process_name, pid
+            if let Ok(max_lifetime_ms) = opts_table.get::<_, u64>("max_lifetime_ms") {
+                max_lifetime = Some(Duration::from_millis(max_lifetime_ms));
+            }
+            if let Ok(on_exit) = opts_table.get::<_, Function>("on_exit") {
+                on_exit_key = Some(Arc::new(lua.create_registry_value(on_exit)?));
+            }
+            if let Ok(nice) = opts_table.get::<_, i32>("nice") {
+                if (MIN_NICE..=MAX_NICE).contains(&nice) {
+                    priority.nice = Some(nice);
+                } else {
+                    self.plugin_instance.warn(format!(
+                        "ignoring nice value {} of process {}, it has to be between {} and {}",
+                        nice, cmd, MIN_NICE, MAX_NICE
+                    ));
+                }
+            }
+            if let Ok(ionice) = opts_table.get::<_, String>("ionice") {
+                match IoniceClass::from_str(&ionice) {
+                    Some(class) => priority.ionice = Some(class),
+                    None => {
+                        self.plugin_instance.warn(format!(
+                            "ignoring unknown ionice class {} of process {}",
+                            ionice, cmd
                         ));
-                        break;
                     }
-                    Err(e) => {
-                        plugin_instance.error(format!(
-                            "error while reading {} of process {} (PID {}): {}",
-                            kind, process_name, pid, e
-                        ));
+                }
+            }
+            if let Ok(module) = opts_table.get::<_, AnyUserData>("module") {
+                match module.borrow::<ModuleHandle>() {
+                    Ok(module_handle) => status_module = Some(module_handle.module.clone()),
+                    Err(_) => {
+                        self.plugin_instance
+                            .warn("module option of spawn_process is no module handle".to_string());
                     }
                 }
             }
         }
 
-        let callback_key = on_output_key.map(Arc::new);
-        let child_stdout = child.stdout.take().unwrap();
-        tokio::spawn(read_lines(
-            child_stdout,
-            self.ctx.event_sender.clone(),
-            cmd.clone(),
-            self.plugin_instance.clone(),
-            callback_key.clone(),
-            pid,
-            "stdout",
-        ));
-        let child_stderr = child.stderr.take().unwrap();
-        tokio::spawn(read_lines(
-            child_stderr,
-            self.ctx.event_sender.clone(),
-            cmd.clone(),
-            self.plugin_instance.clone(),
-            callback_key,
-            pid,
-            "stderr",
-        ));
+        let spawn_limits = self.ctx.spawn_limits(lua);
+        if let Some(allowed_commands) = &spawn_limits.allowed_commands {
+            let reason = if !command_allowed(allowed_commands, &cmd) {
+                Some(format!("{} is not in allowed_commands", cmd))
+            } else {
+                restricted_env(&envs)
+                    .map(|key| format!("envs must not set {} when allowed_commands is set", key))
+            };
+            if let Some(reason) = reason {
+                self.plugin_instance.error(format!(
+                    "refusing to spawn process {}, because {}",
+                    cmd, reason
+                ));
+                return Ok((Value::Nil, Some(reason)));
+            }
+        }
 
-        let child_stdin = child.stdin.take().unwrap();
+        let max_processes = spawn_limits.max_processes_per_plugin;
+        let process_slot =
+            match ProcessSlot::acquire(&self.plugin_instance.running_processes, max_processes) {
+                Some(process_slot) => process_slot,
+                None => {
+                    let reason = format!(
+                        "max_processes_per_plugin ({}) processes are running",
+                        max_processes
+                    );
+                    self.plugin_instance.error(format!(
+                        "refusing to spawn process {}, because {}",
+                        cmd, reason
+                    ));
+                    return Ok((Value::Nil, Some(reason)));
+                }
+            };
 
-        let pid_file_path = self.ctx.pid_dir_path.join(format!("{}.pid", pid));
-        if let Err(e) = std::fs::File::create(&pid_file_path) {
-            self.plugin_instance.error(format!(
-                "couldn't create PID file {}: {}",
-                pid_file_path.display(),
-                e
-            ));
-        }
+        let stdin_contents = match stdin_from {
+            Some(path) => match read_stdin_from(&self.ctx.env_config.channel_home, &path) {
+                Ok(contents) => Some(contents),
+                Err(e) => {
+                    self.plugin_instance.error(format!(
+                        "couldn't read stdin_from file {} for process {}: {}",
+                        path, cmd, e
+                    ));
+                    return Ok((Value::Nil, None));
+                }
+            },
+            None => None,
+        };
 
+        let spawner = ProcessSpawner {
+            cmd: cmd.clone(),
+            args,
+            envs,
+            merge_stderr,
+            priority,
+            output_listeners: output_listeners.clone(),
+            recent_output: recent_output.clone(),
+            event_sender: self.ctx.event_sender.clone(),
+            plugin_instance: self.plugin_instance.clone(),
+            pid_dir_path: self.ctx.pid_dir_path.clone(),
+        };
+        let mut spawned = match spawner.spawn() {
+            Ok(spawned) => spawned,
+            Err(_) => return Ok((Value::Nil, None)),
+        };
+
+        let current = Arc::new(CurrentProcess::new(spawned.pid, spawned.stdin.take()));
+        if let Some(ref contents) = stdin_contents {
+            spawn_stdin_write(&current, contents.clone(), keep_stdin_open, cmd.clone());
+        }
         let (kill_tx, kill_rx) = oneshot::channel();
 
-        // Shutdown handler
+        // Shutdown handler, which also restarts the process if `restart_on_exit` is set
         tokio::spawn({
             let plugin_shutdown_wait_sender = self
                 .ctx
@@ -217,97 +281,131 @@ impl PluginInstanceHandle {
                 .unwrap()
                 .as_ref()
                 .clone();
-            let cmd = cmd.clone();
+            let current = current.clone();
+            let notification_sender = self.ctx.notification_sender.clone();
+            let event_sender = self.ctx.event_sender.clone();
             async move {
-                tokio::select!(
-                    _ = kill_rx => {
-                        match child.kill().await {
-                            Ok(_) => {
-                                let _ = child.wait().await;
-                            }
-                            Err(e) => {
-                                error!("tried to kill process {} (PID {}) which is not running: {}", cmd, pid, e);
+                let restart = restart_policy.map(|policy| Restart {
+                    policy,
+                    stdin_contents,
+                    keep_stdin_open,
+                });
+                let plugin_instance = spawner.plugin_instance.clone();
+                let cmd = spawner.cmd.clone();
+                supervise_process(
+                    spawned,
+                    kill_rx,
+                    restart,
+                    max_lifetime,
+                    &current,
+                    || spawner.spawn(),
+                    || {
+                        plugin_instance.error(format!(
+                            "process {} exited too often, not restarting it anymore",
+                            cmd
+                        ));
+                        if let Some(module) = status_module {
+                            module.set_status(Some("error".to_string()), &notification_sender);
+                        }
+                    },
+                    |exit| {
+                        let event = on_exit_key.as_ref().map(|callback_key| Event::ProcessExit {
+                            process_name: cmd.clone(),
+                            plugin_instance: plugin_instance.clone(),
+                            callback_key: callback_key.clone(),
+                            exit,
+                        });
+                        let event_sender = event_sender.clone();
+                        async move {
+                            if let Some(event) = event {
+                                let _ = event_sender.send(event).await;
                             }
                         }
                     },
-                    _ = child.wait() => {},
-                );
-                if let Err(e) = tokio::fs::remove_file(&pid_file_path).await {
-                    error!(
-                        "couldn't remove PID file {}: {}",
-                        pid_file_path.display(),
-                        e
-                    );
-                }
+                )
+                .await;
+                drop(process_slot);
                 drop(plugin_shutdown_wait_sender);
             }
         });
 
         let process_handle = ProcessHandle {
             cmd,
-            pid,
+            current,
             ctx: self.ctx.clone(),
-            child_stdin,
             kill_sender: Some(kill_tx),
+            output_listeners,
+            recent_output,
             plugin_instance: self.plugin_instance.clone(),
         };
 
-        lua.pack(process_handle)
+        Ok((lua.pack(process_handle)?, None))
     }
 
-    fn get_min_geometry_from_value(
+    fn claim_window<'lua>(
         &self,
-        lua: &Lua,
-        min_geometry_val: Value,
-    ) -> mlua::Result<MinGeometry> {
-        let mut min_geometry = Default::default();
-        match min_geometry_val {
-            Value::String(min_geometry_str) => match min_geometry_str.to_string_lossy().parse() {
-                Ok(parsed) => min_geometry = parsed,
-                Err(e) => {
-                    self.plugin_instance.warn(format!(
-                        "invalid geometry string for window (using default): {}",
-                        e
-                    ));
-                }
-            },
-            Value::Function(min_geometry_fn) => {
-                let key = lua.create_registry_value(min_geometry_fn)?;
-                min_geometry = MinGeometry::Dynamic {
-                    callback_key: Arc::new(key),
-                }
-            }
-            Value::Nil => {}
-            _ => {
-                error!("unexpected value for min_geometry");
-            }
+        lua: &'lua Lua,
+        (class, opts): (String, Value),
+    ) -> mlua::Result<(Value<'lua>, Option<Table<'lua>>)> {
+        let (window_handle, claim_error) = self.claim(lua, &class, opts)?;
+        let claim_error = match claim_error {
+            Some(claim_error) => Some(claim_error.to_lua_table(lua)?),
+            None => None,
         };
-        Ok(min_geometry)
+        Ok((lua.pack(window_handle)?, claim_error))
     }
 
-    fn claim_window<'lua>(
+    /// Like `claim_window`, but fails with an error naming the class and the timeout or the owner
+    /// of the window when no window could be claimed
+    fn assert_window<'lua>(
         &self,
         lua: &'lua Lua,
         (class, opts): (String, Value),
     ) -> mlua::Result<Value<'lua>> {
+        match self.claim(lua, &class, opts)? {
+            (Some(window_handle), _) => lua.pack(window_handle),
+            (None, Some(claim_error)) => Err(mlua::Error::RuntimeError(format!(
+                "couldn't claim window with class {}: {}",
+                class, claim_error
+            ))),
+            (None, None) => Err(mlua::Error::RuntimeError(format!(
+                "couldn't claim window with class {}: the window manager isn't available",
+                class
+            ))),
+        }
+    }
+
+    /// Returns the window handle or why no window could be claimed. Both are `None` when the window
+    /// manager isn't available.
+    fn claim(
+        &self,
+        lua: &Lua,
+        class: &str,
+        opts: Value,
+    ) -> mlua::Result<(Option<WindowHandle>, Option<ClaimError>)> {
         self.plugin_instance
             .debug(format!("Claiming window with class {}", class));
 
         let poll_interval_ms = 50;
-        let mut timeout_ms = 250;
+        let mut timeout_ms = DEFAULT_CLAIM_TIMEOUT_MS;
         let mut min_geometry = MinGeometry::default();
         let mut ignore_managed = false;
+        let mut allow_steal = false;
 
         if let Value::Table(opts_table) = opts {
             if let Ok(timeout) = opts_table.get::<_, u64>("timeout_ms") {
                 timeout_ms = timeout;
             }
             if let Ok(min_geometry_val) = opts_table.get::<_, Value>("min_geometry") {
-                min_geometry = self.get_min_geometry_from_value(lua, min_geometry_val)?;
+                min_geometry =
+                    get_min_geometry_from_value(lua, &self.plugin_instance, min_geometry_val)?;
             }
             if let Ok(ignore_managed_arg) = opts_table.get::<_, bool>("ignore_managed") {
                 ignore_managed = ignore_managed_arg;
             }
+            if let Ok(allow_steal_arg) = opts_table.get::<_, bool>("allow_steal") {
+                allow_steal = allow_steal_arg;
+            }
         }
 
         self.plugin_instance.debug(format!(
@@ -317,25 +415,67 @@ impl PluginInstanceHandle {
 
         let mut window_manager = match self.ctx.write_window_manager() {
             Some(wm) => wm,
-            None => return Ok(Value::Nil),
+            None => return Ok((None, None)),
         };
 
+        let mut claim_error = ClaimError::Timeout { timeout_ms };
         let timeout_end = Instant::now() + Duration::from_millis(timeout_ms);
         while Instant::now() < timeout_end {
-            match window_manager.get_window_by_class(&class, ignore_managed) {
-                Ok(Some(window)) => {
+            let window = match window_manager.get_window_by_class(class, ignore_managed) {
+                Ok(Some(candidate)) => match claim_step(candidate, allow_steal) {
+                    ClaimStep::Claim(window) => Some(window),
+                    ClaimStep::Steal {
+                        window,
+                        managed_wid,
+                    } => {
+                        self.plugin_instance.info(format!(
+                            "Stealing already managed window with class {}",
+                            class
+                        ));
+                        match window_manager.release_window(lua, managed_wid) {
+                            Ok(()) => Some(window),
+                            Err(e) => {
+                                self.plugin_instance.error(format!(
+                                    "Couldn't release window with class {} from its owner: {}",
+                                    class, e
+                                ));
+                                None
+                            }
+                        }
+                    }
+                    // Waiting wouldn't help, the window stays managed until its owner releases it
+                    ClaimStep::Conflict(error) => {
+                        claim_error = error;
+                        break;
+                    }
+                },
+                Ok(None) => None,
+                Err(e) => {
+                    self.plugin_instance
+                        .error(format!("Error getting window with class {}: {}", class, e));
+                    continue;
+                }
+            };
+
+            match window {
+                Some(window) => {
                     self.plugin_instance.debug(format!(
                         "Got window with class {}; letting the window manager manage it",
                         class
                     ));
-                    match window_manager.manage_x_window(lua, window, min_geometry.clone()) {
+                    match window_manager.manage_x_window(
+                        lua,
+                        window,
+                        min_geometry.clone(),
+                        self.plugin_instance.name.clone(),
+                    ) {
                         Ok(id) => {
                             let window_handle = WindowHandle {
                                 id,
                                 ctx: self.ctx.clone(),
                                 plugin_instance: self.plugin_instance.clone(),
                             };
-                            return lua.pack(window_handle);
+                            return Ok((Some(window_handle), None));
                         }
                         Err(e) => {
                             self.plugin_instance.error(format!(
@@ -345,7 +485,7 @@ impl PluginInstanceHandle {
                         }
                     }
                 }
-                Ok(None) => {
+                None => {
                     let sleep_time = std::cmp::min(
                         Duration::from_millis(poll_interval_ms),
                         timeout_end - Instant::now(),
@@ -354,18 +494,58 @@ impl PluginInstanceHandle {
                         thread::sleep(sleep_time);
                     }
                 }
-                Err(e) => {
-                    self.plugin_instance
-                        .error(format!("Error getting window with class {}: {}", class, e));
-                }
             }
         }
 
         self.plugin_instance.warn(format!(
-            "Couldn't claim window with class {} (timeout)",
-            class
+            "Couldn't claim window with class {} ({})",
+            class, claim_error
         ));
-        Ok(Value::Nil)
+        Ok((None, Some(claim_error)))
+    }
+
+    fn schedule_timer(
+        &self,
+        lua: &Lua,
+        delay_ms: u64,
+        repeat: bool,
+        callback: Function,
+    ) -> mlua::Result<TimerId> {
+        let delay = Duration::from_millis(delay_ms);
+        if repeat && delay.is_zero() {
+            self.plugin_instance
+                .warn("interval of 0ms is too short, using 1ms instead".to_string());
+        }
+        let interval = repeat.then(|| delay.max(Duration::from_millis(1)));
+        let plugin_instance = self.plugin_instance.clone();
+        Ok(self.plugin_instance.timers.schedule(
+            delay,
+            interval,
+            lua.create_registry_value(callback)?,
+            &self.ctx.main_runtime_handle,
+            self.ctx.event_sender.as_ref().clone(),
+            move |timer_id| Event::PluginTimer {
+                plugin_instance: plugin_instance.clone(),
+                timer_id,
+            },
+        ))
+    }
+
+    fn list_timers<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Table<'lua>> {
+        let timer_tables = self
+            .plugin_instance
+            .timers
+            .list()
+            .into_iter()
+            .map(|timer| {
+                let timer_table = lua.create_table()?;
+                timer_table.set("id", timer.id)?;
+                timer_table.set("remaining_ms", timer.remaining.as_millis() as u64)?;
+                timer_table.set("repeat", timer.repeat)?;
+                Ok(timer_table)
+            })
+            .collect::<mlua::Result<Vec<_>>>()?;
+        lua.create_sequence_from(timer_tables)
     }
 
     fn create_virtual_window<'lua>(
@@ -409,9 +589,70 @@ impl PluginInstanceHandle {
             }
         };
 
+        let callbacks = VirtualWindowCallbacks {
+            set_geometry_key,
+            map_key,
+            unmap_key,
+        };
+        self.manage_virtual_window(lua, name, callbacks, &opts)
+    }
+
+    fn create_image_window<'lua>(
+        &self,
+        lua: &'lua Lua,
+        (name, image_path, opts): (String, String, Option<Table<'lua>>),
+    ) -> mlua::Result<Value<'lua>> {
+        self.plugin_instance.debug(format!(
+            "Creating image window with name {} for image {}",
+            name, image_path
+        ));
+        if !self.ctx.env_config.channel_home.join(&image_path).is_file() {
+            self.plugin_instance.warn(format!(
+                "image {} of image window with name {} doesn't exist (yet)",
+                image_path, name
+            ));
+        }
+
+        let opts = match opts {
+            Some(opts) => opts,
+            None => lua.create_table()?,
+        };
+        // Invisible until the window manager shows the window
+        let state = Arc::new(Mutex::new(ImageWindowInfo {
+            plugin_instance: self.plugin_instance.name.clone(),
+            name: name.clone(),
+            image_path,
+            visible: false,
+            geometry: None,
+        }));
+        let callbacks =
+            image_window_callbacks(lua, self.ctx.notification_sender.clone(), state.clone())?;
+        let window_handle = self.manage_virtual_window(lua, name, callbacks, &opts)?;
+        if let Value::UserData(ref window_handle) = window_handle {
+            if let Ok(window_handle) = window_handle.borrow::<WindowHandle>() {
+                self.plugin_instance
+                    .image_windows
+                    .lock()
+                    .unwrap()
+                    .insert(window_handle.id, state);
+            }
+        }
+        Ok(window_handle)
+    }
+
+    /// Lets the window manager manage a virtual window with the options shared by
+    /// `create_virtual_window` and `create_image_window`
+    fn manage_virtual_window<'lua>(
+        &self,
+        lua: &'lua Lua,
+        name: String,
+        callbacks: VirtualWindowCallbacks,
+        opts: &Table,
+    ) -> mlua::Result<Value<'lua>> {
         let mut min_geometry = MinGeometry::default();
         if let Ok(min_geometry_val) = opts.get::<_, Value>("min_geometry") {
-            min_geometry = self.get_min_geometry_from_value(lua, min_geometry_val)?;
+            min_geometry =
+                get_min_geometry_from_value(lua, &self.plugin_instance, min_geometry_val)?;
         }
 
         let mut primary_demotion_action = PrimaryDemotionAction::default();
@@ -428,11 +669,14 @@ impl PluginInstanceHandle {
             }
         }
 
-        let callbacks = VirtualWindowCallbacks {
-            set_geometry_key,
-            map_key,
-            unmap_key,
-        };
+        let mut frontend_hint = FrontendHint::default();
+        if let Ok(frontend_id) = opts.get::<_, String>("frontend_id") {
+            frontend_hint.frontend_id = Some(frontend_id);
+        }
+        match opts.get::<_, Value>("metadata") {
+            Ok(Value::Nil) | Err(_) => {}
+            Ok(metadata) => frontend_hint.metadata_key = Some(lua.create_registry_value(metadata)?),
+        }
 
         let mut wm = match self.ctx.write_window_manager() {
             Some(wm) => wm,
@@ -444,6 +688,8 @@ impl PluginInstanceHandle {
             callbacks,
             min_geometry,
             primary_demotion_action,
+            frontend_hint,
+            self.plugin_instance.name.clone(),
         ) {
             Ok(id) => {
                 let window_handle = WindowHandle {
@@ -494,10 +740,27 @@ impl UserData for PluginInstanceHandle {
         methods.add_method("claim_window", |lua, this, (class, opts)| {
             this.claim_window(lua, (class, opts))
         });
+        methods.add_method("assert_window", |lua, this, (class, opts)| {
+            this.assert_window(lua, (class, opts))
+        });
 
         methods.add_method("create_virtual_window", |lua, this, (name, opts)| {
             this.create_virtual_window(lua, (name, opts))
         });
+
+        methods.add_method("set_timeout", |lua, this, (delay_ms, callback)| {
+            this.schedule_timer(lua, delay_ms, false, callback)
+        });
+        methods.add_method("set_interval", |lua, this, (interval_ms, callback)| {
+            this.schedule_timer(lua, interval_ms, true, callback)
+        });
+        methods.add_method("clear_timer", |_lua, this, id: TimerId| {
+            Ok(this.plugin_instance.timers.cancel(id))
+        });
+        methods.add_method("clear_all_timers", |_lua, this, ()| {
+            Ok(this.plugin_instance.timers.cancel_all())
+        });
+        methods.add_method("list_timers", |lua, this, ()| this.list_timers(lua));
     }
 }
 
@@ -512,50 +775,28 @@ impl ModuleHandle {
         lua: &Lua,
         (name, callback, opts): (String, Function, Value),
     ) -> mlua::Result<()> {
-        let mut actions = self.module.actions.write().unwrap();
-        if actions.iter().any(|a| a.name == name) {
-            self.module.error(format!(
-                "tried registering action with duplicate name {}",
-                name
-            ));
-        } else {
-            self.module.debug(format!("registering action {}", name));
-
-            let mut display_name = None;
-            if let Value::Table(opts_table) = opts {
-                if let Ok(display_name_arg) = opts_table.get::<_, String>("display_name") {
-                    display_name = Some(display_name_arg);
-                }
-            }
-
-            let key = lua.create_registry_value(callback)?;
-            let action = Action {
-                name,
-                display_name,
-                key,
-            };
-            actions.push(action);
+        if add_action(lua, &self.module, name, callback, opts)? {
+            self.module
+                .notify_actions_update(&self.ctx.notification_sender);
         }
         Ok(())
     }
 
+    fn unregister_action(&self, lua: &Lua, name: String) -> mlua::Result<bool> {
+        if !remove_action(lua, &self.module, &name) {
+            return Ok(false);
+        }
+        self.module
+            .notify_actions_update(&self.ctx.notification_sender);
+        Ok(true)
+    }
+
     fn set_status(&self, status: Option<ModuleStatus>) -> mlua::Result<()> {
         self.module
             .debug(format!("setting module status to '{:?}'", status));
-        let mut module_status = self.module.status.write().unwrap();
-        *module_status = status.clone();
-
-        let _ = self
-            .ctx
-            .notification_sender
-            .send(Notification::ModuleStatusUpdate {
-                module_identifier: ModuleIdentifier {
-                    plugin_instance: self.module.plugin_instance_name.clone(),
-                    module: self.module.name.clone(),
-                },
-                new_status: status,
-            });
-
+        *self.module.status.write().unwrap() = status;
+        self.ctx
+            .notify_module_update(&self.module, UpdateKind::Status);
         Ok(())
     }
 
@@ -570,41 +811,31 @@ impl ModuleHandle {
     fn set_message(&self, message: Option<ModuleMessage>) -> mlua::Result<()> {
         self.module
             .debug(format!("setting module message to '{:?}'", message));
-        let mut module_message = self.module.message.write().unwrap();
-        *module_message = message.clone();
-
-        let _ = self
-            .ctx
-            .notification_sender
-            .send(Notification::ModuleMessageUpdate {
-                module_identifier: ModuleIdentifier {
-                    plugin_instance: self.module.plugin_instance_name.clone(),
-                    module: self.module.name.clone(),
-                },
-                new_message: message,
-            });
-
+        *self.module.message.write().unwrap() = message;
+        self.ctx
+            .notify_module_update(&self.module, UpdateKind::Message);
         Ok(())
     }
 
     fn set_active_actions(&self, actions: Vec<String>) -> mlua::Result<()> {
         self.module
-            .debug(format!("setting active actions to '{:?}'", actions));
-        let mut active_actions = self.module.active_actions.write().unwrap();
-        active_actions.clear();
-        active_actions.extend(actions);
-
-        let _ = self
-            .ctx
-            .notification_sender
-            .send(Notification::ModuleActiveActionsUpdate {
-                module_identifier: ModuleIdentifier {
-                    plugin_instance: self.module.plugin_instance_name.clone(),
-                    module: self.module.name.clone(),
-                },
-                new_active_actions: active_actions.clone(),
-            });
+            .set_active_actions(actions, &self.ctx.notification_sender);
+        Ok(())
+    }
 
+    fn attach_window(&self, window: AnyUserData) -> mlua::Result<()> {
+        let id = match window.borrow::<WindowHandle>() {
+            Ok(window_handle) => window_handle.id,
+            Err(_) => {
+                self.module
+                    .error("tried attaching something that is no window handle".to_string());
+                return Ok(());
+            }
+        };
+        self.module
+            .debug(format!("attaching window with managed wid {}", id));
+        *self.module.attached_window.write().unwrap() = Some(id);
+        self.ctx.sync_attached_windows();
         Ok(())
     }
 }
@@ -632,6 +863,10 @@ impl UserData for ModuleHandle {
             this.register_action(lua, args)
         });
 
+        methods.add_method("unregister_action", |lua, this, name| {
+            this.unregister_action(lua, name)
+        });
+
         methods.add_method("set_status", |_lua, this, status| this.set_status(status));
 
         methods.add_method("get_status", |lua, this, ()| this.get_status(lua));
@@ -640,376 +875,4779 @@ impl UserData for ModuleHandle {
             this.set_message(message)
         });
 
+        methods.add_method("set_icon", |_lua, this, icon| {
+            this.module.set_icon(icon, &this.ctx.notification_sender);
+            Ok(())
+        });
+
         methods.add_method("set_active_actions", |_lua, this, actions| {
             this.set_active_actions(actions)
         });
-    }
-}
-
-struct ProcessHandle {
-    child_stdin: ChildStdin,
-    kill_sender: Option<oneshot::Sender<()>>,
-    ctx: Arc<LuaContext>,
-    cmd: String,
-    pid: u32,
-    plugin_instance: Arc<PluginInstance>,
-}
-
-impl ProcessHandle {
-    fn write(&mut self, _lua: &Lua, buf: String) -> mlua::Result<()> {
-        self.ctx.plugin_runtime.block_on(async {
-            // Fails when process is not running anymore
-            self.child_stdin.write_all(buf.as_bytes()).await
-        })?;
-        Ok(())
-    }
 
-    fn writeln(&mut self, lua: &Lua, line: String) -> mlua::Result<()> {
-        self.write(lua, line + "\n")
+        methods.add_method("attach_window", |_lua, this, window| {
+            this.attach_window(window)
+        });
     }
+}
 
-    fn kill(&mut self) -> mlua::Result<()> {
-        self.plugin_instance
-            .debug(format!("killing process {} (PID {})", self.cmd, self.pid));
-        match self.kill_sender.take() {
-            Some(kill_tx) => {
-                if kill_tx.send(()).is_err() {
-                    self.plugin_instance.warn(format!(
-                        "tried to kill process {} (PID {}) which is not running",
-                        self.cmd, self.pid
-                    ));
+/// Checks the `requires` option of `register_plugin_instance` and `register_module` against the
+/// active config. The error names the first requirement that isn't satisfied.
+fn check_requirements(spawn_limits: &config::SpawnLimits, opts: &Value) -> Result<(), String> {
+    let requires = match opts {
+        Value::Table(opts_table) => match opts_table.get::<_, Option<Vec<String>>>("requires") {
+            Ok(requires) => requires.unwrap_or_default(),
+            Err(_) => return Err("requires has to be a list of strings".to_string()),
+        },
+        _ => return Ok(()),
+    };
+    for requirement in requires {
+        match requirement.split_once(':') {
+            Some(("spawn", cmd)) => {
+                if let Some(allowed_commands) = &spawn_limits.allowed_commands {
+                    if !command_allowed(allowed_commands, cmd) {
+                        return Err(format!(
+                            "requirement {} is not satisfied, because {} is not in allowed_commands",
+                            requirement, cmd
+                        ));
+                    }
                 }
             }
-            None => {
-                self.plugin_instance.warn(format!(
-                    "tried to kill process {} (PID {}) which was already killed explicitly",
-                    self.cmd, self.pid
+            _ => {
+                return Err(format!(
+                    "requirement {} is not supported by this version of neopult",
+                    requirement
                 ));
             }
         }
-        Ok(())
     }
+    Ok(())
 }
 
-impl UserData for ProcessHandle {
-    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method_mut("write", |lua, this, buf| this.write(lua, buf));
-        methods.add_method_mut("writeln", |lua, this, line| this.writeln(lua, line));
-        methods.add_method_mut("kill", |_lua, this, ()| this.kill());
-    }
+/// Environment variables that change which program a command runs, so plugins can't set them when
+/// `allowed_commands` is set
+const RESTRICTED_ENVS: &[&str] = &["PATH", "LD_PRELOAD", "LD_LIBRARY_PATH"];
+
+/// Commands are compared with the entries of `allowed_commands` as plain strings. A bare name like
+/// `ffmpeg` is then looked up in neopult's PATH when spawning, since plugins can't override it
+/// (see `restricted_env`).
+fn command_allowed(allowed_commands: &[String], cmd: &str) -> bool {
+    allowed_commands.iter().any(|allowed| allowed == cmd)
 }
 
-struct WindowHandle {
-    id: ManagedWid,
-    ctx: Arc<LuaContext>,
-    plugin_instance: Arc<PluginInstance>,
+fn restricted_env(envs: &HashMap<String, String>) -> Option<&str> {
+    envs.keys()
+        .map(String::as_str)
+        .find(|key| RESTRICTED_ENVS.contains(key))
 }
 
-impl WindowHandle {
-    fn max(&self, lua: &Lua, (size_table, opts): (Table, Value)) -> mlua::Result<()> {
-        self.plugin_instance.debug(format!(
-            "setting mode of window with managed wid {} to max",
-            self.id
-        ));
+/// Reads the file whose contents are piped to the stdin of a process. Relative paths are relative
+/// to the channel home.
+fn read_stdin_from(channel_home: &Path, path: &str) -> std::io::Result<Vec<u8>> {
+    std::fs::read(channel_home.join(path))
+}
 
-        let width = match size_table.get::<_, u16>(1) {
-            Ok(w) => w,
-            Err(e) => {
-                self.plugin_instance
-                    .error(format!("couldn't get width: {}", e));
-                return Ok(());
-            }
-        };
+/// Last lines of a process output, see the `buffer_lines` option of `spawn_process`
+#[derive(Debug)]
+struct RecentOutput {
+    capacity: usize,
+    lines: Mutex<VecDeque<String>>,
+}
 
-        let height = match size_table.get::<_, u16>(2) {
-            Ok(h) => h,
-            Err(e) => {
-                self.plugin_instance
-                    .error(format!("couldn't get height: {}", e));
-                return Ok(());
-            }
-        };
+impl RecentOutput {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lines: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
 
-        let mut margin = Margin::default();
+    fn push(&self, line: &str) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == self.capacity {
+            lines.pop_front();
+        }
+        lines.push_back(line.to_string());
+    }
 
-        if let Value::Table(opts_table) = opts {
-            if let Ok(margin_table) = opts_table.get::<_, Table>("margin") {
-                if let Ok(top) = margin_table.get::<_, u16>("top") {
-                    margin.top = top;
-                }
-                if let Ok(right) = margin_table.get::<_, u16>("right") {
-                    margin.right = right;
-                }
-                if let Ok(bottom) = margin_table.get::<_, u16>("bottom") {
-                    margin.bottom = bottom;
+    fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Forwards every line of the process output to the event loop, where the output listeners are
+/// called. Lines are also kept in `recent_output`, if given.
+#[allow(clippy::too_many_arguments)]
+async fn read_process_lines(
+    source: impl AsyncReadExt + Unpin,
+    event_sender: Arc<mpsc::Sender<Event>>,
+    process_name: String,
+    plugin_instance: Arc<PluginInstance>,
+    listeners: Arc<OutputListeners>,
+    recent_output: Option<Arc<RecentOutput>>,
+    pid: u32,
+    kind: &str,
+) {
+    let mut reader = BufReader::new(source);
+    let mut buf = Vec::new();
+    loop {
+        match next_line_lossy(&mut reader, &mut buf).await {
+            Ok(Some(line)) => {
+                plugin_instance.debug(format!(
+                    "process {} (PID {}) {} line: {}",
+                    process_name, pid, kind, line
+                ));
+                if let Some(recent_output) = recent_output.as_ref() {
+                    recent_output.push(&line);
                 }
-                if let Ok(left) = margin_table.get::<_, u16>("left") {
-                    margin.left = left;
+                if !listeners.is_empty() {
+                    let event = Event::ProcessOutput {
+                        line,
+                        process_name: process_name.clone(),
+                        plugin_instance: plugin_instance.clone(),
+                        listeners: listeners.clone(),
+                    };
+                    if event_sender.send(event).await.is_err() {
+                        plugin_instance.warn(format!(
+                            "event receiver was dropped, couldn't send process output ({})",
+                            kind
+                        ));
+                        break;
+                    };
                 }
             }
+            Ok(None) => {
+                plugin_instance.debug(format!(
+                    "{} of process {} (PID {}) closed",
+                    kind, process_name, pid
+                ));
+                break;
+            }
+            Err(e) => {
+                plugin_instance.error(format!(
+                    "error while reading {} of process {} (PID {}): {}",
+                    kind, process_name, pid, e
+                ));
+            }
         }
+    }
+}
 
-        let mut wm = match self.ctx.write_window_manager() {
-            Some(wm) => wm,
-            None => return Ok(()),
-        };
-        if let Err(e) = wm.max_window(lua, self.id, (width, height), margin) {
-            self.plugin_instance
-                .error(format!("error setting window mode to max: {}", e));
-        }
+const DEFAULT_MAX_RESTARTS: u32 = 5;
+const DEFAULT_RESTART_DELAY_MS: u64 = 1000;
+/// Processes that ran at least this long before exiting didn't crash rapidly, so their restart
+/// count is reset
+const RESTART_RESET_AFTER: Duration = Duration::from_secs(60);
+/// How long the output of an ended process is read before its exit is reported anyway, e.g.
+/// because a child of the process keeps the output open
+const OUTPUT_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
 
-        Ok(())
-    }
+/// Decides when processes spawned with `restart_on_exit` are restarted. The delay doubles with
+/// every restart.
+#[derive(Debug, Clone, Copy)]
+struct RestartPolicy {
+    max_restarts: u32,
+    delay: Duration,
+    restarts: u32,
+}
 
-    fn min(&self, lua: &Lua) -> mlua::Result<()> {
-        self.plugin_instance.debug(format!(
-            "setting mode of window with managed wid {} to min",
-            self.id
-        ));
-        let mut wm = match self.ctx.write_window_manager() {
-            Some(wm) => wm,
-            None => return Ok(()),
-        };
-        if let Err(e) = wm.min_window(lua, self.id) {
-            self.plugin_instance
-                .error(format!("error setting window mode to min: {}", e));
+impl RestartPolicy {
+    fn new(max_restarts: u32, delay: Duration) -> Self {
+        Self {
+            max_restarts,
+            delay,
+            restarts: 0,
         }
-        Ok(())
     }
 
-    fn hide(&self, lua: &Lua) -> mlua::Result<()> {
-        self.plugin_instance
-            .debug(format!("hiding window with managed wid {}", self.id));
-        let mut wm = match self.ctx.write_window_manager() {
-            Some(wm) => wm,
-            None => return Ok(()),
-        };
-        if let Err(e) = wm.hide_window(lua, self.id) {
-            self.plugin_instance
-                .error(format!("error hiding window: {}", e));
+    /// Returns how long to wait before restarting a process that exited after `uptime` or `None`
+    /// if the process crashed too often.
+    fn next_delay(&mut self, uptime: Duration) -> Option<Duration> {
+        if uptime >= RESTART_RESET_AFTER {
+            self.restarts = 0;
         }
-        Ok(())
-    }
-
-    fn unclaim(&self, lua: &Lua) -> mlua::Result<()> {
-        self.plugin_instance
-            .debug(format!("unclaiming window with managed wid {}", self.id));
-        let mut wm = match self.ctx.write_window_manager() {
-            Some(wm) => wm,
-            None => return Ok(()),
-        };
-        if let Err(e) = wm.release_window(lua, self.id) {
-            self.plugin_instance
-                .error(format!("error unclaiming window: {}", e));
+        if self.restarts >= self.max_restarts {
+            return None;
         }
-        Ok(())
+        let delay = self.delay.saturating_mul(1 << self.restarts.min(16));
+        self.restarts += 1;
+        Some(delay)
     }
+}
 
-    fn is_primary_window(&self) -> mlua::Result<bool> {
-        let wm = match self.ctx.read_window_manager() {
-            Some(wm) => wm,
-            None => return Ok(false),
-        };
-        Ok(wm.is_primary_window(self.id))
-    }
+#[derive(Debug)]
+struct Restart {
+    policy: RestartPolicy,
+    /// Written to every restarted process, like to the first one
+    stdin_contents: Option<Vec<u8>>,
+    keep_stdin_open: bool,
 }
 
-impl UserData for WindowHandle {
-    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_method("max", |lua, this, args| this.max(lua, args));
-        methods.add_method("min", |lua, this, ()| this.min(lua));
-        methods.add_method("hide", |lua, this, ()| this.hide(lua));
-        methods.add_method("unclaim", |lua, this, ()| this.unclaim(lua));
-        methods.add_method("is_primary_window", |_lua, this, ()| {
-            this.is_primary_window()
-        });
-    }
+/// Spawns the process and forwards its output. This is repeated when the process is restarted.
+struct ProcessSpawner {
+    cmd: String,
+    args: Vec<String>,
+    envs: HashMap<String, String>,
+    /// Whether stdout and stderr share one pipe, so that their lines arrive in the order in which
+    /// they were written
+    merge_stderr: bool,
+    priority: ProcessPriority,
+    output_listeners: Arc<OutputListeners>,
+    recent_output: Option<Arc<RecentOutput>>,
+    event_sender: Arc<mpsc::Sender<Event>>,
+    plugin_instance: Arc<PluginInstance>,
+    pid_dir_path: PathBuf,
 }
 
-#[derive(Debug)]
-struct Store {
-    subscriber_callbacks: HashSet<Arc<RegistryKey>>,
+const MIN_NICE: i32 = -20;
+const MAX_NICE: i32 = 19;
+
+/// I/O scheduling class as understood by `ioprio_set(2)`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IoniceClass {
+    /// Only gets disk time when no other process needs it
+    Idle,
+    /// The default class, with the lowest priority level
+    BestEffort,
 }
 
-impl Store {
-    fn new() -> Self {
-        Self {
-            subscriber_callbacks: HashSet::new(),
+impl IoniceClass {
+    fn from_str(class: &str) -> Option<Self> {
+        match class {
+            "idle" => Some(IoniceClass::Idle),
+            "best_effort" => Some(IoniceClass::BestEffort),
+            _ => None,
         }
     }
-}
 
-impl UserData for Store {
-    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
-        methods.add_function("get", |_lua, store: AnyUserData| {
-            store.get_user_value::<Value>()
-        });
-
-        methods.add_function("set", |lua, (store, value): (AnyUserData, Value)| {
-            store.set_user_value(value.clone())?;
-            let mut callbacks = vec![];
-            for cb_key in &store.borrow::<Store>()?.subscriber_callbacks {
-                if let Ok(cb) = lua.registry_value::<Function>(cb_key) {
-                    callbacks.push(cb);
-                };
-            }
-            // Call callbacks outside of store borrow, so that callbacks can call unscubscribe
-            for cb in callbacks {
-                if let Err(e) = cb.call::<_, Value>(value.clone()) {
-                    error!("error in store subscriber callback: {:?}", e);
-                }
+    /// Value of the `ioprio` argument of `ioprio_set(2)`
+    fn ioprio(self) -> libc::c_int {
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        const IOPRIO_CLASS_BE: libc::c_int = 2;
+        const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+        const IOPRIO_BE_LOWEST_LEVEL: libc::c_int = 7;
+        match self {
+            IoniceClass::Idle => IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            IoniceClass::BestEffort => {
+                (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | IOPRIO_BE_LOWEST_LEVEL
             }
-            Ok(())
-        });
-
-        methods.add_function("subscribe", |lua, (store, cb): (AnyUserData, Function)| {
-            let cb_key = Arc::new(lua.create_registry_value(cb)?);
-            store
-                .borrow_mut::<Store>()?
-                .subscriber_callbacks
-                .insert(cb_key.clone());
-            Ok(StoreSubscription::new(cb_key))
-        });
-
-        methods.add_function(
-            "unsubscribe",
-            |_lua, (store, subscription): (AnyUserData, StoreSubscription)| {
-                store
-                    .borrow_mut::<Store>()?
-                    .subscriber_callbacks
-                    .remove(&subscription.callback_key);
-                Ok(())
-            },
-        );
+        }
     }
 }
 
-#[derive(Debug, Clone)]
-struct StoreSubscription {
-    callback_key: Arc<RegistryKey>,
+/// Scheduling priority of a spawned process. Unset values are inherited from neopult.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct ProcessPriority {
+    nice: Option<i32>,
+    ionice: Option<IoniceClass>,
 }
 
-impl StoreSubscription {
-    fn new(callback_key: Arc<RegistryKey>) -> Self {
-        Self { callback_key }
+impl ProcessPriority {
+    /// Applies the priority in the child before it execs, so the process never runs with
+    /// neopult's priority. Lowering the nice value below neopult's requires privileges, without
+    /// them spawning fails.
+    fn apply_to(self, command: &mut Command) {
+        if self == ProcessPriority::default() {
+            return;
+        }
+        // SAFETY: The closure only does async-signal-safe syscalls.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(nice) = self.nice {
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                if let Some(ionice) = self.ionice {
+                    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+                    let ret =
+                        libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ionice.ioprio());
+                    if ret == -1 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
     }
 }
 
-impl UserData for StoreSubscription {}
+impl ProcessSpawner {
+    /// Has to be called inside of a runtime with an I/O driver. Errors are already logged.
+    fn spawn(&self) -> io::Result<SpawnedProcess> {
+        let mut command = Command::new(&self.cmd);
+        command
+            .args(&self.args)
+            .envs(&self.envs)
+            .stdin(Stdio::piped());
+        self.priority.apply_to(&mut command);
+        let merged_output = if self.merge_stderr {
+            let (reader, stdout_writer, stderr_writer) = match merged_output_pipe() {
+                Ok(pipe) => pipe,
+                Err(e) => {
+                    self.plugin_instance.error(format!(
+                        "couldn't create output pipe for process {}: {}",
+                        self.cmd, e
+                    ));
+                    return Err(e);
+                }
+            };
+            command.stdout(stdout_writer).stderr(stderr_writer);
+            Some(reader)
+        } else {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+            None
+        };
+        let child_result = command.spawn();
+        // Closes the write ends of the merged output pipe, so that reading it ends once the child
+        // exits
+        drop(command);
 
-fn register_plugin_instance<'lua>(
-    lua: &'lua Lua,
-    (name, opts): (String, Value),
-    ctx: Arc<LuaContext>,
-) -> mlua::Result<Value<'lua>> {
-    let mut plugin_instances = ctx.plugin_instances.write().unwrap();
-    if plugin_instances.iter().any(|p| p.name == name) {
-        error!(
-            "tried registering plugin instance with duplicate name {}",
-            name
-        );
-        Ok(Value::Nil)
-    } else {
-        debug!("registering plugin instance {}", name);
-        let mut cleanup_key = None;
+        let mut child = match child_result {
+            Err(e) => {
+                self.plugin_instance.error(format!(
+                    "couldn't spawn process {} with args {:?} and envs {:?}: {}",
+                    self.cmd, self.args, self.envs, e
+                ));
+                return Err(e);
+            }
+            Ok(c) => c,
+        };
+        let pid = child.id().unwrap();
+        self.plugin_instance.debug(format!(
+            "spawned process {} with args {:?} and envs {:?} (PID {})",
+            self.cmd, self.args, self.envs, pid,
+        ));
 
-        if let Value::Table(opts_table) = opts {
-            if let Ok(cb) = opts_table.get::<_, Function>("on_cleanup") {
-                cleanup_key = Some(lua.create_registry_value(cb)?);
+        let output_tasks = match merged_output {
+            Some(reader) => {
+                vec![tokio::spawn(read_process_lines(
+                    reader,
+                    self.event_sender.clone(),
+                    self.cmd.clone(),
+                    self.plugin_instance.clone(),
+                    self.output_listeners.clone(),
+                    self.recent_output.clone(),
+                    pid,
+                    "output",
+                ))]
             }
-        }
+            None => {
+                let child_stdout = child.stdout.take().unwrap();
+                let child_stderr = child.stderr.take().unwrap();
+                vec![
+                    tokio::spawn(read_process_lines(
+                        child_stdout,
+                        self.event_sender.clone(),
+                        self.cmd.clone(),
+                        self.plugin_instance.clone(),
+                        self.output_listeners.clone(),
+                        self.recent_output.clone(),
+                        pid,
+                        "stdout",
+                    )),
+                    tokio::spawn(read_process_lines(
+                        child_stderr,
+                        self.event_sender.clone(),
+                        self.cmd.clone(),
+                        self.plugin_instance.clone(),
+                        self.output_listeners.clone(),
+                        self.recent_output.clone(),
+                        pid,
+                        "stderr",
+                    )),
+                ]
+            }
+        };
 
-        let plugin_instance = Arc::new(PluginInstance::new(name, cleanup_key));
-        let plugin_instance_handle = PluginInstanceHandle {
-            plugin_instance: plugin_instance.clone(),
-            ctx: ctx.clone(),
+        let pid_file_path = match create_pid_file(&self.pid_dir_path, pid, &self.cmd) {
+            Ok(pid_file_path) => Some(pid_file_path),
+            Err(e) => {
+                self.plugin_instance.error(format!(
+                    "couldn't create PID file for process {} (PID {}) in {}: {}",
+                    self.cmd,
+                    pid,
+                    self.pid_dir_path.display(),
+                    e
+                ));
+                None
+            }
         };
-        plugin_instances.push(plugin_instance);
-        lua.pack(plugin_instance_handle)
+
+        Ok(SpawnedProcess {
+            name: self.cmd.clone(),
+            stdin: child.stdin.take(),
+            child,
+            pid,
+            pid_file_path,
+            output_tasks,
+        })
     }
 }
 
-fn generate_token(num_chars: u8) -> mlua::Result<String> {
-    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), num_chars as usize);
-    Ok(token)
+/// Returns the nonblocking read end and a write end each for stdout and stderr. Has to be called
+/// inside of a runtime with an I/O driver.
+fn merged_output_pipe() -> io::Result<(OutputPipe, std::io::PipeWriter, std::io::PipeWriter)> {
+    let (reader, stdout_writer) = std::io::pipe()?;
+    let stderr_writer = stdout_writer.try_clone()?;
+    let reader = File::from(OwnedFd::from(reader));
+    let fd = reader.as_raw_fd();
+    // SAFETY: The file owns the file descriptor and keeps it open.
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((
+        OutputPipe(AsyncFd::new(reader)?),
+        stdout_writer,
+        stderr_writer,
+    ))
 }
 
-fn get_channel(_lua: &Lua, _: Value, ctx: Arc<LuaContext>) -> mlua::Result<u8> {
-    Ok(ctx.env_config.channel)
+/// Read end of a pipe that is read on the reactor, unlike `tokio::fs::File`, which would keep a
+/// thread of the blocking pool busy for as long as the process runs
+struct OutputPipe(AsyncFd<File>);
+
+impl AsyncRead for OutputPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = match self.0.poll_read_ready(cx) {
+                Poll::Ready(Ok(guard)) => guard,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            };
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|pipe| pipe.get_ref().read(unfilled)) {
+                Ok(Ok(len)) => {
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                // Not readable after all, the readiness was cleared
+                Err(_would_block) => continue,
+            }
+        }
+    }
 }
 
-fn get_channel_home(_lua: &Lua, _: Value, ctx: Arc<LuaContext>) -> mlua::Result<String> {
-    Ok(ctx.env_config.channel_home.display().to_string())
+struct SpawnedProcess {
+    name: String,
+    child: Child,
+    pid: u32,
+    stdin: Option<ChildStdin>,
+    pid_file_path: Option<PathBuf>,
+    /// Tasks reading the output, which end once the output is closed
+    output_tasks: Vec<JoinHandle<()>>,
 }
 
-fn create_store<'lua>(lua: &'lua Lua, value: Value<'lua>) -> mlua::Result<AnyUserData<'lua>> {
-    let store: mlua::AnyUserData = lua.create_userdata(Store::new())?;
-    store.set_user_value(value)?;
-    Ok(store)
+impl SpawnedProcess {
+    /// Waits until the output of the ended process was read, so that the exit is reported after
+    /// the last output line
+    async fn drain_output(&mut self) {
+        let output_tasks = std::mem::take(&mut self.output_tasks);
+        let drained = tokio::time::timeout(OUTPUT_DRAIN_TIMEOUT, async {
+            for output_task in output_tasks {
+                let _ = output_task.await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "output of process {} (PID {}) is still open after it ended",
+                self.name, self.pid
+            );
+        }
+    }
 }
 
-fn reposition_windows(lua: &Lua, _: Value, ctx: Arc<LuaContext>) -> mlua::Result<()> {
-    let mut wm = match ctx.write_window_manager() {
-        Some(wm) => wm,
-        None => return Ok(()),
+/// The process that a `ProcessHandle` controls, which changes when the process is restarted
+#[derive(Debug)]
+struct CurrentProcess {
+    pid: AtomicU32,
+    /// `None` when stdin was closed after writing the `stdin_from` contents. Stays locked while
+    /// the contents are written, so that other writes come after them.
+    stdin: Arc<tokio::sync::Mutex<Option<ChildStdin>>>,
+}
+
+impl CurrentProcess {
+    fn new(pid: u32, stdin: Option<ChildStdin>) -> Self {
+        Self {
+            pid: AtomicU32::new(pid),
+            stdin: Arc::new(tokio::sync::Mutex::new(stdin)),
+        }
+    }
+}
+
+/// Writes the `stdin_from` contents in a task, so that contents that don't fit into the pipe
+/// don't block the event loop until the process reads them.
+fn spawn_stdin_write(current: &CurrentProcess, contents: Vec<u8>, keep_open: bool, name: String) {
+    let pid = current.pid.load(Ordering::SeqCst);
+    // Locked before the task starts, so that writes of the plugin can't overtake the contents
+    if let Ok(mut stdin) = current.stdin.clone().try_lock_owned() {
+        tokio::spawn(async move {
+            write_stdin_contents(&mut stdin, &contents, keep_open, &name, pid).await;
+        });
+    }
+}
+
+/// Closes stdin afterwards unless `keep_open` is set
+async fn write_stdin_contents(
+    stdin: &mut Option<ChildStdin>,
+    contents: &[u8],
+    keep_open: bool,
+    name: &str,
+    pid: u32,
+) {
+    if let Some(child_stdin) = stdin {
+        if let Err(e) = child_stdin.write_all(contents).await {
+            error!(
+                "couldn't write stdin_from contents to process {} (PID {}): {}",
+                name, pid, e
+            );
+        }
+    }
+    if !keep_open {
+        *stdin = None;
+    }
+}
+
+/// Sends SIGINT to the process and kills it with SIGKILL if it is still alive after the grace
+/// period, like old processes are killed on startup.
+async fn kill_gracefully(spawned: &mut SpawnedProcess) {
+    if let Err(e) = signal::kill(Pid::from_raw(spawned.pid as i32), Signal::SIGINT) {
+        warn!(
+            "error sending SIGINT to process {} (PID {}): {}",
+            spawned.name, spawned.pid, e
+        );
+    }
+    let wait = tokio::time::timeout(OLD_PROCESS_SHUTDOWN_GRACE_PERIOD, spawned.child.wait());
+    if wait.await.is_err() {
+        debug!(
+            "process {} (PID {}) is still alive after grace period -- killing with SIGKILL",
+            spawned.name, spawned.pid
+        );
+        match spawned.child.kill().await {
+            Ok(_) => {
+                let _ = spawned.child.wait().await;
+            }
+            Err(e) => {
+                error!(
+                    "error sending SIGKILL to process {} (PID {}): {}",
+                    spawned.name, spawned.pid, e
+                );
+            }
+        }
+    }
+}
+
+/// Waits until the process exits, is killed via `kill_rx` or exceeds `max_lifetime`. Exited
+/// processes are restarted with `spawn` if `restart` is set, `on_give_up` is called when they
+/// crashed too often. `on_exit` is called whenever a process ends, after its output was read.
+#[allow(clippy::too_many_arguments)]
+async fn supervise_process<F: Future<Output = ()>>(
+    mut spawned: SpawnedProcess,
+    mut kill_rx: oneshot::Receiver<()>,
+    mut restart: Option<Restart>,
+    max_lifetime: Option<Duration>,
+    current: &CurrentProcess,
+    mut spawn: impl FnMut() -> io::Result<SpawnedProcess>,
+    on_give_up: impl FnOnce(),
+    mut on_exit: impl FnMut(ProcessExit) -> F,
+) {
+    loop {
+        let started = Instant::now();
+        let lifetime_end = async {
+            match max_lifetime {
+                Some(max_lifetime) => tokio::time::sleep(max_lifetime).await,
+                None => std::future::pending().await,
+            }
+        };
+        let exit = tokio::select!(
+            _ = &mut kill_rx => {
+                match spawned.child.kill().await {
+                    Ok(_) => {
+                        let _ = spawned.child.wait().await;
+                    }
+                    Err(e) => {
+                        error!("tried to kill process {} (PID {}) which is not running: {}", spawned.name, spawned.pid, e);
+                    }
+                }
+                ProcessExit::Killed
+            },
+            _ = lifetime_end => {
+                warn!(
+                    "process {} (PID {}) exceeded its max lifetime of {}ms, killing it",
+                    spawned.name,
+                    spawned.pid,
+                    max_lifetime.unwrap_or_default().as_millis()
+                );
+                kill_gracefully(&mut spawned).await;
+                ProcessExit::Timeout
+            },
+            status = spawned.child.wait() => ProcessExit::Exited {
+                code: status.ok().and_then(|status| status.code()),
+            },
+        );
+        let exited = matches!(exit, ProcessExit::Exited { .. });
+        spawned.drain_output().await;
+        on_exit(exit).await;
+        if let Some(ref pid_file_path) = spawned.pid_file_path {
+            if let Err(e) = tokio::fs::remove_file(pid_file_path).await {
+                error!(
+                    "couldn't remove PID file {}: {}",
+                    pid_file_path.display(),
+                    e
+                );
+            }
+        }
+
+        let restart = match restart.as_mut() {
+            Some(restart) if exited => restart,
+            _ => return,
+        };
+        let mut uptime = started.elapsed();
+        spawned = loop {
+            let delay = match restart.policy.next_delay(uptime) {
+                Some(delay) => delay,
+                None => {
+                    on_give_up();
+                    return;
+                }
+            };
+            warn!(
+                "process {} (PID {}) exited, restarting it in {}ms",
+                spawned.name,
+                spawned.pid,
+                delay.as_millis()
+            );
+            tokio::select!(
+                _ = &mut kill_rx => return,
+                _ = tokio::time::sleep(delay) => {},
+            );
+            match spawn() {
+                Ok(spawned) => break spawned,
+                // Failed spawns count as crashes, e.g. when the executable was removed
+                Err(_) => uptime = Duration::ZERO,
+            }
+        };
+
+        let mut stdin = current.stdin.lock().await;
+        *stdin = spawned.stdin.take();
+        current.pid.store(spawned.pid, Ordering::SeqCst);
+        if let Some(ref contents) = restart.stdin_contents {
+            write_stdin_contents(
+                &mut stdin,
+                contents,
+                restart.keep_stdin_open,
+                &spawned.name,
+                spawned.pid,
+            )
+            .await;
+        }
+    }
+}
+
+struct ProcessHandle {
+    current: Arc<CurrentProcess>,
+    kill_sender: Option<oneshot::Sender<()>>,
+    /// Shared with the spawner, so that restarted processes keep the listeners
+    output_listeners: Arc<OutputListeners>,
+    recent_output: Option<Arc<RecentOutput>>,
+    ctx: Arc<LuaContext>,
+    cmd: String,
+    plugin_instance: Arc<PluginInstance>,
+}
+
+impl ProcessHandle {
+    fn pid(&self) -> u32 {
+        self.current.pid.load(Ordering::SeqCst)
+    }
+
+    fn write(&mut self, _lua: &Lua, buf: String) -> mlua::Result<()> {
+        let stdin = self.current.stdin.clone();
+        // Waits for the `stdin_from` contents and restarts, which hold the lock meanwhile
+        let result = self.ctx.plugin_runtime.block_on(async move {
+            match stdin.lock().await.as_mut() {
+                // Fails when process is not running anymore
+                Some(child_stdin) => Some(child_stdin.write_all(buf.as_bytes()).await),
+                None => None,
+            }
+        });
+        match result {
+            Some(result) => result?,
+            None => self.plugin_instance.warn(format!(
+                "tried to write to closed stdin of process {} (PID {})",
+                self.cmd,
+                self.pid()
+            )),
+        }
+        Ok(())
+    }
+
+    fn writeln(&mut self, lua: &Lua, line: String) -> mlua::Result<()> {
+        self.write(lua, line + "\n")
+    }
+
+    fn add_output_listener(&self, lua: &Lua, callback: Function) -> mlua::Result<u64> {
+        let callback_key = Arc::new(lua.create_registry_value(callback)?);
+        let id = self.output_listeners.add(callback_key);
+        self.plugin_instance.debug(format!(
+            "added output listener {} to process {} (PID {})",
+            id,
+            self.cmd,
+            self.pid()
+        ));
+        Ok(id)
+    }
+
+    fn get_recent_output(&self) -> Option<Vec<String>> {
+        match self.recent_output.as_ref() {
+            Some(recent_output) => Some(recent_output.lines()),
+            None => {
+                self.plugin_instance.warn(format!(
+                    "tried getting recent output of process {}, which was spawned without buffer_lines",
+                    self.cmd
+                ));
+                None
+            }
+        }
+    }
+
+    fn remove_output_listener(&self, lua: &Lua, id: u64) -> bool {
+        let removed = self.output_listeners.remove(lua, id);
+        if !removed {
+            self.plugin_instance.warn(format!(
+                "tried removing unknown output listener {} of process {}",
+                id, self.cmd
+            ));
+        }
+        removed
+    }
+
+    fn kill(&mut self) -> mlua::Result<()> {
+        self.plugin_instance
+            .debug(format!("killing process {} (PID {})", self.cmd, self.pid()));
+        match self.kill_sender.take() {
+            Some(kill_tx) => {
+                if kill_tx.send(()).is_err() {
+                    self.plugin_instance.warn(format!(
+                        "tried to kill process {} (PID {}) which is not running",
+                        self.cmd,
+                        self.pid()
+                    ));
+                }
+            }
+            None => {
+                self.plugin_instance.warn(format!(
+                    "tried to kill process {} (PID {}) which was already killed explicitly",
+                    self.cmd,
+                    self.pid()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn signal(&self, sig: Value) -> mlua::Result<bool> {
+        let signal = parse_signal(&sig).ok_or_else(|| {
+            let sig = match &sig {
+                Value::String(name) => name.to_string_lossy().to_string(),
+                Value::Integer(num) => num.to_string(),
+                Value::Number(num) => num.to_string(),
+                other => other.type_name().to_string(),
+            };
+            mlua::Error::RuntimeError(format!(
+                "unknown signal {}, accepted are the numbers and names (with or without the SIG prefix) of {}",
+                sig,
+                Signal::iterator()
+                    .map(Signal::as_str)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        })?;
+        let pid = self.pid();
+        self.plugin_instance.debug(format!(
+            "sending {} to process {} (PID {})",
+            signal, self.cmd, pid
+        ));
+        match signal::kill(Pid::from_raw(pid as i32), signal) {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                self.plugin_instance.warn(format!(
+                    "couldn't send {} to process {} (PID {}): {}",
+                    signal, self.cmd, pid, e
+                ));
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Returns whether the action existed. Clients are not notified.
+pub(super) fn remove_action(lua: &Lua, module: &Module, name: &str) -> bool {
+    let mut actions = module.actions.write().unwrap();
+    let action = match actions.iter().position(|a| a.name == name) {
+        Some(index) => actions.remove(index),
+        None => {
+            module.warn(format!("tried unregistering unknown action {}", name));
+            return false;
+        }
     };
-    if let Err(e) = wm.reposition_windows(lua) {
-        error!("error when repositioning windows: {}", e);
+    drop(actions);
+    module.debug(format!("unregistering action {}", name));
+    let _ = lua.remove_registry_value(action.key);
+    true
+}
+
+/// Returns whether the action was registered, either as a new action or by replacing an existing
+/// action when the `replace` option is set.
+fn add_action(
+    lua: &Lua,
+    module: &Module,
+    name: String,
+    callback: Function,
+    opts: Value,
+) -> mlua::Result<bool> {
+    let mut display_name = None;
+    let mut tags = Vec::new();
+    let mut confirm = false;
+    let mut cooldown = None;
+    let mut priority = ActionPriority::default();
+    let mut replace = false;
+    if let Value::Table(opts_table) = opts {
+        if let Ok(display_name_arg) = opts_table.get::<_, String>("display_name") {
+            display_name = Some(display_name_arg);
+        }
+        if let Ok(tags_arg) = opts_table.get::<_, Vec<String>>("tags") {
+            tags = tags_arg;
+        }
+        if let Ok(confirm_arg) = opts_table.get::<_, bool>("confirm") {
+            confirm = confirm_arg;
+        }
+        if let Ok(cooldown_ms) = opts_table.get::<_, u64>("cooldown_ms") {
+            cooldown = Some(Duration::from_millis(cooldown_ms));
+        }
+        if let Ok(priority_arg) = opts_table.get::<_, String>("priority") {
+            match priority_arg.parse() {
+                Ok(priority_arg) => priority = priority_arg,
+                Err(e) => module.error(format!("{} (action {})", e, name)),
+            }
+        }
+        if let Ok(replace_arg) = opts_table.get::<_, bool>("replace") {
+            replace = replace_arg;
+        }
     }
-    Ok(())
+
+    let mut actions = module.actions.write().unwrap();
+    let existing = actions.iter().position(|a| a.name == name);
+    if existing.is_some() && !replace {
+        module.error(format!(
+            "tried registering action with duplicate name {}",
+            name
+        ));
+        return Ok(false);
+    }
+
+    let key = lua.create_registry_value(callback)?;
+    let action = Action {
+        name,
+        display_name,
+        tags,
+        confirm,
+        cooldown,
+        priority,
+        last_call: Default::default(),
+        key,
+    };
+    match existing {
+        Some(index) => {
+            module.debug(format!("replacing action {}", action.name));
+            // Keep the position, so that the action doesn't move around in clients
+            let old_action = std::mem::replace(&mut actions[index], action);
+            let _ = lua.remove_registry_value(old_action.key);
+        }
+        None => {
+            module.debug(format!("registering action {}", action.name));
+            actions.push(action);
+        }
+    }
+    Ok(true)
 }
 
-fn run_later(lua: &Lua, func: Function, ctx: Arc<LuaContext>) -> mlua::Result<()> {
-    let func_key = lua.create_registry_value(func)?;
-    ctx.run_later_tasks.lock().unwrap().push_back(func_key);
+/// Registers the actions of the `actions` list passed to `register_module` in order. Each entry
+/// holds the `name` and `callback` of the action alongside the options of `register_action`.
+fn add_actions(lua: &Lua, module: &Module, actions: Table) -> mlua::Result<()> {
+    for entry in actions.sequence_values::<Table>() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                module.error(format!("action entry is no table: {}", e));
+                continue;
+            }
+        };
+        let name = match entry.get::<_, String>("name") {
+            Ok(name) => name,
+            Err(_) => {
+                module.error("action entry has no name".to_string());
+                continue;
+            }
+        };
+        let callback = match entry.get::<_, Function>("callback") {
+            Ok(callback) => callback,
+            Err(_) => {
+                module.error(format!("action entry {} has no callback", name));
+                continue;
+            }
+        };
+        add_action(lua, module, name, callback, Value::Table(entry))?;
+    }
     Ok(())
 }
 
-fn escape_html(unescaped: String) -> mlua::Result<String> {
-    let escaped = unescaped
-        .replace("&", "&amp;")
-        .replace("<", "&lt;")
-        .replace(">", "&gt;")
-        .replace("\"", "&quot;")
-        .replace("\'", "&#039;");
-    Ok(escaped)
+/// Like `AsyncBufReadExt::lines`, but decodes invalid UTF-8 with replacement characters instead of
+/// failing, so that output of programs that don't emit UTF-8 isn't dropped.
+async fn next_line_lossy(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    buf: &mut Vec<u8>,
+) -> io::Result<Option<String>> {
+    buf.clear();
+    if reader.read_until(b'\n', buf).await? == 0 {
+        return Ok(None);
+    }
+    if buf.ends_with(b"\n") {
+        buf.pop();
+        if buf.ends_with(b"\r") {
+            buf.pop();
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(buf).into_owned()))
 }
 
-pub(super) fn inject_api_functions(
+/// String properties take a string, cardinal properties an integer or a list of integers.
+fn parse_property_value(
+    property_type: PropertyType,
+    value: Value,
+) -> anyhow::Result<PropertyValue> {
+    let parsed = match (property_type, value) {
+        (PropertyType::String | PropertyType::Utf8String, Value::String(value)) => {
+            Some(PropertyValue::String(value.to_str()?.to_string()))
+        }
+        (PropertyType::Cardinal, Value::Integer(cardinal)) => u32::try_from(cardinal)
+            .ok()
+            .map(|cardinal| PropertyValue::Cardinals(vec![cardinal])),
+        (PropertyType::Cardinal, Value::Table(cardinals)) => cardinals
+            .sequence_values::<u32>()
+            .collect::<mlua::Result<Vec<_>>>()
+            .ok()
+            .map(PropertyValue::Cardinals),
+        _ => None,
+    };
+    parsed.with_context(|| format!("invalid value for a {:?} property", property_type))
+}
+
+/// Accepts signal numbers and signal names with or without the `SIG` prefix (e.g. `"SIGHUP"`,
+/// `"hup"` or `1`).
+fn parse_signal(sig: &Value) -> Option<Signal> {
+    match sig {
+        Value::Integer(num) => Signal::try_from(*num as i32).ok(),
+        Value::Number(num) if num.fract() == 0.0 => Signal::try_from(*num as i32).ok(),
+        Value::String(name) => {
+            let name = name.to_str().ok()?.to_uppercase();
+            if name.starts_with("SIG") {
+                name.parse().ok()
+            } else {
+                format!("SIG{}", name).parse().ok()
+            }
+        }
+        _ => None,
+    }
+}
+
+impl UserData for ProcessHandle {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method_mut("write", |lua, this, buf| this.write(lua, buf));
+        methods.add_method_mut("writeln", |lua, this, line| this.writeln(lua, line));
+        methods.add_method_mut("kill", |_lua, this, ()| this.kill());
+        methods.add_method("signal", |_lua, this, sig| this.signal(sig));
+        methods.add_method("add_output_listener", |lua, this, callback| {
+            this.add_output_listener(lua, callback)
+        });
+        methods.add_method("remove_output_listener", |lua, this, id| {
+            Ok(this.remove_output_listener(lua, id))
+        });
+        methods.add_method("get_recent_output", |_lua, this, ()| {
+            Ok(this.get_recent_output())
+        });
+    }
+}
+
+fn get_min_geometry_from_value(
     lua: &Lua,
-    neopult: &Table,
-    ctx: Arc<LuaContext>,
-) -> mlua::Result<()> {
-    let api = lua.create_table()?;
+    plugin_instance: &PluginInstance,
+    min_geometry_val: Value,
+) -> mlua::Result<MinGeometry> {
+    if let Value::Nil = min_geometry_val {
+        return Ok(Default::default());
+    }
+    match parse_min_geometry(lua, min_geometry_val) {
+        Ok(min_geometry) => Ok(min_geometry),
+        Err(e) => {
+            plugin_instance.warn(format!(
+                "invalid min_geometry for window (using default): {:#}",
+                e
+            ));
+            Ok(Default::default())
+        }
+    }
+}
 
-    api.set(
-        "register_plugin_instance",
-        create_context_function(lua, ctx.clone(), register_plugin_instance)?,
-    )?;
-    api.set(
-        "generate_token",
-        lua.create_function(|_lua, num_chars| generate_token(num_chars))?,
-    )?;
-    api.set(
-        "get_channel",
-        create_context_function(lua, ctx.clone(), get_channel)?,
-    )?;
-    api.set(
-        "get_channel_home",
-        create_context_function(lua, ctx.clone(), get_channel_home)?,
-    )?;
-    api.set("create_store", lua.create_function(create_store)?)?;
-    api.set(
-        "reposition_windows",
-        create_context_function(lua, ctx.clone(), reposition_windows)?,
-    )?;
-    api.set("run_later", create_context_function(lua, ctx, run_later)?)?;
-    api.set(
-        "escape_html",
-        lua.create_function(|_lua, unescaped| escape_html(unescaped))?,
-    )?;
+/// Accepts a geometry string, a geometry table or a function that returns a geometry string
+fn parse_min_geometry(lua: &Lua, min_geometry_val: Value) -> anyhow::Result<MinGeometry> {
+    match min_geometry_val {
+        Value::String(min_geometry_str) => min_geometry_str
+            .to_string_lossy()
+            .parse()
+            .context("invalid geometry string"),
+        Value::Table(min_geometry_table) => {
+            MinGeometry::from_table(&min_geometry_table).context("invalid geometry table")
+        }
+        Value::Function(min_geometry_fn) => {
+            let key = lua.create_registry_value(min_geometry_fn)?;
+            Ok(MinGeometry::Dynamic {
+                callback_key: Arc::new(key),
+            })
+        }
+        _ => anyhow::bail!("expected a string, table or function"),
+    }
+}
 
-    neopult.set("api", api)?;
+/// Counts a spawned process towards `max_processes_per_plugin` until it is dropped, i.e. until
+/// the process exited.
+#[derive(Debug)]
+struct ProcessSlot(Arc<AtomicUsize>);
 
-    Ok(())
+impl ProcessSlot {
+    /// Returns `None` if `max_processes` processes are already running
+    fn acquire(running_processes: &Arc<AtomicUsize>, max_processes: u64) -> Option<ProcessSlot> {
+        running_processes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |running| {
+                ((running as u64) < max_processes).then(|| running + 1)
+            })
+            .ok()?;
+        Some(ProcessSlot(running_processes.clone()))
+    }
+}
+
+impl Drop for ProcessSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+struct WindowHandle {
+    id: ManagedWid,
+    ctx: Arc<LuaContext>,
+    plugin_instance: Arc<PluginInstance>,
+}
+
+impl WindowHandle {
+    fn max(&self, lua: &Lua, (size_table, opts): (Table, Value)) -> mlua::Result<()> {
+        self.plugin_instance.debug(format!(
+            "setting mode of window with managed wid {} to max",
+            self.id
+        ));
+
+        let width = match size_table.get::<_, u16>(1) {
+            Ok(w) => w,
+            Err(e) => {
+                self.plugin_instance
+                    .error(format!("couldn't get width: {}", e));
+                return Ok(());
+            }
+        };
+
+        let height = match size_table.get::<_, u16>(2) {
+            Ok(h) => h,
+            Err(e) => {
+                self.plugin_instance
+                    .error(format!("couldn't get height: {}", e));
+                return Ok(());
+            }
+        };
+
+        let mut margin = Margin::default();
+
+        if let Value::Table(opts_table) = opts {
+            if let Ok(margin_table) = opts_table.get::<_, Table>("margin") {
+                if let Ok(top) = margin_table.get::<_, u16>("top") {
+                    margin.top = top;
+                }
+                if let Ok(right) = margin_table.get::<_, u16>("right") {
+                    margin.right = right;
+                }
+                if let Ok(bottom) = margin_table.get::<_, u16>("bottom") {
+                    margin.bottom = bottom;
+                }
+                if let Ok(left) = margin_table.get::<_, u16>("left") {
+                    margin.left = left;
+                }
+            }
+        }
+
+        let mut wm = match self.ctx.write_window_manager() {
+            Some(wm) => wm,
+            None => return Ok(()),
+        };
+        if let Err(e) = wm.max_window(lua, self.id, (width, height), margin) {
+            self.plugin_instance
+                .error(format!("error setting window mode to max: {}", e));
+        }
+        drop(wm);
+        self.ctx.sync_attached_windows();
+
+        Ok(())
+    }
+
+    fn min(&self, lua: &Lua) -> mlua::Result<()> {
+        self.plugin_instance.debug(format!(
+            "setting mode of window with managed wid {} to min",
+            self.id
+        ));
+        let mut wm = match self.ctx.write_window_manager() {
+            Some(wm) => wm,
+            None => return Ok(()),
+        };
+        if let Err(e) = wm.min_window(lua, self.id) {
+            self.plugin_instance
+                .error(format!("error setting window mode to min: {}", e));
+        }
+        drop(wm);
+        self.ctx.sync_attached_windows();
+        Ok(())
+    }
+
+    fn hide(&self, lua: &Lua) -> mlua::Result<()> {
+        self.plugin_instance
+            .debug(format!("hiding window with managed wid {}", self.id));
+        let mut wm = match self.ctx.write_window_manager() {
+            Some(wm) => wm,
+            None => return Ok(()),
+        };
+        if let Err(e) = wm.hide_window(lua, self.id) {
+            self.plugin_instance
+                .error(format!("error hiding window: {}", e));
+        }
+        drop(wm);
+        self.ctx.sync_attached_windows();
+        Ok(())
+    }
+
+    fn unclaim(&self, lua: &Lua) -> mlua::Result<()> {
+        self.plugin_instance
+            .debug(format!("unclaiming window with managed wid {}", self.id));
+        let mut wm = match self.ctx.write_window_manager() {
+            Some(wm) => wm,
+            None => return Ok(()),
+        };
+        if let Err(e) = wm.release_window(lua, self.id) {
+            self.plugin_instance
+                .error(format!("error unclaiming window: {}", e));
+        }
+        drop(wm);
+        self.ctx.sync_attached_windows();
+        Ok(())
+    }
+
+    fn center(&self, lua: &Lua, (width, height): (u16, u16)) -> mlua::Result<()> {
+        self.plugin_instance.debug(format!(
+            "centering window with managed wid {} with size {}x{}",
+            self.id, width, height
+        ));
+        let mut wm = match self.ctx.write_window_manager() {
+            Some(wm) => wm,
+            None => return Ok(()),
+        };
+        if let Err(e) = wm.center_window(lua, self.id, (width, height)) {
+            self.plugin_instance
+                .error(format!("error centering window: {}", e));
+        }
+        drop(wm);
+        self.ctx.sync_attached_windows();
+        Ok(())
+    }
+
+    fn set_min_geometry(&self, lua: &Lua, min_geometry_val: Value) -> mlua::Result<bool> {
+        let min_geometry = match parse_min_geometry(lua, min_geometry_val) {
+            Ok(min_geometry) => min_geometry,
+            Err(e) => {
+                self.plugin_instance
+                    .error(format!("error setting min geometry of window: {:#}", e));
+                return Ok(false);
+            }
+        };
+        self.plugin_instance.debug(format!(
+            "setting min geometry of window with managed wid {}",
+            self.id
+        ));
+        let mut wm = match self.ctx.write_window_manager() {
+            Some(wm) => wm,
+            None => return Ok(false),
+        };
+        let result = wm.set_min_geometry(lua, self.id, min_geometry);
+        drop(wm);
+        match result {
+            Ok(()) => {
+                self.ctx.sync_attached_windows();
+                Ok(true)
+            }
+            Err(e) => {
+                self.plugin_instance
+                    .error(format!("error setting min geometry of window: {}", e));
+                Ok(false)
+            }
+        }
+    }
+
+    fn is_primary_window(&self) -> mlua::Result<bool> {
+        let wm = match self.ctx.read_window_manager() {
+            Some(wm) => wm,
+            None => return Ok(false),
+        };
+        Ok(wm.is_primary_window(self.id))
+    }
+
+    fn get_property<'lua>(&self, lua: &'lua Lua, name: String) -> mlua::Result<Value<'lua>> {
+        let wm = match self.ctx.read_window_manager() {
+            Some(wm) => wm,
+            None => return Ok(Value::Nil),
+        };
+        match wm.get_window_property(self.id, &name) {
+            Ok(Some(PropertyValue::String(value))) => lua.pack(value),
+            Ok(Some(PropertyValue::Cardinals(cardinals))) => lua.pack(cardinals),
+            Ok(None) => Ok(Value::Nil),
+            Err(e) => {
+                self.plugin_instance
+                    .error(format!("error getting window property {}: {:?}", name, e));
+                Ok(Value::Nil)
+            }
+        }
+    }
+
+    fn set_property(
+        &self,
+        (name, property_type, value): (String, String, Value),
+    ) -> mlua::Result<bool> {
+        let parsed = property_type
+            .parse::<PropertyType>()
+            .and_then(|property_type| {
+                Ok((property_type, parse_property_value(property_type, value)?))
+            });
+        let (property_type, value) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.plugin_instance
+                    .error(format!("error setting window property {}: {:#}", name, e));
+                return Ok(false);
+            }
+        };
+        let wm = match self.ctx.read_window_manager() {
+            Some(wm) => wm,
+            None => return Ok(false),
+        };
+        match wm.set_window_property(self.id, &name, property_type, value) {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                self.plugin_instance
+                    .error(format!("error setting window property {}: {:?}", name, e));
+                Ok(false)
+            }
+        }
+    }
+
+    fn flash(&self, lua: &Lua, opts: Option<Table>) -> mlua::Result<bool> {
+        let (highlight, duration) = match parse_flash_opts(opts) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                self.plugin_instance
+                    .error(format!("error flashing window: {:#}", e));
+                return Ok(false);
+            }
+        };
+        let mut wm = match self.ctx.write_window_manager() {
+            Some(wm) => wm,
+            None => return Ok(false),
+        };
+        let highlight_id = match wm.push_highlight(self.id, highlight) {
+            Ok(Some(highlight_id)) => highlight_id,
+            // Virtual windows can't be flashed
+            Ok(None) => return Ok(false),
+            Err(e) => {
+                self.plugin_instance
+                    .error(format!("error flashing window: {:?}", e));
+                return Ok(false);
+            }
+        };
+        drop(wm);
+
+        let ctx = self.ctx.clone();
+        let plugin_instance = self.plugin_instance.clone();
+        let id = self.id;
+        let restore = lua.create_function(move |_lua, ()| {
+            if let Some(mut wm) = ctx.write_window_manager() {
+                if let Err(e) = wm.remove_highlight(id, highlight_id) {
+                    plugin_instance
+                        .error(format!("error restoring border of flashed window: {:?}", e));
+                }
+            }
+            Ok(())
+        })?;
+        call_later(
+            &self.ctx.main_runtime_handle,
+            self.ctx.event_sender.clone(),
+            duration,
+            Arc::new(lua.create_registry_value(restore)?),
+        );
+        Ok(true)
+    }
+}
+
+fn parse_flash_opts(opts: Option<Table>) -> anyhow::Result<(Highlight, Duration)> {
+    let (duration_ms, color, border_width) = match opts {
+        Some(opts) => (
+            opts.get::<_, Option<u64>>("duration_ms")?,
+            opts.get::<_, Option<String>>("color")?,
+            opts.get::<_, Option<u16>>("border_width")?,
+        ),
+        None => (None, None, None),
+    };
+    let highlight = Highlight {
+        color: color.as_deref().unwrap_or(DEFAULT_FLASH_COLOR).parse()?,
+        border_width: border_width.unwrap_or(DEFAULT_FLASH_BORDER_WIDTH),
+    };
+    let duration = Duration::from_millis(duration_ms.unwrap_or(DEFAULT_FLASH_DURATION_MS));
+    Ok((highlight, duration))
+}
+
+/// Calls the callback from the event loop once `delay` has passed.
+fn call_later(
+    runtime_handle: &tokio::runtime::Handle,
+    event_sender: Arc<mpsc::Sender<Event>>,
+    delay: Duration,
+    callback_key: Arc<RegistryKey>,
+) {
+    runtime_handle.spawn(async move {
+        tokio::time::sleep(delay).await;
+        let _ = event_sender.send(Event::Timer { callback_key }).await;
+    });
+}
+
+impl UserData for WindowHandle {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_method("max", |lua, this, args| this.max(lua, args));
+        methods.add_method("min", |lua, this, ()| this.min(lua));
+        methods.add_method("hide", |lua, this, ()| this.hide(lua));
+        methods.add_method("unclaim", |lua, this, ()| this.unclaim(lua));
+        methods.add_method("center", |lua, this, args| this.center(lua, args));
+        methods.add_method("set_min_geometry", |lua, this, min_geometry| {
+            this.set_min_geometry(lua, min_geometry)
+        });
+        methods.add_method("is_primary_window", |_lua, this, ()| {
+            this.is_primary_window()
+        });
+        methods.add_method("flash", |lua, this, opts| this.flash(lua, opts));
+        methods.add_method("get_property", |lua, this, name| {
+            this.get_property(lua, name)
+        });
+        methods.add_method("set_property", |_lua, this, args| this.set_property(args));
+    }
+}
+
+#[derive(Debug)]
+struct Store {
+    subscriber_callbacks: HashSet<Arc<RegistryKey>>,
+}
+
+impl Store {
+    fn new() -> Self {
+        Self {
+            subscriber_callbacks: HashSet::new(),
+        }
+    }
+}
+
+impl UserData for Store {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_function("get", |_lua, store: AnyUserData| {
+            store.get_user_value::<Value>()
+        });
+
+        methods.add_function("set", |lua, (store, value): (AnyUserData, Value)| {
+            store.set_user_value(value.clone())?;
+            let mut callbacks = vec![];
+            for cb_key in &store.borrow::<Store>()?.subscriber_callbacks {
+                if let Ok(cb) = lua.registry_value::<Function>(cb_key) {
+                    callbacks.push(cb);
+                };
+            }
+            // Call callbacks outside of store borrow, so that callbacks can call unscubscribe
+            for cb in callbacks {
+                if let Err(e) = cb.call::<_, Value>(value.clone()) {
+                    error!("error in store subscriber callback: {:?}", e);
+                }
+            }
+            Ok(())
+        });
+
+        methods.add_function("subscribe", |lua, (store, cb): (AnyUserData, Function)| {
+            let cb_key = Arc::new(lua.create_registry_value(cb)?);
+            store
+                .borrow_mut::<Store>()?
+                .subscriber_callbacks
+                .insert(cb_key.clone());
+            Ok(StoreSubscription::new(cb_key))
+        });
+
+        methods.add_function(
+            "unsubscribe",
+            |_lua, (store, subscription): (AnyUserData, StoreSubscription)| {
+                store
+                    .borrow_mut::<Store>()?
+                    .subscriber_callbacks
+                    .remove(&subscription.callback_key);
+                Ok(())
+            },
+        );
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ThrottleDecision {
+    CallNow,
+    /// The call is delayed to the end of the interval
+    ScheduleTrailing(Duration),
+    /// A trailing call is already scheduled, which will use the arguments of this call instead
+    Defer,
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    interval: Duration,
+    last_call: Option<Instant>,
+    trailing_scheduled: bool,
+    /// Arguments of the most recent call that was throttled, packed into a table, and their count
+    pending_args: Option<(RegistryKey, usize)>,
+}
+
+impl ThrottleState {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_call: None,
+            trailing_scheduled: false,
+            pending_args: None,
+        }
+    }
+
+    fn on_call(&mut self, now: Instant) -> ThrottleDecision {
+        if self.trailing_scheduled {
+            return ThrottleDecision::Defer;
+        }
+        match self.last_call {
+            Some(last_call) if now.duration_since(last_call) < self.interval => {
+                self.trailing_scheduled = true;
+                ThrottleDecision::ScheduleTrailing(self.interval - now.duration_since(last_call))
+            }
+            _ => {
+                self.last_call = Some(now);
+                ThrottleDecision::CallNow
+            }
+        }
+    }
+
+    fn on_trailing_call(&mut self, now: Instant) -> Option<(RegistryKey, usize)> {
+        self.trailing_scheduled = false;
+        self.last_call = Some(now);
+        self.pending_args.take()
+    }
+}
+
+/// Callable that calls the inner callback at most once per interval. Calls in between are
+/// dropped, except for the last one, which is made once the interval has passed.
+struct Throttle {
+    callback_key: Arc<RegistryKey>,
+    trailing_call_key: Arc<RegistryKey>,
+    state: Arc<Mutex<ThrottleState>>,
+    ctx: Arc<LuaContext>,
+}
+
+impl Throttle {
+    fn call(&self, lua: &Lua, args: MultiValue) -> mlua::Result<()> {
+        let decision = self.state.lock().unwrap().on_call(Instant::now());
+        match decision {
+            ThrottleDecision::CallNow => call_throttled_callback(lua, &self.callback_key, args),
+            ThrottleDecision::ScheduleTrailing(delay) => {
+                self.set_pending_args(lua, args)?;
+                call_later(
+                    &self.ctx.main_runtime_handle,
+                    self.ctx.event_sender.clone(),
+                    delay,
+                    self.trailing_call_key.clone(),
+                );
+                Ok(())
+            }
+            ThrottleDecision::Defer => self.set_pending_args(lua, args),
+        }
+    }
+
+    fn set_pending_args(&self, lua: &Lua, args: MultiValue) -> mlua::Result<()> {
+        let count = args.len();
+        let key = lua.create_registry_value(lua.create_sequence_from(args)?)?;
+        let dropped = self
+            .state
+            .lock()
+            .unwrap()
+            .pending_args
+            .replace((key, count));
+        // Only the latest arguments are passed to the trailing call
+        if let Some((dropped_key, _)) = dropped {
+            lua.remove_registry_value(dropped_key)?;
+        }
+        Ok(())
+    }
+}
+
+impl UserData for Throttle {
+    fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+        methods.add_meta_method(MetaMethod::Call, |lua, this, args| this.call(lua, args));
+    }
+}
+
+fn call_throttled_callback(
+    lua: &Lua,
+    callback_key: &RegistryKey,
+    args: MultiValue,
+) -> mlua::Result<()> {
+    let callback = lua.registry_value::<Function>(callback_key)?;
+    if let Err(e) = callback.call::<_, Value>(args) {
+        error!("error in throttled callback: {:?}", e);
+    }
+    Ok(())
+}
+
+fn create_trailing_call<'lua>(
+    lua: &'lua Lua,
+    callback_key: Arc<RegistryKey>,
+    state: Arc<Mutex<ThrottleState>>,
+) -> mlua::Result<Function<'lua>> {
+    lua.create_function(move |lua, ()| {
+        let pending_args = state.lock().unwrap().on_trailing_call(Instant::now());
+        let args = match pending_args {
+            Some((key, count)) => {
+                let table = lua.registry_value::<Table>(&key)?;
+                lua.remove_registry_value(key)?;
+                (1..=count)
+                    .map(|i| table.raw_get::<_, Value>(i))
+                    .collect::<mlua::Result<MultiValue>>()?
+            }
+            None => MultiValue::new(),
+        };
+        call_throttled_callback(lua, &callback_key, args)
+    })
+}
+
+fn throttle(
+    lua: &Lua,
+    (interval_ms, callback): (u64, Function),
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<Throttle> {
+    let callback_key = Arc::new(lua.create_registry_value(callback)?);
+    let state = Arc::new(Mutex::new(ThrottleState::new(Duration::from_millis(
+        interval_ms,
+    ))));
+    let trailing_call = create_trailing_call(lua, callback_key.clone(), state.clone())?;
+    Ok(Throttle {
+        callback_key,
+        trailing_call_key: Arc::new(lua.create_registry_value(trailing_call)?),
+        state,
+        ctx,
+    })
+}
+
+#[derive(Debug, Clone)]
+struct StoreSubscription {
+    callback_key: Arc<RegistryKey>,
+}
+
+impl StoreSubscription {
+    fn new(callback_key: Arc<RegistryKey>) -> Self {
+        Self { callback_key }
+    }
+}
+
+impl UserData for StoreSubscription {}
+
+fn register_plugin_instance<'lua>(
+    lua: &'lua Lua,
+    (name, opts): (String, Value),
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<Value<'lua>> {
+    let plugin_instance = {
+        let spawn_limits = ctx.spawn_limits(lua);
+        let mut plugin_instances = ctx.plugin_instances.write().unwrap();
+        add_plugin_instance(lua, &spawn_limits, &mut plugin_instances, name, opts)?
+    };
+    match plugin_instance {
+        Some(plugin_instance) => lua.pack(PluginInstanceHandle {
+            plugin_instance,
+            ctx: ctx.clone(),
+        }),
+        None => Ok(Value::Nil),
+    }
+}
+
+/// Creates a plugin instance and adds it to `plugin_instances`. Returns `None` after logging an
+/// error if the name is taken or the requirements aren't met.
+fn add_plugin_instance(
+    lua: &Lua,
+    spawn_limits: &config::SpawnLimits,
+    plugin_instances: &mut Vec<Arc<PluginInstance>>,
+    name: String,
+    opts: Value,
+) -> mlua::Result<Option<Arc<PluginInstance>>> {
+    if plugin_instances.iter().any(|p| p.name == name) {
+        error!(
+            "tried registering plugin instance with duplicate name {}",
+            name
+        );
+        Ok(None)
+    } else {
+        if let Err(e) = check_requirements(spawn_limits, &opts) {
+            error!("refusing to register plugin instance {}: {}", name, e);
+            return Ok(None);
+        }
+
+        debug!("registering plugin instance {}", name);
+        let mut callbacks = PluginInstanceCallbacks::default();
+        let mut self_test = None;
+
+        if let Value::Table(opts_table) = opts {
+            if let Ok(cb) = opts_table.get::<_, Function>("on_cleanup") {
+                callbacks.on_cleanup = Some(lua.create_registry_value(cb)?);
+            }
+            if let Ok(cb) = opts_table.get::<_, Function>("on_idle") {
+                callbacks.on_idle = Some(lua.create_registry_value(cb)?);
+            }
+            if let Ok(cb) = opts_table.get::<_, Function>("on_resume") {
+                callbacks.on_resume = Some(lua.create_registry_value(cb)?);
+            }
+            if let Ok(cb) = opts_table.get::<_, Function>("on_screen_resolution_change") {
+                callbacks.on_screen_resolution_change = Some(lua.create_registry_value(cb)?);
+            }
+            if let Ok(cb) = opts_table.get::<_, Function>("on_windows_lost") {
+                callbacks.on_windows_lost = Some(lua.create_registry_value(cb)?);
+            }
+            if let Ok(cb) = opts_table.get::<_, Function>("self_test") {
+                self_test = Some(cb);
+            }
+        }
+
+        let plugin_instance = Arc::new(PluginInstance::new(name, callbacks));
+        if let Some(self_test) = self_test {
+            register_self_test(lua, &plugin_instance, self_test)?;
+        }
+        plugin_instances.push(plugin_instance.clone());
+        Ok(Some(plugin_instance))
+    }
+}
+
+/// Creates a module and adds it to the plugin instance. Returns `None` after logging an error if
+/// the name is taken or the requirements aren't met.
+fn add_module(
+    lua: &Lua,
+    spawn_limits: &config::SpawnLimits,
+    plugin_instance: &PluginInstance,
+    name: String,
+    opts: Value,
+) -> mlua::Result<Option<Arc<Module>>> {
+    let mut modules = plugin_instance.modules.write().unwrap();
+
+    if modules.iter().any(|m| m.name == name) {
+        plugin_instance.error(format!(
+            "tried registering module with duplicate name {}",
+            name
+        ));
+        Ok(None)
+    } else {
+        plugin_instance.debug(format!("registering module {}", name));
+
+        if let Err(e) = check_requirements(spawn_limits, &opts) {
+            plugin_instance.error(format!("refusing to register module {}: {}", name, e));
+            return Ok(None);
+        }
+
+        let mut display_name = None;
+        let mut icon = None;
+        let mut actions = None;
+        if let Value::Table(opts_table) = opts {
+            if let Ok(display_name_arg) = opts_table.get::<_, String>("display_name") {
+                display_name = Some(display_name_arg)
+            }
+            if let Ok(icon_arg) = opts_table.get::<_, String>("icon") {
+                icon = Some(icon_arg)
+            }
+            if let Ok(actions_arg) = opts_table.get::<_, Table>("actions") {
+                actions = Some(actions_arg);
+            }
+        }
+
+        let module = Arc::new(Module::new(
+            name,
+            plugin_instance.name.clone(),
+            display_name,
+        ));
+        *module.icon.write().unwrap() = icon;
+        if let Some(actions) = actions {
+            add_actions(lua, &module, actions)?;
+        }
+        modules.push(module.clone());
+        Ok(Some(module))
+    }
+}
+
+/// Removes the plugin instance after calling its cleanup callback and releases all of its windows.
+fn unregister_plugin_instance(lua: &Lua, name: String, ctx: Arc<LuaContext>) -> mlua::Result<bool> {
+    let plugin_instance = {
+        let mut plugin_instances = ctx.plugin_instances.write().unwrap();
+        match plugin_instances.iter().position(|p| p.name == name) {
+            Some(pos) => plugin_instances.remove(pos),
+            None => {
+                error!("tried unregistering unknown plugin instance {}", name);
+                return Ok(false);
+            }
+        }
+    };
+    debug!("unregistering plugin instance {}", name);
+    plugin_instance.timers.cancel_all();
+    plugin_instance.call_callback(lua, &plugin_instance.callbacks.on_cleanup, "cleanup");
+    ctx.status_observers
+        .lock()
+        .unwrap()
+        .remove_plugin_instance(lua, &name);
+
+    if let Some(mut wm) = ctx.write_window_manager() {
+        match wm.release_owned_windows(lua, &name) {
+            Ok(released) if released.is_empty() => {}
+            Ok(released) => {
+                plugin_instance.debug(format!("released windows with managed wids {:?}", released))
+            }
+            Err(e) => plugin_instance.error(format!("couldn't release windows: {:?}", e)),
+        }
+    }
+    ctx.sync_attached_windows();
+    Ok(true)
+}
+
+/// Why `PluginInstanceHandle:claim_window` couldn't claim a window
+#[derive(Debug, PartialEq, Eq)]
+enum ClaimError {
+    Timeout {
+        timeout_ms: u64,
+    },
+    /// Only windows that are managed already have the class. `owner` is the plugin instance that
+    /// claimed the window, if it is known.
+    AlreadyManaged {
+        owner: Option<String>,
+    },
+}
+
+impl ClaimError {
+    fn to_lua_table<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Table<'lua>> {
+        let table = lua.create_table()?;
+        match self {
+            ClaimError::Timeout { .. } => table.set("reason", "timeout")?,
+            ClaimError::AlreadyManaged { owner } => {
+                table.set("reason", "already_managed")?;
+                table.set("owner", owner.as_deref())?;
+            }
+        }
+        Ok(table)
+    }
+}
+
+impl fmt::Display for ClaimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClaimError::Timeout { timeout_ms } => write!(f, "no window within {} ms", timeout_ms),
+            ClaimError::AlreadyManaged { owner: Some(owner) } => {
+                write!(f, "already managed by plugin instance {}", owner)
+            }
+            ClaimError::AlreadyManaged { owner: None } => {
+                write!(f, "already managed by another neopult instance")
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ClaimStep {
+    Claim(x::Window),
+    /// Release the window from its current owner before claiming it
+    Steal {
+        window: x::Window,
+        managed_wid: ManagedWid,
+    },
+    /// Report the error right away
+    Conflict(ClaimError),
+}
+
+/// Only windows of this neopult instance can be stolen. Windows of other instances can't be
+/// released from here, so they are a conflict even with `allow_steal`.
+fn claim_step(candidate: ClaimCandidate, allow_steal: bool) -> ClaimStep {
+    match candidate {
+        ClaimCandidate::Unmanaged(window) => ClaimStep::Claim(window),
+        ClaimCandidate::Managed {
+            window,
+            managed_wid: Some(managed_wid),
+            ..
+        } if allow_steal => ClaimStep::Steal {
+            window,
+            managed_wid,
+        },
+        ClaimCandidate::Managed { owner, .. } => {
+            ClaimStep::Conflict(ClaimError::AlreadyManaged { owner })
+        }
+    }
+}
+
+/// Virtual window callbacks that notify the frontends about the geometry and visibility of an
+/// image window, so they can render the image in place of the window. The window manager only sets
+/// the geometry of shown windows, so setting it makes the window visible.
+fn image_window_callbacks(
+    lua: &Lua,
+    notification_sender: Arc<broadcast::Sender<Notification>>,
+    state: Arc<Mutex<ImageWindowInfo>>,
+) -> mlua::Result<VirtualWindowCallbacks> {
+    let set_geometry = {
+        let state = state.clone();
+        let notification_sender = notification_sender.clone();
+        lua.create_function(
+            move |_lua,
+                  (x_offset, y_offset, width, height, alignment, z): (
+                u16,
+                u16,
+                u16,
+                u16,
+                String,
+                u16,
+            )| {
+                let mut state = state.lock().unwrap();
+                state.geometry = Some(ImageWindowGeometry {
+                    x_offset,
+                    y_offset,
+                    width,
+                    height,
+                    alignment,
+                    z,
+                });
+                state.visible = true;
+                let _ = notification_sender.send(Notification::ImageWindowUpdate(state.clone()));
+                Ok(())
+            },
+        )?
+    };
+    let set_visible = |visible: bool| {
+        let state = state.clone();
+        let notification_sender = notification_sender.clone();
+        lua.create_function(move |_lua, ()| {
+            let mut state = state.lock().unwrap();
+            state.visible = visible;
+            let _ = notification_sender.send(Notification::ImageWindowUpdate(state.clone()));
+            Ok(())
+        })
+    };
+
+    Ok(VirtualWindowCallbacks {
+        set_geometry_key: lua.create_registry_value(set_geometry)?,
+        map_key: lua.create_registry_value(set_visible(true)?)?,
+        unmap_key: lua.create_registry_value(set_visible(false)?)?,
+    })
+}
+
+fn create_image_window<'lua>(
+    lua: &'lua Lua,
+    (plugin_instance, name, image_path, opts): (
+        AnyUserData<'lua>,
+        String,
+        String,
+        Option<Table<'lua>>,
+    ),
+) -> mlua::Result<Value<'lua>> {
+    match plugin_instance.borrow::<PluginInstanceHandle>() {
+        Ok(handle) => handle.create_image_window(lua, (name, image_path, opts)),
+        Err(_) => {
+            error!(
+                "couldn't create image window with name {} -- first argument is no plugin instance handle",
+                name
+            );
+            Ok(Value::Nil)
+        }
+    }
+}
+
+/// Exposes the self test of a plugin instance as the action `<plugin instance>::__meta::self_test`,
+/// which fails with the message of the self test when it doesn't pass.
+fn register_self_test(
+    lua: &Lua,
+    plugin_instance: &PluginInstance,
+    self_test: Function,
+) -> mlua::Result<()> {
+    // The wrapper is written in lua, because errors must not be raised from rust callbacks
+    let callback: Function = lua
+        .load(
+            r#"
+            local self_test = ...
+            return function()
+                local passed, msg = self_test()
+                if not passed then
+                    error("self test failed: " .. (msg or "no reason given"), 0)
+                end
+            end
+            "#,
+        )
+        .set_name("self_test_wrapper")?
+        .call(self_test)?;
+
+    let module = Arc::new(Module::new(
+        META_MODULE_NAME.to_string(),
+        plugin_instance.name.clone(),
+        Some("Meta".to_string()),
+    ));
+    let opts = lua.create_table()?;
+    opts.set("display_name", "Self test")?;
+    add_action(
+        lua,
+        &module,
+        "self_test".to_string(),
+        callback,
+        Value::Table(opts),
+    )?;
+    plugin_instance.modules.write().unwrap().push(module);
+    Ok(())
+}
+
+fn generate_token(num_chars: u8) -> mlua::Result<String> {
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), num_chars as usize);
+    Ok(token)
+}
+
+fn create_access_token(
+    _lua: &Lua,
+    ttl_ms: u64,
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<(String, u64)> {
+    ctx.access_tokens
+        .create(Duration::from_millis(ttl_ms))
+        .map_err(|e| mlua::Error::RuntimeError(e.to_string()))
+}
+
+fn get_channel(_lua: &Lua, _: Value, ctx: Arc<LuaContext>) -> mlua::Result<u8> {
+    Ok(ctx.env_config.channel)
+}
+
+fn get_channel_home(_lua: &Lua, _: Value, ctx: Arc<LuaContext>) -> mlua::Result<String> {
+    Ok(ctx.env_config.channel_home.display().to_string())
+}
+
+fn on_status_change(
+    lua: &Lua,
+    (plugin_instance, callback): (AnyUserData, Function),
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<()> {
+    let plugin_instance = match plugin_instance.borrow::<PluginInstanceHandle>() {
+        Ok(handle) => handle.plugin_instance.name.clone(),
+        Err(_) => {
+            error!("couldn't add status observer -- first argument is no plugin instance handle");
+            return Ok(());
+        }
+    };
+    let callback_key = Arc::new(lua.create_registry_value(callback)?);
+    let mut status_observers = ctx.status_observers.lock().unwrap();
+    // Status updates are only forwarded to the event loop while somebody observes them
+    if status_observers.forwarder.is_none() {
+        status_observers.forwarder = Some(ctx.main_runtime_handle.spawn(forward_status_updates(
+            ctx.notification_sender.subscribe(),
+            ctx.event_sender.clone(),
+        )));
+    }
+    status_observers.observers.push(StatusObserver {
+        plugin_instance,
+        callback_key,
+    });
+    Ok(())
+}
+
+fn env_config<'lua>(lua: &'lua Lua, _: Value, ctx: Arc<LuaContext>) -> mlua::Result<Table<'lua>> {
+    env_config_table(lua, &ctx.env_config)
+}
+
+fn env_config_table<'lua>(lua: &'lua Lua, env_config: &EnvConfig) -> mlua::Result<Table<'lua>> {
+    let table = lua.create_table()?;
+    table.set("channel", env_config.channel)?;
+    table.set(
+        "neopult_home",
+        env_config.neopult_home.display().to_string(),
+    )?;
+    table.set(
+        "channel_home",
+        env_config.channel_home.display().to_string(),
+    )?;
+    Ok(table)
+}
+
+fn get_channel_config_path(
+    _lua: &Lua,
+    _: Value,
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<Option<String>> {
+    match ctx.env_config.ensure_channel_dirs() {
+        Ok(path) => Ok(Some(path.display().to_string())),
+        Err(e) => {
+            error!("couldn't create directories in the channel home: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+fn create_store<'lua>(lua: &'lua Lua, value: Value<'lua>) -> mlua::Result<AnyUserData<'lua>> {
+    let store: mlua::AnyUserData = lua.create_userdata(Store::new())?;
+    store.set_user_value(value)?;
+    Ok(store)
+}
+
+fn reposition_windows(lua: &Lua, _: Value, ctx: Arc<LuaContext>) -> mlua::Result<()> {
+    let mut wm = match ctx.write_window_manager() {
+        Some(wm) => wm,
+        None => return Ok(()),
+    };
+    if let Err(e) = wm.reposition_windows(lua) {
+        error!("error when repositioning windows: {}", e);
+    }
+    drop(wm);
+    ctx.sync_attached_windows();
+    Ok(())
+}
+
+fn set_background_color(lua: &Lua, color: String, ctx: Arc<LuaContext>) -> mlua::Result<bool> {
+    let color: Color = match color.parse() {
+        Ok(color) => color,
+        Err(e) => {
+            error!("error when setting background color: {}", e);
+            return Ok(false);
+        }
+    };
+    let mut wm = match ctx.write_window_manager() {
+        Some(wm) => wm,
+        None => return Ok(false),
+    };
+    if let Err(e) = wm.set_background_color(lua, color) {
+        error!("error when setting background color: {:?}", e);
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+fn action_list<'lua>(
+    lua: &'lua Lua,
+    plugin_instances: &[Arc<PluginInstance>],
+) -> mlua::Result<Table<'lua>> {
+    let mut action_tables = vec![];
+    for entry in action_catalog(plugin_instances) {
+        let action_table = lua.create_table()?;
+        action_table.set("plugin_instance", entry.identifier.plugin_instance)?;
+        action_table.set("module", entry.identifier.module)?;
+        action_table.set("action", entry.identifier.action)?;
+        action_table.set("display_name", entry.display_name)?;
+        action_tables.push(action_table);
+    }
+    lua.create_sequence_from(action_tables)
+}
+
+fn get_action_list<'lua>(
+    lua: &'lua Lua,
+    _: Value,
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<Table<'lua>> {
+    let plugin_instances = ctx.plugin_instances.read().unwrap().clone();
+    action_list(lua, &plugin_instances)
+}
+
+/// Table with the owning plugin instance of the window and the module that has the window
+/// attached, if any
+fn window_owner_table<'lua>(
+    lua: &'lua Lua,
+    plugin_instances: &[Arc<PluginInstance>],
+    (id, owner): (ManagedWid, &str),
+) -> mlua::Result<Table<'lua>> {
+    let owner_table = lua.create_table()?;
+    owner_table.set("plugin_instance", owner)?;
+    let module = plugin_instances
+        .iter()
+        .filter(|plugin_instance| plugin_instance.name == owner)
+        .flat_map(|plugin_instance| plugin_instance.modules.read().unwrap().clone())
+        .find(|module| *module.attached_window.read().unwrap() == Some(id));
+    if let Some(module) = module {
+        owner_table.set("module", module.name.clone())?;
+    }
+    Ok(owner_table)
+}
+
+fn get_primary_window_owner<'lua>(
+    lua: &'lua Lua,
+    _: Value,
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<Value<'lua>> {
+    let wm = match ctx.read_window_manager() {
+        Some(wm) => wm,
+        None => return Ok(Value::Nil),
+    };
+    match wm.primary_window_owner() {
+        Some(owner) => {
+            let plugin_instances = ctx.plugin_instances.read().unwrap().clone();
+            Ok(Value::Table(window_owner_table(
+                lua,
+                &plugin_instances,
+                owner,
+            )?))
+        }
+        None => Ok(Value::Nil),
+    }
+}
+
+fn list_windows<'lua>(lua: &'lua Lua, _: Value, ctx: Arc<LuaContext>) -> mlua::Result<Value<'lua>> {
+    let wm = match ctx.read_window_manager() {
+        Some(wm) => wm,
+        None => return Ok(Value::Nil),
+    };
+    let plugin_instances = ctx.plugin_instances.read().unwrap().clone();
+    let window_tables = wm
+        .list_windows()
+        .into_iter()
+        .map(|window| {
+            let window_table =
+                window_owner_table(lua, &plugin_instances, (window.id, &window.owner))?;
+            window_table.set("id", window.id)?;
+            window_table.set("name", window.name)?;
+            window_table.set("mode", window_mode_state(window.mode).0)?;
+            window_table.set("primary", window.primary)?;
+            Ok(window_table)
+        })
+        .collect::<mlua::Result<Vec<_>>>()?;
+    Ok(Value::Table(lua.create_sequence_from(window_tables)?))
+}
+
+fn list_claimable_windows<'lua>(
+    lua: &'lua Lua,
+    class_filter: Option<String>,
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<Value<'lua>> {
+    let wm = match ctx.read_window_manager() {
+        Some(wm) => wm,
+        None => return Ok(Value::Nil),
+    };
+    let windows = match wm.list_unmanaged_windows(class_filter.as_deref().unwrap_or("")) {
+        Ok(windows) => windows,
+        Err(e) => {
+            error!("error when listing claimable windows: {:?}", e);
+            return Ok(Value::Nil);
+        }
+    };
+
+    let mut window_tables = Vec::with_capacity(windows.len());
+    for window in windows {
+        let window_table = lua.create_table()?;
+        window_table.set("id", window.window_id)?;
+        window_table.set("class", window.class)?;
+        window_table.set("name", window.name)?;
+        window_tables.push(window_table);
+    }
+    Ok(Value::Table(lua.create_sequence_from(window_tables)?))
+}
+
+fn get_display_info<'lua>(
+    lua: &'lua Lua,
+    _: Value,
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<Value<'lua>> {
+    let wm = match ctx.read_window_manager() {
+        Some(wm) => wm,
+        None => return Ok(Value::Nil),
+    };
+    let outputs = match wm.get_display_info() {
+        Ok(outputs) => outputs,
+        Err(e) => {
+            error!("error when getting display info: {}", e);
+            return Ok(Value::Nil);
+        }
+    };
+
+    let mode_table = |mode: DisplayMode| -> mlua::Result<Table> {
+        let table = lua.create_table()?;
+        table.set("width", mode.width)?;
+        table.set("height", mode.height)?;
+        Ok(table)
+    };
+    let mut output_tables = Vec::with_capacity(outputs.len());
+    for output in outputs {
+        let output_table = lua.create_table()?;
+        output_table.set("name", output.name)?;
+        output_table.set("connected", output.connected)?;
+        if let Some(current_mode) = output.current_mode {
+            output_table.set("current_mode", mode_table(current_mode)?)?;
+        }
+        let modes = output
+            .modes
+            .into_iter()
+            .map(mode_table)
+            .collect::<mlua::Result<Vec<_>>>()?;
+        output_table.set("modes", lua.create_sequence_from(modes)?)?;
+        output_tables.push(output_table);
+    }
+    let outputs_table = lua.create_sequence_from(output_tables)?;
+    Ok(Value::Table(outputs_table))
+}
+
+fn run_later(lua: &Lua, func: Function, ctx: Arc<LuaContext>) -> mlua::Result<()> {
+    let func_key = lua.create_registry_value(func)?;
+    ctx.run_later_tasks.lock().unwrap().push_back(func_key);
+    Ok(())
+}
+
+fn defer_until_idle(lua: &Lua, func: Function, ctx: Arc<LuaContext>) -> mlua::Result<()> {
+    let func_key = lua.create_registry_value(func)?;
+    ctx.idle_tasks.lock().unwrap().push_back(func_key);
+    Ok(())
+}
+
+fn schedule_at(
+    lua: &Lua,
+    (unix_timestamp, callback): (f64, Function),
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<Option<bool>> {
+    schedule_callback_at(
+        lua,
+        unix_timestamp,
+        callback,
+        &ctx.main_runtime_handle,
+        ctx.event_sender.clone(),
+    )
+}
+
+/// Returns `None` for timestamps that can't be scheduled at all.
+fn schedule_callback_at(
+    lua: &Lua,
+    unix_timestamp: f64,
+    callback: Function,
+    runtime_handle: &tokio::runtime::Handle,
+    event_sender: Arc<mpsc::Sender<Event>>,
+) -> mlua::Result<Option<bool>> {
+    let delay = match delay_until(unix_timestamp, SystemTime::now()) {
+        Ok(Some(delay)) => delay,
+        Ok(None) => {
+            warn!(
+                "not scheduling callback for timestamp {}, which isn't in the future",
+                unix_timestamp
+            );
+            return Ok(Some(false));
+        }
+        Err(e) => {
+            error!("couldn't schedule callback: {:#}", e);
+            return Ok(None);
+        }
+    };
+    call_later(
+        runtime_handle,
+        event_sender,
+        delay,
+        Arc::new(lua.create_registry_value(callback)?),
+    );
+    Ok(Some(true))
+}
+
+fn schedule_cron(
+    lua: &Lua,
+    (expression, callback): (String, Function),
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<bool> {
+    let schedule: CronSchedule = match expression.parse() {
+        Ok(schedule) => schedule,
+        Err(e) => {
+            error!("couldn't parse cron expression {}: {:#}", expression, e);
+            return Ok(false);
+        }
+    };
+    let callback_key = Arc::new(lua.create_registry_value(callback)?);
+    arm_cron(lua, ctx, Arc::new(schedule), callback_key, Local::now())
+}
+
+/// Schedules the next run of the cron callback after `after`, which is the time of the previous
+/// run, so that timers that fire slightly early don't run the callback twice.
+fn arm_cron(
+    lua: &Lua,
+    ctx: Arc<LuaContext>,
+    schedule: Arc<CronSchedule>,
+    callback_key: Arc<RegistryKey>,
+    after: DateTime<Local>,
+) -> mlua::Result<bool> {
+    let next = match schedule.next_after(after) {
+        Some(next) => next,
+        None => {
+            warn!("cron schedule {:?} never matches again", schedule);
+            return Ok(false);
+        }
+    };
+    let delay = (next - Local::now()).to_std().unwrap_or_default();
+    let runtime_handle = ctx.main_runtime_handle.clone();
+    let event_sender = ctx.event_sender.clone();
+    let run = lua.create_function(move |lua, ()| {
+        match lua.registry_value::<Function>(&callback_key) {
+            Ok(callback) => {
+                if let Err(e) = callback.call::<_, Value>(()) {
+                    error!("error when calling cron callback: {:?}", e);
+                }
+            }
+            Err(e) => error!("couldn't get cron callback from lua registry: {:?}", e),
+        }
+        if let Err(e) = arm_cron(
+            lua,
+            ctx.clone(),
+            schedule.clone(),
+            callback_key.clone(),
+            next,
+        ) {
+            error!("couldn't schedule next run of cron callback: {:?}", e);
+        }
+        Ok(())
+    })?;
+    call_later(
+        &runtime_handle,
+        event_sender,
+        delay,
+        Arc::new(lua.create_registry_value(run)?),
+    );
+    Ok(true)
+}
+
+fn call_action_from_lua(
+    lua: &Lua,
+    (plugin_instance, module, action, args): (String, String, String, Value),
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<(bool, Option<String>)> {
+    let identifier = ActionIdentifier {
+        plugin_instance,
+        module,
+        action,
+    };
+    // Cloning the list, so that the lock isn't held while the action runs
+    let plugin_instances = ctx.plugin_instances.read().unwrap().clone();
+    match call_nested_action(
+        lua,
+        &plugin_instances,
+        &ctx.action_callers,
+        identifier.clone(),
+        args,
+    ) {
+        Ok(_) => Ok((true, None)),
+        Err(e) => {
+            error!("error when calling action {} from lua: {:#}", identifier, e);
+            Ok((false, Some(format!("{:#}", e))))
+        }
+    }
+}
+
+/// Calls the action while keeping track of how deeply actions are nested, so that actions that
+/// call each other in a cycle are aborted instead of overflowing the stack. The action gets the
+/// role of the outermost caller, so that viewers can't call admin actions through other actions.
+fn call_nested_action(
+    lua: &Lua,
+    plugin_instances: &[Arc<PluginInstance>],
+    action_callers: &Mutex<Vec<Caller>>,
+    identifier: ActionIdentifier,
+    args: Value,
+) -> anyhow::Result<()> {
+    let caller = {
+        let callers = action_callers.lock().unwrap();
+        if callers.len() >= MAX_ACTION_CALL_DEPTH {
+            anyhow::bail!(
+                "maximum action call depth of {} exceeded when calling {}",
+                MAX_ACTION_CALL_DEPTH,
+                identifier
+            );
+        }
+        match callers.first() {
+            Some(outer) => Caller {
+                role: outer.role,
+                token_id: outer.token_id,
+                ..Caller::PLUGIN
+            },
+            None => Caller::PLUGIN,
+        }
+    };
+    call_tracked_action(
+        lua,
+        plugin_instances,
+        action_callers,
+        identifier,
+        args,
+        caller,
+    )
+}
+
+fn once(
+    _lua: &Lua,
+    (key, callback): (String, Function),
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<bool> {
+    run_once(&ctx.once_keys, key, callback)
+}
+
+fn with_lock(
+    lua: &Lua,
+    (name, callback): (String, Function),
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<bool> {
+    let defer: DeferFn = {
+        let ctx = ctx.clone();
+        Arc::new(move |task_key| ctx.run_later_tasks.lock().unwrap().push_back(task_key))
+    };
+    run_with_lock(lua, &ctx.held_locks, &defer, name, callback)
+}
+
+type DeferFn = Arc<dyn Fn(RegistryKey) + Send + Sync>;
+
+/// Runs `callback` while holding the lock `name`. Callbacks are never interrupted by the event
+/// loop, so the lock can only be held further up the call stack, e.g. when an action calls another
+/// action. In that case the call is retried later via `defer`.
+fn run_with_lock(
+    lua: &Lua,
+    held_locks: &Arc<Mutex<HashSet<String>>>,
+    defer: &DeferFn,
+    name: String,
+    callback: Function,
+) -> mlua::Result<bool> {
+    if !held_locks.lock().unwrap().insert(name.clone()) {
+        debug!("lock {} is held, deferring callback", name);
+        let callback_key = lua.create_registry_value(callback)?;
+        let retry = {
+            let held_locks = held_locks.clone();
+            let defer = defer.clone();
+            let name = name.clone();
+            lua.create_function(move |lua, ()| {
+                let callback = lua.registry_value::<Function>(&callback_key)?;
+                run_with_lock(lua, &held_locks, &defer, name.clone(), callback)
+            })?
+        };
+        defer(lua.create_registry_value(retry)?);
+        return Ok(false);
+    }
+    // The lock must not be held while calling the callback, so that it can call `with_lock` itself
+    if let Err(e) = callback.call::<_, Value>(()) {
+        error!("error when calling callback with lock {}: {:?}", name, e);
+    }
+    held_locks.lock().unwrap().remove(&name);
+    Ok(true)
+}
+
+fn register_cli_command(
+    lua: &Lua,
+    (name, callback): (String, Function),
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<bool> {
+    add_cli_command(lua, &ctx.cli_commands, name, callback)
+}
+
+fn add_cli_command(
+    lua: &Lua,
+    cli_commands: &Mutex<HashMap<String, RegistryKey>>,
+    name: String,
+    callback: Function,
+) -> mlua::Result<bool> {
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        error!("cli command name {:?} must be a single word", name);
+        return Ok(false);
+    }
+    if BUILTIN_CLI_COMMANDS.contains(&name.as_str()) {
+        error!(
+            "can't register cli command {}, which is a built-in command",
+            name
+        );
+        return Ok(false);
+    }
+    let key = lua.create_registry_value(callback)?;
+    if cli_commands
+        .lock()
+        .unwrap()
+        .insert(name.clone(), key)
+        .is_some()
+    {
+        warn!(
+            "cli command {} was registered again, replacing the previous one",
+            name
+        );
+    }
+    Ok(true)
+}
+
+fn run_once(
+    once_keys: &Mutex<HashSet<String>>,
+    key: String,
+    callback: Function,
+) -> mlua::Result<bool> {
+    // The lock must not be held while calling the callback, so that it can call `once` itself
+    if !once_keys.lock().unwrap().insert(key.clone()) {
+        debug!("once callback with key {} already ran", key);
+        return Ok(false);
+    }
+    if let Err(e) = callback.call::<_, Value>(()) {
+        error!("error when calling once callback with key {}: {:?}", key, e);
+    }
+    Ok(true)
+}
+
+/// Whether neopult is a debug build, which is used during development
+fn is_dev() -> bool {
+    cfg!(debug_assertions)
+}
+
+fn escape_html(unescaped: String) -> mlua::Result<String> {
+    let escaped = unescaped
+        .replace("&", "&amp;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;")
+        .replace("\"", "&quot;")
+        .replace("\'", "&#039;");
+    Ok(escaped)
+}
+
+const STATUS_SEVERITIES: &[&str] = &["info", "success", "warning", "error"];
+
+/// Escapes the text and wraps it in a span whose class corresponds to the severity, which
+/// defaults to "info".
+pub(super) fn format_status_html(text: String, severity: Option<String>) -> mlua::Result<String> {
+    let severity = match severity.as_deref() {
+        None => "info",
+        Some(severity) if STATUS_SEVERITIES.contains(&severity) => severity,
+        Some(severity) => {
+            warn!(
+                "unknown status severity {} (using info), expected one of {:?}",
+                severity, STATUS_SEVERITIES
+            );
+            "info"
+        }
+    };
+    Ok(format!(
+        "<span class=\"severity-{}\">{}</span>",
+        severity,
+        escape_html(text)?
+    ))
+}
+
+/// Current UTC time with second precision, e.g. `2022-05-14T18:03:09Z`
+fn now_iso8601() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Formats a unix timestamp with a strftime-like format string in local time or, if `utc` is set,
+/// in UTC. Returns `None` for invalid format strings and out of range timestamps.
+fn format_timestamp(unix: i64, fmt: &str, utc: bool) -> Option<String> {
+    let items = StrftimeItems::new(fmt).collect::<Vec<_>>();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        error!("invalid timestamp format {:?}", fmt);
+        return None;
+    }
+    let formatted = if utc {
+        let time = Utc.timestamp_opt(unix, 0).single()?;
+        time.format_with_items(items.into_iter()).to_string()
+    } else {
+        let time = Local.timestamp_opt(unix, 0).single()?;
+        time.format_with_items(items.into_iter()).to_string()
+    };
+    Some(formatted)
+}
+
+/// Replaces `{{KEY}}` placeholders with the values of `vars`. Placeholders without a value are
+/// left intact.
+fn interpolate(template: String, vars: Table) -> mlua::Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        let after_open = &rest[start + 2..];
+        let end = match after_open.find("}}") {
+            Some(end) => end,
+            None => {
+                rest = &rest[start..];
+                break;
+            }
+        };
+        let key = &after_open[..end];
+        match vars.get::<_, Option<String>>(key) {
+            Ok(Some(value)) => result.push_str(&value),
+            _ => {
+                warn!("no value for placeholder {{{{{}}}}} in template", key);
+                result.push_str(&rest[start..start + 2 + end + 2]);
+            }
+        }
+        rest = &after_open[end + 2..];
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+/// Tables nested deeper than this are most likely cyclic and abort `deep_merge`.
+const DEEP_MERGE_MAX_DEPTH: usize = 64;
+
+/// Tables with only the keys `1..n` are arrays. They are replaced as a whole instead of being
+/// merged key by key.
+fn is_array(table: &Table) -> mlua::Result<bool> {
+    let len = table.raw_len();
+    if len == 0 {
+        return Ok(false);
+    }
+    let mut count = 0;
+    for pair in table.clone().pairs::<Value, Value>() {
+        pair?;
+        count += 1;
+    }
+    Ok(count == len)
+}
+
+fn deep_copy<'lua>(lua: &'lua Lua, value: Value<'lua>, depth: usize) -> mlua::Result<Value<'lua>> {
+    match value {
+        Value::Table(table) => {
+            if depth > DEEP_MERGE_MAX_DEPTH {
+                return Err(mlua::Error::RuntimeError(
+                    "tables are nested too deeply, they might be cyclic".to_string(),
+                ));
+            }
+            let copy = lua.create_table()?;
+            for pair in table.pairs::<Value, Value>() {
+                let (key, value) = pair?;
+                copy.raw_set(key, deep_copy(lua, value, depth + 1)?)?;
+            }
+            Ok(Value::Table(copy))
+        }
+        value => Ok(value),
+    }
+}
+
+fn merge_tables<'lua>(
+    lua: &'lua Lua,
+    base: Table<'lua>,
+    override_table: Table<'lua>,
+    depth: usize,
+) -> mlua::Result<Table<'lua>> {
+    let merged = match deep_copy(lua, Value::Table(base), depth)? {
+        Value::Table(merged) => merged,
+        _ => unreachable!(),
+    };
+    for pair in override_table.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        let value = match (merged.raw_get::<_, Value>(key.clone())?, value) {
+            (Value::Table(base), Value::Table(value))
+                if !is_array(&base)? && !is_array(&value)? =>
+            {
+                Value::Table(merge_tables(lua, base, value, depth + 1)?)
+            }
+            (_, value) => deep_copy(lua, value, depth + 1)?,
+        };
+        merged.raw_set(key, value)?;
+    }
+    Ok(merged)
+}
+
+/// Returns a new table with `override_value` recursively merged into `base`. Maps are merged key
+/// by key while arrays and all other values of `override_value` replace the ones of `base`. A nil
+/// argument is treated like an empty table. Neither argument is modified.
+fn deep_merge<'lua>(
+    lua: &'lua Lua,
+    base: Value<'lua>,
+    override_value: Value<'lua>,
+) -> mlua::Result<Option<Table<'lua>>> {
+    let as_table = |value: Value<'lua>, name: &str| match value {
+        Value::Nil => lua.create_table().map(Some),
+        Value::Table(table) => Ok(Some(table)),
+        value => {
+            error!(
+                "deep_merge expects {} to be a table or nil, got {}",
+                name,
+                value.type_name()
+            );
+            Ok(None)
+        }
+    };
+    let (base, override_table) = match (
+        as_table(base, "base")?,
+        as_table(override_value, "override")?,
+    ) {
+        (Some(base), Some(override_table)) => (base, override_table),
+        _ => return Ok(None),
+    };
+    match merge_tables(lua, base, override_table, 0) {
+        Ok(merged) => Ok(Some(merged)),
+        Err(e) => {
+            error!("error when merging tables: {}", e);
+            Ok(None)
+        }
+    }
+}
+
+fn hash(algorithm: String, data: mlua::String) -> mlua::Result<Option<String>> {
+    let digest = match algorithm.as_str() {
+        "sha256" => Sha256::digest(data.as_bytes()).to_vec(),
+        "sha512" => Sha512::digest(data.as_bytes()).to_vec(),
+        _ => {
+            error!("tried hashing with unsupported algorithm {}", algorithm);
+            return Ok(None);
+        }
+    };
+    let hex = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(Some(hex))
+}
+
+pub(super) fn inject_api_functions(
+    lua: &Lua,
+    neopult: &Table,
+    ctx: Arc<LuaContext>,
+) -> mlua::Result<()> {
+    let api = lua.create_table()?;
+
+    api.set(
+        "register_plugin_instance",
+        create_context_function(lua, ctx.clone(), register_plugin_instance)?,
+    )?;
+    api.set(
+        "unregister_plugin_instance",
+        create_context_function(lua, ctx.clone(), unregister_plugin_instance)?,
+    )?;
+    api.set(
+        "generate_token",
+        lua.create_function(|_lua, num_chars| generate_token(num_chars))?,
+    )?;
+    api.set(
+        "create_access_token",
+        create_context_function(lua, ctx.clone(), create_access_token)?,
+    )?;
+    api.set(
+        "get_channel",
+        create_context_function(lua, ctx.clone(), get_channel)?,
+    )?;
+    api.set(
+        "get_channel_home",
+        create_context_function(lua, ctx.clone(), get_channel_home)?,
+    )?;
+    api.set(
+        "on_status_change",
+        create_context_function(lua, ctx.clone(), on_status_change)?,
+    )?;
+    api.set(
+        "env_config",
+        create_context_function(lua, ctx.clone(), env_config)?,
+    )?;
+    api.set(
+        "get_channel_config_path",
+        create_context_function(lua, ctx.clone(), get_channel_config_path)?,
+    )?;
+    api.set("is_dev", lua.create_function(|_lua, ()| Ok(is_dev()))?)?;
+    api.set("create_store", lua.create_function(create_store)?)?;
+    api.set(
+        "reposition_windows",
+        create_context_function(lua, ctx.clone(), reposition_windows)?,
+    )?;
+    api.set(
+        "set_background_color",
+        create_context_function(lua, ctx.clone(), set_background_color)?,
+    )?;
+    api.set(
+        "get_action_list",
+        create_context_function(lua, ctx.clone(), get_action_list)?,
+    )?;
+    api.set(
+        "list_claimable_windows",
+        create_context_function(lua, ctx.clone(), list_claimable_windows)?,
+    )?;
+    api.set(
+        "list_windows",
+        create_context_function(lua, ctx.clone(), list_windows)?,
+    )?;
+    api.set(
+        "get_primary_window_owner",
+        create_context_function(lua, ctx.clone(), get_primary_window_owner)?,
+    )?;
+    api.set(
+        "get_display_info",
+        create_context_function(lua, ctx.clone(), get_display_info)?,
+    )?;
+    api.set(
+        "run_later",
+        create_context_function(lua, ctx.clone(), run_later)?,
+    )?;
+    api.set(
+        "defer_until_idle",
+        create_context_function(lua, ctx.clone(), defer_until_idle)?,
+    )?;
+    api.set(
+        "schedule_at",
+        create_context_function(lua, ctx.clone(), schedule_at)?,
+    )?;
+    api.set(
+        "schedule_cron",
+        create_context_function(lua, ctx.clone(), schedule_cron)?,
+    )?;
+    api.set(
+        "call_action",
+        create_context_function(lua, ctx.clone(), call_action_from_lua)?,
+    )?;
+    api.set(
+        "throttle",
+        create_context_function(lua, ctx.clone(), throttle)?,
+    )?;
+    api.set(
+        "register_cli_command",
+        create_context_function(lua, ctx.clone(), register_cli_command)?,
+    )?;
+    api.set(
+        "with_lock",
+        create_context_function(lua, ctx.clone(), with_lock)?,
+    )?;
+    api.set("once", create_context_function(lua, ctx, once)?)?;
+    api.set(
+        "escape_html",
+        lua.create_function(|_lua, unescaped| escape_html(unescaped))?,
+    )?;
+    api.set(
+        "format_status_html",
+        lua.create_function(|_lua, (text, severity)| format_status_html(text, severity))?,
+    )?;
+    api.set(
+        "hash",
+        lua.create_function(|_lua, (algorithm, data)| hash(algorithm, data))?,
+    )?;
+    api.set(
+        "now_iso8601",
+        lua.create_function(|_lua, ()| Ok(now_iso8601()))?,
+    )?;
+    api.set(
+        "format_timestamp",
+        lua.create_function(|_lua, (unix, fmt, opts): (i64, String, Option<Table>)| {
+            let utc = match opts {
+                Some(opts) => opts.get::<_, Option<bool>>("utc")?.unwrap_or(false),
+                None => false,
+            };
+            Ok(format_timestamp(unix, &fmt, utc))
+        })?,
+    )?;
+    api.set(
+        "create_image_window",
+        lua.create_function(|lua, args| create_image_window(lua, args))?,
+    )?;
+    api.set(
+        "interpolate",
+        lua.create_function(|_lua, (template, vars)| interpolate(template, vars))?,
+    )?;
+    api.set(
+        "deep_merge",
+        lua.create_function(|lua, (base, override_value)| deep_merge(lua, base, override_value))?,
+    )?;
+
+    neopult.set("api", api)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin_system::{
+        build_process_io_runtime, call_action, call_cli_command, is_same_process,
+        process_start_time, system_info,
+        test_support::{self, TestPluginSystem},
+        CallerSource, Role,
+    };
+    use crate::test_support::temp_dir;
+    use crate::window_manager::{fake_backend::FakeRequest, Geometry};
+    use std::{collections::VecDeque, time::UNIX_EPOCH};
+
+    #[test]
+    fn test_window_owner_table() {
+        let lua = Lua::new();
+        let (plugin_instance, viewer) = test_support::vnc_viewer();
+        let other = Arc::new(Module::new("other".to_string(), "vnc".to_string(), None));
+        *viewer.attached_window.write().unwrap() = Some(3);
+        *other.attached_window.write().unwrap() = Some(4);
+        plugin_instance.modules.write().unwrap().push(other);
+        let plugin_instances = [plugin_instance];
+
+        let owner = window_owner_table(&lua, &plugin_instances, (3, "vnc")).unwrap();
+        assert_eq!(owner.get::<_, String>("plugin_instance").unwrap(), "vnc");
+        assert_eq!(owner.get::<_, String>("module").unwrap(), "viewer");
+
+        // Windows that aren't attached to a module only report the plugin instance
+        let owner = window_owner_table(&lua, &plugin_instances, (5, "vnc")).unwrap();
+        assert_eq!(owner.get::<_, String>("plugin_instance").unwrap(), "vnc");
+        assert_eq!(owner.get::<_, Option<String>>("module").unwrap(), None);
+
+        // Modules of other plugin instances don't own the window
+        let owner = window_owner_table(&lua, &plugin_instances, (3, "obs")).unwrap();
+        assert_eq!(owner.get::<_, Option<String>>("module").unwrap(), None);
+    }
+
+    #[test]
+    fn test_action_list() {
+        let lua = Lua::new();
+        let (plugin_instance, module) = test_support::vnc_viewer();
+        let actions: Table = lua
+            .load(
+                r#"{
+                    { name = "max", callback = function() end, display_name = "Maximize" },
+                    { name = "stop", callback = function() end },
+                }"#,
+            )
+            .eval()
+            .unwrap();
+        add_actions(&lua, &module, actions).unwrap();
+
+        let list = action_list(&lua, &[plugin_instance]).unwrap();
+        assert_eq!(list.len().unwrap(), 2);
+        let max: Table = list.get(1).unwrap();
+        assert_eq!(max.get::<_, String>("plugin_instance").unwrap(), "vnc");
+        assert_eq!(max.get::<_, String>("module").unwrap(), "viewer");
+        assert_eq!(max.get::<_, String>("action").unwrap(), "max");
+        assert_eq!(max.get::<_, String>("display_name").unwrap(), "Maximize");
+        let stop: Table = list.get(2).unwrap();
+        assert_eq!(stop.get::<_, String>("action").unwrap(), "stop");
+        assert_eq!(stop.get::<_, Option<String>>("display_name").unwrap(), None);
+    }
+
+    #[test]
+    fn test_read_process_lines_on_process_io_runtime() {
+        const PROCESSES: usize = 8;
+        const LINES: usize = 500;
+
+        let runtime = build_process_io_runtime(2).unwrap();
+        let lua = Lua::new();
+        let callback = lua.create_function(|_, ()| Ok(())).unwrap();
+        let listeners = Arc::new(OutputListeners::default());
+        listeners.add(Arc::new(lua.create_registry_value(callback).unwrap()));
+        let plugin_instance = Arc::new(PluginInstance::new("test".to_string(), Default::default()));
+        let (event_tx, mut event_rx) = mpsc::channel(64);
+        let event_tx = Arc::new(event_tx);
+
+        runtime.block_on(async {
+            for process in 0..PROCESSES {
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(format!(
+                        "for i in $(seq {}); do echo {} $i; done",
+                        LINES, process
+                    ))
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .unwrap();
+                tokio::spawn(read_process_lines(
+                    child.stdout.take().unwrap(),
+                    event_tx.clone(),
+                    format!("chatty-{}", process),
+                    plugin_instance.clone(),
+                    listeners.clone(),
+                    None,
+                    child.id().unwrap(),
+                    "stdout",
+                ));
+                tokio::spawn(async move { child.wait().await });
+            }
+        });
+        drop(event_tx);
+
+        // Lines of every process have to arrive completely and in order
+        let mut next_lines = [1; PROCESSES];
+        while let Some(event) = event_rx.blocking_recv() {
+            match event {
+                Event::ProcessOutput { line, .. } => {
+                    let (process, n) = line.split_once(' ').unwrap();
+                    let process: usize = process.parse().unwrap();
+                    assert_eq!(n.parse::<usize>().unwrap(), next_lines[process]);
+                    next_lines[process] += 1;
+                }
+                other => panic!("unexpected {} event", other.kind()),
+            }
+        }
+        assert!(next_lines.iter().all(|&n| n == LINES + 1));
+    }
+
+    #[test]
+    fn test_spawn_writes_pid_file() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let pid_dir_path = temp_dir("spawn-pid");
+        let plugin_instance = Arc::new(PluginInstance::new("test".to_string(), Default::default()));
+        let (event_tx, _event_rx) = mpsc::channel(8);
+
+        runtime.block_on(async {
+            let spawner = ProcessSpawner {
+                cmd: "sleep".to_string(),
+                args: vec!["5".to_string()],
+                envs: HashMap::new(),
+                merge_stderr: false,
+                priority: ProcessPriority::default(),
+                output_listeners: Arc::new(OutputListeners::default()),
+                recent_output: None,
+                event_sender: Arc::new(event_tx),
+                plugin_instance,
+                pid_dir_path: pid_dir_path.clone(),
+            };
+            let mut spawned = spawner.spawn().unwrap();
+            let pid_file_path = spawned.pid_file_path.clone().unwrap();
+            assert_eq!(
+                pid_file_path,
+                pid_dir_path.join(format!("{}.pid", spawned.pid))
+            );
+
+            // The start time identifies the process and the command tells users what it was
+            let contents = std::fs::read_to_string(&pid_file_path).unwrap();
+            let start_time = process_start_time(spawned.pid as i32).unwrap();
+            assert_eq!(contents, format!("{}\nsleep\n", start_time));
+            assert!(is_same_process(spawned.pid as i32, &contents));
+
+            spawned.child.kill().await.unwrap();
+        });
+        std::fs::remove_dir_all(&pid_dir_path).unwrap();
+    }
+
+    #[test]
+    fn test_merge_stderr() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let lua = Lua::new();
+        let callback = lua.create_function(|_, ()| Ok(())).unwrap();
+        let listeners = Arc::new(OutputListeners::default());
+        listeners.add(Arc::new(lua.create_registry_value(callback).unwrap()));
+        let plugin_instance = Arc::new(PluginInstance::new("test".to_string(), Default::default()));
+        let (event_tx, mut event_rx) = mpsc::channel(8);
+
+        let lines = runtime.block_on(async {
+            let spawner = ProcessSpawner {
+                cmd: "sh".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    "echo out 1; echo err 1 >&2; echo out 2; echo err 2 >&2".to_string(),
+                ],
+                envs: HashMap::new(),
+                merge_stderr: true,
+                priority: ProcessPriority::default(),
+                output_listeners: listeners,
+                recent_output: None,
+                event_sender: Arc::new(event_tx),
+                plugin_instance,
+                pid_dir_path: std::env::temp_dir(),
+            };
+            let mut spawned = spawner.spawn().unwrap();
+            drop(spawner);
+            spawned.child.wait().await.unwrap();
+            if let Some(pid_file_path) = spawned.pid_file_path {
+                let _ = std::fs::remove_file(pid_file_path);
+            }
+
+            let mut lines = vec![];
+            while let Some(event) = event_rx.recv().await {
+                match event {
+                    Event::ProcessOutput { line, .. } => lines.push(line),
+                    other => panic!("unexpected {} event", other.kind()),
+                }
+            }
+            lines
+        });
+        assert_eq!(lines, vec!["out 1", "err 1", "out 2", "err 2"]);
+    }
+
+    #[test]
+    fn test_recent_output() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let plugin_instance = Arc::new(PluginInstance::new("test".to_string(), Default::default()));
+        let recent_output = Arc::new(RecentOutput::new(3));
+        let (event_tx, mut event_rx) = mpsc::channel(8);
+
+        runtime.block_on(async {
+            // Lines are buffered without any output listener
+            let spawner = ProcessSpawner {
+                cmd: "seq".to_string(),
+                args: vec!["10".to_string()],
+                envs: HashMap::new(),
+                merge_stderr: false,
+                priority: ProcessPriority::default(),
+                output_listeners: Arc::new(OutputListeners::default()),
+                recent_output: Some(recent_output.clone()),
+                event_sender: Arc::new(event_tx),
+                plugin_instance,
+                pid_dir_path: std::env::temp_dir(),
+            };
+            let mut spawned = spawner.spawn().unwrap();
+            drop(spawner);
+            spawned.child.wait().await.unwrap();
+            if let Some(pid_file_path) = spawned.pid_file_path {
+                let _ = std::fs::remove_file(pid_file_path);
+            }
+            // The channel closes once all output was read
+            assert!(event_rx.recv().await.is_none());
+        });
+        assert_eq!(recent_output.lines(), vec!["8", "9", "10"]);
+    }
+
+    #[test]
+    fn test_stdin_from() {
+        let channel_home = temp_dir("stdin");
+        std::fs::write(channel_home.join("input.txt"), "first line\nsecond line\n").unwrap();
+        assert!(read_stdin_from(&channel_home, "missing.txt").is_err());
+        let contents = read_stdin_from(&channel_home, "input.txt").unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let lua = Lua::new();
+        let callback = lua.create_function(|_, ()| Ok(())).unwrap();
+        let listeners = Arc::new(OutputListeners::default());
+        listeners.add(Arc::new(lua.create_registry_value(callback).unwrap()));
+        let plugin_instance = Arc::new(PluginInstance::new("test".to_string(), Default::default()));
+        let (event_tx, mut event_rx) = mpsc::channel(8);
+
+        runtime.block_on(async {
+            // Contents that don't fit into the pipe don't block until the process reads them
+            let mut sleeping = Command::new("sleep")
+                .arg("10")
+                .stdin(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let current = CurrentProcess::new(sleeping.id().unwrap(), sleeping.stdin.take());
+            let started = Instant::now();
+            spawn_stdin_write(&current, vec![b'x'; 1 << 20], false, "sleep".to_string());
+            assert!(started.elapsed() < Duration::from_millis(500));
+            assert!(current.stdin.try_lock().is_err());
+            sleeping.kill().await.unwrap();
+
+            let mut child = Command::new("cat")
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let current = CurrentProcess::new(child.id().unwrap(), child.stdin.take());
+            spawn_stdin_write(&current, contents, true, "cat".to_string());
+            // Like `ProcessHandle:write`, which has to wait for the contents
+            {
+                let mut stdin = current.stdin.lock().await;
+                stdin
+                    .as_mut()
+                    .unwrap()
+                    .write_all(b"third line\n")
+                    .await
+                    .unwrap();
+                // cat only exits once stdin is closed
+                *stdin = None;
+            }
+            read_process_lines(
+                child.stdout.take().unwrap(),
+                Arc::new(event_tx),
+                "cat".to_string(),
+                plugin_instance,
+                listeners,
+                None,
+                child.id().unwrap(),
+                "stdout",
+            )
+            .await;
+            child.wait().await.unwrap();
+        });
+
+        let mut lines = vec![];
+        while let Some(Event::ProcessOutput { line, .. }) = event_rx.blocking_recv() {
+            lines.push(line);
+        }
+        assert_eq!(lines, vec!["first line", "second line", "third line"]);
+
+        std::fs::remove_dir_all(&channel_home).unwrap();
+    }
+
+    #[test]
+    fn test_format_status_html() {
+        assert_eq!(
+            format_status_html("<b>Tom & Jerry</b>".to_string(), None).unwrap(),
+            r#"<span class="severity-info">&lt;b&gt;Tom &amp; Jerry&lt;/b&gt;</span>"#
+        );
+        assert_eq!(
+            format_status_html("stream lost".to_string(), Some("error".to_string())).unwrap(),
+            r#"<span class="severity-error">stream lost</span>"#
+        );
+        assert_eq!(
+            format_status_html("\" onclick=\"".to_string(), Some("\"><script>".to_string()))
+                .unwrap(),
+            r#"<span class="severity-info">&quot; onclick=&quot;</span>"#
+        );
+    }
+
+    #[test]
+    fn test_command_allowed() {
+        let allowed_commands = vec!["ffmpeg".to_string(), "/usr/bin/vlc".to_string()];
+        assert!(command_allowed(&allowed_commands, "ffmpeg"));
+        assert!(command_allowed(&allowed_commands, "/usr/bin/vlc"));
+        assert!(!command_allowed(&allowed_commands, "vlc"));
+        assert!(!command_allowed(&allowed_commands, "/tmp/evil/ffmpeg"));
+        assert!(!command_allowed(&allowed_commands, "sh"));
+        assert!(!command_allowed(&[], "ffmpeg"));
+
+        let mut envs = HashMap::from([("DISPLAY".to_string(), ":1".to_string())]);
+        assert_eq!(restricted_env(&envs), None);
+        envs.insert("PATH".to_string(), "/tmp/evil".to_string());
+        assert_eq!(restricted_env(&envs), Some("PATH"));
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let mut policy = RestartPolicy::new(3, Duration::from_millis(10));
+        assert_eq!(
+            policy.next_delay(Duration::ZERO),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(
+            policy.next_delay(Duration::ZERO),
+            Some(Duration::from_millis(20))
+        );
+        // A process that ran for a while didn't crash rapidly
+        assert_eq!(
+            policy.next_delay(RESTART_RESET_AFTER),
+            Some(Duration::from_millis(10))
+        );
+    }
+
+    #[test]
+    fn test_restart_on_exit_gives_up() {
+        let mut system = TestPluginSystem::new("restart-on-exit");
+        let spawns_path = system.channel_home.join("spawns");
+        system
+            .lua()
+            .globals()
+            .set("spawns_path", spawns_path.display().to_string())
+            .unwrap();
+        system.exec(
+            r#"
+            plugin_instance = neopult.api.register_plugin_instance("vnc")
+            module = plugin_instance:register_module("viewer", {})
+            process = plugin_instance:spawn_process("sh", {
+                args = { "-c", "echo spawned >> " .. spawns_path .. "; exit 1" },
+                restart_on_exit = true,
+                max_restarts = 3,
+                restart_delay_ms = 1,
+                module = module,
+            })
+            "#,
+        );
+        assert!(system.eval::<bool>("process ~= nil"));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let is_error_status = |notification: &Notification| {
+            matches!(
+                notification,
+                Notification::ModuleStatusUpdate { module_identifier, new_status }
+                    if module_identifier.module == "viewer"
+                        && new_status.as_deref() == Some("error")
+            )
+        };
+        while !system.take_notifications().iter().any(is_error_status) {
+            assert!(Instant::now() < deadline, "process was not given up");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // The first spawn and three restarts
+        let spawns = std::fs::read_to_string(&spawns_path).unwrap();
+        assert_eq!(spawns.lines().count(), 4);
+    }
+
+    #[test]
+    fn test_max_lifetime_kills_process() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let current = CurrentProcess::new(0, None);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let mut exits = vec![];
+
+        let elapsed = runtime.block_on(async {
+            let mut child = Command::new("sleep")
+                .arg("10")
+                .stdin(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let spawned = SpawnedProcess {
+                name: "sleep".to_string(),
+                pid: child.id().unwrap(),
+                stdin: child.stdin.take(),
+                child,
+                pid_file_path: None,
+                output_tasks: vec![],
+            };
+            let restart = Restart {
+                policy: RestartPolicy::new(3, Duration::from_millis(1)),
+                stdin_contents: None,
+                keep_stdin_open: false,
+            };
+            let start = Instant::now();
+            supervise_process(
+                spawned,
+                kill_rx,
+                // Processes that were killed due to their max lifetime are not restarted
+                Some(restart),
+                Some(Duration::from_millis(100)),
+                &current,
+                || panic!("process was restarted"),
+                || {},
+                |exit| {
+                    exits.push(exit);
+                    async {}
+                },
+            )
+            .await;
+            start.elapsed()
+        });
+
+        assert_eq!(exits, [ProcessExit::Timeout]);
+        assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+        let lua = Lua::new();
+        let table = ProcessExit::Timeout.to_lua_table(&lua).unwrap();
+        assert_eq!(table.get::<_, String>("reason").unwrap(), "timeout");
+    }
+
+    #[test]
+    fn test_exit_is_reported_after_output() {
+        const LINES: usize = 200;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let lua = Lua::new();
+        let callback = lua.create_function(|_, ()| Ok(())).unwrap();
+        let listeners = Arc::new(OutputListeners::default());
+        listeners.add(Arc::new(lua.create_registry_value(callback).unwrap()));
+        let on_exit = lua.create_function(|_, ()| Ok(())).unwrap();
+        let callback_key = Arc::new(lua.create_registry_value(on_exit).unwrap());
+        let plugin_instance = Arc::new(PluginInstance::new("test".to_string(), Default::default()));
+        let current = CurrentProcess::new(0, None);
+        let (_kill_tx, kill_rx) = oneshot::channel();
+        let (event_tx, mut event_rx) = mpsc::channel(8);
+        let event_tx = Arc::new(event_tx);
+
+        let events = runtime.block_on(async {
+            let spawner = ProcessSpawner {
+                cmd: "seq".to_string(),
+                args: vec![LINES.to_string()],
+                envs: HashMap::new(),
+                merge_stderr: false,
+                priority: ProcessPriority::default(),
+                output_listeners: listeners,
+                recent_output: None,
+                event_sender: event_tx.clone(),
+                plugin_instance: plugin_instance.clone(),
+                pid_dir_path: std::env::temp_dir(),
+            };
+            let spawned = spawner.spawn().unwrap();
+            drop(spawner);
+            let supervisor = tokio::spawn(async move {
+                supervise_process(
+                    spawned,
+                    kill_rx,
+                    None,
+                    None,
+                    &current,
+                    || panic!("process was restarted"),
+                    || {},
+                    |exit| {
+                        let event = Event::ProcessExit {
+                            process_name: "seq".to_string(),
+                            plugin_instance: plugin_instance.clone(),
+                            callback_key: callback_key.clone(),
+                            exit,
+                        };
+                        let event_tx = event_tx.clone();
+                        async move {
+                            let _ = event_tx.send(event).await;
+                        }
+                    },
+                )
+                .await;
+            });
+
+            let mut events = vec![];
+            while let Some(event) = event_rx.recv().await {
+                events.push(match event {
+                    Event::ProcessOutput { line, .. } => line,
+                    Event::ProcessExit { exit, .. } => format!("{:?}", exit),
+                    other => panic!("unexpected {} event", other.kind()),
+                });
+            }
+            supervisor.await.unwrap();
+            events
+        });
+
+        assert_eq!(events.len(), LINES + 1);
+        for (i, line) in events[..LINES].iter().enumerate() {
+            assert_eq!(*line, (i + 1).to_string());
+        }
+        assert_eq!(
+            events[LINES],
+            format!("{:?}", ProcessExit::Exited { code: Some(0) })
+        );
+    }
+
+    #[test]
+    fn test_process_priority() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        // The highest nice value can always be set without privileges
+        ProcessPriority {
+            nice: Some(MAX_NICE),
+            ionice: Some(IoniceClass::Idle),
+        }
+        .apply_to(&mut command);
+        let mut child = runtime.block_on(async { command.spawn().unwrap() });
+        let pid = child.id().unwrap();
+
+        let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, pid) };
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        let ioprio = unsafe { libc::syscall(libc::SYS_ioprio_get, IOPRIO_WHO_PROCESS, pid) };
+        runtime.block_on(async { child.kill().await.unwrap() });
+
+        assert_eq!(nice, MAX_NICE);
+        assert_eq!(ioprio as libc::c_int, IoniceClass::Idle.ioprio());
+        assert_eq!(
+            IoniceClass::from_str("best_effort"),
+            Some(IoniceClass::BestEffort)
+        );
+        assert_eq!(IoniceClass::from_str("realtime"), None);
+    }
+
+    #[test]
+    fn test_max_processes_per_plugin() {
+        let system = TestPluginSystem::new("max-processes");
+        system.exec(
+            r#"
+            neopult.config.max_processes_per_plugin = 2
+            plugin_instance = neopult.api.register_plugin_instance("vnc")
+            "#,
+        );
+        let lua = system.lua();
+        let plugin_instance: AnyUserData = lua.globals().get("plugin_instance").unwrap();
+        let plugin_instance = plugin_instance.borrow::<PluginInstanceHandle>().unwrap();
+        let spawn = |script: &str| {
+            let opts: Value = lua
+                .load(&format!(r#"{{ args = {{ "-c", "{}" }} }}"#, script))
+                .eval()
+                .unwrap();
+            plugin_instance
+                .spawn_process(lua, ("sh".to_string(), opts))
+                .unwrap()
+                .0
+        };
+        let running_processes = || {
+            plugin_instance
+                .plugin_instance
+                .running_processes
+                .load(Ordering::SeqCst)
+        };
+        let wait_for_running_processes = |expected: usize| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while running_processes() != expected {
+                assert!(
+                    Instant::now() < deadline,
+                    "{} processes",
+                    running_processes()
+                );
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        let first = spawn("sleep 5");
+        let second = spawn("sleep 5");
+        assert!(!matches!(first, Value::Nil));
+        assert!(!matches!(second, Value::Nil));
+        assert!(matches!(spawn("exit 0"), Value::Nil));
+        assert_eq!(running_processes(), 2);
+        // Plugins can tell the limit apart from other failures
+        system.exec(r#"rejected, rejection = plugin_instance:spawn_process("sh", { args = { "-c", "exit 0" } })"#);
+        assert!(matches!(system.eval::<Value>("rejected"), Value::Nil));
+        assert_eq!(
+            system.eval::<String>("rejection"),
+            "max_processes_per_plugin (2) processes are running"
+        );
+
+        // The slot of an exited process is free again
+        lua.globals().set("first", first).unwrap();
+        lua.load("first:kill()").exec().unwrap();
+        wait_for_running_processes(1);
+        assert!(!matches!(spawn("exit 0"), Value::Nil));
+        wait_for_running_processes(1);
+
+        lua.globals().set("second", second).unwrap();
+        lua.load("second:kill()").exec().unwrap();
+        wait_for_running_processes(0);
+    }
+
+    #[test]
+    fn test_spawn_allowed_commands() {
+        let system = TestPluginSystem::new("spawn-allowed-commands");
+        system.exec(
+            r#"
+            neopult.config.allowed_commands = { "sh" }
+            plugin_instance = neopult.api.register_plugin_instance("vnc")
+            allowed, allowed_rejection = plugin_instance:spawn_process("sh", { args = { "-c", "exit 0" } })
+            rejected, rejection = plugin_instance:spawn_process("ls")
+            with_env, env_rejection = plugin_instance:spawn_process("sh", { envs = { PATH = "/tmp" } })
+            "#,
+        );
+        assert!(matches!(
+            system.eval::<Value>("allowed"),
+            Value::UserData(_)
+        ));
+        assert_eq!(system.eval::<Option<String>>("allowed_rejection"), None);
+        assert!(matches!(system.eval::<Value>("rejected"), Value::Nil));
+        assert_eq!(
+            system.eval::<String>("rejection"),
+            "ls is not in allowed_commands"
+        );
+        assert!(matches!(system.eval::<Value>("with_env"), Value::Nil));
+        assert_eq!(
+            system.eval::<String>("env_rejection"),
+            "envs must not set PATH when allowed_commands is set"
+        );
+    }
+
+    #[test]
+    fn test_throttle_calls_callback_once_per_interval() {
+        let mut system = TestPluginSystem::new("throttle");
+        system.exec(
+            r#"
+            calls = 0
+            throttled = neopult.api.throttle(100, function(arg)
+                calls = calls + 1
+                last_arg = arg
+            end)
+            for i = 1, 10 do
+                throttled(i)
+            end
+            -- Arguments of calls that are dropped are released right away
+            local function call_with_marker()
+                local marker = newproxy(true)
+                getmetatable(marker).__gc = function() dropped_collected = true end
+                throttled(marker)
+            end
+            call_with_marker()
+            throttled(11)
+            "#,
+        );
+        system.exec("collectgarbage(); collectgarbage()");
+        assert_eq!(system.eval::<u32>("calls"), 1);
+        assert_eq!(system.eval::<u32>("last_arg"), 1);
+        assert!(system.eval::<bool>("dropped_collected"));
+
+        // The calls within the interval end up in one trailing call with the latest arguments
+        let event = system
+            .next_event(Duration::from_secs(5))
+            .expect("no trailing call");
+        system.handle_event(event);
+        assert_eq!(system.eval::<u32>("calls"), 2);
+        assert_eq!(system.eval::<u32>("last_arg"), 11);
+        assert!(system.next_event(Duration::from_millis(300)).is_none());
+        assert_eq!(system.eval::<u32>("calls"), 2);
+    }
+
+    #[test]
+    fn test_spawn_limits_default() {
+        let lua = Lua::new();
+        lua.load("neopult = { config = {} }").exec().unwrap();
+        assert_eq!(
+            config::get_spawn_limits(&lua)
+                .unwrap()
+                .max_processes_per_plugin,
+            config::DEFAULT_MAX_PROCESSES_PER_PLUGIN
+        );
+    }
+
+    #[test]
+    fn test_check_requirements() {
+        let lua = Lua::new();
+        let spawn_limits = config::SpawnLimits {
+            allowed_commands: Some(vec!["ffmpeg".to_string()]),
+            ..Default::default()
+        };
+        let opts = |requires: &str| -> Value {
+            lua.load(&format!("{{ requires = {} }}", requires))
+                .eval()
+                .unwrap()
+        };
+
+        assert!(check_requirements(&spawn_limits, &Value::Nil).is_ok());
+        assert!(check_requirements(&spawn_limits, &opts("nil")).is_ok());
+        assert!(check_requirements(&spawn_limits, &opts(r#"{ "spawn:ffmpeg" }"#)).is_ok());
+
+        let err = check_requirements(&spawn_limits, &opts(r#"{ "spawn:ffmpeg", "spawn:vlc" }"#))
+            .unwrap_err();
+        assert!(err.contains("spawn:vlc"), "{}", err);
+        assert!(!err.contains("spawn:ffmpeg"), "{}", err);
+        let err = check_requirements(&spawn_limits, &opts(r#"{ "input_injection" }"#)).unwrap_err();
+        assert!(err.contains("input_injection"), "{}", err);
+        assert!(check_requirements(&spawn_limits, &opts(r#""spawn:ffmpeg""#)).is_err());
+
+        let unrestricted = config::SpawnLimits::default();
+        assert!(check_requirements(&unrestricted, &opts(r#"{ "spawn:vlc" }"#)).is_ok());
+    }
+
+    #[test]
+    fn test_registration_checks_requirements() {
+        let lua = Lua::new();
+        let spawn_limits = config::SpawnLimits {
+            allowed_commands: Some(vec!["ffmpeg".to_string()]),
+            ..Default::default()
+        };
+        let opts = |requires: &str| -> Value {
+            lua.load(&format!("{{ requires = {} }}", requires))
+                .eval()
+                .unwrap()
+        };
+        let mut plugin_instances = Vec::new();
+
+        let refused = add_plugin_instance(
+            &lua,
+            &spawn_limits,
+            &mut plugin_instances,
+            "vlc".to_string(),
+            opts(r#"{ "spawn:vlc" }"#),
+        )
+        .unwrap();
+        assert!(refused.is_none());
+        assert!(plugin_instances.is_empty());
+
+        let plugin_instance = add_plugin_instance(
+            &lua,
+            &spawn_limits,
+            &mut plugin_instances,
+            "camera".to_string(),
+            opts(r#"{ "spawn:ffmpeg" }"#),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(plugin_instances.len(), 1);
+
+        let refused = add_module(
+            &lua,
+            &spawn_limits,
+            &plugin_instance,
+            "player".to_string(),
+            opts(r#"{ "spawn:vlc" }"#),
+        )
+        .unwrap();
+        assert!(refused.is_none());
+        let module = add_module(
+            &lua,
+            &spawn_limits,
+            &plugin_instance,
+            "recorder".to_string(),
+            opts(r#"{ "spawn:ffmpeg" }"#),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(module.name, "recorder");
+        let modules = plugin_instance.modules.read().unwrap();
+        assert_eq!(modules.len(), 1);
+        assert_eq!(modules[0].name, "recorder");
+    }
+
+    #[test]
+    fn test_get_spawn_limits() {
+        let lua = Lua::new();
+        lua.load("neopult = { config = {} }").exec().unwrap();
+        assert_eq!(
+            config::get_spawn_limits(&lua).unwrap(),
+            config::SpawnLimits::default()
+        );
+
+        lua.load(r#"neopult.config.allowed_commands = { "ffmpeg" }"#)
+            .exec()
+            .unwrap();
+        lua.load("neopult.config.max_processes_per_plugin = 2")
+            .exec()
+            .unwrap();
+        assert_eq!(
+            config::get_spawn_limits(&lua).unwrap(),
+            config::SpawnLimits {
+                allowed_commands: Some(vec!["ffmpeg".to_string()]),
+                max_processes_per_plugin: 2,
+            }
+        );
+
+        // An invalid list must not lift the restriction
+        lua.load("neopult.config.allowed_commands = 42")
+            .exec()
+            .unwrap();
+        assert_eq!(
+            config::get_spawn_limits(&lua).unwrap().allowed_commands,
+            Some(Vec::new())
+        );
+    }
+
+    #[test]
+    fn test_with_lock_runs_serially() {
+        let lua = Lua::new();
+        let held_locks = Arc::new(Mutex::new(HashSet::new()));
+        let deferred = Arc::new(Mutex::new(VecDeque::new()));
+        let defer: DeferFn = {
+            let deferred = deferred.clone();
+            Arc::new(move |task_key| deferred.lock().unwrap().push_back(task_key))
+        };
+        let with_lock = {
+            let held_locks = held_locks.clone();
+            let defer = defer.clone();
+            lua.create_function(move |lua, (name, callback): (String, Function)| {
+                run_with_lock(lua, &held_locks, &defer, name, callback)
+            })
+            .unwrap()
+        };
+        lua.globals().set("with_lock", with_lock).unwrap();
+
+        let ran_immediately: (bool, bool) = lua
+            .load(
+                r#"
+                log = {}
+                local nested_ran
+                local ran = with_lock("scene", function()
+                    table.insert(log, "first start")
+                    nested_ran = with_lock("scene", function()
+                        table.insert(log, "second")
+                    end)
+                    table.insert(log, "first end")
+                end)
+                return ran, nested_ran
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(ran_immediately, (true, false));
+        assert!(held_locks.lock().unwrap().is_empty());
+
+        // Like the event loop runs `run_later` tasks
+        let task_key = deferred.lock().unwrap().pop_front().unwrap();
+        let retry = lua.registry_value::<Function>(&task_key).unwrap();
+        assert!(retry.call::<_, bool>(()).unwrap());
+        assert!(deferred.lock().unwrap().is_empty());
+
+        let log: Vec<String> = lua.globals().get("log").unwrap();
+        assert_eq!(log, vec!["first start", "first end", "second"]);
+    }
+
+    #[test]
+    fn test_cli_command() {
+        let lua = Lua::new();
+        let cli_commands = Mutex::new(HashMap::new());
+        let callback: Function = lua
+            .load(r#"function(...) return "args: " .. table.concat({ ... }, ",") end"#)
+            .eval()
+            .unwrap();
+
+        assert!(add_cli_command(&lua, &cli_commands, "obs".to_string(), callback.clone()).unwrap());
+        assert!(
+            !add_cli_command(&lua, &cli_commands, "actions".to_string(), callback.clone()).unwrap()
+        );
+        assert!(!add_cli_command(&lua, &cli_commands, "two words".to_string(), callback).unwrap());
+
+        assert_eq!(
+            call_cli_command(&lua, &cli_commands, "obs scenes  main").as_deref(),
+            Some("args: scenes,main")
+        );
+        assert_eq!(
+            call_cli_command(&lua, &cli_commands, "obs").as_deref(),
+            Some("args: ")
+        );
+        assert_eq!(call_cli_command(&lua, &cli_commands, "unknown"), None);
+        assert_eq!(call_cli_command(&lua, &cli_commands, ""), None);
+    }
+
+    #[test]
+    fn test_now_iso8601() {
+        let before = Utc::now().timestamp();
+        let now = now_iso8601();
+        let after = Utc::now().timestamp();
+
+        assert_eq!(now.len(), "2022-05-14T18:03:09Z".len());
+        assert!(now.ends_with('Z'));
+        let parsed = chrono::DateTime::parse_from_rfc3339(&now)
+            .unwrap()
+            .timestamp();
+        assert!(before <= parsed && parsed <= after);
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(
+            format_timestamp(1652551389, "%Y-%m-%dT%H:%M:%SZ", true).as_deref(),
+            Some("2022-05-14T18:03:09Z")
+        );
+        assert_eq!(
+            format_timestamp(1652551389, "recording_%Y%m%d_%H%M%S.mkv", true).as_deref(),
+            Some("recording_20220514_180309.mkv")
+        );
+        let local = format_timestamp(1652551389, "%+", false).unwrap();
+        assert_eq!(
+            chrono::DateTime::parse_from_rfc3339(&local)
+                .unwrap()
+                .timestamp(),
+            1652551389
+        );
+        assert_eq!(format_timestamp(1652551389, "%Q", true), None);
+        assert_eq!(format_timestamp(i64::MAX, "%Y", true), None);
+    }
+
+    #[test]
+    fn test_is_dev() {
+        let system = TestPluginSystem::new("is-dev");
+        let is_dev: Value = system.eval("neopult.api.is_dev()");
+        assert_eq!(is_dev, Value::Boolean(cfg!(debug_assertions)));
+    }
+
+    #[test]
+    fn test_hash() {
+        let lua = Lua::new();
+        let hash_fn = lua
+            .create_function(|_lua, (algorithm, data)| hash(algorithm, data))
+            .unwrap();
+
+        let digest: String = hash_fn.call(("sha256", "")).unwrap();
+        assert_eq!(
+            digest,
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        let digest: String = hash_fn.call(("sha256", "neopult")).unwrap();
+        assert_eq!(
+            digest,
+            "6ed8405ad25161cc7cd62338772b186d1efce2bbda0286d5a54136a73231bd76"
+        );
+        // Lua strings may contain bytes that aren't valid UTF-8
+        let binary = lua.create_string(&[0x00, 0xff]).unwrap();
+        let digest: String = hash_fn.call(("sha256", binary)).unwrap();
+        assert_eq!(
+            digest,
+            "06eb7d6a69ee19e5fbdf749018d3d2abfa04bcbd1365db312eb86dc7169389b8"
+        );
+        let digest: String = hash_fn.call(("sha256", "abc")).unwrap();
+        assert_eq!(
+            digest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let digest: String = hash_fn.call(("sha512", "abc")).unwrap();
+        assert_eq!(
+            digest,
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+            2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        );
+
+        let digest: Option<String> = hash_fn.call(("md4", "abc")).unwrap();
+        assert!(digest.is_none());
+    }
+
+    #[test]
+    fn test_call_nested_action() {
+        let lua = Lua::new();
+        let plugin_instance =
+            Arc::new(PluginInstance::new("scene".to_string(), Default::default()));
+        let module = Arc::new(Module::new("main".to_string(), "scene".to_string(), None));
+        plugin_instance
+            .modules
+            .write()
+            .unwrap()
+            .push(module.clone());
+        let plugin_instances = vec![plugin_instance];
+        let action_callers = Arc::new(Mutex::new(Vec::new()));
+
+        let call = lua
+            .create_function({
+                let plugin_instances = plugin_instances.clone();
+                let action_callers = action_callers.clone();
+                move |lua, action: String| {
+                    let identifier = ActionIdentifier {
+                        plugin_instance: "scene".to_string(),
+                        module: "main".to_string(),
+                        action,
+                    };
+                    let result = call_nested_action(
+                        lua,
+                        &plugin_instances,
+                        &action_callers,
+                        identifier,
+                        Value::Nil,
+                    );
+                    Ok(result.err().map(|e| format!("{:#}", e)))
+                }
+            })
+            .unwrap();
+        lua.globals().set("call", call).unwrap();
+
+        let actions: Table = lua
+            .load(
+                r#"{
+                    { name = "inner", callback = function() inner_ran = true end },
+                    {
+                        name = "whoami",
+                        callback = function(_, caller) role, source = caller.role, caller.source end,
+                    },
+                    { name = "delegate", callback = function() call("whoami") end },
+                    { name = "outer", callback = function() outer_ran = true; call("inner") end },
+                    {
+                        name = "recurse",
+                        callback = function()
+                            recursions = (recursions or 0) + 1
+                            local err = call("recurse")
+                            if err then recursion_error = recursion_error or err end
+                        end,
+                    },
+                }"#,
+            )
+            .eval()
+            .unwrap();
+        add_actions(&lua, &module, actions).unwrap();
+        let call: Function = lua.globals().get("call").unwrap();
+
+        assert_eq!(call.call::<_, Option<String>>("outer").unwrap(), None);
+        assert!(lua.globals().get::<_, bool>("outer_ran").unwrap());
+        assert!(lua.globals().get::<_, bool>("inner_ran").unwrap());
+
+        assert_eq!(call.call::<_, Option<String>>("recurse").unwrap(), None);
+        assert_eq!(
+            lua.globals().get::<_, usize>("recursions").unwrap(),
+            MAX_ACTION_CALL_DEPTH
+        );
+        let recursion_error: String = lua.globals().get("recursion_error").unwrap();
+        assert!(recursion_error.contains("maximum action call depth"));
+
+        // Plugins calling actions on their own get the admin role
+        assert_eq!(call.call::<_, Option<String>>("whoami").unwrap(), None);
+        assert_eq!(lua.globals().get::<_, String>("role").unwrap(), "admin");
+        assert_eq!(lua.globals().get::<_, String>("source").unwrap(), "plugin");
+
+        // Actions called on behalf of a viewer keep the viewer role
+        let viewer = Caller {
+            role: Role::Viewer,
+            source: CallerSource::Websocket,
+            token_id: None,
+        };
+        call_tracked_action(
+            &lua,
+            &plugin_instances,
+            &action_callers,
+            ActionIdentifier {
+                plugin_instance: "scene".to_string(),
+                module: "main".to_string(),
+                action: "delegate".to_string(),
+            },
+            Value::Nil,
+            viewer,
+        )
+        .unwrap();
+        assert_eq!(lua.globals().get::<_, String>("role").unwrap(), "viewer");
+        assert_eq!(lua.globals().get::<_, String>("source").unwrap(), "plugin");
+        assert!(action_callers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_set_min_geometry() {
+        let system = TestPluginSystem::new("set-min-geometry");
+        system.fake_backend(|backend| backend.add_top_level_window(20, "vnc"));
+        system.exec(
+            r#"
+            plugin_instance = neopult.api.register_plugin_instance("vnc")
+            window = plugin_instance:claim_window("vnc", { timeout_ms = 1000 })
+            "#,
+        );
+        system.fake_backend(|backend| backend.take_requests());
+
+        // The window is in min mode after claiming, so it moves right away
+        assert!(system.eval::<bool>(r#"window:set_min_geometry("320x180-10-20")"#));
+        let moved_to = Geometry {
+            x: 950,
+            y: 520,
+            width: 320,
+            height: 180,
+        };
+        system.fake_backend(|backend| {
+            assert!(backend
+                .take_requests()
+                .contains(&FakeRequest::Configure(20, moved_to)));
+        });
+
+        // Hidden windows keep the new geometry until they are put to min mode again
+        system.exec("window:hide()");
+        system.fake_backend(|backend| backend.take_requests());
+        assert!(system.eval::<bool>(
+            "window:set_min_geometry({ width = 640, height = 360, x_offset = 0, y_offset = 0, alignment = 'lt' })"
+        ));
+        system.fake_backend(|backend| {
+            assert!(!backend
+                .take_requests()
+                .iter()
+                .any(|request| matches!(request, FakeRequest::Configure(20, _))));
+        });
+        system.exec("window:min()");
+        let shown_at = Geometry {
+            x: 0,
+            y: 0,
+            width: 640,
+            height: 360,
+        };
+        system.fake_backend(|backend| {
+            assert!(backend
+                .take_requests()
+                .contains(&FakeRequest::Configure(20, shown_at)));
+        });
+    }
+
+    #[test]
+    fn test_create_access_token() {
+        let system = TestPluginSystem::new("create-access-token");
+        system.exec("token, token_id = neopult.api.create_access_token(60000)");
+        let token: String = system.eval("token");
+        let token_id: u64 = system.eval("token_id");
+        assert_eq!(system.ctx().access_tokens.validate(&token), Some(token_id));
+
+        // Raising the error through Lua is up to mlua, so the errors are checked on the function
+        let create = |ttl_ms| match create_access_token(system.lua(), ttl_ms, system.ctx().clone())
+        {
+            Err(mlua::Error::RuntimeError(message)) => message,
+            other => panic!("expected a runtime error, got {:?}", other),
+        };
+        assert_eq!(create(0), "the ttl of an access token must be positive");
+        assert_eq!(
+            create(u64::MAX),
+            format!(
+                "the ttl of an access token must be at most 86400000ms, got {}ms",
+                u64::MAX
+            )
+        );
+    }
+
+    #[test]
+    fn test_assert_window() {
+        let system = TestPluginSystem::new("assert-window");
+        system.fake_backend(|backend| {
+            backend.add_top_level_window(20, "vnc");
+            backend.add_top_level_window(21, "firefox");
+            backend.set_foreign_managed_hint(21);
+        });
+        system.exec(
+            r#"
+            screen = neopult.api.register_plugin_instance("screen")
+            vnc = neopult.api.register_plugin_instance("vnc")
+            window = screen:assert_window("vnc", { timeout_ms = 1000 })
+            "#,
+        );
+        assert!(system.eval::<bool>("window ~= nil"));
+
+        // Raising the error through Lua is up to mlua, so the errors are checked on the method
+        let assert_window = |class: &str, opts: &str| {
+            let lua = system.lua();
+            let vnc: AnyUserData = lua.globals().get("vnc").unwrap();
+            let vnc = vnc.borrow::<PluginInstanceHandle>().unwrap();
+            let opts: Value = lua.load(opts).eval().unwrap();
+            match vnc.assert_window(lua, (class.to_string(), opts)) {
+                Err(mlua::Error::RuntimeError(message)) => message,
+                other => panic!("expected a runtime error, got {:?}", other),
+            }
+        };
+        assert_eq!(
+            assert_window("vlc", "{ timeout_ms = 10 }"),
+            "couldn't claim window with class vlc: no window within 10 ms"
+        );
+        assert_eq!(
+            assert_window("vnc", "nil"),
+            "couldn't claim window with class vnc: already managed by plugin instance screen"
+        );
+        assert_eq!(
+            assert_window("firefox", "nil"),
+            "couldn't claim window with class firefox: already managed by another neopult instance"
+        );
+    }
+
+    #[test]
+    fn test_env_config_table() {
+        let lua = Lua::new();
+        let env_config = EnvConfig {
+            channel: 3,
+            neopult_home: PathBuf::from("/home/neopult"),
+            channel_home: PathBuf::from("/home/neopult/channel-3"),
+            pid_dir_base: PathBuf::from("/tmp"),
+            data_dir: PathBuf::from("/usr/share/neopult"),
+            notification_capacity: 64,
+        };
+
+        let table = env_config_table(&lua, &env_config).unwrap();
+        assert_eq!(table.get::<_, u8>("channel").unwrap(), 3);
+        assert_eq!(
+            table.get::<_, String>("neopult_home").unwrap(),
+            "/home/neopult"
+        );
+        assert_eq!(
+            table.get::<_, String>("channel_home").unwrap(),
+            "/home/neopult/channel-3"
+        );
+    }
+
+    #[test]
+    fn test_claim_conflict() {
+        let system = TestPluginSystem::new("claim-conflict");
+        system.fake_backend(|backend| {
+            backend.add_top_level_window(20, "vnc");
+            backend.add_top_level_window(30, "firefox");
+            backend.set_foreign_managed_hint(30);
+        });
+        system.exec(
+            r#"
+            screen = neopult.api.register_plugin_instance("screen")
+            camera = neopult.api.register_plugin_instance("camera")
+            screen_window = screen:claim_window("vnc", { timeout_ms = 1000 })
+            "#,
+        );
+        system.fake_backend(|backend| backend.take_requests());
+
+        // A second claim of the same window reports the owner without waiting for the timeout
+        let started = Instant::now();
+        system.exec(r#"window, err = camera:claim_window("vnc", { timeout_ms = 5000 })"#);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(system.eval::<bool>("window == nil"));
+        assert_eq!(system.eval::<String>("err.reason"), "already_managed");
+        assert_eq!(system.eval::<String>("err.owner"), "screen");
+
+        // ... and succeeds when stealing is allowed, which releases the window from its owner
+        system.exec(r#"window, err = camera:claim_window("vnc", { allow_steal = true })"#);
+        assert!(system.eval::<bool>("window ~= nil and err == nil"));
+        assert!(system
+            .fake_backend(|backend| backend.take_requests())
+            .contains(&FakeRequest::SetManagedHint(20)));
+        system.exec(r#"window, err = screen:claim_window("vnc")"#);
+        assert_eq!(system.eval::<String>("err.owner"), "camera");
+
+        // Windows of other neopult instances can't be released, so they aren't stolen
+        system.exec(r#"window, err = camera:claim_window("firefox", { allow_steal = true })"#);
+        assert!(system.eval::<bool>("window == nil"));
+        assert_eq!(system.eval::<String>("err.reason"), "already_managed");
+        assert!(system.eval::<bool>("err.owner == nil"));
+        assert!(system
+            .fake_backend(|backend| backend.take_requests())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_claim_window() {
+        let system = TestPluginSystem::new("claim-window");
+        system.fake_backend(|backend| backend.add_top_level_window(20, "vnc"));
+        system.exec(
+            r#"
+            plugin_instance = neopult.api.register_plugin_instance("vnc")
+            window, err = plugin_instance:claim_window("vnc", { timeout_ms = 1000 })
+            "#,
+        );
+
+        assert!(system.eval::<bool>("window ~= nil and err == nil"));
+        system.fake_backend(|backend| {
+            assert!(backend.is_managed(20));
+            assert!(backend
+                .take_requests()
+                .contains(&FakeRequest::SetManagedHint(20)));
+        });
+    }
+
+    #[test]
+    fn test_create_image_window() {
+        let mut system = TestPluginSystem::new("create-image-window");
+        std::fs::write(system.channel_home.join("slide.png"), b"png").unwrap();
+        let image_window_updates = |system: &mut TestPluginSystem| {
+            system
+                .take_notifications()
+                .into_iter()
+                .filter_map(|notification| match notification {
+                    Notification::ImageWindowUpdate(info) => Some(info),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let image_windows = |system: &TestPluginSystem| {
+            let plugin_instances = system.ctx().plugin_instances.read().unwrap();
+            serde_json::to_value(system_info(&plugin_instances)).unwrap()["plugin_instances"][0]
+                ["image_windows"]
+                .clone()
+        };
+
+        // The window manager shows new windows right away
+        system.exec(
+            r#"
+            plugin_instance = neopult.api.register_plugin_instance("slides")
+            window = neopult.api.create_image_window(plugin_instance, "slide", "slide.png")
+            "#,
+        );
+        let updates = image_window_updates(&mut system);
+        let shown = updates.last().expect("no image window update");
+        assert_eq!(shown.plugin_instance, "slides");
+        assert_eq!(shown.name, "slide");
+        assert_eq!(shown.image_path, "slide.png");
+        assert!(shown.visible);
+        let min_geometry = shown.geometry.clone().unwrap();
+        assert_eq!(image_windows(&system)[0]["visible"], true);
+
+        system.exec("window:max({ 640, 360 })");
+        let updates = image_window_updates(&mut system);
+        let maximized = updates.last().expect("no image window update");
+        assert!(maximized.visible);
+        assert_ne!(maximized.geometry, Some(min_geometry));
+        assert_eq!(
+            serde_json::to_value(&maximized.geometry).unwrap(),
+            image_windows(&system)[0]["geometry"]
+        );
+
+        // Unclaimed windows are forgotten after a last notification hiding them
+        system.exec("window:unclaim()");
+        let updates = image_window_updates(&mut system);
+        assert!(!updates.last().expect("no image window update").visible);
+        assert!(image_windows(&system).as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_self_test_action() {
+        let lua = Lua::new();
+        let passing = Arc::new(PluginInstance::new("vnc".to_string(), Default::default()));
+        let failing = Arc::new(PluginInstance::new(
+            "camera".to_string(),
+            Default::default(),
+        ));
+        let self_test: Function = lua.load("function() return true end").eval().unwrap();
+        register_self_test(&lua, &passing, self_test).unwrap();
+        let self_test: Function = lua
+            .load(r#"function() return false, "camera server is not running" end"#)
+            .eval()
+            .unwrap();
+        register_self_test(&lua, &failing, self_test).unwrap();
+        let plugin_instances = [passing, failing];
+
+        let identifier = |plugin_instance: &str| ActionIdentifier {
+            plugin_instance: plugin_instance.to_string(),
+            module: META_MODULE_NAME.to_string(),
+            action: "self_test".to_string(),
+        };
+        assert!(call_action(
+            &lua,
+            &plugin_instances,
+            identifier("vnc"),
+            Value::Nil,
+            Caller::LOCAL
+        )
+        .is_ok());
+        let err = call_action(
+            &lua,
+            &plugin_instances,
+            identifier("camera"),
+            Value::Nil,
+            Caller::LOCAL,
+        )
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("self test failed: camera server is not running"));
+    }
+
+    #[test]
+    fn test_throttle_state() {
+        let interval = Duration::from_millis(100);
+        let mut state = ThrottleState::new(interval);
+        let start = Instant::now();
+
+        // Many calls within one interval only lead to one immediate and one trailing call
+        let decisions: Vec<_> = (0..20)
+            .map(|i| state.on_call(start + Duration::from_millis(i * 4)))
+            .collect();
+        assert_eq!(decisions[0], ThrottleDecision::CallNow);
+        assert_eq!(
+            decisions[1],
+            ThrottleDecision::ScheduleTrailing(Duration::from_millis(96))
+        );
+        assert!(decisions[2..].iter().all(|d| *d == ThrottleDecision::Defer));
+
+        state.on_trailing_call(start + interval);
+        assert_eq!(
+            state.on_call(start + Duration::from_millis(150)),
+            ThrottleDecision::ScheduleTrailing(Duration::from_millis(50))
+        );
+        state.on_trailing_call(start + Duration::from_millis(200));
+        assert_eq!(
+            state.on_call(start + Duration::from_millis(300)),
+            ThrottleDecision::CallNow
+        );
+    }
+
+    #[test]
+    fn test_throttle_trailing_call_uses_latest_args() {
+        let lua = Lua::new();
+        let callback: Function = lua
+            .load("function(...) received = { n = select('#', ...), ... } end")
+            .eval()
+            .unwrap();
+        let callback_key = Arc::new(lua.create_registry_value(callback).unwrap());
+        let state = Arc::new(Mutex::new(ThrottleState::new(Duration::from_millis(100))));
+        let trailing_call = create_trailing_call(&lua, callback_key, state.clone()).unwrap();
+
+        let args = mlua::ToLuaMulti::to_lua_multi(("line", Value::Nil, 3), &lua).unwrap();
+        let key = lua
+            .create_registry_value(lua.create_sequence_from(args.clone()).unwrap())
+            .unwrap();
+        state.lock().unwrap().pending_args = Some((key, args.len()));
+        trailing_call.call::<_, ()>(()).unwrap();
+
+        let received: Table = lua.globals().get("received").unwrap();
+        assert_eq!(received.get::<_, usize>("n").unwrap(), 3);
+        assert_eq!(received.get::<_, String>(1).unwrap(), "line");
+        assert_eq!(received.get::<_, Value>(2).unwrap(), Value::Nil);
+        assert_eq!(received.get::<_, u8>(3).unwrap(), 3);
+        assert!(state.lock().unwrap().pending_args.is_none());
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let lua = Lua::new();
+        let vars: Table = lua
+            .load(r#"{ CHANNEL = 3, HOST = "example.com" }"#)
+            .eval()
+            .unwrap();
+        let interpolate = |template: &str| interpolate(template.to_string(), vars.clone()).unwrap();
+
+        assert_eq!(
+            interpolate("https://{{HOST}}/channel/{{CHANNEL}}"),
+            "https://example.com/channel/3"
+        );
+        assert_eq!(interpolate("{{CHANNEL}}-{{CHANNEL}}"), "3-3");
+        assert_eq!(
+            interpolate("{{HOST}}:{{PORT}}/{{CHANNEL}}"),
+            "example.com:{{PORT}}/3"
+        );
+        assert_eq!(interpolate("no placeholders"), "no placeholders");
+        assert_eq!(interpolate("{{HOST}} {{unclosed"), "example.com {{unclosed");
+    }
+
+    #[test]
+    fn test_deep_merge() {
+        let lua = Lua::new();
+        lua.globals()
+            .set(
+                "deep_merge",
+                lua.create_function(|lua, (base, override_value)| {
+                    deep_merge(lua, base, override_value)
+                })
+                .unwrap(),
+            )
+            .unwrap();
+
+        let nested_maps: bool = lua
+            .load(
+                r#"
+                local base = { size = { width = 640, height = 480 }, title = "cam", tags = { "a", "b", "c" } }
+                local merged = deep_merge(base, { size = { width = 1280 }, tags = { "d" }, mode = "fill" })
+                return merged.size.width == 1280 and merged.size.height == 480
+                    and merged.title == "cam" and merged.mode == "fill"
+                    and #merged.tags == 1 and merged.tags[1] == "d"
+                    -- base is left untouched and no tables are shared
+                    and base.size.width == 640 and #base.tags == 3 and merged.size ~= base.size
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert!(nested_maps);
+
+        let map_replaces_array: bool = lua
+            .load(
+                r#"
+                local merged = deep_merge({ list = { 1, 2 }, value = { x = 1 } }, { list = { x = 1 }, value = 5 })
+                return merged.list[1] == nil and merged.list.x == 1 and merged.value == 5
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert!(map_replaces_array);
+
+        let nil_handling: bool = lua
+            .load(
+                r#"
+                local base = { a = { b = 1 } }
+                local from_nil_override = deep_merge(base, nil)
+                local from_nil_base = deep_merge(nil, base)
+                local empty = deep_merge(nil, nil)
+                return from_nil_override.a.b == 1 and from_nil_override.a ~= base.a
+                    and from_nil_base.a.b == 1 and next(empty) == nil
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert!(nil_handling);
+
+        let invalid: Option<Table> = lua.load(r#"deep_merge({}, "string")"#).eval().unwrap();
+        assert!(invalid.is_none());
+        let cyclic: Option<Table> = lua
+            .load("local t = {}; t.t = t; return deep_merge({}, t)")
+            .eval()
+            .unwrap();
+        assert!(cyclic.is_none());
+    }
+
+    #[test]
+    fn test_run_once() {
+        let lua = Lua::new();
+        let once_keys = Mutex::new(HashSet::new());
+        lua.load("calls = 0").exec().unwrap();
+        let callback: Function = lua.load("function() calls = calls + 1 end").eval().unwrap();
+
+        let ran = run_once(&once_keys, "setup".to_string(), callback.clone()).unwrap();
+        assert!(ran);
+        let ran = run_once(&once_keys, "setup".to_string(), callback.clone()).unwrap();
+        assert!(!ran);
+        assert_eq!(lua.globals().get::<_, u32>("calls").unwrap(), 1);
+
+        let ran = run_once(&once_keys, "other".to_string(), callback).unwrap();
+        assert!(ran);
+        assert_eq!(lua.globals().get::<_, u32>("calls").unwrap(), 2);
+    }
+
+    #[test]
+    fn test_add_actions_in_order() {
+        let lua = Lua::new();
+        let (plugin_instance, module) = test_support::vnc_viewer();
+        let actions: Table = lua
+            .load(
+                r#"{
+                    { name = "start", callback = function() end, display_name = "Start" },
+                    { name = "max", callback = function() end, tags = { "layout" } },
+                    { name = "broken" },
+                    { name = "hide", callback = function() end },
+                }"#,
+            )
+            .eval()
+            .unwrap();
+        add_actions(&lua, &module, actions).unwrap();
+        let callback = lua.create_function(|_, ()| Ok(())).unwrap();
+        add_action(&lua, &module, "stop".to_string(), callback, Value::Nil).unwrap();
+
+        let system_info = system_info(&[plugin_instance]);
+        let actions = &system_info.plugin_instances[0].modules[0].actions;
+        let names: Vec<&str> = actions.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["start", "max", "hide", "stop"]);
+        assert_eq!(actions[0].display_name.as_deref(), Some("Start"));
+    }
+
+    #[test]
+    fn test_replace_action() {
+        let lua = Lua::new();
+        let module = Module::new("viewer".to_string(), "vnc".to_string(), None);
+        // The proxy is only referenced by the old callback, so it is collected once the callback
+        // is removed from the registry
+        let old_callback: Function = lua
+            .load(
+                r#"
+                local marker = newproxy(true)
+                getmetatable(marker).__gc = function() old_collected = true end
+                return function() local _ = marker; return "old" end
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert!(add_action(&lua, &module, "start".to_string(), old_callback, Value::Nil).unwrap());
+        let callback = lua.load(r#"function() return "new" end"#).eval().unwrap();
+        assert!(!add_action(&lua, &module, "start".to_string(), callback, Value::Nil).unwrap());
+
+        let callback: Function = lua.load(r#"function() return "new" end"#).eval().unwrap();
+        let opts = lua
+            .load(r#"{ replace = true, display_name = "Start" }"#)
+            .eval()
+            .unwrap();
+        assert!(add_action(&lua, &module, "start".to_string(), callback, opts).unwrap());
+
+        let actions = module.actions.read().unwrap();
+        assert_eq!(actions.len(), 1);
+        assert_eq!(actions[0].display_name.as_deref(), Some("Start"));
+        let result: String = lua
+            .registry_value::<Function>(&actions[0].key)
+            .unwrap()
+            .call(())
+            .unwrap();
+        assert_eq!(result, "new");
+
+        lua.gc_collect().unwrap();
+        lua.gc_collect().unwrap();
+        assert!(lua.globals().get::<_, bool>("old_collected").unwrap());
+    }
+
+    #[test]
+    fn test_flash_restores_after_duration() {
+        let mut system = TestPluginSystem::new("flash");
+        system.fake_backend(|backend| backend.add_top_level_window(20, "vnc"));
+        system.exec(
+            r#"
+            plugin_instance = neopult.api.register_plugin_instance("vnc")
+            window = plugin_instance:claim_window("vnc", { timeout_ms = 1000 })
+            "#,
+        );
+        let border_requests = |system: &TestPluginSystem| {
+            system
+                .fake_backend(|backend| backend.take_requests())
+                .into_iter()
+                .filter(|request| matches!(request, FakeRequest::SetBorder(..)))
+                .collect::<Vec<_>>()
+        };
+        // Claiming the window sends requests as well
+        system.fake_backend(|backend| backend.take_requests());
+
+        let started = Instant::now();
+        assert!(system.eval::<bool>("window:flash({ duration_ms = 100, border_width = 4 })"));
+        assert_eq!(
+            border_requests(&system),
+            [FakeRequest::SetBorder(20, 4, 0xff0000)]
+        );
+        // Invalid options don't touch the border
+        assert!(!system.eval::<bool>("window:flash({ color = 'red' })"));
+        assert!(border_requests(&system).is_empty());
+
+        let event = system
+            .next_event(Duration::from_secs(5))
+            .expect("no timer event");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(matches!(event, Event::Timer { .. }));
+        assert!(border_requests(&system).is_empty());
+        system.handle_event(event);
+        assert_eq!(border_requests(&system), [FakeRequest::SetBorder(20, 2, 0)]);
+    }
+
+    #[test]
+    fn test_windows_lost_after_reconnect() {
+        let mut system = TestPluginSystem::new("windows-lost");
+        system.fake_backend(|backend| backend.add_top_level_window(20, "vnc"));
+        system.exec(
+            r#"
+            lost_wids = nil
+            plugin_instance = neopult.api.register_plugin_instance("vnc", {
+                on_windows_lost = function(wids)
+                    lost_wids = wids
+                end,
+            })
+            module = plugin_instance:register_module("viewer", {})
+            window = plugin_instance:claim_window("vnc", { timeout_ms = 1000 })
+            module:attach_window(window)
+            window:max({ 640, 360 })
+            "#,
+        );
+        let module = system.ctx().plugin_instances.read().unwrap()[0]
+            .modules
+            .read()
+            .unwrap()[0]
+            .clone();
+        assert!(module.attached_window.read().unwrap().is_some());
+        // Maxing the window changed the screen resolution
+        while system.next_event(Duration::from_millis(50)).is_some() {}
+
+        // The window doesn't exist on the new X server anymore, so the retried request fails
+        system.fake_backend(|backend| backend.lose_connection());
+        system.exec("window:min()");
+        assert!(module.attached_window.read().unwrap().is_none());
+        assert_eq!(system.eval::<usize>("#neopult.api.list_windows()"), 0);
+
+        let event = system
+            .next_event(Duration::from_secs(5))
+            .expect("no windows lost event");
+        assert!(matches!(event, Event::WindowsLost { .. }));
+        system.handle_event(event);
+        assert_eq!(system.eval::<Vec<usize>>("lost_wids"), [0]);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_at_fires_at_target_time() {
+        let lua = Lua::new();
+        let (event_tx, mut event_rx) = mpsc::channel(1);
+        let event_tx = Arc::new(event_tx);
+        let schedule_at = lua
+            .create_function(move |lua, (unix_timestamp, callback): (f64, Function)| {
+                schedule_callback_at(
+                    lua,
+                    unix_timestamp,
+                    callback,
+                    &tokio::runtime::Handle::current(),
+                    event_tx.clone(),
+                )
+            })
+            .unwrap();
+        lua.globals().set("schedule_at", schedule_at).unwrap();
+        let target = SystemTime::now() + Duration::from_millis(200);
+        let target_timestamp = target.duration_since(UNIX_EPOCH).unwrap().as_secs_f64();
+
+        // Invalid timestamps are reported as nil instead of panicking
+        let results = lua
+            .load(
+                r#"
+                local callback = function() end
+                return schedule_at(math.huge, callback),
+                    schedule_at(0 / 0, callback),
+                    schedule_at(2 ^ 1000, callback),
+                    schedule_at(0, callback)
+                "#,
+            )
+            .eval::<(Option<bool>, Option<bool>, Option<bool>, Option<bool>)>()
+            .unwrap();
+        assert_eq!(results, (None, None, None, Some(false)));
+
+        let scheduled = lua
+            .load("return schedule_at(..., function() fired = true end)")
+            .call::<_, Option<bool>>(target_timestamp)
+            .unwrap();
+        assert_eq!(scheduled, Some(true));
+
+        match event_rx.recv().await {
+            Some(Event::Timer { callback_key }) => {
+                let now = SystemTime::now();
+                assert!(now >= target - Duration::from_millis(5));
+                assert!(now < target + Duration::from_millis(500));
+                lua.registry_value::<Function>(&callback_key)
+                    .unwrap()
+                    .call::<_, ()>(())
+                    .unwrap();
+            }
+            event => panic!("expected timer event, got {:?}", event),
+        }
+        assert!(lua.globals().get::<_, bool>("fired").unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_next_line_lossy() {
+        let mut reader = BufReader::new(&b"caf\xe9 au lait\r\nplain\n\xff"[..]);
+        let mut buf = Vec::new();
+        assert_eq!(
+            next_line_lossy(&mut reader, &mut buf).await.unwrap(),
+            Some("caf\u{fffd} au lait".to_string())
+        );
+        assert_eq!(
+            next_line_lossy(&mut reader, &mut buf).await.unwrap(),
+            Some("plain".to_string())
+        );
+        assert_eq!(
+            next_line_lossy(&mut reader, &mut buf).await.unwrap(),
+            Some("\u{fffd}".to_string())
+        );
+        assert_eq!(next_line_lossy(&mut reader, &mut buf).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_parse_signal() {
+        let lua = Lua::new();
+        let name = |s: &str| Value::String(lua.create_string(s).unwrap());
+
+        assert_eq!(parse_signal(&name("SIGHUP")), Some(Signal::SIGHUP));
+        assert_eq!(parse_signal(&name("usr1")), Some(Signal::SIGUSR1));
+        assert_eq!(parse_signal(&name("SigCont")), Some(Signal::SIGCONT));
+        assert_eq!(parse_signal(&Value::Integer(15)), Some(Signal::SIGTERM));
+        assert_eq!(parse_signal(&Value::Number(19.0)), Some(Signal::SIGSTOP));
+        assert_eq!(parse_signal(&name("SIGNOPE")), None);
+        assert_eq!(parse_signal(&Value::Integer(1000)), None);
+        assert_eq!(parse_signal(&Value::Number(1.5)), None);
+        assert_eq!(parse_signal(&Value::Nil), None);
+    }
+
+    #[test]
+    fn test_remove_output_listener_releases_callback() {
+        let system = TestPluginSystem::new("remove-output-listener");
+        system.exec(
+            r#"
+            plugin_instance = neopult.api.register_plugin_instance("camera")
+            process = plugin_instance:spawn_process("sleep", { args = { "5" } })
+            collected = false
+            local proxy = newproxy(true)
+            getmetatable(proxy).__gc = function() collected = true end
+            listener_id = process:add_output_listener(function() return proxy end)
+            "#,
+        );
+        system.exec(r#"collectgarbage("collect")"#);
+        assert!(!system.eval::<bool>("collected"));
+
+        // Nothing but the registry refers to the callback
+        assert!(system.eval::<bool>("process:remove_output_listener(listener_id)"));
+        system.exec(r#"collectgarbage("collect")"#);
+        assert!(system.eval::<bool>("collected"));
+        system.exec("process:kill()");
+    }
+
+    #[test]
+    fn test_signal_reaches_process() {
+        let system = TestPluginSystem::new("signal");
+        let ready_path = system.channel_home.join("ready");
+        let got_usr1_path = system.channel_home.join("got-usr1");
+        let script = format!(
+            "trap 'touch {}; exit 0' USR1; touch {}; while true; do sleep 0.05; done",
+            got_usr1_path.display(),
+            ready_path.display()
+        );
+        system.lua().globals().set("script", script).unwrap();
+        system.exec(
+            r#"
+            plugin_instance = neopult.api.register_plugin_instance("signal")
+            process = plugin_instance:spawn_process("sh", { args = { "-c", script } })
+            "#,
+        );
+        let wait_until = |condition: &dyn Fn() -> bool| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while !condition() {
+                assert!(Instant::now() < deadline, "timed out");
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+        wait_until(&|| ready_path.exists());
+
+        // Raising the error through Lua is up to mlua, so the error is checked on the method
+        {
+            let process: AnyUserData = system.lua().globals().get("process").unwrap();
+            let process = process.borrow::<ProcessHandle>().unwrap();
+            let sig = Value::String(system.lua().create_string("NOPE").unwrap());
+            match process.signal(sig) {
+                Err(mlua::Error::RuntimeError(message)) => {
+                    assert!(message.starts_with("unknown signal NOPE,"), "{}", message);
+                    assert!(message.contains("SIGHUP, SIGINT"), "{}", message);
+                    assert!(message.contains("SIGUSR1"), "{}", message);
+                }
+                other => panic!("expected a runtime error, got {:?}", other),
+            }
+            assert!(process.signal(Value::Integer(1000)).is_err());
+        }
+
+        assert!(system.eval::<bool>(r#"process:signal("usr1")"#));
+        wait_until(&|| got_usr1_path.exists());
+
+        // The process is gone
+        wait_until(&|| !system.eval::<bool>(r#"process:signal("usr1")"#));
+    }
 }
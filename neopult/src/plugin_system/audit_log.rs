@@ -0,0 +1,158 @@
+use super::{ActionIdentifier, Caller};
+use log::error;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub(super) const DEFAULT_AUDIT_LOG_FILE_NAME: &str = "audit.log";
+pub(super) const DEFAULT_AUDIT_LOG_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Append-only record of the actions that were called by clients. When the log grows beyond
+/// `max_bytes`, it is moved to `<path>.1` (replacing the previous one) and a new log is started.
+#[derive(Debug)]
+pub(super) struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+impl AuditLog {
+    pub(super) fn new(path: PathBuf, max_bytes: u64) -> Self {
+        Self { path, max_bytes }
+    }
+
+    pub(super) fn record_call_action(
+        &self,
+        identifier: &ActionIdentifier,
+        caller: Caller,
+        result: &anyhow::Result<()>,
+    ) {
+        let outcome = match result {
+            Ok(_) => "ok".to_string(),
+            // Keeping the entry on one line makes the log easy to grep
+            Err(e) => format!("error: {:#}", e).replace('\n', " "),
+        };
+        // Tokens are logged by id, so that the calls of a shared token can be traced
+        let token_id = caller
+            .token_id
+            .map_or_else(|| "-".to_string(), |id| id.to_string());
+        let line = format!(
+            "{} call_action {} role={} source={} token_id={} {}\n",
+            unix_timestamp_ms(),
+            identifier,
+            caller.role.as_str(),
+            caller.source.as_str(),
+            token_id,
+            outcome
+        );
+        if let Err(e) = self.append(&line) {
+            error!("couldn't write to audit log {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn append(&self, line: &str) -> io::Result<()> {
+        self.rotate_if_full()?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())
+    }
+
+    fn rotate_if_full(&self) -> io::Result<()> {
+        match fs::metadata(&self.path) {
+            Ok(metadata) if metadata.len() >= self.max_bytes => {
+                fs::rename(&self.path, rotated_path(&self.path))
+            }
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn rotated_path(path: &Path) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(".1");
+    PathBuf::from(rotated)
+}
+
+fn unix_timestamp_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        plugin_system::{CallerSource, Role},
+        test_support::temp_dir,
+    };
+
+    fn identifier() -> ActionIdentifier {
+        ActionIdentifier {
+            plugin_instance: "vnc".to_string(),
+            module: "viewer".to_string(),
+            action: "max".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_record_call_action() {
+        let dir = temp_dir("audit-log");
+        let path = dir.join(DEFAULT_AUDIT_LOG_FILE_NAME);
+        let audit_log = AuditLog::new(path.clone(), DEFAULT_AUDIT_LOG_MAX_BYTES);
+
+        let token_caller = Caller {
+            role: Role::Admin,
+            source: CallerSource::Websocket,
+            token_id: Some(7),
+        };
+        audit_log.record_call_action(&identifier(), token_caller, &Ok(()));
+        audit_log.record_call_action(
+            &identifier(),
+            Caller::LOCAL,
+            &Err(anyhow::anyhow!("boom\nagain")),
+        );
+
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0]
+            .ends_with(" call_action vnc::viewer::max role=admin source=websocket token_id=7 ok"));
+        assert!(lines[1].ends_with(
+            " call_action vnc::viewer::max role=admin source=local token_id=- error: boom again"
+        ));
+        let timestamp = lines[0].split(' ').next().unwrap();
+        assert!(timestamp.parse::<u128>().is_ok());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = temp_dir("audit-log-rotation");
+        let path = dir.join(DEFAULT_AUDIT_LOG_FILE_NAME);
+        let audit_log = AuditLog::new(path.clone(), 10);
+
+        audit_log.record_call_action(&identifier(), Caller::LOCAL, &Ok(()));
+        audit_log.record_call_action(
+            &identifier(),
+            Caller::LOCAL,
+            &Err(anyhow::anyhow!("failed")),
+        );
+
+        let rotated = fs::read_to_string(rotated_path(&path)).unwrap();
+        assert!(rotated.ends_with(" ok\n"));
+        let current = fs::read_to_string(&path).unwrap();
+        assert_eq!(current.lines().count(), 1);
+        assert!(current.contains("error: failed"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use super::{Module, Notification};
+use std::sync::{Arc, Mutex};
+use tokio::{
+    sync::broadcast,
+    time::{self, Duration},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum UpdateKind {
+    Status,
+    Message,
+}
+
+/// Collapses status and message updates of the same module that are made within `window` into a
+/// single notification. The notification is sent at the end of the window and carries the state of
+/// the module at that time, so it always ends with the latest value.
+#[derive(Debug)]
+pub(super) struct NotificationCoalescer {
+    window: Duration,
+    notification_sender: Arc<broadcast::Sender<Notification>>,
+    pending: Mutex<Vec<(Arc<Module>, UpdateKind)>>,
+}
+
+impl NotificationCoalescer {
+    pub(super) fn new(
+        window: Duration,
+        notification_sender: Arc<broadcast::Sender<Notification>>,
+    ) -> Self {
+        Self {
+            window,
+            notification_sender,
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Has to be called after the state of the module was changed. The first update of a window
+    /// schedules the flush on `runtime_handle`.
+    pub(super) fn update(
+        self: &Arc<Self>,
+        module: &Arc<Module>,
+        kind: UpdateKind,
+        runtime_handle: &tokio::runtime::Handle,
+    ) {
+        let mut pending = self.pending.lock().unwrap();
+        let is_pending = pending
+            .iter()
+            .any(|(m, k)| Arc::ptr_eq(m, module) && *k == kind);
+        if is_pending {
+            return;
+        }
+        pending.push((module.clone(), kind));
+
+        if pending.len() == 1 {
+            let coalescer = self.clone();
+            runtime_handle.spawn(async move {
+                time::sleep(coalescer.window).await;
+                coalescer.flush();
+            });
+        }
+    }
+
+    fn flush(&self) {
+        let pending: Vec<_> = self.pending.lock().unwrap().drain(..).collect();
+        for (module, kind) in pending {
+            let notification = match kind {
+                UpdateKind::Status => Notification::ModuleStatusUpdate {
+                    module_identifier: module.identifier(),
+                    new_status: module.status.read().unwrap().clone(),
+                },
+                UpdateKind::Message => Notification::ModuleMessageUpdate {
+                    module_identifier: module.identifier(),
+                    new_message: module.message.read().unwrap().clone(),
+                },
+            };
+            let _ = self.notification_sender.send(notification);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_coalesce_status_updates() {
+        let (notification_tx, mut notification_rx) = broadcast::channel(256);
+        let coalescer = Arc::new(NotificationCoalescer::new(
+            Duration::from_millis(20),
+            Arc::new(notification_tx),
+        ));
+        let module = Arc::new(Module::new("stream".to_string(), "obs".to_string(), None));
+        let runtime_handle = tokio::runtime::Handle::current();
+
+        for i in 0..100 {
+            *module.status.write().unwrap() = Some(i.to_string());
+            coalescer.update(&module, UpdateKind::Status, &runtime_handle);
+        }
+        *module.message.write().unwrap() = Some("done".to_string());
+        coalescer.update(&module, UpdateKind::Message, &runtime_handle);
+
+        time::sleep(Duration::from_millis(60)).await;
+
+        let mut statuses = vec![];
+        let mut messages = vec![];
+        while let Ok(notification) = notification_rx.try_recv() {
+            match notification {
+                Notification::ModuleStatusUpdate { new_status, .. } => statuses.push(new_status),
+                Notification::ModuleMessageUpdate { new_message, .. } => messages.push(new_message),
+                other => panic!("unexpected notification {:?}", other),
+            }
+        }
+        assert!(!statuses.is_empty() && statuses.len() < 100);
+        assert_eq!(statuses.last().unwrap().as_deref(), Some("99"));
+        assert_eq!(messages, vec![Some("done".to_string())]);
+    }
+}
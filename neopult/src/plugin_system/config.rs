@@ -1,42 +1,353 @@
+use super::{audit_log::DEFAULT_AUDIT_LOG_MAX_BYTES, PluginSystemError};
+use crate::{
+    config::DEFAULT_WEBSOCKET_PASSWORD,
+    window_manager::{ModeFallback, Reanchor},
+};
 use log::{error, warn};
-use mlua::{Lua, Table, Value};
+use mlua::{FromLua, Lua, Table, Value};
+use serde::Deserialize;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 
+pub(super) const DEFAULT_SLOW_EVENT_THRESHOLD_MS: u64 = 1000;
+pub(super) const DEFAULT_MAX_MESSAGE_BYTES: u64 = 64 * 1024;
+pub(super) const DEFAULT_MAX_PROCESSES_PER_PLUGIN: u64 = 64;
+pub(super) const CONFIG_FILE_NAME: &str = "neopult.toml";
+
+#[derive(Debug)]
 pub(super) struct LuaConfig {
-    pub websocket_password: String,
+    pub websocket_passwords: Vec<String>,
+    pub viewer_websocket_passwords: Vec<String>,
+    pub idle_timeout_ms: Option<u64>,
+    pub idle_disconnect_ms: Option<u64>,
+    pub slow_event_threshold_ms: u64,
+    /// Relative paths are relative to the channel home
+    pub audit_log_path: Option<PathBuf>,
+    pub audit_log_max_bytes: u64,
+    pub cors_allowed_origins: Vec<String>,
+    pub max_message_bytes: u64,
+    pub notification_coalesce_ms: Option<u64>,
+    pub reanchor: Reanchor,
+    pub mode_fallback: ModeFallback,
 }
 
 impl Default for LuaConfig {
     fn default() -> Self {
         LuaConfig {
-            websocket_password: "admin".to_string(),
+            websocket_passwords: vec![DEFAULT_WEBSOCKET_PASSWORD.to_string()],
+            viewer_websocket_passwords: Vec::new(),
+            idle_timeout_ms: None,
+            idle_disconnect_ms: None,
+            slow_event_threshold_ms: DEFAULT_SLOW_EVENT_THRESHOLD_MS,
+            audit_log_path: None,
+            audit_log_max_bytes: DEFAULT_AUDIT_LOG_MAX_BYTES,
+            cors_allowed_origins: Vec::new(),
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            notification_coalesce_ms: None,
+            reanchor: Reanchor::default(),
+            mode_fallback: ModeFallback::default(),
+        }
+    }
+}
+
+/// Optional config file in the channel home, so that channels can be configured without editing
+/// lua. It has the same keys as `neopult.config`, which takes precedence over the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    websocket_password: Option<Passwords>,
+    viewer_websocket_password: Option<Passwords>,
+    idle_timeout_ms: Option<u64>,
+    idle_disconnect_ms: Option<u64>,
+    slow_event_threshold_ms: Option<u64>,
+    audit_log_path: Option<PathBuf>,
+    audit_log_max_bytes: Option<u64>,
+    cors_allowed_origins: Option<Vec<String>>,
+    max_message_bytes: Option<u64>,
+    notification_coalesce_ms: Option<u64>,
+    reanchor: Option<String>,
+    mode_fallback: Option<String>,
+    /// Only read by `get_data_dir`, because the lua search path is set before `init.lua` runs
+    data_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Passwords {
+    Single(String),
+    List(Vec<String>),
+}
+
+impl Passwords {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            Passwords::Single(password) => vec![password],
+            Passwords::List(passwords) => passwords,
+        }
+    }
+}
+
+impl FileConfig {
+    fn apply(self, lua_config: &mut LuaConfig) -> Result<(), PluginSystemError> {
+        if let Some(passwords) = self.websocket_password {
+            let passwords = passwords.into_vec();
+            if passwords.is_empty() {
+                return Err(file_config_error(
+                    "websocket_password must not be an empty list",
+                ));
+            }
+            lua_config.websocket_passwords = passwords;
+        }
+        if let Some(passwords) = self.viewer_websocket_password {
+            lua_config.viewer_websocket_passwords = passwords.into_vec();
+        }
+        if let Some(timeout_ms) = self.idle_timeout_ms {
+            lua_config.idle_timeout_ms = Some(timeout_ms);
+        }
+        if let Some(disconnect_ms) = self.idle_disconnect_ms {
+            lua_config.idle_disconnect_ms = Some(disconnect_ms);
+        }
+        if let Some(threshold_ms) = self.slow_event_threshold_ms {
+            lua_config.slow_event_threshold_ms = threshold_ms;
+        }
+        if let Some(path) = self.audit_log_path {
+            lua_config.audit_log_path = Some(path);
+        }
+        if let Some(max_bytes) = self.audit_log_max_bytes {
+            lua_config.audit_log_max_bytes = max_bytes;
+        }
+        if let Some(origins) = self.cors_allowed_origins {
+            lua_config.cors_allowed_origins = origins;
+        }
+        if let Some(max_bytes) = self.max_message_bytes {
+            lua_config.max_message_bytes = max_bytes;
+        }
+        if let Some(window_ms) = self.notification_coalesce_ms {
+            lua_config.notification_coalesce_ms = Some(window_ms);
+        }
+        if let Some(reanchor) = self.reanchor {
+            lua_config.reanchor = reanchor.parse().map_err(|_| {
+                file_config_error("reanchor has to be \"absolute\" or \"proportional\"")
+            })?;
+        }
+        if let Some(mode_fallback) = self.mode_fallback {
+            lua_config.mode_fallback = mode_fallback.parse().map_err(|_| {
+                file_config_error("mode_fallback has to be \"nearest\" or \"error\"")
+            })?;
         }
+        Ok(())
     }
 }
 
+fn file_config_error(msg: &str) -> PluginSystemError {
+    PluginSystemError::Config(format!("{} in {}", msg, CONFIG_FILE_NAME))
+}
+
+/// A missing file is fine. An invalid or unreadable one is an error, because falling back to the
+/// defaults could e.g. start the server with the default websocket password.
+fn read_file_config(channel_home: &Path) -> Result<FileConfig, PluginSystemError> {
+    let path = channel_home.join(CONFIG_FILE_NAME);
+    let contents = match fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(FileConfig::default()),
+        Err(e) => {
+            return Err(PluginSystemError::io("couldn't read neopult.toml")(e));
+        }
+    };
+    toml::from_str(&contents).map_err(|source| PluginSystemError::ConfigFile { path, source })
+}
+
+/// `data_dir` of the config file, relative to the channel home. `None` if the file doesn't set it.
+pub fn get_data_dir(channel_home: &Path) -> Result<Option<PathBuf>, PluginSystemError> {
+    Ok(read_file_config(channel_home)?
+        .data_dir
+        .map(|data_dir| channel_home.join(data_dir)))
+}
+
 pub(super) fn inject_config_table(lua: &Lua, neopult: &Table) -> mlua::Result<()> {
     let config_table = lua.create_table()?;
     neopult.set("config", config_table)
 }
 
-pub(super) fn get_config(lua: &Lua) -> mlua::Result<LuaConfig> {
-    let mut lua_config = LuaConfig::default();
+/// Config that restricts which processes plugins can spawn. Plugins can write to `neopult.config`,
+/// so it is read only once, see `LuaContext::spawn_limits`.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct SpawnLimits {
+    /// `None` means that every command is allowed
+    pub allowed_commands: Option<Vec<String>>,
+    pub max_processes_per_plugin: u64,
+}
+
+impl Default for SpawnLimits {
+    fn default() -> Self {
+        SpawnLimits {
+            allowed_commands: None,
+            max_processes_per_plugin: DEFAULT_MAX_PROCESSES_PER_PLUGIN,
+        }
+    }
+}
 
+/// Reads `allowed_commands` and `max_processes_per_plugin`. An invalid `allowed_commands` allows
+/// no command at all, so that a typo doesn't lift the restriction.
+pub(super) fn get_spawn_limits(lua: &Lua) -> mlua::Result<SpawnLimits> {
     let config_table = lua
         .globals()
         .get::<_, Table>("neopult")?
         .get::<_, Table>("config")?;
+    let mut spawn_limits = SpawnLimits::default();
+
+    match config_table.get::<_, Option<Vec<String>>>("allowed_commands") {
+        Ok(allowed_commands) => spawn_limits.allowed_commands = allowed_commands,
+        Err(_) => {
+            error!("allowed_commands has to be a list of strings, no command is allowed");
+            spawn_limits.allowed_commands = Some(Vec::new());
+        }
+    }
+    match config_table.get::<_, Option<u64>>("max_processes_per_plugin") {
+        Ok(Some(max_processes)) => spawn_limits.max_processes_per_plugin = max_processes,
+        Ok(None) => {}
+        Err(_) => error!("max_processes_per_plugin has to be a non-negative integer"),
+    }
+
+    Ok(spawn_limits)
+}
+
+/// Values of the config file in the channel home are overridden by `neopult.config`.
+pub(super) fn get_config(lua: &Lua, channel_home: &Path) -> Result<LuaConfig, PluginSystemError> {
+    let mut lua_config = LuaConfig::default();
+    read_file_config(channel_home)?.apply(&mut lua_config)?;
+
+    let config_table = lua
+        .globals()
+        .get::<_, Table>("neopult")
+        .and_then(|neopult| neopult.get::<_, Table>("config"))
+        .map_err(PluginSystemError::lua("error when reading neopult.config"))?;
 
     for pair in config_table.pairs::<String, Value>() {
         match pair {
             Ok((key, value)) => match key.as_str() {
                 "websocket_password" => match value {
                     Value::String(password) => {
-                        lua_config.websocket_password = password.to_string_lossy().to_string();
+                        lua_config.websocket_passwords =
+                            vec![password.to_string_lossy().to_string()];
                     }
+                    Value::Table(_) => match Vec::<String>::from_lua(value, lua) {
+                        Ok(passwords) if !passwords.is_empty() => {
+                            lua_config.websocket_passwords = passwords;
+                        }
+                        Ok(_) => {
+                            error!("websocket_password must not be an empty list");
+                        }
+                        Err(_) => {
+                            error!("websocket_password has to be a list of strings");
+                        }
+                    },
                     _ => {
-                        error!("websocket_password has to be a string string");
+                        error!("websocket_password has to be a string or a list of strings");
+                    }
+                },
+                "viewer_websocket_password" => match value {
+                    Value::String(password) => {
+                        lua_config.viewer_websocket_passwords =
+                            vec![password.to_string_lossy().to_string()];
+                    }
+                    Value::Table(_) => match Vec::<String>::from_lua(value, lua) {
+                        Ok(passwords) => {
+                            lua_config.viewer_websocket_passwords = passwords;
+                        }
+                        Err(_) => {
+                            error!("viewer_websocket_password has to be a list of strings");
+                        }
+                    },
+                    _ => {
+                        error!("viewer_websocket_password has to be a string or a list of strings");
+                    }
+                },
+                "idle_timeout_ms" => match u64::from_lua(value, lua) {
+                    Ok(timeout_ms) => {
+                        lua_config.idle_timeout_ms = Some(timeout_ms);
+                    }
+                    Err(_) => {
+                        error!("idle_timeout_ms has to be a non-negative integer");
+                    }
+                },
+                "idle_disconnect_ms" => match u64::from_lua(value, lua) {
+                    Ok(disconnect_ms) => {
+                        lua_config.idle_disconnect_ms = Some(disconnect_ms);
+                    }
+                    Err(_) => {
+                        error!("idle_disconnect_ms has to be a non-negative integer");
+                    }
+                },
+                "slow_event_threshold_ms" => match u64::from_lua(value, lua) {
+                    Ok(threshold_ms) => {
+                        lua_config.slow_event_threshold_ms = threshold_ms;
+                    }
+                    Err(_) => {
+                        error!("slow_event_threshold_ms has to be a non-negative integer");
+                    }
+                },
+                "audit_log_path" => match value {
+                    Value::String(path) => {
+                        lua_config.audit_log_path =
+                            Some(PathBuf::from(path.to_string_lossy().to_string()));
+                    }
+                    _ => {
+                        error!("audit_log_path has to be a string");
+                    }
+                },
+                "audit_log_max_bytes" => match u64::from_lua(value, lua) {
+                    Ok(max_bytes) => {
+                        lua_config.audit_log_max_bytes = max_bytes;
+                    }
+                    Err(_) => {
+                        error!("audit_log_max_bytes has to be a non-negative integer");
+                    }
+                },
+                "cors_allowed_origins" => match Vec::<String>::from_lua(value, lua) {
+                    Ok(origins) => {
+                        lua_config.cors_allowed_origins = origins;
+                    }
+                    Err(_) => {
+                        error!("cors_allowed_origins has to be a list of strings");
                     }
                 },
+                "max_message_bytes" => match u64::from_lua(value, lua) {
+                    Ok(max_bytes) => {
+                        lua_config.max_message_bytes = max_bytes;
+                    }
+                    Err(_) => {
+                        error!("max_message_bytes has to be a non-negative integer");
+                    }
+                },
+                "notification_coalesce_ms" => match u64::from_lua(value, lua) {
+                    Ok(window_ms) => {
+                        lua_config.notification_coalesce_ms = Some(window_ms);
+                    }
+                    Err(_) => {
+                        error!("notification_coalesce_ms has to be a non-negative integer");
+                    }
+                },
+                "reanchor" => match String::from_lua(value, lua).map(|s| s.parse()) {
+                    Ok(Ok(reanchor)) => {
+                        lua_config.reanchor = reanchor;
+                    }
+                    _ => {
+                        error!("reanchor has to be \"absolute\" or \"proportional\"");
+                    }
+                },
+                "mode_fallback" => match String::from_lua(value, lua).map(|s| s.parse()) {
+                    Ok(Ok(mode_fallback)) => {
+                        lua_config.mode_fallback = mode_fallback;
+                    }
+                    _ => {
+                        error!("mode_fallback has to be \"nearest\" or \"error\"");
+                    }
+                },
+                // Read by `get_spawn_limits`
+                "allowed_commands" | "max_processes_per_plugin" => {}
                 _ => {
                     warn!("unknown config key: {}", key);
                 }
@@ -49,3 +360,72 @@ pub(super) fn get_config(lua: &Lua) -> mlua::Result<LuaConfig> {
 
     Ok(lua_config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_support::temp_dir, window_manager::Reanchor};
+
+    #[test]
+    fn test_config_file() {
+        let channel_home = temp_dir("config-file");
+        fs::write(
+            channel_home.join(CONFIG_FILE_NAME),
+            r#"
+                websocket_password = "from-file"
+                viewer_websocket_password = ["viewer-1", "viewer-2"]
+                idle_timeout_ms = 5000
+                idle_disconnect_ms = 600000
+                max_message_bytes = 1024
+                reanchor = "proportional"
+            "#,
+        )
+        .unwrap();
+
+        let lua = Lua::new();
+        let neopult = lua.create_table().unwrap();
+        inject_config_table(&lua, &neopult).unwrap();
+        lua.globals().set("neopult", neopult).unwrap();
+        lua.load("neopult.config.max_message_bytes = 2048")
+            .exec()
+            .unwrap();
+
+        let lua_config = get_config(&lua, &channel_home).unwrap();
+        assert_eq!(lua_config.websocket_passwords, vec!["from-file"]);
+        assert_eq!(
+            lua_config.viewer_websocket_passwords,
+            vec!["viewer-1", "viewer-2"]
+        );
+        assert_eq!(lua_config.idle_timeout_ms, Some(5000));
+        assert_eq!(lua_config.idle_disconnect_ms, Some(600000));
+        assert_eq!(lua_config.reanchor, Reanchor::Proportional);
+        // Lua takes precedence
+        assert_eq!(lua_config.max_message_bytes, 2048);
+
+        // Invalid files must not fall back to the defaults, e.g. the default password
+        fs::write(
+            channel_home.join(CONFIG_FILE_NAME),
+            "websocket_password = \"from-file\"\nunknown_key = 1\n",
+        )
+        .unwrap();
+        let e = get_config(&lua, &channel_home).unwrap_err();
+        assert!(matches!(e, PluginSystemError::ConfigFile { .. }), "{:?}", e);
+        assert!(e.to_string().contains("unknown_key"), "{}", e);
+
+        fs::write(
+            channel_home.join(CONFIG_FILE_NAME),
+            "reanchor = \"sideways\"\n",
+        )
+        .unwrap();
+        let e = get_config(&lua, &channel_home).unwrap_err();
+        assert!(e.to_string().contains("reanchor"), "{}", e);
+
+        // Unreadable files are errors as well
+        fs::remove_file(channel_home.join(CONFIG_FILE_NAME)).unwrap();
+        fs::create_dir(channel_home.join(CONFIG_FILE_NAME)).unwrap();
+        let e = get_config(&lua, &channel_home).unwrap_err();
+        assert!(matches!(e, PluginSystemError::Io { .. }), "{:?}", e);
+
+        fs::remove_dir_all(&channel_home).unwrap();
+    }
+}
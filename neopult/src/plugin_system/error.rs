@@ -0,0 +1,94 @@
+use std::{error, fmt, io, path::PathBuf};
+
+pub type Result<T> = std::result::Result<T, PluginSystemError>;
+
+/// Errors of the plugin system itself. Errors inside of plugin calls are logged instead.
+#[derive(Debug)]
+pub enum PluginSystemError {
+    /// Lua raised an error, e.g. because `init.lua` has a syntax error or a plugin failed to load
+    Lua {
+        context: &'static str,
+        source: mlua::Error,
+    },
+    Io {
+        context: &'static str,
+        source: io::Error,
+    },
+    /// The channel is set up incorrectly, e.g. it has no `init.lua` or `neopult.config` contains
+    /// invalid values
+    Config(String),
+    /// `neopult.toml` can't be parsed or contains invalid values
+    ConfigFile {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+}
+
+impl PluginSystemError {
+    pub(super) fn lua(context: &'static str) -> impl FnOnce(mlua::Error) -> Self {
+        move |source| PluginSystemError::Lua { context, source }
+    }
+
+    pub(super) fn io(context: &'static str) -> impl FnOnce(io::Error) -> Self {
+        move |source| PluginSystemError::Io { context, source }
+    }
+}
+
+impl fmt::Display for PluginSystemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginSystemError::Lua { context, source } => write!(f, "{}: {}", context, source),
+            PluginSystemError::Io { context, source } => write!(f, "{}: {}", context, source),
+            PluginSystemError::Config(msg) => write!(f, "invalid config: {}", msg),
+            PluginSystemError::ConfigFile { path, source } => {
+                write!(f, "invalid config file {}: {}", path.display(), source)
+            }
+        }
+    }
+}
+
+impl error::Error for PluginSystemError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            PluginSystemError::Lua { source, .. } => Some(source),
+            PluginSystemError::Io { source, .. } => Some(source),
+            PluginSystemError::Config(_) => None,
+            PluginSystemError::ConfigFile { source, .. } => Some(source),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_plugin_system_error_display() {
+        let e = PluginSystemError::lua("error when loading plugins")(mlua::Error::RuntimeError(
+            "boom".to_string(),
+        ));
+        assert_eq!(
+            e.to_string(),
+            "error when loading plugins: runtime error: boom"
+        );
+        assert!(e.source().is_some());
+
+        let e = PluginSystemError::io("couldn't build runtime")(io::Error::other("no threads"));
+        assert_eq!(e.to_string(), "couldn't build runtime: no threads");
+        assert!(e.source().is_some());
+
+        let e = PluginSystemError::Config("no init.lua found".to_string());
+        assert_eq!(e.to_string(), "invalid config: no init.lua found");
+        assert!(e.source().is_none());
+
+        let e = PluginSystemError::ConfigFile {
+            path: PathBuf::from("/home/neopult/channel-1/neopult.toml"),
+            source: toml::from_str::<toml::Value>("key = ").unwrap_err(),
+        };
+        assert!(e
+            .to_string()
+            .starts_with("invalid config file /home/neopult/channel-1/neopult.toml: "));
+        assert!(e.source().is_some());
+    }
+}
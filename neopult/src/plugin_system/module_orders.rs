@@ -0,0 +1,76 @@
+use super::{ModuleIdentifier, ModuleOrder};
+use log::{error, warn};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub(super) const MODULE_ORDERS_FILE_NAME: &str = "module_orders.json";
+
+/// Module orders that clients set via `set_module_order`, keyed by module identifier. They are
+/// saved in the state dir of the channel and applied again when the modules are registered after
+/// a restart.
+#[derive(Debug)]
+pub(super) struct ModuleOrders {
+    path: PathBuf,
+    orders: Mutex<HashMap<String, ModuleOrder>>,
+}
+
+impl ModuleOrders {
+    /// Starts without saved orders if the file doesn't exist or can't be read
+    pub(super) fn load(path: PathBuf) -> Self {
+        let orders = match read_orders(&path) {
+            Ok(orders) => orders,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => {
+                warn!("ignoring saved module orders in {}: {}", path.display(), e);
+                HashMap::new()
+            }
+        };
+        Self {
+            path,
+            orders: Mutex::new(orders),
+        }
+    }
+
+    pub(super) fn get(&self, module_identifier: &ModuleIdentifier) -> Option<ModuleOrder> {
+        self.orders
+            .lock()
+            .unwrap()
+            .get(&module_identifier.to_string())
+            .copied()
+    }
+
+    /// Remembers the orders and saves all orders to the file
+    pub(super) fn set(&self, orders: &[(ModuleIdentifier, ModuleOrder)]) {
+        let mut saved_orders = self.orders.lock().unwrap();
+        for (module_identifier, order) in orders {
+            saved_orders.insert(module_identifier.to_string(), *order);
+        }
+        if let Err(e) = write_orders(&self.path, &saved_orders) {
+            error!(
+                "couldn't save module orders to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn read_orders(path: &Path) -> io::Result<HashMap<String, ModuleOrder>> {
+    let json = fs::read_to_string(path)?;
+    serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_orders(path: &Path, orders: &HashMap<String, ModuleOrder>) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let json = serde_json::to_string_pretty(orders)?;
+    // Written next to the file and renamed, so that a crash never leaves a truncated file
+    let tmp_path = path.with_extension("json.tmp");
+    fs::write(&tmp_path, json)?;
+    fs::rename(tmp_path, path)
+}
@@ -0,0 +1,234 @@
+use anyhow::{bail, Context};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// How far `CronSchedule::next_after` looks ahead. Covers February 29 with some margin.
+const CRON_LOOKAHEAD_YEARS: i32 = 5;
+
+/// Returns how long it takes until the unix timestamp (in seconds) is reached or `None` if it is
+/// not in the future. Fails for timestamps that can't be represented as a delay.
+pub(super) fn delay_until(
+    unix_timestamp: f64,
+    now: SystemTime,
+) -> anyhow::Result<Option<Duration>> {
+    if !unix_timestamp.is_finite() {
+        bail!("timestamp {} isn't a finite number", unix_timestamp);
+    }
+    let now = match now.duration_since(UNIX_EPOCH) {
+        Ok(now) => now.as_secs_f64(),
+        Err(_) => return Ok(None),
+    };
+    let delay = unix_timestamp - now;
+    if delay <= 0.0 {
+        return Ok(None);
+    }
+    Duration::try_from_secs_f64(delay)
+        .map(Some)
+        .with_context(|| format!("timestamp {} is too far in the future", unix_timestamp))
+}
+
+/// Minimal cron expression with the fields minute, hour, day of month, month and day of week.
+/// Every field supports `*`, single values, ranges (`a-b`), steps (`*/n`, `a-b/n`, `a/n`) and
+/// lists of those (`1,15,30-40`). Like in cron, a day matches if either the day of month or the
+/// day of week matches when both are restricted.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    /// Sunday is 0
+    days_of_week: u64,
+    days_of_month_restricted: bool,
+    days_of_week_restricted: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            anyhow::bail!(
+                "cron expression must have 5 fields (minute hour day-of-month month day-of-week), \
+                got {}",
+                s
+            );
+        }
+        let days_of_week = parse_cron_field(fields[4], 0, 7).context("invalid day of week")?;
+        Ok(CronSchedule {
+            minutes: parse_cron_field(fields[0], 0, 59).context("invalid minute")?,
+            hours: parse_cron_field(fields[1], 0, 23).context("invalid hour")?,
+            days_of_month: parse_cron_field(fields[2], 1, 31).context("invalid day of month")?,
+            months: parse_cron_field(fields[3], 1, 12).context("invalid month")?,
+            // 7 is another way to write sunday
+            days_of_week: (days_of_week | (days_of_week >> 7)) & 0x7f,
+            days_of_month_restricted: fields[2] != "*",
+            days_of_week_restricted: fields[4] != "*",
+        })
+    }
+}
+
+/// Returns the matching values as bits
+fn parse_cron_field(field: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => anyhow::bail!("step of {} must be a positive integer", part),
+            },
+            None => (part, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse()?, end.parse()?)
+        } else {
+            let value = range.parse()?;
+            // `a/n` starts at `a` and runs to the end of the range
+            (value, if step > 1 { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            anyhow::bail!("{} is out of the range {}-{}", part, min, max);
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+fn has_bit(bits: u64, value: u32) -> bool {
+    bits & (1 << value) != 0
+}
+
+impl CronSchedule {
+    fn matches_day(&self, date: NaiveDate) -> bool {
+        let day_of_month = has_bit(self.days_of_month, date.day());
+        let day_of_week = has_bit(self.days_of_week, date.weekday().num_days_from_sunday());
+        if self.days_of_month_restricted && self.days_of_week_restricted {
+            day_of_month || day_of_week
+        } else {
+            day_of_month && day_of_week
+        }
+    }
+
+    /// First matching minute after `after` in local time. Minutes that don't exist because of a
+    /// DST change are skipped. Returns `None` if no minute matches, e.g. for February 30.
+    pub(super) fn next_after(&self, after: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut time: NaiveDateTime =
+            after.naive_local().with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let last_year = time.year() + CRON_LOOKAHEAD_YEARS;
+        while time.year() <= last_year {
+            let date = time.date();
+            if !has_bit(self.months, time.month()) {
+                let (year, month) = match time.month() {
+                    12 => (time.year() + 1, 1),
+                    month => (time.year(), month + 1),
+                };
+                time = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.matches_day(date) {
+                time = date.succ_opt()?.and_hms_opt(0, 0, 0)?;
+            } else if !has_bit(self.hours, time.hour()) {
+                time = date.and_hms_opt(time.hour(), 0, 0)? + chrono::Duration::hours(1);
+            } else if !has_bit(self.minutes, time.minute()) {
+                time += chrono::Duration::minutes(1);
+            } else {
+                match Local.from_local_datetime(&time).earliest() {
+                    Some(next) if next > after => return Some(next),
+                    _ => time += chrono::Duration::minutes(1),
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_delay_until() {
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        assert_eq!(
+            delay_until(1000.5, now).unwrap(),
+            Some(Duration::from_millis(500))
+        );
+        assert_eq!(delay_until(1000.0, now).unwrap(), None);
+        assert_eq!(delay_until(10.0, now).unwrap(), None);
+        assert!(delay_until(f64::INFINITY, now).is_err());
+        assert!(delay_until(f64::NAN, now).is_err());
+        assert!(delay_until(f64::MAX, now).is_err());
+    }
+
+    #[test]
+    fn test_parse_cron_schedule() {
+        let schedule: CronSchedule = "0,30 */6 * 1-3 7".parse().unwrap();
+        assert_eq!(schedule.minutes, 1 | 1 << 30);
+        assert_eq!(schedule.hours, 1 | 1 << 6 | 1 << 12 | 1 << 18);
+        assert_eq!(schedule.months, 0b1110);
+        assert_eq!(schedule.days_of_week, 1);
+        assert!(!schedule.days_of_month_restricted);
+        assert!(schedule.days_of_week_restricted);
+        assert_eq!(
+            parse_cron_field("10/20", 0, 59).unwrap(),
+            1 << 10 | 1 << 30 | 1 << 50
+        );
+
+        assert!("* * * *".parse::<CronSchedule>().is_err());
+        assert!("60 * * * *".parse::<CronSchedule>().is_err());
+        assert!("* * 0 * *".parse::<CronSchedule>().is_err());
+        assert!("*/0 * * * *".parse::<CronSchedule>().is_err());
+        assert!("5-1 * * * *".parse::<CronSchedule>().is_err());
+    }
+
+    #[test]
+    fn test_cron_next_after() {
+        let daily: CronSchedule = "0 15 * * *".parse().unwrap();
+        assert_eq!(
+            daily.next_after(local(2024, 3, 4, 14, 59)),
+            Some(local(2024, 3, 4, 15, 0))
+        );
+        // Strictly after, so a schedule doesn't fire twice in the same minute
+        assert_eq!(
+            daily.next_after(local(2024, 3, 4, 15, 0)),
+            Some(local(2024, 3, 5, 15, 0))
+        );
+
+        let new_year: CronSchedule = "30 0 1 1 *".parse().unwrap();
+        assert_eq!(
+            new_year.next_after(local(2024, 3, 4, 12, 0)),
+            Some(local(2025, 1, 1, 0, 30))
+        );
+
+        // 2024-03-04 is a monday, either the 10th or a friday matches
+        let dom_or_dow: CronSchedule = "0 9 10 * 5".parse().unwrap();
+        assert_eq!(
+            dom_or_dow.next_after(local(2024, 3, 4, 12, 0)),
+            Some(local(2024, 3, 8, 9, 0))
+        );
+        assert_eq!(
+            dom_or_dow.next_after(local(2024, 3, 8, 12, 0)),
+            Some(local(2024, 3, 10, 9, 0))
+        );
+
+        let leap_day: CronSchedule = "0 0 29 2 *".parse().unwrap();
+        assert_eq!(
+            leap_day.next_after(local(2024, 3, 1, 0, 0)),
+            Some(local(2028, 2, 29, 0, 0))
+        );
+        let never: CronSchedule = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(never.next_after(local(2024, 3, 1, 0, 0)), None);
+    }
+}
@@ -0,0 +1,164 @@
+use super::*;
+use crate::{test_support::temp_dir, window_manager::fake_backend::FakeBackend};
+use mlua::FromLua;
+use std::{fs, thread};
+
+/// The plugin instance `vnc` with its module `viewer`, which is all that most tests need
+pub fn vnc_viewer() -> (Arc<PluginInstance>, Arc<Module>) {
+    let plugin_instance = Arc::new(PluginInstance::new("vnc".to_string(), Default::default()));
+    let module = Arc::new(Module::new("viewer".to_string(), "vnc".to_string(), None));
+    plugin_instance
+        .modules
+        .write()
+        .unwrap()
+        .push(module.clone());
+    (plugin_instance, module)
+}
+
+/// Plugin system with a fake X backend, so that the plugin API can be tested like plugins use it
+pub struct TestPluginSystem {
+    pub plugin_system: PluginSystem,
+    pub notification_receiver: broadcast::Receiver<Notification>,
+    pub channel_home: PathBuf,
+    /// Runs the tasks of the plugin system, like the main runtime does
+    runtime: tokio::runtime::Runtime,
+    channel_home_cleanup: RemoveOnDrop,
+}
+
+/// Removes the channel home once the test is done with it
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Event loop of a `TestPluginSystem` that runs in its own thread
+pub struct RunningEventLoop {
+    pub event_sender: mpsc::Sender<Event>,
+    shutdown_sender: broadcast::Sender<()>,
+    thread: thread::JoinHandle<error::Result<()>>,
+    _runtime: tokio::runtime::Runtime,
+    _channel_home_cleanup: RemoveOnDrop,
+}
+
+impl RunningEventLoop {
+    /// Shuts the plugin system down like ctrl-c does and waits for the event loop to finish
+    pub fn stop(self) {
+        self.shutdown_sender.send(()).unwrap();
+        self.thread.join().unwrap().unwrap();
+    }
+}
+
+impl TestPluginSystem {
+    /// `name` has to be unique among the tests, because it names the channel home
+    pub fn new(name: &str) -> Self {
+        Self::with_channel_home(temp_dir(name))
+    }
+
+    /// Plugin system that starts with the files of an existing channel home, like after a restart
+    pub fn with_channel_home(channel_home: PathBuf) -> Self {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()
+            .unwrap();
+        let env_config = EnvConfig {
+            channel: 0,
+            neopult_home: channel_home.clone(),
+            channel_home: channel_home.clone(),
+            pid_dir_base: channel_home.clone(),
+            data_dir: channel_home.join("data"),
+            notification_capacity: 64,
+        };
+        let (event_tx, event_rx) = mpsc::channel(64);
+        let (notification_tx, notification_receiver) = broadcast::channel(64);
+        let shutdown_channels = ShutdownChannels {
+            shutdown_sender: broadcast::channel(1).0,
+            shutdown_wait_sender: mpsc::channel(1).0,
+        };
+        let plugin_system = PluginSystem::init(
+            runtime.handle().clone(),
+            env_config,
+            None,
+            shutdown_channels,
+            event_tx,
+            event_rx,
+            notification_tx,
+            WindowManager::init().unwrap(),
+        )
+        .unwrap();
+
+        TestPluginSystem {
+            plugin_system,
+            notification_receiver,
+            channel_home_cleanup: RemoveOnDrop(channel_home.clone()),
+            channel_home,
+            runtime,
+        }
+    }
+
+    pub fn lua(&self) -> &Lua {
+        &self.plugin_system.lua
+    }
+
+    pub fn ctx(&self) -> &Arc<LuaContext> {
+        &self.plugin_system.ctx
+    }
+
+    /// Runs lua code like `init.lua` would
+    pub fn exec(&self, chunk: &str) {
+        self.lua().load(chunk).exec().unwrap();
+    }
+
+    pub fn eval<'lua, T: FromLua<'lua>>(&'lua self, chunk: &str) -> T {
+        self.lua().load(chunk).eval().unwrap()
+    }
+
+    pub fn fake_backend<T>(&self, f: impl FnOnce(&FakeBackend) -> T) -> T {
+        f(self.ctx().read_window_manager().unwrap().fake_backend())
+    }
+
+    /// Notifications that were sent since the last call
+    pub fn take_notifications(&mut self) -> Vec<Notification> {
+        let mut notifications = Vec::new();
+        while let Ok(notification) = self.notification_receiver.try_recv() {
+            notifications.push(notification);
+        }
+        notifications
+    }
+
+    /// Runs the event loop like main does
+    pub fn spawn_event_loop(self) -> RunningEventLoop {
+        let event_sender = self.ctx().event_sender.as_ref().clone();
+        let shutdown_sender = self.ctx().shutdown_sender.clone();
+        let plugin_system = self.plugin_system;
+        RunningEventLoop {
+            event_sender,
+            shutdown_sender,
+            thread: thread::spawn(move || plugin_system.event_loop()),
+            _runtime: self.runtime,
+            _channel_home_cleanup: self.channel_home_cleanup,
+        }
+    }
+
+    /// Waits up to `timeout` for the next event that was sent to the event loop, e.g. by a timer
+    pub fn next_event(&mut self, timeout: Duration) -> Option<Event> {
+        let event_receiver = &mut self.plugin_system.event_receiver;
+        self.runtime
+            .block_on(async { tokio::time::timeout(timeout, event_receiver.recv()).await })
+            .ok()
+            .flatten()
+    }
+
+    /// Handles the event like the event loop does
+    pub fn handle_event(&self, event: Event) {
+        let audit_log = AuditLog::new(
+            self.channel_home
+                .join(audit_log::DEFAULT_AUDIT_LOG_FILE_NAME),
+            audit_log::DEFAULT_AUDIT_LOG_MAX_BYTES,
+        );
+        handle_event(self.lua(), self.ctx(), &audit_log, event);
+    }
+}
@@ -0,0 +1,194 @@
+use super::Event;
+use mlua::RegistryKey;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    sync::mpsc,
+    task::JoinHandle,
+    time::{self, Duration, Instant},
+};
+
+pub(super) type TimerId = u64;
+
+#[derive(Debug)]
+struct Timer {
+    callback_key: Arc<RegistryKey>,
+    /// Set for intervals
+    repeat: Option<Duration>,
+    next_fire: Instant,
+    task: JoinHandle<()>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct TimerInfo {
+    pub id: TimerId,
+    pub remaining: Duration,
+    pub repeat: bool,
+}
+
+/// Timeouts and intervals of one plugin instance. The tasks only send events, the callbacks are
+/// called from the event loop via `fire`, which skips timers that were cancelled in between.
+#[derive(Debug, Default)]
+pub(super) struct Timers {
+    next_id: Mutex<TimerId>,
+    timers: Mutex<HashMap<TimerId, Timer>>,
+}
+
+impl Timers {
+    /// Sends the event made by `make_event` after `delay` and, if `repeat` is set, every `repeat`
+    /// afterwards.
+    pub(super) fn schedule(
+        &self,
+        delay: Duration,
+        repeat: Option<Duration>,
+        callback_key: RegistryKey,
+        runtime_handle: &tokio::runtime::Handle,
+        event_sender: mpsc::Sender<Event>,
+        make_event: impl Fn(TimerId) -> Event + Send + 'static,
+    ) -> TimerId {
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        let next_fire = Instant::now() + delay;
+        // The lock is held while spawning, so that `fire` always finds the timer
+        let mut timers = self.timers.lock().unwrap();
+        let task = runtime_handle.spawn(async move {
+            let mut next = next_fire;
+            loop {
+                time::sleep_until(next).await;
+                if event_sender.send(make_event(id)).await.is_err() {
+                    break;
+                }
+                match repeat {
+                    Some(interval) => next += interval,
+                    None => break,
+                }
+            }
+        });
+        timers.insert(
+            id,
+            Timer {
+                callback_key: Arc::new(callback_key),
+                repeat,
+                next_fire,
+                task,
+            },
+        );
+        id
+    }
+
+    /// Returns the callback of the timer or `None` if it was cancelled. Timeouts are removed.
+    pub(super) fn fire(&self, id: TimerId) -> Option<Arc<RegistryKey>> {
+        let mut timers = self.timers.lock().unwrap();
+        let timer = timers.get_mut(&id)?;
+        match timer.repeat {
+            Some(interval) => {
+                timer.next_fire += interval;
+                Some(timer.callback_key.clone())
+            }
+            None => timers.remove(&id).map(|timer| timer.callback_key),
+        }
+    }
+
+    pub(super) fn cancel(&self, id: TimerId) -> bool {
+        match self.timers.lock().unwrap().remove(&id) {
+            Some(timer) => {
+                timer.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the number of cancelled timers.
+    pub(super) fn cancel_all(&self) -> usize {
+        let timers: Vec<_> = self.timers.lock().unwrap().drain().collect();
+        for (_, timer) in timers.iter() {
+            timer.task.abort();
+        }
+        timers.len()
+    }
+
+    /// Active timers ordered by id
+    pub(super) fn list(&self) -> Vec<TimerInfo> {
+        let now = Instant::now();
+        let mut infos: Vec<TimerInfo> = self
+            .timers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(&id, timer)| TimerInfo {
+                id,
+                remaining: timer.next_fire.saturating_duration_since(now),
+                repeat: timer.repeat.is_some(),
+            })
+            .collect();
+        infos.sort_by_key(|info| info.id);
+        infos
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mlua::Lua;
+
+    #[tokio::test]
+    async fn test_cancel_all_timers() {
+        let lua = Lua::new();
+        let timers = Timers::default();
+        let (event_tx, mut event_rx) = mpsc::channel(16);
+        let runtime_handle = tokio::runtime::Handle::current();
+        let callback_key = || {
+            lua.create_registry_value(lua.create_function(|_, ()| Ok(())).unwrap())
+                .unwrap()
+        };
+        let timer_event = |_| Event::Idle;
+
+        let timeout = timers.schedule(
+            Duration::from_millis(20),
+            None,
+            callback_key(),
+            &runtime_handle,
+            event_tx.clone(),
+            timer_event,
+        );
+        let interval = timers.schedule(
+            Duration::from_millis(20),
+            Some(Duration::from_millis(20)),
+            callback_key(),
+            &runtime_handle,
+            event_tx.clone(),
+            timer_event,
+        );
+        let late = timers.schedule(
+            Duration::from_secs(60),
+            None,
+            callback_key(),
+            &runtime_handle,
+            event_tx,
+            timer_event,
+        );
+
+        let list = timers.list();
+        assert_eq!(
+            list.iter().map(|t| (t.id, t.repeat)).collect::<Vec<_>>(),
+            vec![(timeout, false), (interval, true), (late, false)]
+        );
+        assert!(list[0].remaining <= Duration::from_millis(20));
+        assert!(list[2].remaining > Duration::from_secs(59));
+
+        assert_eq!(timers.cancel_all(), 3);
+        assert!(timers.list().is_empty());
+
+        time::sleep(Duration::from_millis(60)).await;
+        assert!(event_rx.try_recv().is_err());
+        // Events that were already queued when cancelling don't call the callbacks anymore
+        assert!(timers.fire(timeout).is_none());
+        assert!(timers.fire(interval).is_none());
+    }
+}
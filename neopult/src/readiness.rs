@@ -0,0 +1,180 @@
+use log::{debug, error};
+use std::{
+    fmt, fs, io,
+    net::SocketAddr,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::net::{self, UnixDatagram},
+    },
+    path::PathBuf,
+    process,
+    str::FromStr,
+};
+use tokio::sync::oneshot;
+
+const NOTIFY_SOCKET_ENV_KEY: &str = "NOTIFY_SOCKET";
+
+/// How neopult signals that the plugins are loaded and the server accepts connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadySignal {
+    /// Sends `READY=1` to the socket in `NOTIFY_SOCKET` like `sd_notify` for `Type=notify` units
+    Systemd,
+    /// Prints a `READY` line to stdout
+    Stdout,
+    /// Writes the PID to the file, which is removed on startup
+    File(PathBuf),
+}
+
+impl FromStr for ReadySignal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "systemd" => Ok(ReadySignal::Systemd),
+            "stdout" => Ok(ReadySignal::Stdout),
+            _ => match s.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => Ok(ReadySignal::File(PathBuf::from(path))),
+                _ => Err(format!(
+                    "{} is no ready signal, expected systemd, stdout or file:<PATH>",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for ReadySignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReadySignal::Systemd => write!(f, "systemd"),
+            ReadySignal::Stdout => write!(f, "stdout"),
+            ReadySignal::File(path) => write!(f, "file:{}", path.display()),
+        }
+    }
+}
+
+impl ReadySignal {
+    /// Removes a marker file of a previous run, so that it only exists once this run is ready.
+    pub fn reset(&self) {
+        if let ReadySignal::File(path) = self {
+            match fs::remove_file(path) {
+                Ok(_) => debug!("removed stale ready file {}", path.display()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => error!("couldn't remove ready file {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    fn emit(&self, channel: u8, addr: SocketAddr) -> io::Result<()> {
+        match self {
+            ReadySignal::Systemd => match std::env::var(NOTIFY_SOCKET_ENV_KEY) {
+                Ok(socket) => notify_systemd(&socket),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is not set", NOTIFY_SOCKET_ENV_KEY),
+                )),
+            },
+            ReadySignal::Stdout => {
+                println!("READY channel={} addr={}", channel, addr);
+                Ok(())
+            }
+            ReadySignal::File(path) => fs::write(path, format!("{}\n", process::id())),
+        }
+    }
+}
+
+/// Sockets starting with `@` are in the abstract namespace.
+fn notify_systemd(socket: &str) -> io::Result<()> {
+    let addr = match socket.strip_prefix('@') {
+        Some(name) => net::SocketAddr::from_abstract_name(name)?,
+        None => net::SocketAddr::from_pathname(socket)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(b"READY=1", &addr)?;
+    Ok(())
+}
+
+/// Waits until the server is bound and emits the ready signal. Must only be called after the
+/// plugin system is initialized. Returns whether the signal was emitted, which isn't the case if
+/// the server couldn't be bound.
+pub async fn signal_when_bound(
+    ready_signal: ReadySignal,
+    channel: u8,
+    server_bound_receiver: oneshot::Receiver<SocketAddr>,
+) -> bool {
+    let addr = match server_bound_receiver.await {
+        Ok(addr) => addr,
+        Err(_) => return false,
+    };
+    match ready_signal.emit(channel, addr) {
+        Ok(_) => {
+            debug!("signaled readiness via {}", ready_signal);
+            true
+        }
+        Err(e) => {
+            error!("couldn't signal readiness via {}: {}", ready_signal, e);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::temp_dir;
+
+    #[test]
+    fn test_parse_ready_signal() {
+        assert_eq!("systemd".parse(), Ok(ReadySignal::Systemd));
+        assert_eq!("stdout".parse(), Ok(ReadySignal::Stdout));
+        assert_eq!(
+            "file:/run/neopult/ready".parse(),
+            Ok(ReadySignal::File(PathBuf::from("/run/neopult/ready")))
+        );
+        assert!("file:".parse::<ReadySignal>().is_err());
+        assert!("syslog".parse::<ReadySignal>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ready_file_is_written_once_server_is_bound() {
+        let dir = temp_dir("ready-file");
+        let path = dir.join("ready");
+        fs::write(&path, "stale").unwrap();
+        let ready_signal = ReadySignal::File(path.clone());
+        ready_signal.reset();
+        assert!(!path.exists());
+
+        let (bound_tx, bound_rx) = oneshot::channel();
+        let signal = tokio::spawn(signal_when_bound(ready_signal.clone(), 3, bound_rx));
+        tokio::task::yield_now().await;
+        assert!(!path.exists());
+        bound_tx
+            .send(SocketAddr::from(([0, 0, 0, 0], 4203)))
+            .unwrap();
+        assert!(signal.await.unwrap());
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+        fs::remove_file(&path).unwrap();
+
+        // The server failed to bind
+        let (bound_tx, bound_rx) = oneshot::channel();
+        drop(bound_tx);
+        assert!(!signal_when_bound(ready_signal, 3, bound_rx).await);
+        assert!(!path.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_notify_systemd() {
+        let dir = temp_dir("notify-systemd");
+        let path = dir.join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify_systemd(path.to_str().unwrap()).unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
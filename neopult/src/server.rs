@@ -1,22 +1,41 @@
 use crate::{
+    access_tokens::AccessTokens,
     config::{Config, WEB_ROOT},
-    plugin_system::{ActionIdentifier, ClientCommand, Event, Notification, SystemInfo},
+    log_buffer::{LogBuffer, LogEntry},
+    plugin_system::{
+        ActionCatalogEntry, ActionIdentifier, Caller, CallerSource, ClientCommand, Event,
+        ModuleIdentifier, ModuleOrder, Notification, Role, SystemInfo,
+    },
 };
+use anyhow::Context;
 use axum::{
     extract::{
+        self,
         ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
         Extension,
     },
-    http::StatusCode,
-    response::IntoResponse,
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    response::{IntoResponse, Response},
     routing::{get, get_service},
-    Router,
+    Json, Router,
 };
 use futures::{sink::SinkExt, stream::StreamExt};
 use log::{debug, error, info, warn};
+use neopult_bind::bind_with_backoff;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{borrow::Cow, io, net::SocketAddr, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
 use tokio::{
     sync::{
         broadcast::{self, error::RecvError},
@@ -24,7 +43,11 @@ use tokio::{
     },
     time::{self, Duration, Instant},
 };
-use tower_http::{services::ServeDir, trace::TraceLayer};
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    services::ServeDir,
+    trace::TraceLayer,
+};
 
 // NOTE: Make sure to adjust the values in the client accordingly
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -32,20 +55,176 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
 const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
 
-const CLOSE_MSG_AUTH: Message = Message::Close(Some(CloseFrame {
-    code: 1,
-    reason: Cow::Borrowed("auth"),
-}));
-const CLOSE_MSG_AUTH_TIMEOUT: Message = Message::Close(Some(CloseFrame {
-    code: 2,
-    reason: Cow::Borrowed("auth_timeout"),
-}));
+// NOTE: Make sure to adjust the value in the client accordingly
+/// Websocket subprotocol of the current wire protocol. A future incompatible protocol gets a new
+/// version, so that old servers reject clients that speak it instead of misunderstanding them.
+const WEBSOCKET_SUBPROTOCOL: &str = "neopult.v1";
+
+/// Number of round-trip times that are kept for the metrics
+const RTT_SAMPLES: usize = 32;
+
+/// Files that the image route serves, by extension. Other files in the channel home, like
+/// neopult.toml, are never served.
+const IMAGE_CONTENT_TYPES: [(&str, &str); 7] = [
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("svg", "image/svg+xml"),
+    ("bmp", "image/bmp"),
+];
+
+// NOTE: Make sure to adjust the reasons in the client accordingly
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CloseReason {
+    Auth,
+    AuthTimeout,
+    Shutdown,
+    MessageTooLarge,
+    Idle,
+}
+
+/// Sent as JSON in the reason of close frames, so that clients know whether they should try to
+/// reconnect.
+#[derive(Debug, Deserialize, Serialize)]
+struct CloseReasonPayload {
+    code: CloseReason,
+    retryable: bool,
+}
+
+impl CloseReason {
+    fn frame_code(self) -> u16 {
+        match self {
+            // Application specific range, which clients accept unlike the codes below 1000
+            CloseReason::Idle => 4000,
+            CloseReason::Auth => 4001,
+            CloseReason::AuthTimeout => 4002,
+            CloseReason::Shutdown => 4003,
+            // Policy violation
+            CloseReason::MessageTooLarge => 1008,
+        }
+    }
+
+    fn is_retryable(self) -> bool {
+        match self {
+            CloseReason::Auth
+            | CloseReason::AuthTimeout
+            | CloseReason::Idle
+            | CloseReason::MessageTooLarge => false,
+            CloseReason::Shutdown => true,
+        }
+    }
+
+    fn close_message(self) -> Message {
+        let payload = CloseReasonPayload {
+            code: self,
+            retryable: self.is_retryable(),
+        };
+        let reason = serde_json::to_string(&payload).unwrap_or_default();
+        Message::Close(Some(CloseFrame {
+            code: self.frame_code(),
+            reason: Cow::Owned(reason),
+        }))
+    }
+}
 
 #[derive(Debug)]
 struct WebContext {
     notification_sender: broadcast::Sender<Notification>,
     event_sender: mpsc::Sender<Event>,
-    websocket_password_hash: Vec<u8>,
+    websocket_password_hashes: Vec<Vec<u8>>,
+    viewer_password_hashes: Vec<Vec<u8>>,
+    access_tokens: Arc<AccessTokens>,
+    max_message_bytes: usize,
+    /// Origins besides the own one that may open websocket connections
+    cors_allowed_origins: Vec<String>,
+    /// Images of image windows are served from here
+    channel_home: PathBuf,
+    /// Connections without requests for this long are closed
+    idle_disconnect: Option<Duration>,
+    shutdown_sender: broadcast::Sender<()>,
+    client_presence_sender: Option<mpsc::UnboundedSender<ClientPresence>>,
+    rtt_stats: RttStats,
+    /// Recent log entries, which admins can fetch
+    log_buffer: Arc<LogBuffer>,
+    /// Unset while plugins are loaded after the server was started, during which the plugin
+    /// system can't handle events
+    plugins_loaded: Arc<AtomicBool>,
+}
+
+/// Round-trip times of the most recent heartbeats of all clients
+#[derive(Debug, Default)]
+struct RttStats {
+    samples: Mutex<VecDeque<u64>>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct RttMetrics {
+    latest_ms: Option<u64>,
+    average_ms: Option<u64>,
+    samples: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Metrics {
+    rtt: RttMetrics,
+}
+
+impl RttStats {
+    fn record(&self, rtt_ms: u64) {
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == RTT_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(rtt_ms);
+    }
+
+    fn metrics(&self) -> RttMetrics {
+        let samples = self.samples.lock().unwrap();
+        let average_ms = if samples.is_empty() {
+            None
+        } else {
+            Some(samples.iter().sum::<u64>() / samples.len() as u64)
+        };
+        RttMetrics {
+            latest_ms: samples.back().copied(),
+            average_ms,
+            samples: samples.len(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ClientPresence {
+    Connected,
+    Disconnected,
+}
+
+/// Reports an authenticated admin to the idle watcher for as long as the guard lives.
+struct ClientPresenceGuard {
+    client_presence_sender: Option<mpsc::UnboundedSender<ClientPresence>>,
+}
+
+impl ClientPresenceGuard {
+    fn new(ctx: &WebContext) -> Self {
+        let client_presence_sender = ctx.client_presence_sender.clone();
+        if let Some(ref sender) = client_presence_sender {
+            let _ = sender.send(ClientPresence::Connected);
+        }
+        Self {
+            client_presence_sender,
+        }
+    }
+}
+
+impl Drop for ClientPresenceGuard {
+    fn drop(&mut self) {
+        if let Some(ref sender) = self.client_presence_sender {
+            let _ = sender.send(ClientPresence::Disconnected);
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -72,6 +251,14 @@ impl ServerResponse {
         Self::new(request_id, false, Some("Internal Server Error".to_string()))
     }
 
+    fn new_not_ready(request_id: String) -> Self {
+        Self::new(
+            request_id,
+            false,
+            Some("Not ready, plugins are still loading".to_string()),
+        )
+    }
+
     fn from_error(request_id: String, error: anyhow::Error) -> Self {
         Self::new(request_id, false, Some(format!("{:?}", error)))
     }
@@ -81,14 +268,40 @@ impl ServerResponse {
 #[serde(rename_all = "snake_case")]
 enum FromServerError {
     ParseError(String),
+    /// A message for the client couldn't be serialized and was dropped
+    SerializationError(String),
+    /// The role of the client doesn't allow the request
+    Forbidden(String),
+}
+
+/// Serializes a message for the client. If that fails, the message is replaced by an error, so
+/// that the connection stays usable.
+fn to_client_json<T: Serialize + std::fmt::Debug>(msg: &T) -> String {
+    match serde_json::to_string(msg) {
+        Ok(json) => json,
+        Err(e) => {
+            error!("could not serialize message {:?}: {}", msg, e);
+            let error = FromServer::Error(FromServerError::SerializationError(e.to_string()));
+            // An error holding a string always serializes
+            serde_json::to_string(&error).unwrap_or_default()
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 enum FromServer {
-    Ping,
-    Pong,
+    /// `server_ts` has to be echoed back by the client in its pong
+    Ping {
+        server_ts: u64,
+    },
+    /// Echoes the timestamp of the client's ping
+    Pong {
+        client_ts: u64,
+    },
     SystemInfo(SystemInfo),
+    ActionCatalog(Vec<ActionCatalogEntry>),
+    Logs(Vec<LogEntry>),
     Notification(Notification),
     Response(ServerResponse),
     Error(FromServerError),
@@ -103,8 +316,21 @@ struct ClientRequest {
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 enum FromClient {
-    Ping,
-    Pong,
+    Ping {
+        client_ts: u64,
+    },
+    Pong {
+        server_ts: u64,
+    },
+    GetActionCatalog,
+    /// Buffered log entries, only allowed for admins
+    GetLogs {
+        /// Unix timestamp in milliseconds
+        #[serde(default)]
+        since_ms: u64,
+        /// Lowercase level names, e.g. "error"; every level if unset
+        levels: Option<Vec<String>>,
+    },
     Request(ClientRequest),
 }
 
@@ -112,104 +338,421 @@ enum FromClient {
 #[serde(rename_all = "snake_case")]
 enum FromClientBody {
     CallAction(ActionIdentifier),
+    /// Only allowed for admins
+    SetModuleOrder(Vec<(ModuleIdentifier, ModuleOrder)>),
+    /// Only allowed for admins
+    SetModuleMessage {
+        module_identifier: ModuleIdentifier,
+        message: Option<String>,
+        severity: Option<String>,
+    },
+}
+
+impl FromClientBody {
+    fn is_allowed(&self, role: Role) -> bool {
+        match self {
+            FromClientBody::CallAction(_) => true,
+            FromClientBody::SetModuleOrder(_) | FromClientBody::SetModuleMessage { .. } => {
+                role == Role::Admin
+            }
+        }
+    }
 }
 
 pub async fn start(
     config: Arc<Config>,
     event_sender: mpsc::Sender<Event>,
     notification_sender: broadcast::Sender<Notification>,
+    shutdown_sender: broadcast::Sender<()>,
+    log_buffer: Arc<LogBuffer>,
+    bound_sender: oneshot::Sender<SocketAddr>,
 ) -> anyhow::Result<()> {
-    let websocket_password_hash = Sha256::new()
-        .chain_update(config.websocket_password.as_bytes())
-        .finalize();
+    let hash_passwords = |passwords: &[String]| {
+        passwords
+            .iter()
+            .map(|password| Sha256::digest(password.as_bytes()).to_vec())
+            .collect()
+    };
+    let websocket_password_hashes = hash_passwords(&config.websocket_passwords);
+    let viewer_password_hashes = hash_passwords(&config.viewer_websocket_passwords);
+
+    let client_presence_sender = config.idle_timeout.map(|idle_timeout| {
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(idle_watcher(idle_timeout, rx, event_sender.clone()));
+        tx
+    });
 
     let ctx = Arc::new(WebContext {
         notification_sender,
         event_sender,
-        websocket_password_hash: websocket_password_hash.to_vec(),
+        websocket_password_hashes,
+        viewer_password_hashes,
+        access_tokens: config.access_tokens.clone(),
+        max_message_bytes: config.max_message_bytes,
+        cors_allowed_origins: config.cors_allowed_origins.clone(),
+        channel_home: config.channel_home.clone(),
+        idle_disconnect: config.idle_disconnect,
+        shutdown_sender,
+        client_presence_sender,
+        rtt_stats: RttStats::default(),
+        log_buffer,
+        plugins_loaded: config.plugins_loaded.clone(),
     });
 
     let app = Router::new()
         .route("/ws", get(websocket_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/images/*path", get(image_handler))
         .fallback(get_service(ServeDir::new(WEB_ROOT)).handle_error(handle_error))
         .layer(Extension(ctx))
+        .layer(cors_layer(&config.cors_allowed_origins))
         .layer(TraceLayer::new_for_http());
     let addr = SocketAddr::from(([0, 0, 0, 0], 4200 + config.channel as u16));
     info!("starting server on {}", addr);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .await?;
+    let listener = bind_with_backoff(addr)
+        .await
+        .with_context(|| format!("couldn't bind server to {}", addr))?;
+    let server = axum::Server::from_tcp(listener)?;
+    let _ = bound_sender.send(addr);
+    server.serve(app.into_make_service()).await?;
     Ok(())
 }
 
+/// Without allowed origins, browsers only allow same-origin requests.
+fn cors_layer(allowed_origins: &[String]) -> CorsLayer {
+    let origins: Vec<HeaderValue> = allowed_origins
+        .iter()
+        .filter_map(|origin| match HeaderValue::from_str(origin) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                warn!("ignoring invalid CORS origin {}", origin);
+                None
+            }
+        })
+        .collect();
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins))
+        .allow_methods([Method::GET])
+}
+
+/// Notifies the plugin system when no client has been connected for `idle_timeout` and when a
+/// client connects again afterwards.
+async fn idle_watcher(
+    idle_timeout: Duration,
+    mut client_presence_receiver: mpsc::UnboundedReceiver<ClientPresence>,
+    event_sender: mpsc::Sender<Event>,
+) {
+    let mut connected_clients: usize = 0;
+    let mut is_idle = false;
+
+    loop {
+        let presence = if connected_clients == 0 && !is_idle {
+            match time::timeout(idle_timeout, client_presence_receiver.recv()).await {
+                Ok(presence) => presence,
+                Err(_) => {
+                    debug!("no clients connected for {:?}", idle_timeout);
+                    is_idle = true;
+                    if event_sender.send(Event::Idle).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            }
+        } else {
+            client_presence_receiver.recv().await
+        };
+
+        match presence {
+            Some(ClientPresence::Connected) => {
+                connected_clients += 1;
+                if is_idle {
+                    is_idle = false;
+                    if event_sender.send(Event::Resume).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            Some(ClientPresence::Disconnected) => {
+                connected_clients = connected_clients.saturating_sub(1);
+            }
+            None => break,
+        }
+    }
+}
+
 async fn handle_error(_err: io::Error) -> impl IntoResponse {
     (StatusCode::INTERNAL_SERVER_ERROR, "Something went wrong...")
 }
 
 async fn websocket_handler(
     ws: WebSocketUpgrade,
+    headers: HeaderMap,
     Extension(ctx): Extension<Arc<WebContext>>,
-) -> impl IntoResponse {
-    ws.on_upgrade(|socket| websocket(socket, ctx))
+) -> Response {
+    if !origin_allowed(&headers, &ctx.cors_allowed_origins) {
+        warn!(
+            "rejecting websocket connection from origin {:?}",
+            headers.get(header::ORIGIN)
+        );
+        return (StatusCode::FORBIDDEN, "Origin not allowed").into_response();
+    }
+    if !subprotocol_supported(headers.get(header::SEC_WEBSOCKET_PROTOCOL)) {
+        warn!(
+            "rejecting websocket connection with unsupported subprotocols {:?}",
+            headers.get(header::SEC_WEBSOCKET_PROTOCOL)
+        );
+        return (StatusCode::BAD_REQUEST, "Unsupported websocket subprotocol").into_response();
+    }
+    // Oversized messages are rejected while reading instead of being buffered first
+    let max_message_bytes = ctx.max_message_bytes;
+    ws.protocols([WEBSOCKET_SUBPROTOCOL])
+        .max_message_size(max_message_bytes)
+        .max_frame_size(max_message_bytes)
+        .on_upgrade(|socket| websocket(socket, ctx))
+        .into_response()
+}
+
+/// CORS doesn't apply to websocket upgrades, so the origin is checked separately. Requests without
+/// an origin don't come from browsers and are accepted. Behind a reverse proxy, the public host is
+/// taken from `X-Forwarded-Host`, which browsers can't set on websocket requests.
+fn origin_allowed(headers: &HeaderMap, allowed_origins: &[String]) -> bool {
+    let origin = match headers.get(header::ORIGIN) {
+        Some(origin) => origin,
+        None => return true,
+    };
+    let origin = match origin.to_str() {
+        Ok(origin) => origin,
+        Err(_) => return false,
+    };
+    if allowed_origins.iter().any(|allowed| allowed == origin) {
+        return true;
+    }
+    // Same origin, if the origin names the host that the request was sent to. Proxies that are
+    // chained append their hosts, so the first one is the host the browser connected to.
+    let host = match headers.get("x-forwarded-host") {
+        Some(forwarded_host) => forwarded_host
+            .to_str()
+            .ok()
+            .and_then(|hosts| hosts.split(',').next())
+            .map(str::trim),
+        None => headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok()),
+    };
+    match (origin.split_once("://"), host) {
+        (Some((_, origin_host)), Some(host)) => origin_host.eq_ignore_ascii_case(host),
+        _ => false,
+    }
+}
+
+/// Clients that don't request a subprotocol are accepted, so that older clients keep working.
+/// Clients that request subprotocols have to include the supported one.
+fn subprotocol_supported(requested: Option<&HeaderValue>) -> bool {
+    let requested = match requested {
+        Some(requested) => requested,
+        None => return true,
+    };
+    requested
+        .to_str()
+        .map(|requested| {
+            requested
+                .split(',')
+                .any(|protocol| protocol.trim() == WEBSOCKET_SUBPROTOCOL)
+        })
+        .unwrap_or(false)
+}
+
+/// Serves the image of an image window. Image paths are relative to the channel home, like the
+/// ones passed to `neopult.api.create_image_window`. Only the images of the image windows that
+/// currently exist are served, so that the route doesn't expose other files of the channel home.
+async fn image_handler(
+    extract::Path(image_path): extract::Path<String>,
+    Extension(ctx): Extension<Arc<WebContext>>,
+) -> Response {
+    let image_path = image_path.trim_start_matches('/').to_string();
+    let system_info = fetch_system_info(&ctx.event_sender, &ctx.plugins_loaded).await;
+    if !system_info
+        .image_paths()
+        .any(|shown| Path::new(shown) == Path::new(&image_path))
+    {
+        debug!(
+            "refusing to serve {}, which no image window shows",
+            image_path
+        );
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let channel_home = ctx.channel_home.clone();
+    let resolved =
+        tokio::task::spawn_blocking(move || resolve_image_path(&channel_home, &image_path)).await;
+    let (path, content_type) = match resolved {
+        Ok(Some(resolved)) => resolved,
+        Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            error!("resolving image path failed: {}", e);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    match tokio::fs::read(&path).await {
+        Ok(image) => ([(header::CONTENT_TYPE, content_type)], image).into_response(),
+        Err(e) => {
+            debug!("couldn't read image {:?}: {}", path, e);
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
+
+/// Returns the path and the content type of the image at `image_path`, unless it isn't an image
+/// or it is outside of the channel home, e.g. because of `..` or a symlink.
+fn resolve_image_path(channel_home: &Path, image_path: &str) -> Option<(PathBuf, &'static str)> {
+    let extension = Path::new(image_path)
+        .extension()?
+        .to_str()?
+        .to_ascii_lowercase();
+    let (_, content_type) = IMAGE_CONTENT_TYPES
+        .iter()
+        .find(|(image_extension, _)| *image_extension == extension)?;
+    let channel_home = channel_home.canonicalize().ok()?;
+    let path = channel_home.join(image_path).canonicalize().ok()?;
+    if !path.starts_with(&channel_home) {
+        warn!(
+            "refusing to serve image {:?} outside of the channel home",
+            path
+        );
+        return None;
+    }
+    Some((path, content_type))
+}
+
+async fn metrics_handler(Extension(ctx): Extension<Arc<WebContext>>) -> impl IntoResponse {
+    Json(Metrics {
+        rtt: ctx.rtt_stats.metrics(),
+    })
+}
+
+/// Milliseconds since the unix epoch, used as timestamps in pings and pongs
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns `None` if the clock went backwards in between.
+fn round_trip_ms(sent_ts: u64, received_ts: u64) -> Option<u64> {
+    received_ts.checked_sub(sent_ts)
+}
+
+/// Returns the close message for the client if reading failed because the client sent a message
+/// larger than `max_message_bytes`.
+fn message_too_large(err: axum::Error, max_message_bytes: usize) -> Option<Message> {
+    let source = std::error::Error::source(&err);
+    match source.and_then(|e| e.downcast_ref::<tungstenite::Error>()) {
+        Some(tungstenite::Error::Capacity(e)) => {
+            warn!(
+                "client sent message which exceeds the maximum of {} bytes: {}",
+                max_message_bytes, e
+            );
+            Some(CloseReason::MessageTooLarge.close_message())
+        }
+        _ => None,
+    }
+}
+
+fn password_matches(password_hashes: &[Vec<u8>], got_password: &str) -> bool {
+    let got_hash = Sha256::digest(got_password.as_bytes());
+    // Compare hashes of the passwords to prevent timing attacks
+    password_hashes.iter().any(|hash| **hash == *got_hash)
+}
+
+/// Admin passwords take precedence, in case a password is configured for both roles. Access
+/// tokens grant the admin role and their id is passed on to the plugins.
+fn authenticate(ctx: &WebContext, got_password: &str) -> Option<Caller> {
+    let (role, token_id) = if password_matches(&ctx.websocket_password_hashes, got_password) {
+        (Role::Admin, None)
+    } else if let Some(token_id) = ctx.access_tokens.validate(got_password) {
+        (Role::Admin, Some(token_id))
+    } else if password_matches(&ctx.viewer_password_hashes, got_password) {
+        (Role::Viewer, None)
+    } else {
+        return None;
+    };
+    Some(Caller {
+        role,
+        source: CallerSource::Websocket,
+        token_id,
+    })
 }
 
 async fn websocket(stream: WebSocket, ctx: Arc<WebContext>) {
     let (mut sender, mut receiver) = stream.split();
-    let mut is_authenticated = false;
+    let mut caller = None;
 
     match time::timeout(AUTH_TIMEOUT, receiver.next()).await {
-        Ok(msg) => {
-            if let Some(Ok(Message::Text(auth_msg))) = msg {
-                if let Some(got_password) = auth_msg.strip_prefix("Password ") {
-                    let got_hash = Sha256::new().chain_update(got_password).finalize();
-                    // Compare hashes of the passwords to prevent timing attacks
-                    if *ctx.websocket_password_hash == *got_hash {
-                        is_authenticated = true;
-                    }
-                }
+        Ok(Some(Ok(Message::Text(auth_msg)))) => {
+            if let Some(got_password) = auth_msg.strip_prefix("Password ") {
+                caller = authenticate(&ctx, got_password);
             }
         }
+        Ok(Some(Err(e))) => {
+            if let Some(close_message) = message_too_large(e, ctx.max_message_bytes) {
+                let _ = sender.send(close_message).await;
+                return;
+            }
+        }
+        Ok(_) => {}
         Err(_) => {
-            let _ = sender.send(CLOSE_MSG_AUTH_TIMEOUT).await;
+            let _ = sender.send(CloseReason::AuthTimeout.close_message()).await;
             return;
         }
     }
 
-    if !is_authenticated {
-        let _ = sender.send(CLOSE_MSG_AUTH).await;
-        return;
-    }
+    let caller = match caller {
+        Some(caller) => caller,
+        None => {
+            let _ = sender.send(CloseReason::Auth.close_message()).await;
+            return;
+        }
+    };
+    let role = caller.role;
+    debug!("client authenticated with role {:?}", role);
 
+    // Connected viewers, e.g. a forgotten projector tab, must not keep the channel out of idle
+    let _client_presence_guard = (role == Role::Admin).then(|| ClientPresenceGuard::new(&ctx));
     let mut notification_receiver = ctx.notification_sender.subscribe();
+    let mut shutdown_receiver = ctx.shutdown_sender.subscribe();
     let event_sender = ctx.event_sender.clone();
 
-    let (tx, rx) = oneshot::channel();
-    event_sender
-        .send(Event::FetchSystemInfo { reply_sender: tx })
-        .await
-        .expect("event receiver was closed");
-    let system_info = rx.await.expect("fetch system info got no reply");
-    let msg = FromServer::SystemInfo(system_info);
-    let json = serde_json::to_string(&msg).expect("serialization failed");
-    // Prevent accidental reuse when using variable with same name
-    drop(msg);
-
+    let system_info = fetch_system_info(&event_sender, &ctx.plugins_loaded).await;
+    let json = to_client_json(&FromServer::SystemInfo(system_info));
     if sender.send(Message::Text(json)).await.is_err() {
         return;
     }
 
     let mut hb = Instant::now();
     let mut hb_interval = time::interval(HEARTBEAT_INTERVAL);
+    let mut last_request = Instant::now();
+    // Only admin sessions are a risk when they are forgotten
+    let idle_disconnect = ctx.idle_disconnect.filter(|_| role == Role::Admin);
 
     loop {
         tokio::select!(
+            _ = shutdown_receiver.recv() => {
+                let _ = sender.send(CloseReason::Shutdown.close_message()).await;
+                break;
+            },
+            _ = idle_deadline(idle_disconnect, last_request) => {
+                debug!("closing connection of idle client");
+                let _ = sender.send(CloseReason::Idle.close_message()).await;
+                break;
+            },
             _ = hb_interval.tick() => {
                 if Instant::now().duration_since(hb) > CLIENT_TIMEOUT {
                     debug!("client timed out");
                     break;
                 }
 
-                let json = serde_json::to_string(&FromServer::Ping).expect("serialization failed");
+                let ping = FromServer::Ping { server_ts: unix_millis() };
+                let json = to_client_json(&ping);
                 if sender.send(Message::Text(json)).await.is_err() {
                     break;
                 }
@@ -224,18 +767,21 @@ async fn websocket(stream: WebSocket, ctx: Arc<WebContext>) {
                     }
                 };
 
-                let msg = FromServer::Notification(notification);
-                let json = match serde_json::to_string(&msg) {
-                    Ok(msg) => msg,
-                    Err(e) => {
-                        error!("could not serialize notification message {:?}: {}", msg, e);
-                        continue;
-                    }
-                };
+                let plugins_loaded = matches!(notification, Notification::PluginsLoaded);
+                let json = to_client_json(&FromServer::Notification(notification));
 
                 if sender.send(Message::Text(json)).await.is_err() {
                     break;
                 }
+
+                // Clients connected while loading only got an empty system info
+                if plugins_loaded {
+                    let system_info = fetch_system_info(&event_sender, &ctx.plugins_loaded).await;
+                    let json = to_client_json(&FromServer::SystemInfo(system_info));
+                    if sender.send(Message::Text(json)).await.is_err() {
+                        break;
+                    }
+                }
             }
             command_option = receiver.next() => {
                 match command_option {
@@ -245,7 +791,7 @@ async fn websocket(stream: WebSocket, ctx: Arc<WebContext>) {
                             Err(e) => {
                                 warn!("could not parse client request: {} -- request was: {}", e, client_json);
                                 let server_msg = FromServer::Error(FromServerError::ParseError(e.to_string()));
-                                let server_json = serde_json::to_string(&server_msg).expect("serialization failed");
+                                let server_json = to_client_json(&server_msg);
                                 if sender.send(Message::Text(server_json)).await.is_err() {
                                     break;
                                 }
@@ -253,48 +799,48 @@ async fn websocket(stream: WebSocket, ctx: Arc<WebContext>) {
                             }
                         };
                         match client_msg {
-                            FromClient::Ping => {
+                            FromClient::Ping { client_ts } => {
                                 hb = Instant::now();
-                                let json = serde_json::to_string(&FromServer::Pong).expect("serialization failed");
+                                let json = to_client_json(&FromServer::Pong { client_ts });
                                 if sender.send(Message::Text(json)).await.is_err() {
                                     break;
                                 }
                             },
-                            FromClient::Pong => {
+                            FromClient::Pong { server_ts } => {
                                 hb = Instant::now();
+                                if let Some(rtt_ms) = round_trip_ms(server_ts, unix_millis()) {
+                                    ctx.rtt_stats.record(rtt_ms);
+                                }
+                            },
+                            FromClient::GetActionCatalog => {
+                                let catalog = fetch_action_catalog(&event_sender, &ctx.plugins_loaded).await;
+                                let json = to_client_json(&FromServer::ActionCatalog(catalog));
+                                if sender.send(Message::Text(json)).await.is_err() {
+                                    break;
+                                }
+                            },
+                            FromClient::GetLogs { since_ms, levels } => {
+                                let msg = get_logs(&ctx.log_buffer, role, since_ms, levels.as_deref());
+                                if sender.send(Message::Text(to_client_json(&msg))).await.is_err() {
+                                    break;
+                                }
                             },
                             FromClient::Request(request) => {
-                                let request_id = request.request_id;
-
-                                match request.body {
-                                    FromClientBody::CallAction(identifier) => {
-                                        let (tx, rx) = oneshot::channel();
-                                        let command = ClientCommand::CallAction {
-                                            identifier: identifier.clone(),
-                                            error_sender: tx
-                                        };
-                                        send_client_command(&event_sender, command).await;
-
-                                        let response = match rx.await {
-                                            Ok(Ok(_)) => ServerResponse::new_success(request_id),
-                                            Ok(Err(e)) => {
-                                                error!("error when calling action {}: {:?}", identifier, e);
-                                                ServerResponse::from_error(request_id, e)
-                                            },
-                                            Err(_) => {
-                                                error!("plugin system didn't reply to call action command");
-                                                ServerResponse::new_internal_error(request_id)
-                                            },
-                                        };
-                                        let json = serde_json::to_string(&FromServer::Response(response)).expect("serialization failed");
-                                        if sender.send(Message::Text(json)).await.is_err() {
-                                            break;
-                                        }
-                                    },
-                                };
+                                last_request = Instant::now();
+                                let response = handle_request(&event_sender, &ctx.plugins_loaded, caller, request).await;
+                                let json = to_client_json(&FromServer::Response(response));
+                                if sender.send(Message::Text(json)).await.is_err() {
+                                    break;
+                                }
                             }
                         }
                     }
+                    Some(Err(e)) => {
+                        if let Some(close_message) = message_too_large(e, ctx.max_message_bytes) {
+                            let _ = sender.send(close_message).await;
+                        }
+                        break;
+                    }
                     _ => {
                         break;
                     }
@@ -304,9 +850,1028 @@ async fn websocket(stream: WebSocket, ctx: Arc<WebContext>) {
     }
 }
 
+fn get_logs(
+    log_buffer: &LogBuffer,
+    role: Role,
+    since_ms: u64,
+    levels: Option<&[String]>,
+) -> FromServer {
+    if role != Role::Admin {
+        return FromServer::Error(FromServerError::Forbidden(
+            "only admins may fetch logs".to_string(),
+        ));
+    }
+    FromServer::Logs(log_buffer.query(since_ms, levels))
+}
+
+/// Completes once a client that sent its last request at `last_request` is idle. Never completes
+/// if `idle_disconnect` is not configured.
+async fn idle_deadline(idle_disconnect: Option<Duration>, last_request: Instant) {
+    match idle_disconnect {
+        Some(idle_disconnect) => time::sleep_until(last_request + idle_disconnect).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Answers with an empty system info while plugins are loading, because the plugin system can't
+/// reply until then.
+async fn fetch_system_info(
+    event_sender: &mpsc::Sender<Event>,
+    plugins_loaded: &AtomicBool,
+) -> SystemInfo {
+    if !plugins_loaded.load(Ordering::SeqCst) {
+        return SystemInfo::loading();
+    }
+    let (tx, rx) = oneshot::channel();
+    event_sender
+        .send(Event::FetchSystemInfo { reply_sender: tx })
+        .await
+        .expect("event receiver was closed");
+    rx.await.expect("fetch system info got no reply")
+}
+
+/// Answers with an empty catalog while plugins are loading or when the plugin system is gone.
+async fn fetch_action_catalog(
+    event_sender: &mpsc::Sender<Event>,
+    plugins_loaded: &AtomicBool,
+) -> Vec<ActionCatalogEntry> {
+    if !plugins_loaded.load(Ordering::SeqCst) {
+        return vec![];
+    }
+    let (tx, rx) = oneshot::channel();
+    if event_sender
+        .send(Event::FetchActionCatalog { reply_sender: tx })
+        .await
+        .is_err()
+    {
+        error!("event receiver was closed when fetching action catalog");
+        return vec![];
+    }
+    match rx.await {
+        Ok(catalog) => catalog,
+        Err(_) => {
+            error!("plugin system didn't reply when fetching action catalog");
+            vec![]
+        }
+    }
+}
+
+async fn handle_request(
+    event_sender: &mpsc::Sender<Event>,
+    plugins_loaded: &AtomicBool,
+    caller: Caller,
+    request: ClientRequest,
+) -> ServerResponse {
+    let request_id = request.request_id;
+    let role = caller.role;
+
+    if !request.body.is_allowed(role) {
+        warn!(
+            "client with role {:?} sent forbidden request {:?}",
+            role, request.body
+        );
+        return ServerResponse::new(request_id, false, Some("Forbidden".to_string()));
+    }
+    if !plugins_loaded.load(Ordering::SeqCst) {
+        debug!(
+            "rejecting request {:?} while plugins are loading",
+            request.body
+        );
+        return ServerResponse::new_not_ready(request_id);
+    }
+
+    let (tx, rx) = oneshot::channel();
+    let (command, description) = match request.body {
+        FromClientBody::CallAction(identifier) => (
+            ClientCommand::CallAction {
+                identifier: identifier.clone(),
+                caller,
+                error_sender: tx,
+            },
+            format!("calling action {}", identifier),
+        ),
+        FromClientBody::SetModuleOrder(orders) => (
+            ClientCommand::SetModuleOrder {
+                orders,
+                error_sender: tx,
+            },
+            "setting module order".to_string(),
+        ),
+        FromClientBody::SetModuleMessage {
+            module_identifier,
+            message,
+            severity,
+        } => (
+            ClientCommand::SetModuleMessage {
+                module_identifier,
+                message,
+                severity,
+                error_sender: tx,
+            },
+            "setting module message".to_string(),
+        ),
+    };
+    send_client_command(event_sender, command).await;
+
+    match rx.await {
+        Ok(Ok(_)) => ServerResponse::new_success(request_id),
+        Ok(Err(e)) => {
+            error!("error when {}: {:?}", description, e);
+            ServerResponse::from_error(request_id, e)
+        }
+        Err(_) => {
+            error!("plugin system didn't reply when {}", description);
+            ServerResponse::new_internal_error(request_id)
+        }
+    }
+}
+
 async fn send_client_command(event_sender: &mpsc::Sender<Event>, command: ClientCommand) {
     event_sender
         .send(Event::ClientCommand(command))
         .await
         .expect("event receiver was closed");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Context of a server whose plugins are still loading, so that requests don't need a plugin
+    /// system. Tests override the fields they need with struct update syntax.
+    fn test_context() -> WebContext {
+        WebContext {
+            notification_sender: broadcast::channel(1).0,
+            event_sender: mpsc::channel(1).0,
+            websocket_password_hashes: vec![Sha256::digest(b"admin").to_vec()],
+            viewer_password_hashes: vec![Sha256::digest(b"viewer").to_vec()],
+            access_tokens: Arc::new(AccessTokens::default()),
+            max_message_bytes: 1024,
+            cors_allowed_origins: vec![],
+            channel_home: PathBuf::new(),
+            idle_disconnect: None,
+            shutdown_sender: broadcast::channel(1).0,
+            client_presence_sender: None,
+            rtt_stats: RttStats::default(),
+            log_buffer: Arc::new(LogBuffer::default()),
+            plugins_loaded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Serves the websocket route with `ctx` on a free port of localhost
+    fn spawn_test_server(ctx: WebContext) -> SocketAddr {
+        let app = Router::new()
+            .route("/ws", get(websocket_handler))
+            .layer(Extension(Arc::new(ctx)));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener).unwrap();
+        tokio::spawn(server.serve(app.into_make_service()));
+        addr
+    }
+
+    #[test]
+    fn test_serialization_error_is_sent_to_client() {
+        // Maps with non-string keys can't be serialized to JSON
+        let unserializable = std::collections::HashMap::from([((1, 2), "value")]);
+        let json = to_client_json(&unserializable);
+        match serde_json::from_str(&json).unwrap() {
+            FromServer::Error(FromServerError::SerializationError(e)) => {
+                assert!(e.contains("key must be a string"), "{}", e)
+            }
+            msg => panic!("expected serialization error, got {:?}", msg),
+        }
+
+        let json = to_client_json(&FromServer::Pong { client_ts: 42 });
+        assert!(matches!(
+            serde_json::from_str(&json).unwrap(),
+            FromServer::Pong { client_ts: 42 }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cors_layer() {
+        use axum::{body::Body, http::Request};
+        use tower::ServiceExt;
+
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(cors_layer(&["https://admin.example.com".to_string()]));
+        let request = |origin: &str| {
+            Request::builder()
+                .uri("/")
+                .header("Origin", origin)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("https://admin.example.com"))
+            .await
+            .unwrap();
+        assert_eq!(
+            response
+                .headers()
+                .get("access-control-allow-origin")
+                .unwrap(),
+            "https://admin.example.com"
+        );
+
+        let response = app
+            .oneshot(request("https://evil.example.com"))
+            .await
+            .unwrap();
+        assert!(response
+            .headers()
+            .get("access-control-allow-origin")
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_image_route() {
+        use axum::{
+            body::{Body, HttpBody},
+            http::Request,
+        };
+        use tower::ServiceExt;
+
+        let dir = crate::test_support::temp_dir("image-route");
+        let channel_home = dir.join("channel-0");
+        std::fs::create_dir_all(channel_home.join("slides")).unwrap();
+        std::fs::write(channel_home.join("slides/title.png"), b"png").unwrap();
+        std::fs::write(channel_home.join("slides/notes.png"), b"notes").unwrap();
+        std::fs::write(channel_home.join("neopult.toml"), b"secret").unwrap();
+        std::fs::write(dir.join("outside.png"), b"outside").unwrap();
+        std::os::unix::fs::symlink(dir.join("outside.png"), channel_home.join("link.png")).unwrap();
+
+        let notification_sender = broadcast::channel(1).0;
+        let (event_sender, event_receiver) = mpsc::channel(1);
+        tokio::spawn(crate::plugin_system::run_fake_event_loop(
+            event_receiver,
+            notification_sender.clone(),
+        ));
+        let ctx = WebContext {
+            notification_sender,
+            event_sender,
+            channel_home: channel_home.clone(),
+            plugins_loaded: Arc::new(AtomicBool::new(true)),
+            ..test_context()
+        };
+        let app = Router::new()
+            .route("/images/*path", get(image_handler))
+            .layer(Extension(Arc::new(ctx)));
+        let get_image = |uri: &str| {
+            let request = Request::builder().uri(uri).body(Body::empty()).unwrap();
+            app.clone().oneshot(request)
+        };
+
+        let response = get_image("/images/slides/title.png").await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/png");
+        let body = response.into_body().data().await.unwrap().unwrap();
+        assert_eq!(&body[..], b"png");
+
+        // Only images that an image window shows are served
+        for uri in [
+            "/images/slides/notes.png",
+            "/images/neopult.toml",
+            "/images/missing.png",
+            "/images/../outside.png",
+            "/images/%2E%2E/outside.png",
+            "/images/link.png",
+        ] {
+            let response = get_image(uri).await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", uri);
+        }
+        assert!(resolve_image_path(&channel_home, "slides/notes.png").is_some());
+        assert!(resolve_image_path(&channel_home, "link.png").is_none());
+        assert!(resolve_image_path(&channel_home, "../outside.png").is_none());
+        assert!(resolve_image_path(&channel_home, "neopult.toml").is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_ping_pong_round_trip() {
+        let server_ts = 1_652_551_389_000;
+        let ping = serde_json::to_string(&FromServer::Ping { server_ts }).unwrap();
+        assert_eq!(ping, r#"{"ping":{"server_ts":1652551389000}}"#);
+
+        // The client echoes the timestamp of the ping
+        let pong: FromClient =
+            serde_json::from_str(r#"{"pong":{"server_ts":1652551389000}}"#).unwrap();
+        let echoed_ts = match pong {
+            FromClient::Pong { server_ts } => server_ts,
+            other => panic!("expected pong, got {:?}", other),
+        };
+        assert_eq!(round_trip_ms(echoed_ts, server_ts + 42), Some(42));
+        assert_eq!(round_trip_ms(echoed_ts, server_ts - 1), None);
+
+        let rtt_stats = RttStats::default();
+        assert_eq!(
+            rtt_stats.metrics(),
+            RttMetrics {
+                latest_ms: None,
+                average_ms: None,
+                samples: 0
+            }
+        );
+        rtt_stats.record(42);
+        rtt_stats.record(18);
+        assert_eq!(
+            rtt_stats.metrics(),
+            RttMetrics {
+                latest_ms: Some(18),
+                average_ms: Some(30),
+                samples: 2
+            }
+        );
+        for _ in 0..RTT_SAMPLES {
+            rtt_stats.record(10);
+        }
+        assert_eq!(rtt_stats.metrics().samples, RTT_SAMPLES);
+        assert_eq!(rtt_stats.metrics().average_ms, Some(10));
+
+        // Pings of the client are answered with its own timestamp
+        let ping: FromClient = serde_json::from_str(r#"{"ping":{"client_ts":7}}"#).unwrap();
+        let client_ts = match ping {
+            FromClient::Ping { client_ts } => client_ts,
+            other => panic!("expected ping, got {:?}", other),
+        };
+        assert_eq!(
+            serde_json::to_string(&FromServer::Pong { client_ts }).unwrap(),
+            r#"{"pong":{"client_ts":7}}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_actions_are_rejected_while_plugins_load() {
+        let call_action = |request_id: &str| ClientRequest {
+            request_id: request_id.to_string(),
+            body: FromClientBody::CallAction(ActionIdentifier {
+                plugin_instance: "vnc".to_string(),
+                module: "viewer".to_string(),
+                action: "start".to_string(),
+            }),
+        };
+        let caller = Caller {
+            role: Role::Admin,
+            source: CallerSource::Websocket,
+            token_id: Some(7),
+        };
+        let plugins_loaded = AtomicBool::new(false);
+        let (event_tx, mut event_rx) = mpsc::channel(1);
+
+        let response = handle_request(&event_tx, &plugins_loaded, caller, call_action("1")).await;
+        assert!(!response.success);
+        assert_eq!(
+            response.message.as_deref(),
+            Some("Not ready, plugins are still loading")
+        );
+        assert!(event_rx.try_recv().is_err());
+        let system_info = fetch_system_info(&event_tx, &plugins_loaded).await;
+        assert_eq!(
+            serde_json::to_value(system_info).unwrap(),
+            serde_json::json!({ "plugin_instances": [], "loading": true })
+        );
+
+        plugins_loaded.store(true, Ordering::SeqCst);
+        let plugin_system = tokio::spawn(async move {
+            match event_rx.recv().await {
+                Some(Event::ClientCommand(ClientCommand::CallAction {
+                    identifier,
+                    caller,
+                    error_sender,
+                })) => {
+                    assert_eq!(identifier.action, "start");
+                    assert_eq!(caller.token_id, Some(7));
+                    error_sender.send(Ok(())).unwrap();
+                }
+                event => panic!("expected call action command, got {:?}", event),
+            }
+        });
+        let response = handle_request(&event_tx, &plugins_loaded, caller, call_action("2")).await;
+        assert!(response.success);
+        assert_eq!(response.request_id, "2");
+        plugin_system.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_action_catalog_without_plugin_system() {
+        let plugins_loaded = AtomicBool::new(true);
+
+        // Plugin system drops the reply sender
+        let (event_tx, mut event_rx) = mpsc::channel(1);
+        let plugin_system = tokio::spawn(async move {
+            match event_rx.recv().await {
+                Some(Event::FetchActionCatalog { reply_sender }) => drop(reply_sender),
+                event => panic!("expected fetch action catalog, got {:?}", event),
+            }
+        });
+        assert!(fetch_action_catalog(&event_tx, &plugins_loaded)
+            .await
+            .is_empty());
+        plugin_system.await.unwrap();
+
+        // Plugin system is gone
+        let (event_tx, event_rx) = mpsc::channel(1);
+        drop(event_rx);
+        assert!(fetch_action_catalog(&event_tx, &plugins_loaded)
+            .await
+            .is_empty());
+    }
+
+    #[test]
+    fn test_set_module_message_requires_admin() {
+        let request: FromClient = serde_json::from_str(
+            r#"{"request": {"request_id": "1", "body": {"set_module_message": {
+                "module_identifier": {"plugin_instance": "vnc", "module": "viewer"},
+                "message": "Back in 5 minutes",
+                "severity": "warning"
+            }}}}"#,
+        )
+        .unwrap();
+        let body = match request {
+            FromClient::Request(request) => request.body,
+            other => panic!("expected request, got {:?}", other),
+        };
+        assert!(body.is_allowed(Role::Admin));
+        assert!(!body.is_allowed(Role::Viewer));
+        assert!(FromClientBody::SetModuleOrder(Vec::new()).is_allowed(Role::Admin));
+        assert!(!FromClientBody::SetModuleOrder(Vec::new()).is_allowed(Role::Viewer));
+    }
+
+    #[test]
+    fn test_get_logs() {
+        let log_buffer = LogBuffer::default();
+        for (timestamp_ms, level) in [(100, "info"), (200, "error"), (300, "warn")] {
+            log_buffer.push(LogEntry {
+                timestamp_ms,
+                level: level.to_string(),
+                target: "neopult".to_string(),
+                message: format!("{} message", level),
+            });
+        }
+        let request: FromClient =
+            serde_json::from_str(r#"{"get_logs": {"since_ms": 150, "levels": ["error"]}}"#)
+                .unwrap();
+        let (since_ms, levels) = match request {
+            FromClient::GetLogs { since_ms, levels } => (since_ms, levels),
+            other => panic!("expected get logs, got {:?}", other),
+        };
+
+        match get_logs(&log_buffer, Role::Admin, since_ms, levels.as_deref()) {
+            FromServer::Logs(entries) => {
+                assert_eq!(entries.len(), 1);
+                assert_eq!(entries[0].message, "error message");
+            }
+            msg => panic!("expected logs, got {:?}", msg),
+        }
+        match get_logs(&log_buffer, Role::Viewer, 0, None) {
+            FromServer::Error(FromServerError::Forbidden(_)) => {}
+            msg => panic!("expected forbidden error, got {:?}", msg),
+        }
+
+        let request: FromClient = serde_json::from_str(r#"{"get_logs": {}}"#).unwrap();
+        assert!(matches!(
+            request,
+            FromClient::GetLogs {
+                since_ms: 0,
+                levels: None
+            }
+        ));
+    }
+
+    #[test]
+    fn test_password_matches() {
+        let hashes: Vec<Vec<u8>> = ["old-secret", "new-secret"]
+            .iter()
+            .map(|password| Sha256::digest(password.as_bytes()).to_vec())
+            .collect();
+        assert!(password_matches(&hashes, "old-secret"));
+        assert!(password_matches(&hashes, "new-secret"));
+        assert!(!password_matches(&hashes, "other-secret"));
+        assert!(!password_matches(&hashes, ""));
+    }
+
+    #[test]
+    fn test_authenticate_with_access_token() {
+        let ctx = WebContext {
+            plugins_loaded: Arc::new(AtomicBool::new(true)),
+            ..test_context()
+        };
+        let (token, token_id) = ctx.access_tokens.create(Duration::from_millis(50)).unwrap();
+        let (long_lived_token, long_lived_token_id) =
+            ctx.access_tokens.create(Duration::from_secs(60)).unwrap();
+        assert_ne!(token, long_lived_token);
+        assert_ne!(token_id, long_lived_token_id);
+
+        let authenticated = |password: &str| {
+            authenticate(&ctx, password).map(|caller| (caller.role, caller.token_id))
+        };
+        assert_eq!(authenticated(&token), Some((Role::Admin, Some(token_id))));
+        assert_eq!(authenticated("admin"), Some((Role::Admin, None)));
+        assert_eq!(authenticated("not-a-token"), None);
+
+        std::thread::sleep(Duration::from_millis(80));
+        assert_eq!(authenticated(&token), None);
+        assert_eq!(
+            authenticated(&long_lived_token),
+            Some((Role::Admin, Some(long_lived_token_id)))
+        );
+    }
+
+    #[test]
+    fn test_subprotocol_supported() {
+        assert!(subprotocol_supported(None));
+        assert!(subprotocol_supported(Some(&HeaderValue::from_static(
+            "neopult.v1"
+        ))));
+        assert!(subprotocol_supported(Some(&HeaderValue::from_static(
+            "neopult.v2, neopult.v1"
+        ))));
+        assert!(!subprotocol_supported(Some(&HeaderValue::from_static(
+            "neopult.v2"
+        ))));
+        assert!(!subprotocol_supported(Some(&HeaderValue::from_static(""))));
+    }
+
+    #[tokio::test]
+    async fn test_websocket_subprotocol_negotiation() {
+        use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+        let addr = spawn_test_server(test_context());
+
+        let connect = |protocols: Option<&'static str>| async move {
+            let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+            if let Some(protocols) = protocols {
+                request.headers_mut().insert(
+                    header::SEC_WEBSOCKET_PROTOCOL,
+                    HeaderValue::from_static(protocols),
+                );
+            }
+            tokio_tungstenite::connect_async(request).await
+        };
+
+        let (_socket, response) = connect(Some("neopult.v1")).await.unwrap();
+        assert_eq!(
+            response
+                .headers()
+                .get(header::SEC_WEBSOCKET_PROTOCOL)
+                .unwrap(),
+            "neopult.v1"
+        );
+
+        let (_socket, response) = connect(None).await.unwrap();
+        assert!(response
+            .headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .is_none());
+
+        match connect(Some("neopult.v2")).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            }
+            result => panic!("expected rejection, got {:?}", result.map(|(_, r)| r)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_websocket_origin() {
+        use tokio_tungstenite::tungstenite::{self, client::IntoClientRequest};
+
+        let ctx = WebContext {
+            cors_allowed_origins: vec!["https://admin.example.com".to_string()],
+            ..test_context()
+        };
+        let addr = spawn_test_server(ctx);
+
+        let connect = |origin: Option<String>| async move {
+            let mut request = format!("ws://{}/ws", addr).into_client_request().unwrap();
+            if let Some(origin) = origin {
+                request
+                    .headers_mut()
+                    .insert(header::ORIGIN, HeaderValue::from_str(&origin).unwrap());
+            }
+            tokio_tungstenite::connect_async(request).await
+        };
+
+        assert!(connect(None).await.is_ok());
+        assert!(connect(Some(format!("http://{}", addr))).await.is_ok());
+        assert!(connect(Some("https://admin.example.com".to_string()))
+            .await
+            .is_ok());
+        match connect(Some("https://evil.example.com".to_string())).await {
+            Err(tungstenite::Error::Http(response)) => {
+                assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            }
+            result => panic!("expected rejection, got {:?}", result.map(|(_, r)| r)),
+        }
+    }
+
+    #[test]
+    fn test_origin_allowed_behind_proxy() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, HeaderValue::from_static(value));
+            }
+            headers
+        };
+
+        // The proxy connects to neopult on localhost, but the browser to the public host
+        let proxied = [
+            ("origin", "https://neopult.example.com"),
+            ("host", "127.0.0.1:4000"),
+        ];
+        assert!(!origin_allowed(&headers(&proxied), &[]));
+        assert!(origin_allowed(
+            &headers(&proxied),
+            &["https://neopult.example.com".to_string()]
+        ));
+        assert!(origin_allowed(
+            &headers(&[
+                ("origin", "https://neopult.example.com"),
+                ("host", "127.0.0.1:4000"),
+                (
+                    "x-forwarded-host",
+                    "neopult.example.com, internal.example.com"
+                ),
+            ]),
+            &[]
+        ));
+        // The forwarded host replaces the host instead of adding to it
+        assert!(!origin_allowed(
+            &headers(&[
+                ("origin", "http://127.0.0.1:4000"),
+                ("host", "127.0.0.1:4000"),
+                ("x-forwarded-host", "neopult.example.com"),
+            ]),
+            &[]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_idle_disconnect() {
+        use tokio_tungstenite::tungstenite;
+
+        let idle_disconnect = Duration::from_millis(300);
+        let ctx = WebContext {
+            idle_disconnect: Some(idle_disconnect),
+            ..test_context()
+        };
+        let addr = spawn_test_server(ctx);
+
+        let connect = |password: &'static str| async move {
+            let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+                .await
+                .unwrap();
+            socket
+                .send(tungstenite::Message::Text(format!("Password {}", password)))
+                .await
+                .unwrap();
+            socket
+        };
+        let mut idle = connect("admin").await;
+        let mut active = connect("admin").await;
+        let mut viewer = connect("viewer").await;
+        let started = Instant::now();
+
+        let idle_client = async {
+            loop {
+                match idle.next().await {
+                    Some(Ok(tungstenite::Message::Close(Some(frame)))) => break frame,
+                    // Pings don't keep the connection open
+                    Some(Ok(tungstenite::Message::Text(json))) if json.contains("ping") => {
+                        idle.send(tungstenite::Message::Text(json.replace("ping", "pong")))
+                            .await
+                            .unwrap();
+                    }
+                    Some(Ok(_)) => {}
+                    msg => panic!("expected close frame, got {:?}", msg),
+                }
+            }
+        };
+        let active_client = async {
+            let request = r#"{"request":{"request_id":"1","body":{"set_module_order":[]}}}"#;
+            while started.elapsed() < idle_disconnect * 2 {
+                active
+                    .send(tungstenite::Message::Text(request.to_string()))
+                    .await
+                    .unwrap();
+                time::sleep(idle_disconnect / 3).await;
+            }
+        };
+        // Idle viewers aren't disconnected
+        let viewer_client = async {
+            let deadline = started + idle_disconnect * 2;
+            loop {
+                match time::timeout_at(deadline, viewer.next()).await {
+                    Err(_) => break,
+                    Ok(Some(Ok(tungstenite::Message::Text(json)))) if json.contains("ping") => {
+                        viewer
+                            .send(tungstenite::Message::Text(json.replace("ping", "pong")))
+                            .await
+                            .unwrap();
+                    }
+                    Ok(Some(Ok(tungstenite::Message::Text(_)))) => {}
+                    msg => panic!("expected viewer connection to stay open, got {:?}", msg),
+                }
+            }
+        };
+        let (close_frame, (), ()) = tokio::join!(idle_client, active_client, viewer_client);
+        assert!(started.elapsed() >= idle_disconnect);
+        assert_eq!(u16::from(close_frame.code), 4000);
+        let payload: CloseReasonPayload = serde_json::from_str(&close_frame.reason).unwrap();
+        assert_eq!(payload.code, CloseReason::Idle);
+        assert!(!payload.retryable);
+
+        // The active connection still answers requests
+        active
+            .send(tungstenite::Message::Text(
+                r#"{"request":{"request_id":"last","body":{"set_module_order":[]}}}"#.to_string(),
+            ))
+            .await
+            .unwrap();
+        loop {
+            match active.next().await {
+                Some(Ok(tungstenite::Message::Text(json))) if json.contains("last") => break,
+                Some(Ok(tungstenite::Message::Text(_))) => {}
+                msg => panic!("expected response, got {:?}", msg),
+            }
+        }
+    }
+
+    fn close_reason_payload(msg: Message) -> (u16, CloseReasonPayload) {
+        match msg {
+            Message::Close(Some(frame)) => {
+                (frame.code, serde_json::from_str(&frame.reason).unwrap())
+            }
+            _ => panic!("expected close frame"),
+        }
+    }
+
+    #[test]
+    fn test_close_messages() {
+        let (code, payload) = close_reason_payload(CloseReason::Auth.close_message());
+        assert_eq!(code, 4001);
+        assert_eq!(payload.code, CloseReason::Auth);
+        assert!(!payload.retryable);
+
+        let (code, payload) = close_reason_payload(CloseReason::AuthTimeout.close_message());
+        assert_eq!(code, 4002);
+        assert_eq!(payload.code, CloseReason::AuthTimeout);
+        assert!(!payload.retryable);
+
+        let (code, payload) = close_reason_payload(CloseReason::Shutdown.close_message());
+        assert_eq!(code, 4003);
+        assert_eq!(payload.code, CloseReason::Shutdown);
+        assert!(payload.retryable);
+
+        let (code, payload) = close_reason_payload(CloseReason::MessageTooLarge.close_message());
+        assert_eq!(code, 1008);
+        assert_eq!(payload.code, CloseReason::MessageTooLarge);
+        assert!(!payload.retryable);
+
+        match CloseReason::Auth.close_message() {
+            Message::Close(Some(frame)) => {
+                assert_eq!(frame.reason, r#"{"code":"auth","retryable":false}"#)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_module_message_over_websocket() {
+        use tokio_tungstenite::tungstenite;
+
+        let notification_sender = broadcast::channel(16).0;
+        let (event_sender, event_receiver) = mpsc::channel(8);
+        tokio::spawn(crate::plugin_system::run_fake_event_loop(
+            event_receiver,
+            notification_sender.clone(),
+        ));
+        let ctx = WebContext {
+            notification_sender,
+            event_sender,
+            plugins_loaded: Arc::new(AtomicBool::new(true)),
+            ..test_context()
+        };
+        let addr = spawn_test_server(ctx);
+
+        let connect = |password: &'static str| async move {
+            let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+                .await
+                .unwrap();
+            socket
+                .send(tungstenite::Message::Text(format!("Password {}", password)))
+                .await
+                .unwrap();
+            socket
+        };
+        // Skips pings, which the test doesn't need to answer
+        async fn next_json(
+            socket: &mut tokio_tungstenite::WebSocketStream<
+                tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+            >,
+        ) -> serde_json::Value {
+            loop {
+                match socket.next().await {
+                    Some(Ok(tungstenite::Message::Text(json))) => {
+                        let msg: serde_json::Value = serde_json::from_str(&json).unwrap();
+                        if msg.get("ping").is_none() {
+                            return msg;
+                        }
+                    }
+                    msg => panic!("expected text message, got {:?}", msg),
+                }
+            }
+        }
+        let request = |request_id: &str| {
+            tungstenite::Message::Text(
+                serde_json::json!({ "request": { "request_id": request_id, "body": {
+                    "set_module_message": {
+                        "module_identifier": { "plugin_instance": "vnc", "module": "viewer" },
+                        "message": "Break <5 min>",
+                        "severity": "warning",
+                    }
+                }}})
+                .to_string(),
+            )
+        };
+        let expected = "<span class=\"severity-warning\">Break &lt;5 min&gt;</span>";
+
+        let mut admin = connect("admin").await;
+        let system_info = next_json(&mut admin).await;
+        assert!(
+            system_info["system_info"]["plugin_instances"][0]["modules"][0]["message"].is_null()
+        );
+        let mut viewer = connect("viewer").await;
+        next_json(&mut viewer).await;
+
+        // Viewers may not set messages
+        viewer.send(request("1")).await.unwrap();
+        let response = next_json(&mut viewer).await;
+        assert_eq!(response["response"]["request_id"], "1");
+        assert_eq!(response["response"]["success"], false);
+        assert_eq!(response["response"]["message"], "Forbidden");
+
+        // The update is broadcast to every client, including the one that set it
+        admin.send(request("2")).await.unwrap();
+        let (mut got_response, mut got_notification) = (false, false);
+        while !got_response || !got_notification {
+            let msg = next_json(&mut admin).await;
+            if let Some(response) = msg.get("response") {
+                assert_eq!(response["request_id"], "2");
+                assert_eq!(response["success"], true);
+                got_response = true;
+            } else if let Some(notification) = msg.get("notification") {
+                let update = &notification["module_message_update"];
+                assert_eq!(update["plugin_instance"], "vnc");
+                assert_eq!(update["module"], "viewer");
+                assert_eq!(update["new_message"], expected);
+                got_notification = true;
+            }
+        }
+        let notification = next_json(&mut viewer).await;
+        assert_eq!(
+            notification["notification"]["module_message_update"]["new_message"],
+            expected
+        );
+
+        // Clients that connect later get the message with the system info
+        let mut late = connect("viewer").await;
+        let system_info = next_json(&mut late).await;
+        assert_eq!(
+            system_info["system_info"]["plugin_instances"][0]["modules"][0]["message"],
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_too_large() {
+        use tokio_tungstenite::tungstenite;
+
+        let ctx = WebContext {
+            max_message_bytes: 64,
+            ..test_context()
+        };
+        let addr = spawn_test_server(ctx);
+
+        let connect = || async {
+            tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+                .await
+                .unwrap()
+                .0
+        };
+        let expect_too_large =
+            |msg: Option<Result<tungstenite::Message, tungstenite::Error>>| match msg {
+                Some(Ok(tungstenite::Message::Close(Some(frame)))) => {
+                    assert_eq!(u16::from(frame.code), 1008);
+                    let payload: CloseReasonPayload = serde_json::from_str(&frame.reason).unwrap();
+                    assert_eq!(payload.code, CloseReason::MessageTooLarge);
+                    assert!(!payload.retryable);
+                }
+                msg => panic!("expected close frame, got {:?}", msg),
+            };
+
+        // Oversized auth messages are rejected as well
+        let mut socket = connect().await;
+        socket
+            .send(tungstenite::Message::Text(format!(
+                "Password {}",
+                "x".repeat(64)
+            )))
+            .await
+            .unwrap();
+        expect_too_large(socket.next().await);
+
+        let mut socket = connect().await;
+        socket
+            .send(tungstenite::Message::Text("Password admin".to_string()))
+            .await
+            .unwrap();
+        let request = format!(
+            r#"{{"request":{{"request_id":"{}","body":{{"set_module_order":[]}}}}}}"#,
+            "x".repeat(64)
+        );
+        socket
+            .send(tungstenite::Message::Text(request))
+            .await
+            .unwrap();
+        loop {
+            match socket.next().await {
+                // The system info is sent after authenticating
+                Some(Ok(tungstenite::Message::Text(_))) => {}
+                msg => break expect_too_large(msg),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_watcher() {
+        use tokio_tungstenite::tungstenite;
+
+        let idle_timeout = Duration::from_millis(50);
+        let (presence_tx, presence_rx) = mpsc::unbounded_channel();
+        let (event_tx, mut event_rx) = mpsc::channel(8);
+        tokio::spawn(idle_watcher(idle_timeout, presence_rx, event_tx));
+
+        let started = Instant::now();
+        let event = time::timeout(Duration::from_secs(1), event_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(event, Some(Event::Idle)));
+        assert!(started.elapsed() >= idle_timeout);
+
+        presence_tx.send(ClientPresence::Connected).unwrap();
+        let event = time::timeout(Duration::from_secs(1), event_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(event, Some(Event::Resume)));
+
+        // No idle event while a client is connected
+        assert!(time::timeout(idle_timeout * 3, event_rx.recv())
+            .await
+            .is_err());
+
+        presence_tx.send(ClientPresence::Disconnected).unwrap();
+        let event = time::timeout(Duration::from_secs(1), event_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(event, Some(Event::Idle)));
+
+        // Only admins are reported to the idle watcher
+        let addr = spawn_test_server(WebContext {
+            client_presence_sender: Some(presence_tx),
+            ..test_context()
+        });
+        let connect = |password: &'static str| async move {
+            let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr))
+                .await
+                .unwrap();
+            socket
+                .send(tungstenite::Message::Text(format!("Password {}", password)))
+                .await
+                .unwrap();
+            // The system info is sent after the presence is reported
+            match socket.next().await {
+                Some(Ok(tungstenite::Message::Text(json))) => assert!(json.contains("system_info")),
+                msg => panic!("expected system info, got {:?}", msg),
+            }
+            socket
+        };
+        let _viewer = connect("viewer").await;
+        assert!(time::timeout(idle_timeout * 3, event_rx.recv())
+            .await
+            .is_err());
+
+        let _admin = connect("admin").await;
+        let event = time::timeout(Duration::from_secs(1), event_rx.recv())
+            .await
+            .unwrap();
+        assert!(matches!(event, Some(Event::Resume)));
+    }
+}
@@ -0,0 +1,51 @@
+use log::{LevelFilter, Log, Metadata, Record};
+use std::{
+    env, fs,
+    path::PathBuf,
+    process,
+    sync::{Mutex, Once},
+};
+
+/// Empty directory in the temp dir that is unique to the test `name` and this process
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("neopult-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+static CAPTURED_LOGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+struct CapturingLogger;
+
+impl Log for CapturingLogger {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        CAPTURED_LOGS
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", record.level(), record.args()));
+    }
+
+    fn flush(&self) {}
+}
+
+/// Starts capturing the log messages of all tests, see `captured_logs`. Tests run in parallel, so
+/// they have to look for messages that only they log.
+pub fn capture_logs() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        // Nothing is captured if another logger was installed first
+        if log::set_logger(&CapturingLogger).is_ok() {
+            log::set_max_level(LevelFilter::Debug);
+        }
+    });
+}
+
+/// Log messages since `capture_logs` was called first, prefixed with their level
+pub fn captured_logs() -> Vec<String> {
+    CAPTURED_LOGS.lock().unwrap().clone()
+}
@@ -1,24 +1,32 @@
 use anyhow::Context;
-use log::{debug, error, warn};
-use mlua::{Function, Lua, RegistryKey, Value};
+use log::{debug, error, info, warn};
+use mlua::{Function, Lua, RegistryKey, Table, Value};
 use std::{
     collections::HashMap,
-    fmt::Debug,
+    fmt::{self, Debug, Display, Formatter},
     str::{self, FromStr},
     sync::Arc,
 };
 use xcb::{randr, x, Connection, Xid};
 
+#[cfg(test)]
+pub mod fake_backend;
+
 const MANAGED_HINT: &str = "MANAGED";
 
 const MIN_Z: u16 = 1;
 const MAX_Z: u16 = 0;
 
+/// Properties are read up to this many 32-bit units (256 KiB)
+const PROPERTY_MAX_LONGS: u32 = 1 << 16;
+
 pub type ManagedWid = usize;
 
 #[derive(Debug)]
 pub struct ManagedWindow {
     id: ManagedWid,
+    /// Name of the plugin instance that claimed or created the window
+    owner: String,
     variant: WindowVariant,
     min_geometry: MinGeometry,
     mode: Mode,
@@ -46,11 +54,11 @@ pub struct Margin {
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-struct Geometry {
-    x: i16,
-    y: i16,
-    width: u16,
-    height: u16,
+pub struct Geometry {
+    pub x: i16,
+    pub y: i16,
+    pub width: u16,
+    pub height: u16,
 }
 
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
@@ -61,15 +69,29 @@ pub enum Alignment {
     BottomLeft,
 }
 
-impl ToString for Alignment {
-    fn to_string(&self) -> String {
-        match self {
+impl Display for Alignment {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let s = match self {
             Alignment::TopLeft => "lt",
             Alignment::TopRight => "rt",
             Alignment::BottomRight => "rb",
             Alignment::BottomLeft => "lb",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+impl FromStr for Alignment {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lt" => Ok(Alignment::TopLeft),
+            "rt" => Ok(Alignment::TopRight),
+            "rb" => Ok(Alignment::BottomRight),
+            "lb" => Ok(Alignment::BottomLeft),
+            _ => anyhow::bail!("alignment must be one of lt, rt, rb and lb, got {}", s),
         }
-        .to_string()
     }
 }
 
@@ -152,18 +174,19 @@ impl FromStr for AlignedGeometry {
 }
 
 impl AlignedGeometry {
-    fn as_geometry(&self, wm: &WindowManager) -> Geometry {
+    /// Windows that don't fit on the screen stick to the top or left edge
+    fn as_geometry(&self, (screen_width, screen_height): (u16, u16)) -> Geometry {
+        let right_x = screen_width
+            .saturating_sub(self.width)
+            .saturating_sub(self.x_offset);
+        let bottom_y = screen_height
+            .saturating_sub(self.height)
+            .saturating_sub(self.y_offset);
         let (x, y) = match self.alignment {
             Alignment::TopLeft => (self.x_offset, self.y_offset),
-            Alignment::TopRight => (wm.screen_width - self.width - self.x_offset, self.y_offset),
-            Alignment::BottomRight => (
-                wm.screen_width - self.width - self.x_offset,
-                wm.screen_height - self.height - self.y_offset,
-            ),
-            Alignment::BottomLeft => (
-                self.x_offset,
-                wm.screen_height - self.height - self.y_offset,
-            ),
+            Alignment::TopRight => (right_x, self.y_offset),
+            Alignment::BottomRight => (right_x, bottom_y),
+            Alignment::BottomLeft => (self.x_offset, bottom_y),
         };
         Geometry {
             x: x as i16,
@@ -174,10 +197,502 @@ impl AlignedGeometry {
     }
 }
 
+/// How the offsets of min geometries are adapted when the screen size changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Reanchor {
+    /// Offsets stay the same number of pixels away from the anchored corner
+    #[default]
+    Absolute,
+    /// Offsets are scaled with the screen size, relative to the screen size at startup
+    Proportional,
+}
+
+impl FromStr for Reanchor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "absolute" => Ok(Reanchor::Absolute),
+            "proportional" => Ok(Reanchor::Proportional),
+            _ => anyhow::bail!("unknown reanchor mode {}", s),
+        }
+    }
+}
+
+/// What happens when the output can't be set to the requested size, because no such mode exists
+/// and the X server doesn't support creating one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ModeFallback {
+    /// Use the largest existing mode of the output that fits into the requested size
+    #[default]
+    Nearest,
+    /// Fail the resolution change
+    Error,
+}
+
+impl FromStr for ModeFallback {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(ModeFallback::Nearest),
+            "error" => Ok(ModeFallback::Error),
+            _ => anyhow::bail!("unknown mode fallback {}", s),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum OutputMode<M> {
+    Existing { id: u32, size: (u16, u16) },
+    Created(M),
+}
+
+/// Color in the `#rrggbb` notation
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct Color {
+    red: u8,
+    green: u8,
+    blue: u8,
+}
+
+impl FromStr for Color {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("color must have the format #rrggbb, got {}", s);
+        }
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+        Ok(Color {
+            red: channel(0),
+            green: channel(2),
+            blue: channel(4),
+        })
+    }
+}
+
+/// Bit masks of the color channels in a pixel value, taken from the visual of the root window
+#[derive(Debug, Copy, Clone)]
+struct ColorMasks {
+    red: u32,
+    green: u32,
+    blue: u32,
+}
+
+impl ColorMasks {
+    // Tests of the plugin system use the fake backend instead of `XcbBackend`
+    #[cfg_attr(test, allow(dead_code))]
+    fn of_root_visual(screen: &x::Screen) -> Option<ColorMasks> {
+        screen
+            .allowed_depths()
+            .flat_map(|depth| depth.visuals())
+            .find(|visual| visual.visual_id() == screen.root_visual())
+            .map(|visual| ColorMasks {
+                red: visual.red_mask(),
+                green: visual.green_mask(),
+                blue: visual.blue_mask(),
+            })
+    }
+}
+
+/// Only works for TrueColor visuals, which is what VNC servers use.
+fn color_pixel(color: Color, masks: ColorMasks) -> u32 {
+    let scale = |value: u8, mask: u32| {
+        let bits = mask.count_ones();
+        let value = if bits >= 8 {
+            (value as u32) << (bits - 8)
+        } else {
+            (value as u32) >> (8 - bits)
+        };
+        (value << mask.trailing_zeros()) & mask
+    };
+    scale(color.red, masks.red) | scale(color.green, masks.green) | scale(color.blue, masks.blue)
+}
+
+/// Changing the background of a window doesn't repaint it, so the whole window has to be cleared
+/// afterwards.
+fn background_requests(window: x::Window, pixel: u32) -> ([x::Cw; 1], x::ClearArea) {
+    (
+        [x::Cw::BackPixel(pixel)],
+        x::ClearArea {
+            exposures: false,
+            window,
+            x: 0,
+            y: 0,
+            // Zero extends the area to the edges of the window
+            width: 0,
+            height: 0,
+        },
+    )
+}
+
+/// Border that is drawn around an X window to help locating it on the screen
+#[derive(Debug, Copy, Clone)]
+pub struct Highlight {
+    pub color: Color,
+    pub border_width: u16,
+}
+
+/// Identifies a highlight that was pushed with `WindowManager::push_highlight`
+pub type HighlightId = usize;
+
+/// Border of a highlighted window. Highlights can overlap, e.g. when a window is flashed again
+/// before the previous flash ended, so the latest highlight that wasn't removed yet is shown.
+#[derive(Debug)]
+struct HighlightedWindow {
+    /// Border width from before the window was highlighted
+    original_width: u16,
+    highlights: Vec<(HighlightId, Highlight)>,
+}
+
+fn border_requests(border_width: u16, pixel: u32) -> ([x::Cw; 1], [x::ConfigWindow; 1]) {
+    (
+        [x::Cw::BorderPixel(pixel)],
+        [x::ConfigWindow::BorderWidth(border_width as u32)],
+    )
+}
+
+/// Types of X window properties that plugins can read and set. Strings are written with format 8
+/// and cardinals with format 32, but cardinals of any format can be read.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum PropertyType {
+    String,
+    Utf8String,
+    Cardinal,
+}
+
+impl FromStr for PropertyType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(Self::String),
+            "utf8_string" => Ok(Self::Utf8String),
+            "cardinal" => Ok(Self::Cardinal),
+            _ => Err(anyhow::anyhow!(
+                "property type has to be \"string\", \"utf8_string\" or \"cardinal\""
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PropertyValue {
+    String(String),
+    Cardinals(Vec<u32>),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PropertyData {
+    Format8(Vec<u8>),
+    Format16(Vec<u16>),
+    Format32(Vec<u32>),
+}
+
+impl PropertyData {
+    /// Reads the value with the format that the property was set with, since any client can set
+    /// properties with any format. `GetPropertyReply::value` panics when it is read with another
+    /// format.
+    fn from_reply(reply: x::GetPropertyReply) -> Self {
+        let (format, reply) = property_format(reply);
+        match format {
+            8 => PropertyData::Format8(reply.value::<u8>().to_vec()),
+            16 => PropertyData::Format16(reply.value::<u16>().to_vec()),
+            32 => PropertyData::Format32(reply.value::<u32>().to_vec()),
+            // Unset properties have format 0
+            _ => PropertyData::Format8(vec![]),
+        }
+    }
+
+    fn format(&self) -> u8 {
+        match self {
+            PropertyData::Format8(_) => 8,
+            PropertyData::Format16(_) => 16,
+            PropertyData::Format32(_) => 32,
+        }
+    }
+
+    /// Returns the bytes of a string property. Values that were set with another format count as
+    /// empty.
+    fn into_bytes(self) -> Vec<u8> {
+        match self {
+            PropertyData::Format8(bytes) => bytes,
+            PropertyData::Format16(_) | PropertyData::Format32(_) => Vec::new(),
+        }
+    }
+}
+
+/// The X requests that window properties need, so that they can be tested without an X server
+trait PropertyRequests {
+    fn intern_atom(&self, name: &str) -> xcb::Result<x::Atom>;
+
+    /// Returns `None` if the property isn't set
+    fn property_type(&self, window: x::Window, property: x::Atom) -> xcb::Result<Option<x::Atom>>;
+
+    fn get_property(
+        &self,
+        window: x::Window,
+        property: x::Atom,
+        r#type: x::Atom,
+    ) -> xcb::Result<PropertyData>;
+
+    fn change_property(
+        &self,
+        window: x::Window,
+        property: x::Atom,
+        r#type: x::Atom,
+        data: &PropertyData,
+    ) -> xcb::Result<()>;
+}
+
+impl PropertyRequests for Connection {
+    fn intern_atom(&self, name: &str) -> xcb::Result<x::Atom> {
+        let cookie = self.send_request(&x::InternAtom {
+            only_if_exists: false,
+            name: name.as_bytes(),
+        });
+        Ok(self.wait_for_reply(cookie)?.atom())
+    }
+
+    fn property_type(&self, window: x::Window, property: x::Atom) -> xcb::Result<Option<x::Atom>> {
+        let cookie = self.send_request(&x::GetProperty {
+            delete: false,
+            window,
+            property,
+            r#type: x::ATOM_NONE,
+            long_offset: 0,
+            long_length: 0,
+        });
+        let r#type = self.wait_for_reply(cookie)?.r#type();
+        Ok((r#type != x::ATOM_NONE).then(|| r#type))
+    }
+
+    fn get_property(
+        &self,
+        window: x::Window,
+        property: x::Atom,
+        r#type: x::Atom,
+    ) -> xcb::Result<PropertyData> {
+        let cookie = self.send_request(&x::GetProperty {
+            delete: false,
+            window,
+            property,
+            r#type,
+            long_offset: 0,
+            long_length: PROPERTY_MAX_LONGS,
+        });
+        let reply = self.wait_for_reply(cookie)?;
+        Ok(PropertyData::from_reply(reply))
+    }
+
+    fn change_property(
+        &self,
+        window: x::Window,
+        property: x::Atom,
+        r#type: x::Atom,
+        data: &PropertyData,
+    ) -> xcb::Result<()> {
+        match data {
+            PropertyData::Format8(data) => self.send_and_check_request(&x::ChangeProperty {
+                mode: x::PropMode::Replace,
+                window,
+                property,
+                r#type,
+                data: data.as_slice(),
+            })?,
+            PropertyData::Format16(data) => self.send_and_check_request(&x::ChangeProperty {
+                mode: x::PropMode::Replace,
+                window,
+                property,
+                r#type,
+                data: data.as_slice(),
+            })?,
+            PropertyData::Format32(data) => self.send_and_check_request(&x::ChangeProperty {
+                mode: x::PropMode::Replace,
+                window,
+                property,
+                r#type,
+                data: data.as_slice(),
+            })?,
+        }
+        Ok(())
+    }
+}
+
+/// xcb doesn't expose the format of a property, so it is read from the reply itself
+fn property_format(reply: x::GetPropertyReply) -> (u8, x::GetPropertyReply) {
+    // SAFETY: The second byte of a GetProperty reply is the format. The pointer is turned back into
+    // the reply right away, so it is freed as usual.
+    unsafe {
+        let raw = xcb::Reply::into_raw(reply);
+        let format = *raw.add(1);
+        (format, xcb::Reply::from_raw(raw))
+    }
+}
+
+fn property_type_atom(
+    conn: &impl PropertyRequests,
+    property_type: PropertyType,
+) -> xcb::Result<x::Atom> {
+    match property_type {
+        PropertyType::String => Ok(x::ATOM_STRING),
+        PropertyType::Utf8String => conn.intern_atom("UTF8_STRING"),
+        PropertyType::Cardinal => Ok(x::ATOM_CARDINAL),
+    }
+}
+
+/// Returns `None` if the property isn't set and an error if it has an unsupported type.
+fn read_property(
+    conn: &impl PropertyRequests,
+    window: x::Window,
+    name: &str,
+) -> anyhow::Result<Option<PropertyValue>> {
+    let property = conn.intern_atom(name)?;
+    let r#type = match conn.property_type(window, property)? {
+        Some(r#type) => r#type,
+        None => return Ok(None),
+    };
+    let utf8_string = property_type_atom(conn, PropertyType::Utf8String)?;
+    let is_string = r#type == x::ATOM_STRING || r#type == utf8_string;
+    if !is_string && r#type != x::ATOM_CARDINAL {
+        anyhow::bail!("property {} has an unsupported type", name);
+    }
+    let value = match (is_string, conn.get_property(window, property, r#type)?) {
+        (true, PropertyData::Format8(bytes)) => {
+            PropertyValue::String(String::from_utf8_lossy(&bytes).into_owned())
+        }
+        (true, data) => anyhow::bail!(
+            "string property {} has format {} instead of 8",
+            name,
+            data.format()
+        ),
+        (false, PropertyData::Format8(cardinals)) => {
+            PropertyValue::Cardinals(cardinals.into_iter().map(u32::from).collect())
+        }
+        (false, PropertyData::Format16(cardinals)) => {
+            PropertyValue::Cardinals(cardinals.into_iter().map(u32::from).collect())
+        }
+        (false, PropertyData::Format32(cardinals)) => PropertyValue::Cardinals(cardinals),
+    };
+    Ok(Some(value))
+}
+
+fn write_property(
+    conn: &impl PropertyRequests,
+    window: x::Window,
+    name: &str,
+    property_type: PropertyType,
+    value: PropertyValue,
+) -> anyhow::Result<()> {
+    let data = match (property_type, value) {
+        (PropertyType::String | PropertyType::Utf8String, PropertyValue::String(value)) => {
+            PropertyData::Format8(value.into_bytes())
+        }
+        (PropertyType::Cardinal, PropertyValue::Cardinals(cardinals)) => {
+            PropertyData::Format32(cardinals)
+        }
+        (property_type, value) => {
+            anyhow::bail!(
+                "{:?} is no valid value for a {:?} property",
+                value,
+                property_type
+            )
+        }
+    };
+    let property = conn.intern_atom(name)?;
+    let r#type = property_type_atom(conn, property_type)?;
+    conn.change_property(window, property, r#type, &data)?;
+    Ok(())
+}
+
+/// Picks the output mode for `size`: an existing mode of the output with exactly that size, a mode
+/// made by `create_mode` or, if the mode can't be created, the mode chosen by `fallback`.
+fn select_output_mode<M>(
+    output_modes: &[randr::ModeInfo],
+    (width, height): (u16, u16),
+    fallback: ModeFallback,
+    create_mode: impl FnOnce() -> anyhow::Result<M>,
+) -> anyhow::Result<OutputMode<M>> {
+    let existing = |mode: &randr::ModeInfo| OutputMode::Existing {
+        id: mode.id,
+        size: (mode.width, mode.height),
+    };
+    if let Some(mode) = output_modes
+        .iter()
+        .find(|m| m.width == width && m.height == height)
+    {
+        return Ok(existing(mode));
+    }
+
+    let e = match create_mode() {
+        Ok(mode) => return Ok(OutputMode::Created(mode)),
+        Err(e) => e,
+    };
+    match fallback {
+        ModeFallback::Error => Err(e.context(format!(
+            "couldn't create mode {}x{} and the fallback is disabled",
+            width, height
+        ))),
+        ModeFallback::Nearest => {
+            let nearest = output_modes
+                .iter()
+                .filter(|m| m.width <= width && m.height <= height)
+                .min_by_key(|m| (width - m.width) as u32 + (height - m.height) as u32);
+            match nearest {
+                Some(mode) => {
+                    warn!(
+                        "couldn't create mode {}x{} ({:#}), using nearest mode {}x{} instead",
+                        width, height, e, mode.width, mode.height
+                    );
+                    Ok(existing(mode))
+                }
+                None => Err(e.context(format!(
+                    "couldn't create mode {}x{} and the output has no smaller mode",
+                    width, height
+                ))),
+            }
+        }
+    }
+}
+
+impl AlignedGeometry {
+    fn reanchored(
+        &self,
+        reanchor: Reanchor,
+        (reference_width, reference_height): (u16, u16),
+        (screen_width, screen_height): (u16, u16),
+    ) -> AlignedGeometry {
+        let scale = |offset: u16, screen: u16, reference: u16| {
+            if reference == 0 {
+                offset
+            } else {
+                (offset as u32 * screen as u32 / reference as u32) as u16
+            }
+        };
+        match reanchor {
+            Reanchor::Absolute => *self,
+            Reanchor::Proportional => AlignedGeometry {
+                x_offset: scale(self.x_offset, screen_width, reference_width),
+                y_offset: scale(self.y_offset, screen_height, reference_height),
+                ..*self
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum MinGeometry {
     Fixed(AlignedGeometry),
-    Dynamic { callback_key: Arc<RegistryKey> },
+    Dynamic {
+        callback_key: Arc<RegistryKey>,
+    },
+    /// Keeps the window centered on the screen, even when the screen size changes
+    Centered {
+        width: u16,
+        height: u16,
+    },
 }
 
 impl Default for MinGeometry {
@@ -196,9 +711,28 @@ impl FromStr for MinGeometry {
 }
 
 impl MinGeometry {
-    fn get_geometry(&self, lua: &Lua) -> AlignedGeometry {
+    /// Reads a fixed geometry from a table with the keys `width`, `height`, `x_offset`, `y_offset`
+    /// and `alignment`. Only `width` and `height` are required.
+    pub fn from_table(table: &Table) -> anyhow::Result<MinGeometry> {
+        let alignment = match table.get::<_, Option<String>>("alignment")? {
+            Some(alignment) => alignment.parse()?,
+            None => Alignment::TopLeft,
+        };
+        Ok(MinGeometry::Fixed(AlignedGeometry {
+            x_offset: table.get::<_, Option<u16>>("x_offset")?.unwrap_or(0),
+            y_offset: table.get::<_, Option<u16>>("y_offset")?.unwrap_or(0),
+            width: table.get("width").context("width is required")?,
+            height: table.get("height").context("height is required")?,
+            alignment,
+        }))
+    }
+
+    fn get_geometry(&self, lua: &Lua, screen_size: (u16, u16)) -> AlignedGeometry {
         match self {
             MinGeometry::Fixed(aligned_geometry) => *aligned_geometry,
+            MinGeometry::Centered { width, height } => {
+                centered_geometry(screen_size, (*width, *height))
+            }
             MinGeometry::Dynamic { callback_key } => {
                 match lua.registry_value::<Function>(callback_key) {
                     Ok(min_geometry_cb) => match min_geometry_cb.call::<_, String>(()) {
@@ -233,6 +767,213 @@ impl MinGeometry {
     }
 }
 
+fn centered_geometry(
+    (screen_width, screen_height): (u16, u16),
+    (width, height): (u16, u16),
+) -> AlignedGeometry {
+    AlignedGeometry {
+        x_offset: screen_width.saturating_sub(width) / 2,
+        y_offset: screen_height.saturating_sub(height) / 2,
+        width,
+        height,
+        alignment: Alignment::TopLeft,
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub struct DisplayMode {
+    pub width: u16,
+    pub height: u16,
+}
+
+/// RandR output as seen by the window manager
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct OutputInfo {
+    pub name: String,
+    pub connected: bool,
+    /// Mode of the CRTC the output is attached to, if any
+    pub current_mode: Option<DisplayMode>,
+    /// Modes supported by the output
+    pub modes: Vec<DisplayMode>,
+}
+
+impl OutputInfo {
+    /// Resolves the mode ids of an output against the modes of the screen resources.
+    fn new(
+        name: String,
+        connected: bool,
+        current_mode_id: Option<u32>,
+        output_mode_ids: &[u32],
+        screen_modes: &[randr::ModeInfo],
+    ) -> Self {
+        let find_mode = |id: u32| {
+            screen_modes
+                .iter()
+                .find(|mode| mode.id == id)
+                .map(|mode| DisplayMode {
+                    width: mode.width,
+                    height: mode.height,
+                })
+        };
+        Self {
+            name,
+            connected,
+            current_mode: current_mode_id.and_then(find_mode),
+            modes: output_mode_ids
+                .iter()
+                .copied()
+                .filter_map(find_mode)
+                .collect(),
+        }
+    }
+}
+
+/// Top level X window that could be claimed, because it isn't managed yet
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ClaimableWindow {
+    pub window_id: u32,
+    /// Instance and class name of WM_CLASS separated by a space
+    pub class: String,
+    pub name: String,
+}
+
+/// Managed window as reported by `WindowManager::list_windows`
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct WindowInfo {
+    pub id: ManagedWid,
+    /// Name of the plugin instance that claimed or created the window
+    pub owner: String,
+    /// Name of virtual windows, X windows don't have one
+    pub name: Option<String>,
+    pub mode: Mode,
+    pub primary: bool,
+}
+
+fn window_infos(
+    managed_windows: &HashMap<ManagedWid, ManagedWindow>,
+    primary_window: Option<ManagedWid>,
+) -> Vec<WindowInfo> {
+    let mut infos: Vec<WindowInfo> = managed_windows
+        .values()
+        .map(|window| WindowInfo {
+            id: window.id,
+            owner: window.owner.clone(),
+            name: match &window.variant {
+                WindowVariant::XWindow { .. } => None,
+                WindowVariant::VirtualWindow { name, .. } => Some(name.clone()),
+            },
+            mode: window.mode,
+            primary: primary_window == Some(window.id),
+        })
+        .collect();
+    infos.sort_by_key(|info| info.id);
+    infos
+}
+
+/// The max window with the highest priority
+fn find_primary_window(managed_windows: &HashMap<ManagedWid, ManagedWindow>) -> Option<ManagedWid> {
+    managed_windows
+        .values()
+        .filter(|w| matches!(w.mode, Mode::Max { .. }))
+        .max_by_key(|w| match w.mode {
+            Mode::Max { priority, .. } => priority,
+            _ => unreachable!(),
+        })
+        .map(|w| w.id)
+}
+
+/// Stops managing all windows of `owner` and finds a new primary window if the primary window was
+/// one of them. Returns the managed wids of the released windows.
+fn remove_owned_windows(
+    managed_windows: &mut HashMap<ManagedWid, ManagedWindow>,
+    primary_window: &mut Option<ManagedWid>,
+    owner: &str,
+) -> Vec<ManagedWid> {
+    let mut released: Vec<ManagedWid> = managed_windows
+        .values()
+        .filter(|window| window.owner == owner)
+        .map(|window| window.id)
+        .collect();
+    released.sort_unstable();
+    for id in released.iter() {
+        managed_windows.remove(id);
+    }
+    if matches!(primary_window, Some(id) if released.contains(id)) {
+        *primary_window = find_primary_window(managed_windows);
+    }
+    released
+}
+
+/// Compares the raw bytes, since other clients may set the property to anything, including invalid
+/// UTF-8
+fn is_managed_hint(value: &[u8]) -> bool {
+    value == MANAGED_HINT.as_bytes()
+}
+
+/// A window whose class matches the class that should be claimed
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClaimCandidate {
+    Unmanaged(x::Window),
+    /// The window is already managed. `managed_wid` and `owner` are only known when the window is
+    /// managed by this neopult instance and not by another one.
+    Managed {
+        window: x::Window,
+        managed_wid: Option<ManagedWid>,
+        owner: Option<String>,
+    },
+}
+
+fn managed_claim_candidate(
+    window: x::Window,
+    managed_windows: &HashMap<ManagedWid, ManagedWindow>,
+) -> ClaimCandidate {
+    let managed_window = managed_windows.values().find(|managed_window| {
+        matches!(managed_window.variant, WindowVariant::XWindow { window: w } if w == window)
+    });
+    ClaimCandidate::Managed {
+        window,
+        managed_wid: managed_window.map(|managed_window| managed_window.id),
+        owner: managed_window.map(|managed_window| managed_window.owner.clone()),
+    }
+}
+
+/// Raw WM_CLASS, WM_NAME and managed hint of a top level X window, which any client may set with
+/// any format
+#[derive(Debug, Clone)]
+pub struct TopLevelProperties {
+    window: x::Window,
+    class: PropertyData,
+    name: PropertyData,
+    managed: PropertyData,
+}
+
+/// Properties of a top level X window that are relevant for claiming it
+#[derive(Debug)]
+struct TopLevelWindow {
+    window_id: u32,
+    /// Raw WM_CLASS value, instance and class name are null-terminated
+    class: String,
+    name: String,
+    managed: bool,
+}
+
+fn claimable_windows(windows: Vec<TopLevelWindow>, class_filter: &str) -> Vec<ClaimableWindow> {
+    windows
+        .into_iter()
+        .filter(|window| !window.managed && window.class.contains(class_filter))
+        .map(|window| ClaimableWindow {
+            window_id: window.window_id,
+            class: window
+                .class
+                .split('\0')
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+            name: window.name,
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct VirtualWindowCallbacks {
     pub set_geometry_key: RegistryKey,
@@ -240,19 +981,33 @@ pub struct VirtualWindowCallbacks {
     pub unmap_key: RegistryKey,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Lets frontends associate the content they render with a virtual window. Passed to the
+/// `set_geometry` callback alongside the geometry.
+#[derive(Debug, Default)]
+pub struct FrontendHint {
+    pub frontend_id: Option<String>,
+    pub metadata_key: Option<RegistryKey>,
+}
+
+impl FrontendHint {
+    fn to_lua_table<'lua>(&self, lua: &'lua Lua) -> mlua::Result<Table<'lua>> {
+        let table = lua.create_table()?;
+        table.set("id", self.frontend_id.as_deref())?;
+        if let Some(metadata_key) = &self.metadata_key {
+            table.set("metadata", lua.registry_value::<Value>(metadata_key)?)?;
+        }
+        Ok(table)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
 pub enum PrimaryDemotionAction {
+    #[default]
     DoNothing,
     MakeMin,
     Hide,
 }
 
-impl Default for PrimaryDemotionAction {
-    fn default() -> Self {
-        Self::DoNothing
-    }
-}
-
 impl FromStr for PrimaryDemotionAction {
     type Err = anyhow::Error;
 
@@ -275,124 +1030,336 @@ enum WindowVariant {
         name: String,
         callbacks: VirtualWindowCallbacks,
         primary_demotion_action: PrimaryDemotionAction,
+        frontend_hint: FrontendHint,
     },
 }
 
-// NOTE: Remember to adjust Debug implementation when changing something here
-pub struct WindowManager {
+/// The X requests that the window manager needs for managing windows. Tests use a fake backend, so
+/// that the window management logic can be tested without an X server.
+pub trait XBackend: Debug {
+    /// Request that was sent, but whose result wasn't checked yet
+    type Cookie;
+
+    /// Connects to the X server and returns the backend along with the screen size
+    fn connect() -> anyhow::Result<(Self, (u16, u16))>
+    where
+        Self: Sized;
+    /// Whether the connection to the X server broke
+    fn is_disconnected(&self) -> bool;
+    /// Replaces the broken connection and returns the current screen size
+    fn reconnect(&mut self) -> anyhow::Result<(u16, u16)>;
+    fn map_window(&self, window: x::Window) -> xcb::Result<()>;
+    fn unmap_window(&self, window: x::Window) -> xcb::Result<()>;
+    /// Moves, resizes and raises the window without waiting for the X server
+    fn send_configure_window(&self, window: x::Window, geometry: Geometry) -> Self::Cookie;
+    fn check_request(&self, cookie: Self::Cookie) -> xcb::Result<()>;
+    /// Minimum and maximum size of the screen
+    fn screen_size_range(&self) -> xcb::Result<((u16, u16), (u16, u16))>;
+    /// Size of the output that shows the screen
+    fn output_size(&self) -> xcb::Result<(u16, u16)>;
+    fn set_screen_size(&self, size: (u16, u16)) -> xcb::Result<()>;
+    /// Returns the size of the mode that was set, which differs from the requested size when the
+    /// mode fallback was used.
+    fn set_output_size(
+        &self,
+        size: (u16, u16),
+        mode_fallback: ModeFallback,
+    ) -> anyhow::Result<(u16, u16)>;
+    /// Fills the root window with the pixel value
+    fn clear_background(&self, pixel: u32) -> xcb::Result<()>;
+    /// Pixel value of the color in the visual of the root window
+    fn color_pixel(&self, color: Color) -> anyhow::Result<u32>;
+    /// X can't report the border color of a window, so borders are restored to the one that
+    /// windows get by default
+    fn default_border_pixel(&self) -> u32;
+    fn border_width(&self, window: x::Window) -> xcb::Result<u16>;
+    fn set_border(&self, window: x::Window, border_width: u16, pixel: u32) -> xcb::Result<()>;
+    /// Marks the window as managed, so that no other neopult instance claims it
+    fn set_managed_hint(&self, window: x::Window) -> xcb::Result<()>;
+    fn remove_managed_hint(&self, window: x::Window) -> xcb::Result<()>;
+    /// Reads the properties of all top level windows. Windows whose properties can't be read,
+    /// e.g. because they were destroyed in the meantime, are left out.
+    fn top_level_windows(&self) -> anyhow::Result<Vec<TopLevelProperties>>;
+    /// RandR outputs of the screen
+    fn display_info(&self) -> anyhow::Result<Vec<OutputInfo>>;
+    /// Returns `None` if the property isn't set
+    fn read_property(&self, window: x::Window, name: &str)
+        -> anyhow::Result<Option<PropertyValue>>;
+    fn write_property(
+        &self,
+        window: x::Window,
+        name: &str,
+        property_type: PropertyType,
+        value: PropertyValue,
+    ) -> anyhow::Result<()>;
+}
+
+#[cfg_attr(test, allow(dead_code))]
+pub struct XcbBackend {
     conn: Connection,
     screen: x::ScreenBuf,
-    screen_height: u16,
-    screen_width: u16,
-    current_id: ManagedWid,
-    managed_windows: HashMap<ManagedWid, ManagedWindow>,
-    primary_window: Option<ManagedWid>,
     managed_atom: x::Atom,
 }
 
 // xcb::Connection doesn't implement Debug, so we have to implement Debug ourselves
-impl Debug for WindowManager {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.debug_struct("WindowManager")
+impl Debug for XcbBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XcbBackend")
             .field("screen", &self.screen)
-            .field("screen_height", &self.screen_height)
-            .field("screen_width", &self.screen_width)
-            .field("current_id", &self.screen)
-            .field("managed_windows", &self.managed_windows)
-            .field("primary_window", &self.primary_window)
             .field("managed_atom", &self.managed_atom)
             .finish()
     }
 }
 
-impl WindowManager {
-    pub fn init() -> anyhow::Result<Self> {
-        match std::env::var("DISPLAY") {
-            Ok(display) => debug!("DISPLAY environment variable is {}", display),
-            Err(std::env::VarError::NotPresent) => debug!("DISPLAY environment varibale isn't set"),
-            Err(std::env::VarError::NotUnicode(_)) => {
-                warn!("DISPLAY environment varibale isn't valid UTF-8")
-            }
-        }
+impl XBackend for XcbBackend {
+    type Cookie = xcb::VoidCookieChecked;
 
-        let (conn, screen_num) = xcb::Connection::connect(None).context(
-            "couldn't connect to the x server, setting the DISPLAY \
-            environment variable may solve the problem",
-        )?;
+    fn connect() -> anyhow::Result<(Self, (u16, u16))> {
+        connect_x_server()
+    }
 
-        let setup = conn.get_setup();
-        let screen = setup.roots().nth(screen_num as usize).unwrap().to_owned();
+    fn is_disconnected(&self) -> bool {
+        self.conn.has_error().is_err()
+    }
 
-        let screen_res_cookie = conn.send_request(&randr::GetScreenResources {
-            window: screen.root(),
-        });
-        let screen_res_reply = conn
-            .wait_for_reply(screen_res_cookie)
-            .context("error while waiting for GetScreenResources reply")?;
-        let output = screen_res_reply.outputs()[0];
-        let crtc = screen_res_reply.crtcs()[0];
+    fn reconnect(&mut self) -> anyhow::Result<(u16, u16)> {
+        let (backend, screen_size) = connect_x_server()?;
+        *self = backend;
+        Ok(screen_size)
+    }
+
+    fn map_window(&self, window: x::Window) -> xcb::Result<()> {
+        self.conn.send_and_check_request(&x::MapWindow { window })?;
+        Ok(())
+    }
+
+    fn unmap_window(&self, window: x::Window) -> xcb::Result<()> {
+        self.conn
+            .send_and_check_request(&x::UnmapWindow { window })?;
+        Ok(())
+    }
+
+    fn send_configure_window(&self, window: x::Window, geometry: Geometry) -> Self::Cookie {
+        self.conn.send_request_checked(&x::ConfigureWindow {
+            window,
+            value_list: &[
+                x::ConfigWindow::X(geometry.x as i32),
+                x::ConfigWindow::Y(geometry.y as i32),
+                x::ConfigWindow::Width(geometry.width as u32),
+                x::ConfigWindow::Height(geometry.height as u32),
+                // This also raises the window
+                x::ConfigWindow::StackMode(x::StackMode::Above),
+            ],
+        })
+    }
+
+    fn check_request(&self, cookie: Self::Cookie) -> xcb::Result<()> {
+        self.conn.check_request(cookie)?;
+        Ok(())
+    }
+
+    fn screen_size_range(&self) -> xcb::Result<((u16, u16), (u16, u16))> {
+        let cookie = self.conn.send_request(&randr::GetScreenSizeRange {
+            window: self.screen.root(),
+        });
+        let range = self.conn.wait_for_reply(cookie)?;
+        Ok((
+            (range.min_width(), range.min_height()),
+            (range.max_width(), range.max_height()),
+        ))
+    }
+
+    fn output_size(&self) -> xcb::Result<(u16, u16)> {
+        let cookie = self.conn.send_request(&randr::GetScreenResources {
+            window: self.screen.root(),
+        });
+        let screen_resources = self.conn.wait_for_reply(cookie)?;
 
-        let crtc_info_cookie = conn.send_request(&randr::GetCrtcInfo {
+        let crtc = *screen_resources
+            .crtcs()
+            .first()
+            .expect("no crtc in screen resources");
+
+        let cookie = self.conn.send_request(&randr::GetCrtcInfo {
             crtc,
             config_timestamp: x::CURRENT_TIME,
         });
+        let crtc_info = self.conn.wait_for_reply(cookie)?;
+        Ok((crtc_info.width(), crtc_info.height()))
+    }
+
+    fn set_screen_size(&self, (width, height): (u16, u16)) -> xcb::Result<()> {
+        self.conn.send_and_check_request(&randr::SetScreenSize {
+            window: self.screen.root(),
+            width,
+            height,
+            // These two don't really matter for displays without a physical monitor
+            mm_width: 200,
+            mm_height: 200,
+        })?;
+        Ok(())
+    }
+
+    fn set_output_size(
+        &self,
+        (width, height): (u16, u16),
+        mode_fallback: ModeFallback,
+    ) -> anyhow::Result<(u16, u16)> {
+        let cookie = self.conn.send_request(&randr::GetScreenResources {
+            window: self.screen.root(),
+        });
+        let screen_resources = self.conn.wait_for_reply(cookie)?;
+        let output = screen_resources.outputs()[0];
+        let crtc = screen_resources.crtcs()[0];
 
-        let output_info_cookie = conn.send_request(&randr::GetOutputInfo {
+        let cookie = self.conn.send_request(&randr::GetOutputInfo {
             output,
             config_timestamp: x::CURRENT_TIME,
         });
+        let output_info = self.conn.wait_for_reply(cookie)?;
+        let output_modes: Vec<randr::ModeInfo> = screen_resources
+            .modes()
+            .iter()
+            .filter(|m| {
+                output_info
+                    .modes()
+                    .iter()
+                    .any(|om| om.resource_id() == m.id)
+            })
+            .copied()
+            .collect();
+
+        let output_mode =
+            select_output_mode(&output_modes, (width, height), mode_fallback, || {
+                let id = self.conn.generate_id::<randr::Mode>().resource_id();
+                let name_len = width.to_string().len() + height.to_string().len() + 1;
+                let name = format!("{}x{}", width, height);
+                debug!(
+                    "new mode, id: {:?}, name_len: {}, name: {}",
+                    id, name_len, name
+                );
+                // Values reverse engineered from GetScreenResources output and existing modes
+                let cookie = self.conn.send_request(&randr::CreateMode {
+                    window: self.screen.root(),
+                    mode_info: randr::ModeInfo {
+                        id,
+                        width,
+                        height,
+                        name_len: name_len as u16,
+                        dot_clock: 60 * width as u32 * height as u32, // 60 fps
+                        hsync_start: 0,
+                        hsync_end: 0,
+                        htotal: width,
+                        hskew: 0,
+                        vsync_start: 0,
+                        vsync_end: 0,
+                        vtotal: height,
+                        mode_flags: randr::ModeFlag::empty(),
+                    },
+                    name: name.as_bytes(),
+                });
+                let create_mode_resp = self
+                    .conn
+                    .wait_for_reply(cookie)
+                    .context("CreateMode failed")?;
+                let mode = create_mode_resp.mode();
+
+                self.conn
+                    .send_and_check_request(&randr::AddOutputMode { output, mode })
+                    .context("AddOutputMode failed")?;
+
+                Ok(mode)
+            })?;
+        let (mode, size) = match output_mode {
+            OutputMode::Existing { id, size } => {
+                let mode = *output_info
+                    .modes()
+                    .iter()
+                    .find(|m| m.resource_id() == id)
+                    .expect("selected mode is not on the output");
+                (mode, size)
+            }
+            OutputMode::Created(mode) => (mode, (width, height)),
+        };
+
+        let cookie = self.conn.send_request(&randr::SetCrtcConfig {
+            crtc,
+            timestamp: x::CURRENT_TIME,
+            config_timestamp: x::CURRENT_TIME,
+            x: 0,
+            y: 0,
+            mode,
+            rotation: randr::Rotation::ROTATE_0,
+            outputs: &[output],
+        });
+        let _ = self.conn.wait_for_reply(cookie)?;
 
-        let crtc_info_reply = conn
-            .wait_for_reply(crtc_info_cookie)
-            .context("error while waiting for GetCrtcInfo reply")?;
-        let screen_width = crtc_info_reply.width();
-        let screen_height = crtc_info_reply.height();
-        debug!("screen has size {}x{}", screen_width, screen_height);
+        Ok(size)
+    }
 
-        let output_info_reply = conn
-            .wait_for_reply(output_info_cookie)
-            .context("error while waiting for GetOutputInfo reply")?;
-        let output_name = String::from_utf8_lossy(output_info_reply.name());
+    fn clear_background(&self, pixel: u32) -> xcb::Result<()> {
+        let (value_list, clear_area) = background_requests(self.screen.root(), pixel);
+        self.conn
+            .send_and_check_request(&x::ChangeWindowAttributes {
+                window: self.screen.root(),
+                value_list: &value_list,
+            })?;
+        self.conn.send_and_check_request(&clear_area)?;
+        Ok(())
+    }
 
-        if !output_name.starts_with("VNC") {
-            anyhow::bail!(
-                "the x server isn't a vnc server, setting the DISPLAY \
-                environment variable may solve the problem"
-            );
-        }
+    fn color_pixel(&self, color: Color) -> anyhow::Result<u32> {
+        let masks = ColorMasks::of_root_visual(&self.screen)
+            .context("couldn't find the visual of the root window")?;
+        Ok(color_pixel(color, masks))
+    }
 
-        let managed_atom_name = "_NEOPULT_MANAGED";
-        debug!(
-            "creating intern atom for managed state with name {}",
-            managed_atom_name
-        );
+    fn default_border_pixel(&self) -> u32 {
+        // Windows inherit the border of the root window unless their client sets one
+        self.screen.black_pixel()
+    }
 
-        let cookie = conn.send_request(&x::InternAtom {
-            only_if_exists: false,
-            name: managed_atom_name.as_bytes(),
+    fn border_width(&self, window: x::Window) -> xcb::Result<u16> {
+        let cookie = self.conn.send_request(&x::GetGeometry {
+            drawable: x::Drawable::Window(window),
         });
-        let reply = conn
-            .wait_for_reply(cookie)
-            .context("error while waiting for intern atom reply")?;
-        let managed_atom = reply.atom();
+        Ok(self.conn.wait_for_reply(cookie)?.border_width())
+    }
 
-        Ok(WindowManager {
-            conn,
-            screen,
-            screen_height,
-            screen_width,
-            current_id: 0,
-            managed_windows: HashMap::new(),
-            primary_window: None,
-            managed_atom,
-        })
+    fn set_border(&self, window: x::Window, border_width: u16, pixel: u32) -> xcb::Result<()> {
+        let (value_list, config_values) = border_requests(border_width, pixel);
+        self.conn
+            .send_and_check_request(&x::ChangeWindowAttributes {
+                window,
+                value_list: &value_list,
+            })?;
+        self.conn.send_and_check_request(&x::ConfigureWindow {
+            window,
+            value_list: &config_values,
+        })?;
+        Ok(())
     }
 
-    pub fn get_window_by_class(
-        &self,
-        to_claim: &str,
-        ignore_managed: bool,
-    ) -> anyhow::Result<Option<x::Window>> {
+    fn set_managed_hint(&self, window: x::Window) -> xcb::Result<()> {
+        self.conn.send_and_check_request(&x::ChangeProperty {
+            mode: x::PropMode::Replace,
+            window,
+            property: self.managed_atom,
+            r#type: x::ATOM_STRING,
+            data: MANAGED_HINT.as_bytes(),
+        })?;
+        Ok(())
+    }
+
+    fn remove_managed_hint(&self, window: x::Window) -> xcb::Result<()> {
+        self.conn.send_and_check_request(&x::DeleteProperty {
+            window,
+            property: self.managed_atom,
+        })?;
+        Ok(())
+    }
+
+    fn top_level_windows(&self) -> anyhow::Result<Vec<TopLevelProperties>> {
         let cookie = self.conn.send_request(&x::QueryTree {
             window: self.screen.root(),
         });
@@ -401,102 +1368,407 @@ impl WindowManager {
             .wait_for_reply(cookie)
             .context("error while waiting for QueryTree reply")?;
 
-        let children: &[x::Window] = reply.children();
-
-        let mut children_cookies = Vec::with_capacity(children.len());
-
-        for child in children.iter() {
-            let class_cookie = self.conn.send_request(&x::GetProperty {
-                delete: false,
-                window: *child,
-                property: x::ATOM_WM_CLASS,
-                r#type: x::ATOM_STRING,
-                long_offset: 0,
-                // Amount of chars of name to retrieve
-                long_length: 128,
-            });
-            let managed_cookie = self.conn.send_request(&x::GetProperty {
-                delete: false,
-                window: *child,
-                property: self.managed_atom,
-                r#type: x::ATOM_STRING,
-                long_offset: 0,
-                long_length: MANAGED_HINT.len() as u32,
+        let property_cookies: Vec<_> = reply
+            .children()
+            .iter()
+            .map(|&window| {
+                let get_property = |property, r#type, long_length| {
+                    self.conn.send_request(&x::GetProperty {
+                        delete: false,
+                        window,
+                        property,
+                        r#type,
+                        long_offset: 0,
+                        long_length,
+                    })
+                };
+                (
+                    window,
+                    // Amount of chars of the class and name to retrieve
+                    get_property(x::ATOM_WM_CLASS, x::ATOM_STRING, 128),
+                    get_property(x::ATOM_WM_NAME, x::ATOM_ANY, 128),
+                    get_property(self.managed_atom, x::ATOM_STRING, MANAGED_HINT.len() as u32),
+                )
+            })
+            .collect();
+
+        let mut windows = Vec::with_capacity(property_cookies.len());
+        for (window, class_cookie, name_cookie, managed_cookie) in property_cookies {
+            let replies = self.conn.wait_for_reply(class_cookie).and_then(|class| {
+                let name = self.conn.wait_for_reply(name_cookie)?;
+                let managed = self.conn.wait_for_reply(managed_cookie)?;
+                Ok((class, name, managed))
             });
-            children_cookies.push((class_cookie, managed_cookie));
-        }
-
-        for ((name_cookie, managed_cookie), &window) in children_cookies.into_iter().zip(children) {
-            match self.conn.wait_for_reply(name_cookie) {
-                Err(e) => error!("error while waiting for WM_CLASS reply: {}", e),
-                Ok(class_reply) => {
-                    let class = String::from_utf8_lossy(class_reply.value());
-                    if class.contains(to_claim) {
-                        let managed_reply = self.conn.wait_for_reply(managed_cookie)?;
-                        let managed_hint = str::from_utf8(managed_reply.value()).unwrap();
-                        if ignore_managed || managed_hint != MANAGED_HINT {
-                            return Ok(Some(window));
-                        }
-                    }
-                }
+            match replies {
+                Ok((class, name, managed)) => windows.push(TopLevelProperties {
+                    window,
+                    class: PropertyData::from_reply(class),
+                    name: PropertyData::from_reply(name),
+                    managed: PropertyData::from_reply(managed),
+                }),
+                Err(e) => error!(
+                    "error while reading properties of window {}: {}",
+                    window.resource_id(),
+                    e
+                ),
             }
         }
+        Ok(windows)
+    }
+
+    fn display_info(&self) -> anyhow::Result<Vec<OutputInfo>> {
+        let cookie = self.conn.send_request(&randr::GetScreenResources {
+            window: self.screen.root(),
+        });
+        let screen_resources = self
+            .conn
+            .wait_for_reply(cookie)
+            .context("error while waiting for GetScreenResources reply")?;
+
+        let output_info_cookies: Vec<_> = screen_resources
+            .outputs()
+            .iter()
+            .map(|&output| {
+                self.conn.send_request(&randr::GetOutputInfo {
+                    output,
+                    config_timestamp: x::CURRENT_TIME,
+                })
+            })
+            .collect();
+
+        let mut outputs = Vec::with_capacity(output_info_cookies.len());
+        for cookie in output_info_cookies {
+            let output_info = self
+                .conn
+                .wait_for_reply(cookie)
+                .context("error while waiting for GetOutputInfo reply")?;
+
+            let crtc = output_info.crtc();
+            let current_mode_id = if crtc.is_none() {
+                None
+            } else {
+                let cookie = self.conn.send_request(&randr::GetCrtcInfo {
+                    crtc,
+                    config_timestamp: x::CURRENT_TIME,
+                });
+                let crtc_info = self
+                    .conn
+                    .wait_for_reply(cookie)
+                    .context("error while waiting for GetCrtcInfo reply")?;
+                Some(crtc_info.mode().resource_id())
+            };
+
+            let output_mode_ids: Vec<u32> = output_info
+                .modes()
+                .iter()
+                .map(|mode| mode.resource_id())
+                .collect();
+
+            outputs.push(OutputInfo::new(
+                String::from_utf8_lossy(output_info.name()).into_owned(),
+                output_info.connection() == randr::Connection::Connected,
+                current_mode_id,
+                &output_mode_ids,
+                screen_resources.modes(),
+            ));
+        }
 
-        Ok(None)
+        Ok(outputs)
     }
 
-    pub fn manage_x_window(
-        &mut self,
-        lua: &Lua,
+    fn read_property(
+        &self,
         window: x::Window,
-        min_geometry: MinGeometry,
-    ) -> xcb::Result<ManagedWid> {
-        self.conn.send_and_check_request(&x::ChangeProperty {
-            mode: x::PropMode::Replace,
-            window,
-            property: self.managed_atom,
-            r#type: x::ATOM_STRING,
-            data: MANAGED_HINT.as_bytes(),
-        })?;
-        let id = self.current_id;
-        let managed_window = ManagedWindow {
-            id,
-            variant: WindowVariant::XWindow { window },
-            min_geometry,
-            mode: Mode::Min,
-        };
+        name: &str,
+    ) -> anyhow::Result<Option<PropertyValue>> {
+        read_property(&self.conn, window, name)
+    }
 
-        let geometry = managed_window.min_geometry.get_geometry(lua);
-        self.change_window_geometry(lua, &managed_window, geometry, MIN_Z)?;
+    fn write_property(
+        &self,
+        window: x::Window,
+        name: &str,
+        property_type: PropertyType,
+        value: PropertyValue,
+    ) -> anyhow::Result<()> {
+        write_property(&self.conn, window, name, property_type, value)
+    }
+}
 
-        self.managed_windows.insert(id, managed_window);
-        self.current_id += 1;
+/// Backend of the window manager of the plugin system. Tests of the plugin system run without an X
+/// server, so they use the fake backend.
+#[cfg(not(test))]
+pub type SystemBackend = XcbBackend;
+#[cfg(test)]
+pub type SystemBackend = fake_backend::FakeBackend;
 
-        Ok(id)
+#[derive(Debug)]
+pub struct WindowManager<B: XBackend = SystemBackend> {
+    backend: B,
+    screen_height: u16,
+    screen_width: u16,
+    current_id: ManagedWid,
+    managed_windows: HashMap<ManagedWid, ManagedWindow>,
+    primary_window: Option<ManagedWid>,
+    reanchor: Reanchor,
+    mode_fallback: ModeFallback,
+    /// Screen size at startup, which `Reanchor::Proportional` scales from
+    reference_screen_size: (u16, u16),
+    /// New screen size that wasn't announced to clients and plugins yet
+    resolution_change: Option<(u16, u16)>,
+    /// X windows that were dropped when reconnecting to the X server, with their owners, which
+    /// weren't announced to the plugins yet
+    lost_windows: Vec<(String, ManagedWid)>,
+    /// Fills the parts of the screen that aren't covered by windows
+    background_pixel: Option<u32>,
+    highlighted_windows: HashMap<ManagedWid, HighlightedWindow>,
+    current_highlight_id: HighlightId,
+}
+
+impl<B: XBackend> WindowManager<B> {
+    pub fn init() -> anyhow::Result<Self> {
+        match std::env::var("DISPLAY") {
+            Ok(display) => debug!("DISPLAY environment variable is {}", display),
+            Err(std::env::VarError::NotPresent) => debug!("DISPLAY environment varibale isn't set"),
+            Err(std::env::VarError::NotUnicode(_)) => {
+                warn!("DISPLAY environment varibale isn't valid UTF-8")
+            }
+        }
+
+        let (backend, screen_size) = B::connect()?;
+        Ok(WindowManager::with_backend(backend, screen_size))
     }
 
-    pub fn manage_virtual_window(
+    pub fn set_background_color(&mut self, lua: &Lua, color: Color) -> anyhow::Result<()> {
+        self.with_reconnect(lua, |wm| {
+            wm.background_pixel = Some(wm.backend.color_pixel(color)?);
+            wm.clear_background()?;
+            Ok(())
+        })
+    }
+
+    pub fn get_display_info(&self) -> anyhow::Result<Vec<OutputInfo>> {
+        self.backend.display_info()
+    }
+
+    pub fn get_window_property(
+        &self,
+        id: ManagedWid,
+        name: &str,
+    ) -> anyhow::Result<Option<PropertyValue>> {
+        self.backend.read_property(self.x_window(id)?, name)
+    }
+
+    pub fn set_window_property(
+        &self,
+        id: ManagedWid,
+        name: &str,
+        property_type: PropertyType,
+        value: PropertyValue,
+    ) -> anyhow::Result<()> {
+        self.backend
+            .write_property(self.x_window(id)?, name, property_type, value)
+    }
+    pub fn manage_x_window(
         &mut self,
         lua: &Lua,
-        name: String,
-        callbacks: VirtualWindowCallbacks,
+        window: x::Window,
         min_geometry: MinGeometry,
-        primary_demotion_action: PrimaryDemotionAction,
-    ) -> anyhow::Result<ManagedWid> {
+        owner: String,
+    ) -> xcb::Result<ManagedWid> {
+        self.backend.set_managed_hint(window)?;
         let id = self.current_id;
         let managed_window = ManagedWindow {
             id,
-            variant: WindowVariant::VirtualWindow {
-                name,
-                callbacks,
-                primary_demotion_action,
-            },
+            owner,
+            variant: WindowVariant::XWindow { window },
             min_geometry,
             mode: Mode::Min,
         };
 
-        let geometry = managed_window.min_geometry.get_geometry(lua);
-        self.change_window_geometry(lua, &managed_window, geometry, MIN_Z)?;
+        let geometry = self.min_geometry(lua, &managed_window.min_geometry);
+        if let Err(e) = self.change_window_geometry(lua, &managed_window, geometry, MIN_Z) {
+            // Roll back the managed hint, so that a failed claim doesn't leave a window behind
+            // that can't be claimed anymore.
+            if let Err(e) = self.backend.remove_managed_hint(window) {
+                warn!(
+                    "couldn't remove managed hint after failing to manage window: {}",
+                    e
+                );
+            }
+            return Err(e);
+        }
+
+        // Only touch the window manager state once every request succeeded
+        self.managed_windows.insert(id, managed_window);
+        self.current_id += 1;
+
+        Ok(id)
+    }
+
+    /// Looks for a top level window whose class contains `to_claim`, preferring windows that are
+    /// not managed yet. Managed windows count as unmanaged when `ignore_managed` is set.
+    pub fn get_window_by_class(
+        &self,
+        to_claim: &str,
+        ignore_managed: bool,
+    ) -> anyhow::Result<Option<ClaimCandidate>> {
+        let mut matching = Vec::new();
+        for properties in self.backend.top_level_windows()? {
+            let class = properties.class.into_bytes();
+            if String::from_utf8_lossy(&class).contains(to_claim) {
+                let managed = !ignore_managed && is_managed_hint(&properties.managed.into_bytes());
+                if !managed {
+                    return Ok(Some(ClaimCandidate::Unmanaged(properties.window)));
+                }
+                matching.push(properties.window);
+            }
+        }
+
+        Ok(matching
+            .first()
+            .map(|&window| managed_claim_candidate(window, &self.managed_windows)))
+    }
+
+    /// Lists the top level windows whose class contains `class_filter` and that are not managed
+    /// by any neopult instance.
+    pub fn list_unmanaged_windows(
+        &self,
+        class_filter: &str,
+    ) -> anyhow::Result<Vec<ClaimableWindow>> {
+        let windows = self
+            .backend
+            .top_level_windows()?
+            .into_iter()
+            .map(|properties| TopLevelWindow {
+                window_id: properties.window.resource_id(),
+                class: String::from_utf8_lossy(&properties.class.into_bytes()).into_owned(),
+                name: String::from_utf8_lossy(&properties.name.into_bytes()).into_owned(),
+                managed: is_managed_hint(&properties.managed.into_bytes()),
+            })
+            .collect();
+        Ok(claimable_windows(windows, class_filter))
+    }
+
+    fn with_backend(backend: B, (screen_width, screen_height): (u16, u16)) -> Self {
+        WindowManager {
+            backend,
+            screen_height,
+            screen_width,
+            current_id: 0,
+            managed_windows: HashMap::new(),
+            primary_window: None,
+            reanchor: Reanchor::default(),
+            mode_fallback: ModeFallback::default(),
+            reference_screen_size: (screen_width, screen_height),
+            resolution_change: None,
+            lost_windows: Vec::new(),
+            background_pixel: None,
+            highlighted_windows: HashMap::new(),
+            current_highlight_id: 0,
+        }
+    }
+
+    /// Draws a border around the window until the highlight is removed again. Returns `None` for
+    /// virtual windows, which have no border.
+    pub fn push_highlight(
+        &mut self,
+        id: ManagedWid,
+        highlight: Highlight,
+    ) -> anyhow::Result<Option<HighlightId>> {
+        self.ensure_managed(id)?;
+        let window = match self.managed_windows[&id].variant {
+            WindowVariant::XWindow { window } => window,
+            WindowVariant::VirtualWindow { .. } => return Ok(None),
+        };
+
+        let pixel = self.backend.color_pixel(highlight.color)?;
+        if !self.highlighted_windows.contains_key(&id) {
+            let original_width = self.backend.border_width(window)?;
+            self.highlighted_windows.insert(
+                id,
+                HighlightedWindow {
+                    original_width,
+                    highlights: Vec::new(),
+                },
+            );
+        }
+        self.backend
+            .set_border(window, highlight.border_width, pixel)?;
+
+        let highlight_id = self.current_highlight_id;
+        self.current_highlight_id += 1;
+        self.highlighted_windows
+            .get_mut(&id)
+            .unwrap()
+            .highlights
+            .push((highlight_id, highlight));
+        Ok(Some(highlight_id))
+    }
+
+    /// Removes the highlight and shows the border from before it was pushed, which is either an
+    /// older highlight or the original border. Does nothing for released windows.
+    pub fn remove_highlight(
+        &mut self,
+        id: ManagedWid,
+        highlight_id: HighlightId,
+    ) -> anyhow::Result<()> {
+        if !self.highlighted_windows.contains_key(&id) {
+            return Ok(());
+        }
+        let window = self.x_window(id)?;
+        let highlighted_window = self.highlighted_windows.get_mut(&id).unwrap();
+        let was_shown =
+            highlighted_window.highlights.last().map(|(id, _)| *id) == Some(highlight_id);
+        highlighted_window
+            .highlights
+            .retain(|(id, _)| *id != highlight_id);
+        if !was_shown {
+            return Ok(());
+        }
+
+        let (border_width, pixel) = match highlighted_window.highlights.last() {
+            Some((_, highlight)) => (
+                highlight.border_width,
+                self.backend.color_pixel(highlight.color)?,
+            ),
+            None => {
+                let original_width = highlighted_window.original_width;
+                self.highlighted_windows.remove(&id);
+                (original_width, self.backend.default_border_pixel())
+            }
+        };
+        self.backend.set_border(window, border_width, pixel)?;
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn manage_virtual_window(
+        &mut self,
+        lua: &Lua,
+        name: String,
+        callbacks: VirtualWindowCallbacks,
+        min_geometry: MinGeometry,
+        primary_demotion_action: PrimaryDemotionAction,
+        frontend_hint: FrontendHint,
+        owner: String,
+    ) -> anyhow::Result<ManagedWid> {
+        let id = self.current_id;
+        let managed_window = ManagedWindow {
+            id,
+            owner,
+            variant: WindowVariant::VirtualWindow {
+                name,
+                callbacks,
+                primary_demotion_action,
+                frontend_hint,
+            },
+            min_geometry,
+            mode: Mode::Min,
+        };
+
+        let geometry = self.min_geometry(lua, &managed_window.min_geometry);
+        self.change_window_geometry(lua, &managed_window, geometry, MIN_Z)?;
 
         self.managed_windows.insert(id, managed_window);
         self.current_id += 1;
@@ -505,6 +1777,85 @@ impl WindowManager {
     }
 
     pub fn max_window(
+        &mut self,
+        lua: &Lua,
+        id: ManagedWid,
+        size: (u16, u16),
+        margin: Margin,
+    ) -> anyhow::Result<()> {
+        self.with_reconnect(lua, |wm| wm.try_max_window(lua, id, size, margin))
+    }
+
+    pub fn min_window(&mut self, lua: &Lua, id: ManagedWid) -> anyhow::Result<()> {
+        self.with_reconnect(lua, |wm| wm.try_min_window(lua, id))
+    }
+
+    pub fn hide_window(&mut self, lua: &Lua, id: ManagedWid) -> anyhow::Result<()> {
+        self.with_reconnect(lua, |wm| wm.try_hide_window(lua, id))
+    }
+
+    pub fn release_window(&mut self, lua: &Lua, id: ManagedWid) -> anyhow::Result<()> {
+        self.with_reconnect(lua, |wm| wm.try_release_window(lua, id))
+    }
+
+    /// Releases all windows that were claimed or created by the plugin instance `owner`. Returns
+    /// the managed wids of the released windows.
+    pub fn release_owned_windows(
+        &mut self,
+        lua: &Lua,
+        owner: &str,
+    ) -> anyhow::Result<Vec<ManagedWid>> {
+        self.with_reconnect(lua, |wm| wm.try_release_owned_windows(lua, owner))
+    }
+
+    pub fn reposition_windows(&mut self, lua: &Lua) -> anyhow::Result<()> {
+        self.with_reconnect(lua, |wm| wm.try_reposition_windows(lua))
+    }
+
+    fn with_reconnect<T>(
+        &mut self,
+        lua: &Lua,
+        op: impl FnMut(&mut Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        retry_after_reconnect(
+            self,
+            op,
+            |wm| wm.backend.is_disconnected(),
+            |wm| wm.reconnect(lua),
+        )
+    }
+
+    /// Replaces the broken connection, e.g. after the X server was restarted. X windows of the
+    /// old server don't exist anymore, so only virtual windows are kept and repositioned.
+    fn reconnect(&mut self, lua: &Lua) -> anyhow::Result<()> {
+        let (screen_width, screen_height) = self.backend.reconnect()?;
+        self.screen_width = screen_width;
+        self.screen_height = screen_height;
+        self.clear_background()?;
+
+        let lost_windows = &mut self.lost_windows;
+        self.managed_windows
+            .retain(|id, window| match window.variant {
+                WindowVariant::XWindow { .. } => {
+                    warn!(
+                        "releasing x window with managed wid {} after reconnecting to the x server",
+                        id
+                    );
+                    lost_windows.push((window.owner.clone(), *id));
+                    false
+                }
+                WindowVariant::VirtualWindow { .. } => true,
+            });
+        lost_windows.sort_unstable_by_key(|(_, id)| *id);
+        if let Some(primary_window) = self.primary_window {
+            if !self.managed_windows.contains_key(&primary_window) {
+                self.primary_window = self.find_new_primary_window();
+            }
+        }
+        self.try_reposition_windows(lua)
+    }
+
+    fn try_max_window(
         &mut self,
         lua: &Lua,
         id: ManagedWid,
@@ -542,7 +1893,7 @@ impl WindowManager {
         let previous_primary_window = self.primary_window;
 
         self.primary_window = Some(id);
-        self.reposition_windows(lua)?;
+        self.try_reposition_windows(lua)?;
 
         if previous_primary_window != self.primary_window {
             if let Some(wid) = previous_primary_window {
@@ -560,11 +1911,11 @@ impl WindowManager {
                         PrimaryDemotionAction::DoNothing => (),
                         PrimaryDemotionAction::MakeMin => {
                             let id = window.id;
-                            self.min_window(lua, id)?;
+                            self.try_min_window(lua, id)?;
                         }
                         PrimaryDemotionAction::Hide => {
                             let id = window.id;
-                            self.hide_window(lua, id)?;
+                            self.try_hide_window(lua, id)?;
                         }
                     }
                 }
@@ -574,7 +1925,7 @@ impl WindowManager {
         Ok(())
     }
 
-    pub fn min_window(&mut self, lua: &Lua, id: ManagedWid) -> anyhow::Result<()> {
+    fn try_min_window(&mut self, lua: &Lua, id: ManagedWid) -> anyhow::Result<()> {
         self.ensure_managed(id)?;
 
         let was_hidden;
@@ -591,19 +1942,24 @@ impl WindowManager {
                 Some(wid) => debug!("found new primary window with managed wid {}", wid),
                 None => debug!("didn't find new primary window"),
             }
-            self.reposition_windows(lua)?;
+            self.try_reposition_windows(lua)?;
         }
 
         let window = self.managed_windows.get(&id).unwrap();
         if was_hidden {
             self.map_window(lua, window)?;
         }
-        self.change_window_geometry(lua, window, window.min_geometry.get_geometry(lua), MIN_Z)?;
+        self.change_window_geometry(
+            lua,
+            window,
+            self.min_geometry(lua, &window.min_geometry),
+            MIN_Z,
+        )?;
 
         Ok(())
     }
 
-    pub fn hide_window(&mut self, lua: &Lua, id: ManagedWid) -> anyhow::Result<()> {
+    fn try_hide_window(&mut self, lua: &Lua, id: ManagedWid) -> anyhow::Result<()> {
         self.ensure_managed(id)?;
 
         let window = self.managed_windows.get_mut(&id).unwrap();
@@ -621,17 +1977,18 @@ impl WindowManager {
                     Some(wid) => debug!("found new primary window with managed wid {}", wid),
                     None => debug!("didn't find new primary window"),
                 }
-                self.reposition_windows(lua)?;
+                self.try_reposition_windows(lua)?;
             }
         }
 
         Ok(())
     }
 
-    pub fn release_window(&mut self, lua: &Lua, id: ManagedWid) -> anyhow::Result<()> {
+    fn try_release_window(&mut self, lua: &Lua, id: ManagedWid) -> anyhow::Result<()> {
         self.ensure_managed(id)?;
 
         let window = self.managed_windows.remove(&id).unwrap();
+        self.highlighted_windows.remove(&id);
         if self.primary_window == Some(window.id) {
             debug!("primary window released, finding new primary window");
             self.primary_window = self.find_new_primary_window();
@@ -639,13 +1996,34 @@ impl WindowManager {
                 Some(wid) => debug!("found new primary window with managed wid {}", wid),
                 None => debug!("didn't find new primary window"),
             }
-            self.reposition_windows(lua)?;
+            self.try_reposition_windows(lua)?;
         }
 
         Ok(())
     }
 
-    pub fn reposition_windows(&mut self, lua: &Lua) -> anyhow::Result<()> {
+    fn try_release_owned_windows(
+        &mut self,
+        lua: &Lua,
+        owner: &str,
+    ) -> anyhow::Result<Vec<ManagedWid>> {
+        let previous_primary_window = self.primary_window;
+        let released =
+            remove_owned_windows(&mut self.managed_windows, &mut self.primary_window, owner);
+        for id in released.iter() {
+            self.highlighted_windows.remove(id);
+        }
+        if self.primary_window != previous_primary_window {
+            match self.primary_window {
+                Some(wid) => debug!("found new primary window with managed wid {}", wid),
+                None => debug!("didn't find new primary window"),
+            }
+            self.try_reposition_windows(lua)?;
+        }
+        Ok(released)
+    }
+
+    fn try_reposition_windows(&mut self, lua: &Lua) -> anyhow::Result<()> {
         if let Some(primary_window_id) = self.primary_window {
             let primary_window = self
                 .managed_windows
@@ -665,7 +2043,9 @@ impl WindowManager {
                         height,
                         alignment: Alignment::TopLeft,
                     };
-                    self.change_window_geometry(lua, primary_window, aligned_geometry, MAX_Z)?;
+                    let cookie =
+                        self.send_geometry_change(lua, primary_window, aligned_geometry, MAX_Z);
+                    self.check_geometry_changes(cookie)?;
                     self.change_screen_resolution((
                         width + margin.left + margin.right,
                         height + margin.top + margin.bottom,
@@ -677,25 +2057,108 @@ impl WindowManager {
             }
         }
 
+        // The geometry changes of min windows are sent in one batch, so that we only wait for one
+        // round-trip to the X server instead of one per window.
         // TODO: Define some kind of z-order to handle overlapping min windows
-        for window in self.managed_windows.values() {
-            if window.mode == Mode::Min {
-                self.change_window_geometry(
-                    lua,
-                    window,
-                    window.min_geometry.get_geometry(lua),
-                    MIN_Z,
-                )?;
-            }
-        }
+        let cookies: Vec<_> = self
+            .managed_windows
+            .values()
+            .filter(|window| window.mode == Mode::Min)
+            .filter_map(|window| {
+                let geometry = self.min_geometry(lua, &window.min_geometry);
+                self.send_geometry_change(lua, window, geometry, MIN_Z)
+            })
+            .collect();
+        self.check_geometry_changes(cookies)?;
+
+        Ok(())
+    }
 
+    /// Replaces the min geometry of the window and moves the window there if it is in min mode
+    pub fn set_min_geometry(
+        &mut self,
+        lua: &Lua,
+        id: ManagedWid,
+        min_geometry: MinGeometry,
+    ) -> anyhow::Result<()> {
+        self.ensure_managed(id)?;
+        let window = self.managed_windows.get_mut(&id).unwrap();
+        window.min_geometry = min_geometry;
+        if window.mode == Mode::Min {
+            self.min_window(lua, id)?;
+        }
         Ok(())
     }
 
+    /// Puts the window to min mode, centered on the screen with the given size
+    pub fn center_window(
+        &mut self,
+        lua: &Lua,
+        id: ManagedWid,
+        (width, height): (u16, u16),
+    ) -> anyhow::Result<()> {
+        self.ensure_managed(id)?;
+        self.managed_windows.get_mut(&id).unwrap().min_geometry =
+            MinGeometry::Centered { width, height };
+        self.min_window(lua, id)
+    }
+
+    pub fn window_mode(&self, id: ManagedWid) -> Option<Mode> {
+        self.managed_windows.get(&id).map(|window| window.mode)
+    }
+
     pub fn is_primary_window(&self, id: ManagedWid) -> bool {
         self.primary_window == Some(id)
     }
 
+    pub fn list_windows(&self) -> Vec<WindowInfo> {
+        window_infos(&self.managed_windows, self.primary_window)
+    }
+
+    /// Managed wid and owning plugin instance of the primary window
+    pub fn primary_window_owner(&self) -> Option<(ManagedWid, &str)> {
+        let id = self.primary_window?;
+        self.managed_windows
+            .get(&id)
+            .map(|window| (id, window.owner.as_str()))
+    }
+
+    pub fn set_reanchor(&mut self, lua: &Lua, reanchor: Reanchor) -> anyhow::Result<()> {
+        self.reanchor = reanchor;
+        self.reposition_windows(lua)
+    }
+
+    pub fn set_mode_fallback(&mut self, mode_fallback: ModeFallback) {
+        self.mode_fallback = mode_fallback;
+    }
+
+    /// Returns the new screen size if the resolution changed since the last call
+    pub fn take_resolution_change(&mut self) -> Option<(u16, u16)> {
+        self.resolution_change.take()
+    }
+
+    /// Returns the owners and managed wids of the X windows that were lost since the last call
+    pub fn take_lost_windows(&mut self) -> Vec<(String, ManagedWid)> {
+        std::mem::take(&mut self.lost_windows)
+    }
+
+    fn min_geometry(&self, lua: &Lua, min_geometry: &MinGeometry) -> AlignedGeometry {
+        let geometry = min_geometry.get_geometry(lua, self.screen_size());
+        match min_geometry {
+            // Already computed from the current screen size
+            MinGeometry::Centered { .. } => geometry,
+            MinGeometry::Fixed(_) | MinGeometry::Dynamic { .. } => geometry.reanchored(
+                self.reanchor,
+                self.reference_screen_size,
+                self.screen_size(),
+            ),
+        }
+    }
+
+    fn screen_size(&self) -> (u16, u16) {
+        (self.screen_width, self.screen_height)
+    }
+
     fn ensure_managed(&self, id: ManagedWid) -> anyhow::Result<()> {
         if self.managed_windows.contains_key(&id) {
             Ok(())
@@ -704,11 +2167,23 @@ impl WindowManager {
         }
     }
 
+    fn x_window(&self, id: ManagedWid) -> anyhow::Result<x::Window> {
+        self.ensure_managed(id)?;
+        match self.managed_windows[&id].variant {
+            WindowVariant::XWindow { window } => Ok(window),
+            WindowVariant::VirtualWindow { .. } => {
+                anyhow::bail!(
+                    "window {} is a virtual window, which has no X properties",
+                    id
+                )
+            }
+        }
+    }
+
     fn map_window(&self, lua: &Lua, window: &ManagedWindow) -> xcb::Result<()> {
         match &window.variant {
             WindowVariant::XWindow { window } => {
-                self.conn
-                    .send_and_check_request(&x::MapWindow { window: *window })?;
+                self.backend.map_window(*window)?;
             }
             WindowVariant::VirtualWindow {
                 name, callbacks, ..
@@ -737,8 +2212,7 @@ impl WindowManager {
     fn unmap_window(&self, lua: &Lua, window: &ManagedWindow) -> xcb::Result<()> {
         match &window.variant {
             WindowVariant::XWindow { window } => {
-                self.conn
-                    .send_and_check_request(&x::UnmapWindow { window: *window })?;
+                self.backend.unmap_window(*window)?;
             }
             WindowVariant::VirtualWindow {
                 name, callbacks, ..
@@ -765,14 +2239,7 @@ impl WindowManager {
     }
 
     fn find_new_primary_window(&mut self) -> Option<ManagedWid> {
-        self.managed_windows
-            .values()
-            .filter(|w| matches!(w.mode, Mode::Max { .. }))
-            .max_by_key(|w| match w.mode {
-                Mode::Max { priority, .. } => priority,
-                _ => unreachable!(),
-            })
-            .map(|w| w.id)
+        find_primary_window(&self.managed_windows)
     }
 
     fn change_window_geometry(
@@ -782,34 +2249,36 @@ impl WindowManager {
         aligned_geometry: AlignedGeometry,
         z: u16,
     ) -> xcb::Result<()> {
+        let cookie = self.send_geometry_change(lua, managed_window, aligned_geometry, z);
+        self.check_geometry_changes(cookie)
+    }
+
+    /// Sends the geometry change without waiting for the X server. The returned cookie has to be
+    /// checked with `check_geometry_changes`. Virtual windows are changed immediately and don't
+    /// return a cookie.
+    fn send_geometry_change(
+        &self,
+        lua: &Lua,
+        managed_window: &ManagedWindow,
+        aligned_geometry: AlignedGeometry,
+        z: u16,
+    ) -> Option<B::Cookie> {
         match &managed_window.variant {
             WindowVariant::XWindow { window } => {
-                let geometry = aligned_geometry.as_geometry(self);
-                self.conn.send_and_check_request(&x::ConfigureWindow {
-                    window: *window,
-                    value_list: &[
-                        x::ConfigWindow::X(geometry.x as i32),
-                        x::ConfigWindow::Y(geometry.y as i32),
-                        x::ConfigWindow::Width(geometry.width as u32),
-                        x::ConfigWindow::Height(geometry.height as u32),
-                        // This also raises the window
-                        x::ConfigWindow::StackMode(x::StackMode::Above),
-                    ],
-                })?;
+                let geometry = aligned_geometry.as_geometry(self.screen_size());
+                return Some(self.backend.send_configure_window(*window, geometry));
             }
             // TODO: Either implement 'raise' or z-order
             WindowVariant::VirtualWindow {
-                name, callbacks, ..
+                name,
+                callbacks,
+                frontend_hint,
+                ..
             } => match lua.registry_value::<Function>(&callbacks.set_geometry_key) {
                 Ok(callback) => {
-                    if let Err(e) = callback.call::<_, Value>((
-                        aligned_geometry.x_offset,
-                        aligned_geometry.y_offset,
-                        aligned_geometry.width,
-                        aligned_geometry.height,
-                        aligned_geometry.alignment.to_string(),
-                        z,
-                    )) {
+                    if let Err(e) =
+                        call_set_geometry(lua, callback, frontend_hint, aligned_geometry, z)
+                    {
                         error!(
                             "error when calling set geometry callback on virtual window with \
                             name {} (managed wid {}): {}",
@@ -827,52 +2296,53 @@ impl WindowManager {
             },
         }
 
-        Ok(())
+        None
+    }
+
+    /// Waits for the X server to process the geometry changes. All changes are checked, but only
+    /// the first error is returned.
+    fn check_geometry_changes(
+        &self,
+        cookies: impl IntoIterator<Item = B::Cookie>,
+    ) -> xcb::Result<()> {
+        let mut result = Ok(());
+        for cookie in cookies {
+            if let Err(e) = self.backend.check_request(cookie) {
+                if result.is_ok() {
+                    result = Err(e);
+                } else {
+                    error!("error when changing window geometry: {}", e);
+                }
+            }
+        }
+        result
     }
 
     fn change_screen_resolution(
         &mut self,
         (target_width, target_height): (u16, u16),
     ) -> anyhow::Result<()> {
-        let cookie = self.conn.send_request(&randr::GetScreenSizeRange {
-            window: self.screen.root(),
-        });
-        let screen_size_range = self.conn.wait_for_reply(cookie)?;
+        let ((min_width, min_height), (max_width, max_height)) =
+            self.backend.screen_size_range()?;
 
-        if target_width < screen_size_range.min_width()
-            || target_width > screen_size_range.max_width()
-            || target_height < screen_size_range.min_width()
-            || target_height > screen_size_range.max_height()
+        if target_width < min_width
+            || target_width > max_width
+            || target_height < min_height
+            || target_height > max_height
         {
             anyhow::bail!(
                 "Tried to set invalid resolution {}x{}. Minimum resolution is {}x{}. \
                 Maximum resolution is {}x{}",
                 target_width,
                 target_height,
-                screen_size_range.min_width(),
-                screen_size_range.min_height(),
-                screen_size_range.max_width(),
-                screen_size_range.max_height()
+                min_width,
+                min_height,
+                max_width,
+                max_height
             );
         }
 
-        let cookie = self.conn.send_request(&randr::GetScreenResources {
-            window: self.screen.root(),
-        });
-        let screen_resources = self.conn.wait_for_reply(cookie)?;
-
-        let crtc = *screen_resources
-            .crtcs()
-            .first()
-            .expect("no crtc in screen resources");
-
-        let cookie = self.conn.send_request(&randr::GetCrtcInfo {
-            crtc,
-            config_timestamp: x::CURRENT_TIME,
-        });
-        let crtc_info = self.conn.wait_for_reply(cookie)?;
-        let current_width = crtc_info.width();
-        let current_height = crtc_info.height();
+        let (current_width, current_height) = self.backend.output_size()?;
 
         if current_width == target_width && current_height == target_height {
             return Ok(());
@@ -882,129 +2352,1127 @@ impl WindowManager {
         //  1. Grow the one dimension of the screen to the bigger target dimension
         //  2. Shrink the other dimension of the screen to the smaller target dimension
         if target_width < current_width && target_height > current_height {
-            self.randr_set_screen_size((current_width, target_height))?;
+            self.backend
+                .set_screen_size((current_width, target_height))?;
         }
 
         if target_width > current_width && target_height < current_height {
-            self.randr_set_screen_size((target_width, current_height))?;
+            self.backend
+                .set_screen_size((target_width, current_height))?;
         }
 
         // SetCrtcConfig only works if the target output size fits into the current screen size. We
         // can't use it directly when going from 200x300 to 300x200 because the width grows.
         if target_width < current_width || target_height < current_height {
-            self.randr_set_output_size((target_width, target_height))?;
+            self.backend
+                .set_output_size((target_width, target_height), self.mode_fallback)?;
         }
 
         // SetScreenSize only works if the output size (mode from SetCrtcConfig) fits into the
         // target screen size. To achieve that, we shrink the output first.
-        self.randr_set_screen_size((target_width, target_height))?;
+        self.backend
+            .set_screen_size((target_width, target_height))?;
         // Updating output size accordlingly, so that GetCrtcInfo returns the correct size
-        self.randr_set_output_size((target_width, target_height))?;
+        let (output_width, output_height) = self
+            .backend
+            .set_output_size((target_width, target_height), self.mode_fallback)?;
+        // The fallback mode is smaller, so the screen has to shrink to the output
+        if (output_width, output_height) != (target_width, target_height) {
+            self.backend
+                .set_screen_size((output_width, output_height))?;
+        }
 
-        self.screen_width = target_width;
-        self.screen_height = target_height;
+        self.screen_width = output_width;
+        self.screen_height = output_height;
+        if (output_width, output_height) != (current_width, current_height) {
+            self.resolution_change = Some((output_width, output_height));
+            // Areas of the resized screen may show leftovers of the old content
+            self.clear_background()?;
+        }
 
         Ok(())
     }
 
-    fn randr_set_screen_size(&self, (width, height): (u16, u16)) -> xcb::Result<()> {
-        self.conn.send_and_check_request(&randr::SetScreenSize {
-            window: self.screen.root(),
-            width,
-            height,
-            // These two don't really matter for displays without a physical monitor
-            mm_width: 200,
-            mm_height: 200,
-        })?;
+    /// Does nothing when no background color was set
+    fn clear_background(&self) -> xcb::Result<()> {
+        if let Some(pixel) = self.background_pixel {
+            self.backend.clear_background(pixel)?;
+        }
         Ok(())
     }
+}
 
-    fn randr_set_output_size(&self, (width, height): (u16, u16)) -> xcb::Result<()> {
-        let cookie = self.conn.send_request(&randr::GetScreenResources {
-            window: self.screen.root(),
-        });
-        let screen_resources = self.conn.wait_for_reply(cookie)?;
-        let output = screen_resources.outputs()[0];
-        let crtc = screen_resources.crtcs()[0];
-
-        let target_mode_opt = screen_resources
-            .modes()
-            .iter()
-            .find(|m| m.width == width && m.height == height);
+/// Connects to the X server and queries everything the window manager needs to know about it.
+#[cfg_attr(test, allow(dead_code))]
+fn connect_x_server() -> anyhow::Result<(XcbBackend, (u16, u16))> {
+    let (conn, screen_num) = xcb::Connection::connect(None).context(
+        "couldn't connect to the x server, setting the DISPLAY \
+        environment variable may solve the problem",
+    )?;
+
+    let setup = conn.get_setup();
+    let screen = setup.roots().nth(screen_num as usize).unwrap().to_owned();
+
+    let screen_res_cookie = conn.send_request(&randr::GetScreenResources {
+        window: screen.root(),
+    });
+    let screen_res_reply = conn
+        .wait_for_reply(screen_res_cookie)
+        .context("error while waiting for GetScreenResources reply")?;
+    let output = screen_res_reply.outputs()[0];
+    let crtc = screen_res_reply.crtcs()[0];
+
+    let crtc_info_cookie = conn.send_request(&randr::GetCrtcInfo {
+        crtc,
+        config_timestamp: x::CURRENT_TIME,
+    });
+
+    let output_info_cookie = conn.send_request(&randr::GetOutputInfo {
+        output,
+        config_timestamp: x::CURRENT_TIME,
+    });
+
+    let crtc_info_reply = conn
+        .wait_for_reply(crtc_info_cookie)
+        .context("error while waiting for GetCrtcInfo reply")?;
+    let screen_width = crtc_info_reply.width();
+    let screen_height = crtc_info_reply.height();
+    debug!("screen has size {}x{}", screen_width, screen_height);
+
+    let output_info_reply = conn
+        .wait_for_reply(output_info_cookie)
+        .context("error while waiting for GetOutputInfo reply")?;
+    let output_name = String::from_utf8_lossy(output_info_reply.name());
+
+    if !output_name.starts_with("VNC") {
+        anyhow::bail!(
+            "the x server isn't a vnc server, setting the DISPLAY \
+            environment variable may solve the problem"
+        );
+    }
 
-        let mode = match target_mode_opt {
-            Some(target_mode) => {
-                let target_mode_id = target_mode.id;
-                let cookie = self.conn.send_request(&randr::GetOutputInfo {
-                    output,
-                    config_timestamp: x::CURRENT_TIME,
-                });
-                let output_info = self.conn.wait_for_reply(cookie)?;
+    let managed_atom_name = "_NEOPULT_MANAGED";
+    debug!(
+        "creating intern atom for managed state with name {}",
+        managed_atom_name
+    );
+
+    let cookie = conn.send_request(&x::InternAtom {
+        only_if_exists: false,
+        name: managed_atom_name.as_bytes(),
+    });
+    let reply = conn
+        .wait_for_reply(cookie)
+        .context("error while waiting for intern atom reply")?;
+    let managed_atom = reply.atom();
+
+    let backend = XcbBackend {
+        conn,
+        screen,
+        managed_atom,
+    };
+    Ok((backend, (screen_width, screen_height)))
+}
 
-                let mode = *output_info
-                    .modes()
-                    .iter()
-                    .find(|m| m.resource_id() == target_mode_id)
-                    .expect("no matching mode on output");
-                mode
+/// Runs `op` and, if it failed because the connection to the X server was lost, reconnects and
+/// runs it once more.
+fn retry_after_reconnect<S, T>(
+    state: &mut S,
+    mut op: impl FnMut(&mut S) -> anyhow::Result<T>,
+    is_disconnected: impl Fn(&S) -> bool,
+    reconnect: impl FnOnce(&mut S) -> anyhow::Result<()>,
+) -> anyhow::Result<T> {
+    match op(state) {
+        Err(e) if is_disconnected(state) => {
+            warn!("lost connection to the x server ({:?}), reconnecting", e);
+            if let Err(e) = reconnect(state) {
+                error!(
+                    "couldn't reconnect to the x server, windows can't be managed anymore: {:?}",
+                    e
+                );
+                return Err(e.context("lost connection to the x server and couldn't reconnect"));
             }
-            None => {
-                let id = self.conn.generate_id::<randr::Mode>().resource_id();
-                let name_len = width.to_string().len() + height.to_string().len() + 1;
-                let name = format!("{}x{}", width, height);
-                debug!(
-                    "new mode, id: {:?}, name_len: {}, name: {}",
-                    id, name_len, name
-                );
-                // Values reverse engineered from GetScreenResources output and existing modes
-                let cookie = self.conn.send_request(&randr::CreateMode {
-                    window: self.screen.root(),
-                    mode_info: randr::ModeInfo {
-                        id,
-                        width,
-                        height,
-                        name_len: name_len as u16,
-                        dot_clock: 60 * width as u32 * height as u32, // 60 fps
-                        hsync_start: 0,
-                        hsync_end: 0,
-                        htotal: width,
-                        hskew: 0,
-                        vsync_start: 0,
-                        vsync_end: 0,
-                        vtotal: height,
-                        mode_flags: randr::ModeFlag::empty(),
-                    },
-                    name: name.as_bytes(),
-                });
-                let create_mode_resp = self.conn.wait_for_reply(cookie)?;
-                let mode = create_mode_resp.mode();
+            info!("reconnected to the x server");
+            op(state)
+        }
+        result => result,
+    }
+}
 
-                self.conn
-                    .send_and_check_request(&randr::AddOutputMode { output, mode })?;
+fn call_set_geometry(
+    lua: &Lua,
+    callback: Function,
+    frontend_hint: &FrontendHint,
+    aligned_geometry: AlignedGeometry,
+    z: u16,
+) -> mlua::Result<()> {
+    callback.call::<_, Value>((
+        aligned_geometry.x_offset,
+        aligned_geometry.y_offset,
+        aligned_geometry.width,
+        aligned_geometry.height,
+        aligned_geometry.alignment.to_string(),
+        z,
+        frontend_hint.to_lua_table(lua)?,
+    ))?;
+    Ok(())
+}
 
-                mode
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fake_backend::{FakeBackend, FakeRequest};
+
+    /// Window manager with a 1280x720 screen that manages the X windows 10, 11 and 12 in min mode
+    /// as managed wids 0, 1 and 2
+    fn fake_window_manager() -> WindowManager<FakeBackend> {
+        let mut wm = WindowManager::with_backend(FakeBackend::new((1280, 720)), (1280, 720));
+        for id in 0..3 {
+            let window = unsafe { <x::Window as xcb::XidNew>::new(10 + id as u32) };
+            wm.managed_windows.insert(
+                id,
+                ManagedWindow {
+                    id,
+                    owner: "vnc".to_string(),
+                    variant: WindowVariant::XWindow { window },
+                    min_geometry: "320x180-0-0".parse().unwrap(),
+                    mode: Mode::Min,
+                },
+            );
+        }
+        wm.current_id = 3;
+        wm
+    }
+
+    fn geometry(x: i16, y: i16, width: u16, height: u16) -> Geometry {
+        Geometry {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn mode_info(id: u32, width: u16, height: u16) -> randr::ModeInfo {
+        randr::ModeInfo {
+            id,
+            width,
+            height,
+            dot_clock: 0,
+            hsync_start: 0,
+            hsync_end: 0,
+            htotal: width,
+            hskew: 0,
+            vsync_start: 0,
+            vsync_end: 0,
+            vtotal: height,
+            name_len: 0,
+            mode_flags: randr::ModeFlag::empty(),
+        }
+    }
+
+    #[test]
+    fn test_background_color() {
+        let color: Color = "#ff8001".parse().unwrap();
+        assert_eq!(
+            color,
+            Color {
+                red: 0xff,
+                green: 0x80,
+                blue: 0x01
             }
+        );
+        assert!("ff8001".parse::<Color>().is_ok());
+        assert!("#ff80".parse::<Color>().is_err());
+        assert!("#gg8001".parse::<Color>().is_err());
+
+        let rgb888 = ColorMasks {
+            red: 0xff0000,
+            green: 0x00ff00,
+            blue: 0x0000ff,
         };
+        assert_eq!(color_pixel(color, rgb888), 0xff8001);
+        let rgb565 = ColorMasks {
+            red: 0xf800,
+            green: 0x07e0,
+            blue: 0x001f,
+        };
+        assert_eq!(color_pixel(color, rgb565), 0xfc00);
+
+        let root = x::Window::none();
+        let (value_list, clear_area) = background_requests(root, 0xff8001);
+        assert!(matches!(value_list, [x::Cw::BackPixel(0xff8001)]));
+        assert_eq!(clear_area.window, root);
+        assert_eq!((clear_area.x, clear_area.y), (0, 0));
+        assert_eq!((clear_area.width, clear_area.height), (0, 0));
+        assert!(!clear_area.exposures);
+    }
 
-        let cookie = self.conn.send_request(&randr::SetCrtcConfig {
-            crtc,
-            timestamp: x::CURRENT_TIME,
-            config_timestamp: x::CURRENT_TIME,
-            x: 0,
-            y: 0,
+    /// Window whose properties are kept in memory. Interned atoms are taken from the predefined
+    /// atoms, since atoms can't be created otherwise.
+    #[derive(Default)]
+    struct MockWindow {
+        atoms: std::cell::RefCell<Vec<String>>,
+        properties: std::cell::RefCell<HashMap<x::Atom, (x::Atom, PropertyData)>>,
+    }
+
+    const MOCK_ATOMS: [x::Atom; 7] = [
+        x::ATOM_PRIMARY,
+        x::ATOM_SECONDARY,
+        x::ATOM_ARC,
+        x::ATOM_POINT,
+        x::ATOM_BITMAP,
+        x::ATOM_CURSOR,
+        x::ATOM_FONT,
+    ];
+
+    impl PropertyRequests for MockWindow {
+        fn intern_atom(&self, name: &str) -> xcb::Result<x::Atom> {
+            let mut atoms = self.atoms.borrow_mut();
+            let index = match atoms.iter().position(|atom| atom == name) {
+                Some(index) => index,
+                None => {
+                    atoms.push(name.to_string());
+                    atoms.len() - 1
+                }
+            };
+            Ok(MOCK_ATOMS[index])
+        }
+
+        fn property_type(
+            &self,
+            _window: x::Window,
+            property: x::Atom,
+        ) -> xcb::Result<Option<x::Atom>> {
+            Ok(self
+                .properties
+                .borrow()
+                .get(&property)
+                .map(|(r#type, _)| *r#type))
+        }
+
+        fn get_property(
+            &self,
+            _window: x::Window,
+            property: x::Atom,
+            r#type: x::Atom,
+        ) -> xcb::Result<PropertyData> {
+            let properties = self.properties.borrow();
+            let (actual_type, data) = &properties[&property];
+            assert_eq!(*actual_type, r#type);
+            // Like the X server, this returns the data with the format it was set with
+            Ok(data.clone())
+        }
+
+        fn change_property(
+            &self,
+            _window: x::Window,
+            property: x::Atom,
+            r#type: x::Atom,
+            data: &PropertyData,
+        ) -> xcb::Result<()> {
+            self.properties
+                .borrow_mut()
+                .insert(property, (r#type, data.clone()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_window_properties() {
+        let conn = MockWindow::default();
+        let window = x::Window::none();
+        assert_eq!(read_property(&conn, window, "_APP_COMMAND").unwrap(), None);
+
+        let command = PropertyValue::String("open slides.pdf".to_string());
+        write_property(
+            &conn,
+            window,
+            "_APP_COMMAND",
+            PropertyType::Utf8String,
+            command.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            read_property(&conn, window, "_APP_COMMAND").unwrap(),
+            Some(command)
+        );
+
+        let page = PropertyValue::Cardinals(vec![3]);
+        write_property(
+            &conn,
+            window,
+            "_APP_PAGE",
+            PropertyType::Cardinal,
+            page.clone(),
+        )
+        .unwrap();
+        assert_eq!(
+            read_property(&conn, window, "_APP_PAGE").unwrap(),
+            Some(page)
+        );
+        assert!(write_property(
+            &conn,
+            window,
+            "_APP_PAGE",
+            PropertyType::Cardinal,
+            PropertyValue::String("3".to_string())
+        )
+        .is_err());
+
+        // Other types aren't supported
+        let other_property = conn.intern_atom("_APP_WINDOW").unwrap();
+        conn.properties.borrow_mut().insert(
+            other_property,
+            (x::ATOM_WINDOW, PropertyData::Format32(vec![1])),
+        );
+        assert!(read_property(&conn, window, "_APP_WINDOW").is_err());
+
+        // Other clients may set properties with unusual formats
+        let small_cardinal = conn.intern_atom("_APP_SMALL").unwrap();
+        conn.properties.borrow_mut().insert(
+            small_cardinal,
+            (x::ATOM_CARDINAL, PropertyData::Format16(vec![2, 7])),
+        );
+        assert_eq!(
+            read_property(&conn, window, "_APP_SMALL").unwrap(),
+            Some(PropertyValue::Cardinals(vec![2, 7]))
+        );
+        let wide_string = conn.intern_atom("_APP_WIDE").unwrap();
+        conn.properties.borrow_mut().insert(
+            wide_string,
+            (x::ATOM_STRING, PropertyData::Format32(vec![0x41])),
+        );
+        let e = read_property(&conn, window, "_APP_WIDE").unwrap_err();
+        assert!(e.to_string().contains("format 32"), "{}", e);
+    }
+
+    #[test]
+    fn test_border_requests() {
+        let (value_list, config_values) = border_requests(8, 0xff0000);
+        assert!(matches!(value_list, [x::Cw::BorderPixel(0xff0000)]));
+        assert!(matches!(config_values, [x::ConfigWindow::BorderWidth(8)]));
+    }
+
+    #[test]
+    fn test_output_info() {
+        let screen_modes = [
+            mode_info(10, 1920, 1080),
+            mode_info(11, 1280, 720),
+            mode_info(12, 800, 600),
+        ];
+        let output = OutputInfo::new(
+            "VNC-0".to_string(),
+            true,
+            Some(11),
+            &[10, 11, 99],
+            &screen_modes,
+        );
+        assert_eq!(
+            output.current_mode,
+            Some(DisplayMode {
+                width: 1280,
+                height: 720
+            })
+        );
+        assert_eq!(
+            output.modes,
+            vec![
+                DisplayMode {
+                    width: 1920,
+                    height: 1080
+                },
+                DisplayMode {
+                    width: 1280,
+                    height: 720
+                },
+            ]
+        );
+
+        let disabled = OutputInfo::new("VNC-1".to_string(), false, None, &[12], &screen_modes);
+        assert_eq!(disabled.current_mode, None);
+        assert_eq!(disabled.modes.len(), 1);
+    }
+
+    #[test]
+    fn test_set_geometry_passes_frontend_hint() {
+        let lua = Lua::new();
+        let callback: Function = lua
+            .load(
+                r#"function(x, y, width, height, alignment, z, frontend)
+                    received = { x = x, width = width, alignment = alignment, frontend = frontend }
+                end"#,
+            )
+            .eval()
+            .unwrap();
+        let metadata: Table = lua.load(r#"{ slot = 2, label = "cam" }"#).eval().unwrap();
+        let frontend_hint = FrontendHint {
+            frontend_id: Some("camera-3".to_string()),
+            metadata_key: Some(lua.create_registry_value(metadata).unwrap()),
+        };
+        let geometry = "320x180-10+20".parse::<AlignedGeometry>().unwrap();
+
+        call_set_geometry(&lua, callback, &frontend_hint, geometry, MIN_Z).unwrap();
+
+        let received: Table = lua.globals().get("received").unwrap();
+        assert_eq!(received.get::<_, u16>("x").unwrap(), 10);
+        assert_eq!(received.get::<_, u16>("width").unwrap(), 320);
+        assert_eq!(received.get::<_, String>("alignment").unwrap(), "rt");
+        let frontend: Table = received.get("frontend").unwrap();
+        assert_eq!(frontend.get::<_, String>("id").unwrap(), "camera-3");
+        let metadata: Table = frontend.get("metadata").unwrap();
+        assert_eq!(metadata.get::<_, u8>("slot").unwrap(), 2);
+        assert_eq!(metadata.get::<_, String>("label").unwrap(), "cam");
+    }
+
+    struct FakeConnection {
+        connected: bool,
+        reconnects: usize,
+        calls: usize,
+    }
+
+    #[test]
+    fn test_retry_after_reconnect() {
+        let mut conn = FakeConnection {
+            connected: true,
+            reconnects: 0,
+            calls: 0,
+        };
+        let op = |conn: &mut FakeConnection| {
+            conn.calls += 1;
+            if conn.calls == 1 {
+                // Simulates an X server restart during the first request
+                conn.connected = false;
+                anyhow::bail!("connection error");
+            }
+            Ok(conn.calls)
+        };
+        let reconnect = |conn: &mut FakeConnection| {
+            conn.reconnects += 1;
+            conn.connected = true;
+            Ok(())
+        };
+
+        let result = retry_after_reconnect(&mut conn, op, |conn| !conn.connected, reconnect);
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(conn.reconnects, 1);
+
+        // Errors that are not caused by a lost connection are returned without reconnecting
+        let result: anyhow::Result<()> = retry_after_reconnect(
+            &mut conn,
+            |_| anyhow::bail!("bad window"),
+            |conn| !conn.connected,
+            reconnect,
+        );
+        assert!(result.is_err());
+        assert_eq!(conn.reconnects, 1);
+
+        // A failed reconnect is reported
+        conn.calls = 0;
+        let result = retry_after_reconnect(
+            &mut conn,
+            op,
+            |conn| !conn.connected,
+            |_| anyhow::bail!("no x server"),
+        );
+        assert!(format!("{:?}", result.unwrap_err()).contains("couldn't reconnect"));
+    }
+
+    fn virtual_window(lua: &Lua, id: ManagedWid, owner: &str, mode: Mode) -> ManagedWindow {
+        let callback = || {
+            lua.create_registry_value(lua.create_function(|_, ()| Ok(())).unwrap())
+                .unwrap()
+        };
+        ManagedWindow {
+            id,
+            owner: owner.to_string(),
+            variant: WindowVariant::VirtualWindow {
+                name: format!("window-{}", id),
+                callbacks: VirtualWindowCallbacks {
+                    set_geometry_key: callback(),
+                    map_key: callback(),
+                    unmap_key: callback(),
+                },
+                primary_demotion_action: PrimaryDemotionAction::default(),
+                frontend_hint: FrontendHint::default(),
+            },
+            min_geometry: MinGeometry::default(),
             mode,
-            rotation: randr::Rotation::ROTATE_0,
-            outputs: &[output],
-        });
-        let _ = self.conn.wait_for_reply(cookie)?;
+        }
+    }
 
-        Ok(())
+    #[test]
+    fn test_min_geometry_from_table() {
+        let lua = Lua::new();
+        let table = lua
+            .load("{ width = 320, height = 180, x_offset = 10, y_offset = 20, alignment = 'rb' }")
+            .eval::<Table>()
+            .unwrap();
+        assert_eq!(
+            MinGeometry::from_table(&table)
+                .unwrap()
+                .get_geometry(&lua, (1280, 720)),
+            "320x180-10-20"
+                .parse::<MinGeometry>()
+                .unwrap()
+                .get_geometry(&lua, (1280, 720))
+        );
+
+        let missing_height = lua.load("{ width = 320 }").eval::<Table>().unwrap();
+        assert!(MinGeometry::from_table(&missing_height).is_err());
+        let bad_alignment = lua
+            .load("{ width = 320, height = 180, alignment = 'middle' }")
+            .eval::<Table>()
+            .unwrap();
+        assert!(MinGeometry::from_table(&bad_alignment).is_err());
     }
-}
 
-mod tests {
-    #[allow(unused_imports)]
-    use super::*;
+    #[test]
+    fn test_window_infos_report_owner() {
+        let lua = Lua::new();
+        let max = Mode::Max {
+            width: 1920,
+            height: 1080,
+            priority: u32::MAX,
+            margin: Margin::default(),
+        };
+        let managed_windows: HashMap<_, _> = [
+            (1, virtual_window(&lua, 1, "obs", Mode::Min)),
+            (0, virtual_window(&lua, 0, "vnc", max)),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            window_infos(&managed_windows, Some(0)),
+            vec![
+                WindowInfo {
+                    id: 0,
+                    owner: "vnc".to_string(),
+                    name: Some("window-0".to_string()),
+                    mode: max,
+                    primary: true,
+                },
+                WindowInfo {
+                    id: 1,
+                    owner: "obs".to_string(),
+                    name: Some("window-1".to_string()),
+                    mode: Mode::Min,
+                    primary: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_output_mode() {
+        let modes = [
+            mode_info(1, 1920, 1080),
+            mode_info(2, 1280, 720),
+            mode_info(3, 800, 600),
+        ];
+        let unsupported = || -> anyhow::Result<u32> { anyhow::bail!("BadRequest") };
+
+        // Existing modes are used without creating a new one
+        let selected = select_output_mode(
+            &modes,
+            (1280, 720),
+            ModeFallback::Error,
+            || -> anyhow::Result<u32> { panic!("mode shouldn't be created") },
+        );
+        assert_eq!(
+            selected.unwrap(),
+            OutputMode::Existing {
+                id: 2,
+                size: (1280, 720)
+            }
+        );
+
+        let selected = select_output_mode(&modes, (1600, 900), ModeFallback::Nearest, || Ok(4));
+        assert_eq!(selected.unwrap(), OutputMode::Created(4));
+
+        let selected = select_output_mode(&modes, (1600, 900), ModeFallback::Nearest, unsupported);
+        assert_eq!(
+            selected.unwrap(),
+            OutputMode::Existing {
+                id: 2,
+                size: (1280, 720)
+            }
+        );
+
+        let selected = select_output_mode(&modes, (1600, 900), ModeFallback::Error, unsupported);
+        assert!(format!("{:#}", selected.unwrap_err()).contains("BadRequest"));
+
+        // Larger modes don't fit into the screen
+        let selected = select_output_mode(&modes, (640, 480), ModeFallback::Nearest, unsupported);
+        assert!(selected.is_err());
+    }
+
+    #[test]
+    fn test_is_managed_hint() {
+        assert!(is_managed_hint(b"MANAGED"));
+        assert!(!is_managed_hint(b""));
+        assert!(!is_managed_hint(b"MANAGE"));
+        // Invalid UTF-8 counts as unmanaged instead of panicking
+        assert!(!is_managed_hint(&[0xff, 0xfe, 0x4d]));
+        assert!(!is_managed_hint(b"MANAGED\xff"));
+    }
+
+    #[test]
+    fn test_overlapping_highlights_restore_previous_border() {
+        let mut wm = fake_window_manager();
+        let highlight = |color: &str, border_width| Highlight {
+            color: color.parse().unwrap(),
+            border_width,
+        };
+
+        let first = wm
+            .push_highlight(0, highlight("#ff0000", 8))
+            .unwrap()
+            .unwrap();
+        let second = wm
+            .push_highlight(0, highlight("#00ff00", 4))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            wm.backend.take_requests(),
+            [
+                FakeRequest::SetBorder(10, 8, 0xff0000),
+                FakeRequest::SetBorder(10, 4, 0x00ff00),
+            ]
+        );
+
+        // The first flash ends while the second one is still shown
+        wm.remove_highlight(0, first).unwrap();
+        assert!(wm.backend.take_requests().is_empty());
+        // The original border comes back once no highlight is left
+        wm.remove_highlight(0, second).unwrap();
+        assert_eq!(
+            wm.backend.take_requests(),
+            [FakeRequest::SetBorder(10, 2, 0)]
+        );
+        assert!(wm.highlighted_windows.is_empty());
+
+        // Removing the latest highlight shows the previous one again
+        let first = wm
+            .push_highlight(1, highlight("#ff0000", 8))
+            .unwrap()
+            .unwrap();
+        let second = wm
+            .push_highlight(1, highlight("#00ff00", 4))
+            .unwrap()
+            .unwrap();
+        wm.backend.take_requests();
+        wm.remove_highlight(1, second).unwrap();
+        assert_eq!(
+            wm.backend.take_requests(),
+            [FakeRequest::SetBorder(11, 8, 0xff0000)]
+        );
+
+        // Highlights of released windows are forgotten
+        wm.release_owned_windows(&Lua::new(), "vnc").unwrap();
+        wm.remove_highlight(1, first).unwrap();
+        assert!(!wm
+            .backend
+            .take_requests()
+            .iter()
+            .any(|request| matches!(request, FakeRequest::SetBorder(..))));
+    }
+
+    #[test]
+    fn test_failed_manage_x_window_leaves_no_state() {
+        let lua = Lua::new();
+        let window = unsafe { <x::Window as xcb::XidNew>::new(20) };
+        let min_geometry: MinGeometry = "320x180-0-0".parse().unwrap();
+        let mut wm = fake_window_manager();
+
+        // ChangeProperty of the managed hint fails
+        wm.backend.fail_managed_hint = true;
+        assert!(wm
+            .manage_x_window(&lua, window, min_geometry.clone(), "vnc".to_string())
+            .is_err());
+        assert_eq!(wm.managed_windows.len(), 3);
+        assert_eq!(wm.current_id, 3);
+        assert!(wm.backend.take_requests().is_empty());
+
+        // Moving the window fails, so the managed hint is removed again
+        wm.backend.fail_managed_hint = false;
+        wm.backend.fail_configure = true;
+        assert!(wm
+            .manage_x_window(&lua, window, min_geometry.clone(), "vnc".to_string())
+            .is_err());
+        assert_eq!(wm.managed_windows.len(), 3);
+        assert_eq!(wm.current_id, 3);
+        let requests = wm.backend.take_requests();
+        assert_eq!(requests.first(), Some(&FakeRequest::SetManagedHint(20)));
+        assert_eq!(requests.last(), Some(&FakeRequest::RemoveManagedHint(20)));
+
+        wm.backend.fail_configure = false;
+        let id = wm
+            .manage_x_window(&lua, window, min_geometry, "vnc".to_string())
+            .unwrap();
+        assert_eq!(id, 3);
+        assert_eq!(wm.current_id, 4);
+        assert_eq!(wm.managed_windows[&id].owner, "vnc");
+        assert!(!wm
+            .backend
+            .take_requests()
+            .contains(&FakeRequest::RemoveManagedHint(20)));
+    }
+
+    #[test]
+    fn test_get_window_by_class_with_unusual_formats() {
+        let window = |id| unsafe { <x::Window as xcb::XidNew>::new(id) };
+        let top_level = |id, class: &str, managed: PropertyData| TopLevelProperties {
+            window: window(id),
+            class: PropertyData::Format8(class.as_bytes().to_vec()),
+            name: PropertyData::Format32(vec![0x41]),
+            managed,
+        };
+        let mut wm = fake_window_manager();
+        *wm.backend.top_level_windows.get_mut().unwrap() = vec![
+            // Class set with another format than 8, which doesn't count as a string
+            TopLevelProperties {
+                window: window(20),
+                class: PropertyData::Format16(vec![0x76, 0x6e, 0x63]),
+                name: PropertyData::Format8(vec![]),
+                managed: PropertyData::Format8(vec![]),
+            },
+            top_level(21, "obs\0obs\0", PropertyData::Format8(b"MANAGED".to_vec())),
+            top_level(
+                22,
+                "vnc\0Vncviewer\0",
+                PropertyData::Format8(b"MANAGED".to_vec()),
+            ),
+        ];
+        assert_eq!(
+            wm.get_window_by_class("vnc", false).unwrap(),
+            Some(ClaimCandidate::Managed {
+                window: window(22),
+                managed_wid: None,
+                owner: None,
+            })
+        );
+        assert_eq!(
+            wm.get_window_by_class("vnc", true).unwrap(),
+            Some(ClaimCandidate::Unmanaged(window(22)))
+        );
+
+        // A managed hint with another format doesn't panic and counts as unmanaged
+        wm.backend
+            .top_level_windows
+            .get_mut()
+            .unwrap()
+            .push(top_level(
+                23,
+                "vnc\0Vncviewer\0",
+                PropertyData::Format32(vec![1, 2]),
+            ));
+        assert_eq!(
+            wm.get_window_by_class("vnc", false).unwrap(),
+            Some(ClaimCandidate::Unmanaged(window(23)))
+        );
+
+        let windows = wm.list_unmanaged_windows("vnc").unwrap();
+        assert_eq!(
+            windows,
+            [ClaimableWindow {
+                window_id: 23,
+                class: "vnc Vncviewer".to_string(),
+                name: String::new(),
+            }]
+        );
+        assert!(wm.get_window_by_class("xterm", false).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_managed_claim_candidate() {
+        let lua = Lua::new();
+        let window = |id| unsafe { <x::Window as xcb::XidNew>::new(id) };
+        let mut claimed = virtual_window(&lua, 4, "vnc", Mode::Min);
+        claimed.variant = WindowVariant::XWindow { window: window(42) };
+        let managed_windows: HashMap<_, _> =
+            [(3, virtual_window(&lua, 3, "obs", Mode::Min)), (4, claimed)]
+                .into_iter()
+                .collect();
+
+        assert_eq!(
+            managed_claim_candidate(window(42), &managed_windows),
+            ClaimCandidate::Managed {
+                window: window(42),
+                managed_wid: Some(4),
+                owner: Some("vnc".to_string()),
+            }
+        );
+        // Managed by another neopult instance
+        assert_eq!(
+            managed_claim_candidate(window(7), &managed_windows),
+            ClaimCandidate::Managed {
+                window: window(7),
+                managed_wid: None,
+                owner: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_max_and_min_window_with_fake_backend() {
+        let lua = Lua::new();
+        let mut wm = fake_window_manager();
+
+        wm.max_window(&lua, 0, (1920, 1080), Margin::default())
+            .unwrap();
+        assert_eq!(wm.primary_window, Some(0));
+        let requests = wm.backend.take_requests();
+        assert_eq!(
+            requests[..3],
+            [
+                FakeRequest::Configure(10, geometry(0, 0, 1920, 1080)),
+                FakeRequest::SetScreenSize((1920, 1080)),
+                FakeRequest::SetOutputSize((1920, 1080)),
+            ]
+        );
+        // The min windows are moved to the corner of the resized screen
+        let mut min_requests = requests[3..].to_vec();
+        min_requests.sort_by_key(|request| format!("{:?}", request));
+        assert_eq!(
+            min_requests,
+            [
+                FakeRequest::Configure(11, geometry(1600, 900, 320, 180)),
+                FakeRequest::Configure(12, geometry(1600, 900, 320, 180)),
+            ]
+        );
+        assert_eq!(wm.take_resolution_change(), Some((1920, 1080)));
+
+        // The most recently maxed window becomes the primary window ...
+        wm.max_window(&lua, 1, (1920, 1080), Margin::default())
+            .unwrap();
+        assert_eq!(wm.primary_window, Some(1));
+        assert!(matches!(wm.window_mode(0), Some(Mode::Max { .. })));
+        wm.backend.take_requests();
+        assert_eq!(wm.take_resolution_change(), None);
+
+        // ... and the previous one takes over when it goes back to min mode
+        wm.min_window(&lua, 1).unwrap();
+        assert_eq!(wm.primary_window, Some(0));
+        assert_eq!(wm.window_mode(1), Some(Mode::Min));
+        assert!(wm
+            .backend
+            .take_requests()
+            .contains(&FakeRequest::Configure(11, geometry(1600, 900, 320, 180))));
+    }
+
+    #[test]
+    fn test_reposition_windows_batches_configures() {
+        let lua = Lua::new();
+        let mut wm = fake_window_manager();
+        wm.managed_windows.get_mut(&1).unwrap().min_geometry = "320x180+0+0".parse().unwrap();
+
+        wm.reposition_windows(&lua).unwrap();
+        let mut requests = wm.backend.take_requests();
+        requests.sort_by_key(|request| format!("{:?}", request));
+        assert_eq!(
+            requests,
+            [
+                FakeRequest::Configure(10, geometry(960, 540, 320, 180)),
+                FakeRequest::Configure(11, geometry(0, 0, 320, 180)),
+                FakeRequest::Configure(12, geometry(960, 540, 320, 180)),
+            ]
+        );
+        // All requests are sent before the first one is checked
+        assert_eq!(wm.backend.max_unchecked_configures(), 3);
+
+        // Errors are reported at the end of the batch, after all windows were moved
+        wm.backend.fail_configure = true;
+        assert!(wm.reposition_windows(&lua).is_err());
+        assert_eq!(wm.backend.take_requests().len(), 3);
+    }
+
+    #[test]
+    fn test_hide_window_with_fake_backend() {
+        let lua = Lua::new();
+        let mut wm = fake_window_manager();
+        wm.max_window(&lua, 0, (1280, 720), Margin::default())
+            .unwrap();
+        wm.backend.take_requests();
+
+        wm.hide_window(&lua, 0).unwrap();
+        assert_eq!(wm.primary_window, None);
+        assert_eq!(wm.window_mode(0), Some(Mode::Hidden));
+        let requests = wm.backend.take_requests();
+        assert_eq!(requests[0], FakeRequest::Unmap(10));
+        // Only the min windows are repositioned
+        assert!(requests
+            .iter()
+            .all(|request| !matches!(request, FakeRequest::Configure(10, _))));
+
+        // Hidden windows are neither shown nor moved when repositioning
+        wm.reposition_windows(&lua).unwrap();
+        let requests = wm.backend.take_requests();
+        assert_eq!(requests.len(), 2);
+        assert!(!requests.contains(&FakeRequest::Map(10)));
+
+        wm.min_window(&lua, 0).unwrap();
+        assert_eq!(
+            wm.backend.take_requests(),
+            [
+                FakeRequest::Map(10),
+                FakeRequest::Configure(10, geometry(960, 540, 320, 180)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_owned_windows() {
+        let lua = Lua::new();
+        let max = |priority| Mode::Max {
+            width: 1920,
+            height: 1080,
+            priority,
+            margin: Margin::default(),
+        };
+        let mut managed_windows: HashMap<_, _> = [
+            (0, virtual_window(&lua, 0, "obs", max(u32::MAX - 2))),
+            (1, virtual_window(&lua, 1, "vnc", max(u32::MAX - 1))),
+            (2, virtual_window(&lua, 2, "vnc", max(u32::MAX))),
+            (3, virtual_window(&lua, 3, "obs", Mode::Min)),
+        ]
+        .into_iter()
+        .collect();
+        let mut primary_window = Some(2);
+
+        let released = remove_owned_windows(&mut managed_windows, &mut primary_window, "vnc");
+        assert_eq!(released, vec![1, 2]);
+        let mut remaining: Vec<_> = managed_windows.keys().copied().collect();
+        remaining.sort_unstable();
+        assert_eq!(remaining, vec![0, 3]);
+        assert_eq!(primary_window, Some(0));
+
+        // The primary window stays when it isn't owned by the plugin instance
+        let released = remove_owned_windows(&mut managed_windows, &mut primary_window, "unknown");
+        assert!(released.is_empty());
+        assert_eq!(primary_window, Some(0));
+
+        let released = remove_owned_windows(&mut managed_windows, &mut primary_window, "obs");
+        assert_eq!(released, vec![0, 3]);
+        assert!(managed_windows.is_empty());
+        assert_eq!(primary_window, None);
+    }
+
+    #[test]
+    fn test_claimable_windows() {
+        let window = |window_id, class: &str, name: &str, managed| TopLevelWindow {
+            window_id,
+            class: class.to_string(),
+            name: name.to_string(),
+            managed,
+        };
+        let tree = vec![
+            window(1, "firefox\0Firefox\0", "Slides", false),
+            window(2, "firefox\0Firefox\0", "Notes", true),
+            window(3, "xterm\0XTerm\0", "shell", false),
+            window(4, "", "", false),
+        ];
+
+        assert_eq!(
+            claimable_windows(tree, "firefox"),
+            vec![ClaimableWindow {
+                window_id: 1,
+                class: "firefox Firefox".to_string(),
+                name: "Slides".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_reanchor_on_screen_resize() {
+        let bottom_right = AlignedGeometry {
+            x_offset: 30,
+            y_offset: 20,
+            width: 320,
+            height: 180,
+            alignment: Alignment::BottomRight,
+        };
+        let reference_size = (1920, 1080);
+
+        for screen_size in [(1920, 1080), (1280, 720), (3840, 2160)] {
+            let geometry = bottom_right
+                .reanchored(Reanchor::Absolute, reference_size, screen_size)
+                .as_geometry(screen_size);
+            assert_eq!(geometry.x as u16 + geometry.width + 30, screen_size.0);
+            assert_eq!(geometry.y as u16 + geometry.height + 20, screen_size.1);
+        }
+
+        let geometry = bottom_right
+            .reanchored(Reanchor::Proportional, reference_size, (960, 540))
+            .as_geometry((960, 540));
+        assert_eq!((geometry.x, geometry.y), (960 - 320 - 15, 540 - 180 - 10));
+
+        let geometry = bottom_right
+            .reanchored(Reanchor::Proportional, reference_size, (3840, 2160))
+            .as_geometry((3840, 2160));
+        assert_eq!((geometry.x, geometry.y), (3840 - 320 - 60, 2160 - 180 - 40));
+
+        // Windows that are larger than the screen are not moved off-screen
+        let geometry = bottom_right.as_geometry((300, 150));
+        assert_eq!((geometry.x, geometry.y), (0, 0));
+
+        assert_eq!(
+            "proportional".parse::<Reanchor>().unwrap(),
+            Reanchor::Proportional
+        );
+        assert!("relative".parse::<Reanchor>().is_err());
+    }
+
+    #[test]
+    fn test_as_geometry() {
+        let screen_size = (1920, 1080);
+        let geometry = |alignment| {
+            AlignedGeometry {
+                x_offset: 10,
+                y_offset: 20,
+                width: 320,
+                height: 180,
+                alignment,
+            }
+            .as_geometry(screen_size)
+        };
+        let at = |x, y| Geometry {
+            x,
+            y,
+            width: 320,
+            height: 180,
+        };
+        assert_eq!(geometry(Alignment::TopLeft), at(10, 20));
+        assert_eq!(geometry(Alignment::TopRight), at(1590, 20));
+        assert_eq!(geometry(Alignment::BottomRight), at(1590, 880));
+        assert_eq!(geometry(Alignment::BottomLeft), at(10, 880));
+    }
+
+    #[test]
+    fn test_centered_geometry() {
+        assert_eq!(
+            centered_geometry((1920, 1080), (640, 480)),
+            AlignedGeometry {
+                x_offset: 640,
+                y_offset: 300,
+                width: 640,
+                height: 480,
+                alignment: Alignment::TopLeft,
+            }
+        );
+        // Windows that are larger than the screen stick to the top left corner
+        let oversized = centered_geometry((800, 600), (1024, 601));
+        assert_eq!((oversized.x_offset, oversized.y_offset), (0, 0));
+
+        let lua = Lua::new();
+        let min_geometry = MinGeometry::Centered {
+            width: 200,
+            height: 100,
+        };
+        let geometry = min_geometry.get_geometry(&lua, (1280, 720));
+        assert_eq!((geometry.x_offset, geometry.y_offset), (540, 310));
+    }
 
     #[test]
     fn test_aligned_geometry_from_str() {
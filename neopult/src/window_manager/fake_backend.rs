@@ -0,0 +1,289 @@
+use super::*;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FakeRequest {
+    Map(u32),
+    Unmap(u32),
+    Configure(u32, Geometry),
+    SetScreenSize((u16, u16)),
+    SetOutputSize((u16, u16)),
+    ClearBackground(u32),
+    SetManagedHint(u32),
+    RemoveManagedHint(u32),
+    SetBorder(u32, u16, u32),
+}
+
+/// Records the requests instead of sending them to an X server. It is the backend of the plugin
+/// system in tests, see `SystemBackend`.
+#[derive(Debug)]
+pub struct FakeBackend {
+    output_size: Mutex<(u16, u16)>,
+    requests: Mutex<Vec<FakeRequest>>,
+    /// Setting or removing the managed hint of a window changes its properties here
+    pub(super) top_level_windows: Mutex<Vec<TopLevelProperties>>,
+    properties: Mutex<HashMap<(u32, String), PropertyValue>>,
+    /// Makes setting the managed hint fail like a ChangeProperty with a bad window
+    pub(super) fail_managed_hint: bool,
+    /// Makes checking configure requests fail
+    pub(super) fail_configure: bool,
+    /// Configure requests that were sent but not checked yet and the most there ever were, which
+    /// shows whether the requests were batched
+    unchecked_configures: Mutex<(usize, usize)>,
+    /// Makes requests fail until the window manager reconnects, like after the X server crashed
+    disconnected: Mutex<bool>,
+}
+
+fn fake_x_error() -> xcb::Error {
+    xcb::Error::Connection(xcb::ConnError::Connection)
+}
+
+fn x_window(id: u32) -> x::Window {
+    unsafe { <x::Window as xcb::XidNew>::new(id) }
+}
+
+impl FakeBackend {
+    pub fn new(output_size: (u16, u16)) -> Self {
+        FakeBackend {
+            output_size: Mutex::new(output_size),
+            requests: Mutex::new(Vec::new()),
+            top_level_windows: Mutex::new(Vec::new()),
+            properties: Mutex::new(HashMap::new()),
+            fail_managed_hint: false,
+            fail_configure: false,
+            unchecked_configures: Mutex::new((0, 0)),
+            disconnected: Mutex::new(false),
+        }
+    }
+
+    pub fn take_requests(&self) -> Vec<FakeRequest> {
+        std::mem::take(&mut self.requests.lock().unwrap())
+    }
+
+    /// Adds an unmanaged top level window whose WM_CLASS has the instance and class name `class`
+    pub fn add_top_level_window(&self, window_id: u32, class: &str) {
+        self.top_level_windows
+            .lock()
+            .unwrap()
+            .push(TopLevelProperties {
+                window: x_window(window_id),
+                class: PropertyData::Format8(format!("{}\0{}\0", class, class).into_bytes()),
+                name: PropertyData::Format8(Vec::new()),
+                managed: PropertyData::Format8(Vec::new()),
+            });
+    }
+
+    /// Whether the top level window carries the managed hint, e.g. because another neopult
+    /// instance manages it
+    pub fn is_managed(&self, window_id: u32) -> bool {
+        self.top_level_windows
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|properties| {
+                properties.window.resource_id() == window_id
+                    && is_managed_hint(&properties.managed.clone().into_bytes())
+            })
+    }
+
+    /// Sets the managed hint like another neopult instance that claimed the window
+    pub fn set_foreign_managed_hint(&self, window_id: u32) {
+        self.set_managed_property(window_id, MANAGED_HINT.as_bytes().to_vec());
+    }
+
+    fn set_managed_property(&self, window_id: u32, value: Vec<u8>) {
+        for properties in self.top_level_windows.lock().unwrap().iter_mut() {
+            if properties.window.resource_id() == window_id {
+                properties.managed = PropertyData::Format8(value.clone());
+            }
+        }
+    }
+
+    /// Most configure requests that were waiting to be checked at the same time
+    pub fn max_unchecked_configures(&self) -> usize {
+        self.unchecked_configures.lock().unwrap().1
+    }
+
+    /// Loses the connection like a restart of the X server, which also takes all top level windows
+    /// with it
+    pub fn lose_connection(&self) {
+        *self.disconnected.lock().unwrap() = true;
+    }
+
+    fn check_connected(&self) -> xcb::Result<()> {
+        if *self.disconnected.lock().unwrap() {
+            return Err(fake_x_error());
+        }
+        Ok(())
+    }
+
+    fn record(&self, request: FakeRequest) {
+        self.requests.lock().unwrap().push(request);
+    }
+}
+
+impl XBackend for FakeBackend {
+    type Cookie = ();
+
+    fn connect() -> anyhow::Result<(Self, (u16, u16))> {
+        Ok((FakeBackend::new((1280, 720)), (1280, 720)))
+    }
+
+    fn is_disconnected(&self) -> bool {
+        *self.disconnected.lock().unwrap()
+    }
+
+    fn reconnect(&mut self) -> anyhow::Result<(u16, u16)> {
+        if std::mem::take(&mut *self.disconnected.lock().unwrap()) {
+            self.top_level_windows.lock().unwrap().clear();
+        }
+        Ok(*self.output_size.lock().unwrap())
+    }
+
+    fn map_window(&self, window: x::Window) -> xcb::Result<()> {
+        self.check_connected()?;
+        self.record(FakeRequest::Map(window.resource_id()));
+        Ok(())
+    }
+
+    fn unmap_window(&self, window: x::Window) -> xcb::Result<()> {
+        self.check_connected()?;
+        self.record(FakeRequest::Unmap(window.resource_id()));
+        Ok(())
+    }
+
+    fn send_configure_window(&self, window: x::Window, geometry: Geometry) {
+        self.record(FakeRequest::Configure(window.resource_id(), geometry));
+        let mut unchecked = self.unchecked_configures.lock().unwrap();
+        unchecked.0 += 1;
+        unchecked.1 = unchecked.1.max(unchecked.0);
+    }
+
+    fn check_request(&self, _cookie: ()) -> xcb::Result<()> {
+        let mut unchecked = self.unchecked_configures.lock().unwrap();
+        unchecked.0 = unchecked.0.saturating_sub(1);
+        self.check_connected()?;
+        if self.fail_configure {
+            return Err(fake_x_error());
+        }
+        Ok(())
+    }
+
+    fn screen_size_range(&self) -> xcb::Result<((u16, u16), (u16, u16))> {
+        Ok(((320, 200), (4096, 4096)))
+    }
+
+    fn output_size(&self) -> xcb::Result<(u16, u16)> {
+        Ok(*self.output_size.lock().unwrap())
+    }
+
+    fn set_screen_size(&self, size: (u16, u16)) -> xcb::Result<()> {
+        self.record(FakeRequest::SetScreenSize(size));
+        Ok(())
+    }
+
+    fn set_output_size(
+        &self,
+        size: (u16, u16),
+        _mode_fallback: ModeFallback,
+    ) -> anyhow::Result<(u16, u16)> {
+        self.record(FakeRequest::SetOutputSize(size));
+        *self.output_size.lock().unwrap() = size;
+        Ok(size)
+    }
+
+    fn clear_background(&self, pixel: u32) -> xcb::Result<()> {
+        self.record(FakeRequest::ClearBackground(pixel));
+        Ok(())
+    }
+
+    fn color_pixel(&self, color: Color) -> anyhow::Result<u32> {
+        let masks = ColorMasks {
+            red: 0xff0000,
+            green: 0x00ff00,
+            blue: 0x0000ff,
+        };
+        Ok(color_pixel(color, masks))
+    }
+
+    fn default_border_pixel(&self) -> u32 {
+        0
+    }
+
+    /// Every window starts with a border of 2 pixels
+    fn border_width(&self, _window: x::Window) -> xcb::Result<u16> {
+        Ok(2)
+    }
+
+    fn set_border(&self, window: x::Window, border_width: u16, pixel: u32) -> xcb::Result<()> {
+        self.record(FakeRequest::SetBorder(
+            window.resource_id(),
+            border_width,
+            pixel,
+        ));
+        Ok(())
+    }
+
+    fn set_managed_hint(&self, window: x::Window) -> xcb::Result<()> {
+        if self.fail_managed_hint {
+            return Err(fake_x_error());
+        }
+        self.record(FakeRequest::SetManagedHint(window.resource_id()));
+        self.set_managed_property(window.resource_id(), MANAGED_HINT.as_bytes().to_vec());
+        Ok(())
+    }
+
+    fn remove_managed_hint(&self, window: x::Window) -> xcb::Result<()> {
+        self.record(FakeRequest::RemoveManagedHint(window.resource_id()));
+        self.set_managed_property(window.resource_id(), Vec::new());
+        Ok(())
+    }
+
+    fn top_level_windows(&self) -> anyhow::Result<Vec<TopLevelProperties>> {
+        Ok(self.top_level_windows.lock().unwrap().clone())
+    }
+
+    fn display_info(&self) -> anyhow::Result<Vec<OutputInfo>> {
+        let (width, height) = *self.output_size.lock().unwrap();
+        let mode = DisplayMode { width, height };
+        Ok(vec![OutputInfo {
+            name: "VNC-0".to_string(),
+            connected: true,
+            current_mode: Some(mode),
+            modes: vec![mode],
+        }])
+    }
+
+    fn read_property(
+        &self,
+        window: x::Window,
+        name: &str,
+    ) -> anyhow::Result<Option<PropertyValue>> {
+        Ok(self
+            .properties
+            .lock()
+            .unwrap()
+            .get(&(window.resource_id(), name.to_string()))
+            .cloned())
+    }
+
+    fn write_property(
+        &self,
+        window: x::Window,
+        name: &str,
+        _property_type: PropertyType,
+        value: PropertyValue,
+    ) -> anyhow::Result<()> {
+        self.properties
+            .lock()
+            .unwrap()
+            .insert((window.resource_id(), name.to_string()), value);
+        Ok(())
+    }
+}
+
+impl WindowManager<FakeBackend> {
+    pub fn fake_backend(&self) -> &FakeBackend {
+        &self.backend
+    }
+}
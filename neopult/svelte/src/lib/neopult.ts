@@ -6,6 +6,7 @@ export enum SocketError {
     STORED_PASSWORD_INCORRECT,
     PASSWORD_INCORRECT,
     AUTH_TIMEOUT,
+    IDLE,
 }
 
 export interface SocketConnectionState {
@@ -29,6 +30,8 @@ export interface Module {
     displayName: string;
     status: string;
     message: string;
+    icon: string | null;
+    order: number;
     actions: {
         [name: string]: Action;
     };
@@ -45,6 +48,30 @@ export interface NeopultState {
     pluginInstances: {
         [name: string]: PluginInstance;
     };
+    // Plugins are still loading, the server sends the system info again afterwards
+    loading: boolean;
+}
+
+export interface ScreenResolution {
+    width: number;
+    height: number;
+}
+
+export interface ImageWindowGeometry {
+    x_offset: number;
+    y_offset: number;
+    width: number;
+    height: number;
+    alignment: 'lt' | 'rt' | 'rb' | 'lb';
+    z: number;
+}
+
+export interface ImageWindow {
+    pluginInstance: string;
+    name: string;
+    imagePath: string;
+    visible: boolean;
+    geometry: ImageWindowGeometry | null;
 }
 
 export const socketConnectionStore = writable<SocketConnectionState>({
@@ -59,16 +86,58 @@ export const socketConnectionStore = writable<SocketConnectionState>({
 
 export const neopultStore = writable<NeopultState>({
     pluginInstances: {},
+    loading: false,
+});
+
+// Only known after the first resolution change since connecting
+export const screenResolutionStore = writable<ScreenResolution | null>(null);
+
+// Windows created with `neopult.api.create_image_window`, keyed by `<plugin instance>::<name>`
+export const imageWindowStore = writable<{ [key: string]: ImageWindow }>({});
+
+// Image window as sent by the server
+interface ImageWindowInfo {
+    plugin_instance: string;
+    name: string;
+    image_path: string;
+    visible: boolean;
+    geometry: ImageWindowGeometry | null;
+}
+
+const imageWindowKey = (imageWindow: ImageWindowInfo) =>
+    `${imageWindow.plugin_instance}::${imageWindow.name}`;
+
+const toImageWindow = (imageWindow: ImageWindowInfo): ImageWindow => ({
+    pluginInstance: imageWindow.plugin_instance,
+    name: imageWindow.name,
+    imagePath: imageWindow.image_path,
+    visible: imageWindow.visible,
+    geometry: imageWindow.geometry,
 });
 
 // NOTE: Make sure to adjust the timeout when changing the ping interval on the server
 const CONNECTION_TIMEOUT_MS = 10000;
 const RECONNECT_INTERVALS_MS = [1000, 3000, 10000];
 
+// NOTE: Make sure to adjust the value in the server accordingly
+const SOCKET_SUBPROTOCOL = 'neopult.v1';
+
 const SOCKET_DISCONNECT_REASON_AUTH = 'auth';
 const SOCKET_DISCONNECT_REASON_AUTH_TIMEOUT = 'auth_timeout';
+const SOCKET_DISCONNECT_REASON_IDLE = 'idle';
+const SOCKET_DISCONNECT_REASON_SHUTDOWN = 'shutdown';
+const SOCKET_DISCONNECT_REASON_MESSAGE_TOO_LARGE = 'message_too_large';
 const SOCKET_DISCONNECT_REASON_CLIENT_LOGOUT = 'client_logout';
 
+// NOTE: Make sure to adjust the close codes in the server accordingly
+const SOCKET_CLOSE_CODE_REASONS: Record<number, string> = {
+    1008: SOCKET_DISCONNECT_REASON_MESSAGE_TOO_LARGE,
+    4000: SOCKET_DISCONNECT_REASON_IDLE,
+    4001: SOCKET_DISCONNECT_REASON_AUTH,
+    4002: SOCKET_DISCONNECT_REASON_AUTH_TIMEOUT,
+    4003: SOCKET_DISCONNECT_REASON_SHUTDOWN
+};
+
 const params = new URLSearchParams(window.location.search);
 export const channel = parseChannel(params);
 
@@ -80,6 +149,13 @@ const socketUrl = PUBLIC_SOCKET_URL_TEMPLATE.replace('{{PORT}}', port.toString()
     channel.toString()
 );
 
+// The server serves the images of image windows next to the websocket
+export const imageUrl = (imagePath: string) => {
+    const url = new URL(socketUrl.replace(/^ws/, 'http'));
+    const encodedPath = imagePath.split('/').map(encodeURIComponent).join('/');
+    return new URL(`images/${encodedPath}`, url).toString();
+};
+
 let socket: WebSocket;
 let requestId = 0;
 let cachedPassword = '';
@@ -133,10 +209,28 @@ export const logout = () => {
     clearReconnectTimers();
     socket.close();
     localStorage.removeItem(LOCAL_STORAGE_PASSWORD_KEY);
-    handleDisconnect(SOCKET_DISCONNECT_REASON_CLIENT_LOGOUT);
+    handleDisconnect(SOCKET_DISCONNECT_REASON_CLIENT_LOGOUT, false);
 };
 
-const handleDisconnect = (reason: string) => {
+type CloseReason = { code: string; retryable: boolean };
+
+// The server sends close reasons as JSON of the form `{ code, retryable }`. The close code
+// identifies the reason as well, e.g. when a proxy dropped the reason. Connections that were lost
+// without a close code of the server are retryable.
+const parseCloseReason = (event: CloseEvent): CloseReason => {
+    try {
+        const { code, retryable } = JSON.parse(event.reason);
+        return { code, retryable: retryable === true };
+    } catch (e) {
+        const code = SOCKET_CLOSE_CODE_REASONS[event.code];
+        if (code === undefined) {
+            return { code: event.reason, retryable: true };
+        }
+        return { code, retryable: code === SOCKET_DISCONNECT_REASON_SHUTDOWN };
+    }
+};
+
+const handleDisconnect = (reason: string, retryable: boolean = true) => {
     socket.onopen = null;
     socket.onmessage = null;
     socket.onerror = null;
@@ -160,16 +254,15 @@ const handleDisconnect = (reason: string) => {
             localStorage.removeItem(LOCAL_STORAGE_PASSWORD_KEY);
         } else if (reason === SOCKET_DISCONNECT_REASON_AUTH_TIMEOUT) {
             state.error = SocketError.AUTH_TIMEOUT;
+        } else if (reason === SOCKET_DISCONNECT_REASON_IDLE) {
+            state.authenticated = false;
+            state.error = SocketError.IDLE;
         } else if (reason === SOCKET_DISCONNECT_REASON_CLIENT_LOGOUT) {
             state.authenticated = false;
             state.error = null;
         }
 
-        const shouldReconnect =
-            reason !== SOCKET_DISCONNECT_REASON_AUTH &&
-            reason !== SOCKET_DISCONNECT_REASON_AUTH_TIMEOUT &&
-            reason !== SOCKET_DISCONNECT_REASON_CLIENT_LOGOUT;
-        if (shouldReconnect) {
+        if (retryable) {
             if (state.tryingReconnect) {
                 state.reconnectTry += 1;
             } else {
@@ -187,7 +280,7 @@ const handleDisconnect = (reason: string) => {
 
 export const connect = (password: string, rememberPassword: boolean = false) => {
     console.log('Connecting');
-    socket = new WebSocket(socketUrl);
+    socket = new WebSocket(socketUrl, SOCKET_SUBPROTOCOL);
     cachedPassword = password;
 
     socketConnectionStore.update((state) => {
@@ -213,18 +306,25 @@ export const connect = (password: string, rememberPassword: boolean = false) =>
             return;
         }
 
-        if (msg == 'ping') {
+        if (msg.ping) {
             heartbeat();
-            socket.send('"pong"');
-        } else if (msg == 'pong') {
+            socket.send(JSON.stringify({ pong: { server_ts: msg.ping.server_ts } }));
+        } else if (msg.pong) {
             heartbeat();
         } else if (msg.system_info) {
             if (rememberPassword) {
                 localStorage.setItem(LOCAL_STORAGE_PASSWORD_KEY, password);
             }
 
-            const neopultState: NeopultState = { pluginInstances: {} };
+            const neopultState: NeopultState = {
+                pluginInstances: {},
+                loading: msg.system_info.loading,
+            };
+            const imageWindows: { [key: string]: ImageWindow } = {};
             for (const pluginInstance of msg.system_info.plugin_instances) {
+                for (const imageWindow of pluginInstance.image_windows) {
+                    imageWindows[imageWindowKey(imageWindow)] = toImageWindow(imageWindow);
+                }
                 neopultState.pluginInstances[pluginInstance.name] = {
                     name: pluginInstance.name,
                     modules: {},
@@ -235,6 +335,8 @@ export const connect = (password: string, rememberPassword: boolean = false) =>
                         displayName: module.display_name,
                         status: module.status,
                         message: module.message,
+                        icon: module.icon,
+                        order: module.order,
                         actions: {},
                     };
                     for (const action of module.actions) {
@@ -264,6 +366,7 @@ export const connect = (password: string, rememberPassword: boolean = false) =>
                 });
             }
             neopultStore.set(neopultState);
+            imageWindowStore.set(imageWindows);
         } else if (msg.notification) {
             const notification = msg.notification;
             if (notification.module_status_update) {
@@ -282,6 +385,38 @@ export const connect = (password: string, rememberPassword: boolean = false) =>
                     module.message = update.new_message;
                     return state;
                 });
+            } else if (notification.module_order_update) {
+                const update = notification.module_order_update;
+                neopultStore.update((state) => {
+                    const module =
+                        state.pluginInstances[update.plugin_instance].modules[update.module];
+                    module.order = update.new_order;
+                    return state;
+                });
+            } else if (notification.module_icon_update) {
+                const update = notification.module_icon_update;
+                neopultStore.update((state) => {
+                    const module =
+                        state.pluginInstances[update.plugin_instance].modules[update.module];
+                    module.icon = update.new_icon;
+                    return state;
+                });
+            } else if (notification.module_actions_update) {
+                const update = notification.module_actions_update;
+                neopultStore.update((state) => {
+                    const module =
+                        state.pluginInstances[update.plugin_instance].modules[update.module];
+                    const actions: { [name: string]: Action } = {};
+                    for (const action of update.new_actions) {
+                        actions[action.name] = {
+                            name: action.name,
+                            displayName: action.display_name || action.name,
+                            active: module.actions[action.name]?.active ?? false,
+                        };
+                    }
+                    module.actions = actions;
+                    return state;
+                });
             } else if (notification.module_active_actions_update) {
                 const update = notification.module_active_actions_update;
                 neopultStore.update((state) => {
@@ -295,6 +430,15 @@ export const connect = (password: string, rememberPassword: boolean = false) =>
                     }
                     return state;
                 });
+            } else if (notification.screen_resolution_changed) {
+                const update = notification.screen_resolution_changed;
+                screenResolutionStore.set({ width: update.width, height: update.height });
+            } else if (notification.image_window_update) {
+                const update = notification.image_window_update;
+                imageWindowStore.update((windows) => {
+                    windows[imageWindowKey(update)] = toImageWindow(update);
+                    return windows;
+                });
             }
         }
     };
@@ -305,21 +449,20 @@ export const connect = (password: string, rememberPassword: boolean = false) =>
 
     socket.onclose = (event) => {
         console.log('socket close', event);
-        handleDisconnect(event.reason);
+        const { code, retryable } = parseCloseReason(event);
+        handleDisconnect(code, retryable);
     };
 };
 
-export const callAction = (pluginInstance: string, module: string, action: string) => {
+// Same order as in the system info of the server
+export const sortModules = (modules: { [name: string]: Module }) =>
+    Object.values(modules).sort((a, b) => a.order - b.order || a.name.localeCompare(b.name));
+
+const sendRequest = (body: object) => {
     const request = {
         request: {
             request_id: requestId.toString(),
-            body: {
-                call_action: {
-                    plugin_instance: pluginInstance,
-                    module,
-                    action,
-                },
-            },
+            body,
         },
     };
     requestId++;
@@ -327,6 +470,36 @@ export const callAction = (pluginInstance: string, module: string, action: strin
     socket.send(json);
 };
 
+export const callAction = (pluginInstance: string, module: string, action: string) => {
+    sendRequest({
+        call_action: {
+            plugin_instance: pluginInstance,
+            module,
+            action,
+        },
+    });
+};
+
+// Only allowed for admins. The server escapes the message and broadcasts the update to every
+// client. Passing `null` as the message clears it.
+export const setModuleMessage = (
+    pluginInstance: string,
+    module: string,
+    message: string | null,
+    severity: 'success' | 'warning' | 'error' | null = null
+) => {
+    sendRequest({
+        set_module_message: {
+            module_identifier: {
+                plugin_instance: pluginInstance,
+                module,
+            },
+            message,
+            severity,
+        },
+    });
+};
+
 if (hasStoredPassword) {
     connect(storedPassword!);
 }
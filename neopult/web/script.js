@@ -12,6 +12,9 @@
 
     const SOCKET_DISCONNECT_REASON_AUTH = 'auth';
     const SOCKET_DISCONNECT_REASON_AUTH_TIMEOUT = 'auth_timeout';
+    const SOCKET_DISCONNECT_REASON_IDLE = 'idle';
+    const SOCKET_DISCONNECT_REASON_SHUTDOWN = 'shutdown';
+    const SOCKET_DISCONNECT_REASON_MESSAGE_TOO_LARGE = 'message_too_large';
 
     let initialConnect = true;
     let reconnecting = false;
@@ -52,6 +55,8 @@
     const moduleStatusElements = new Map();
     const moduleMessageElements = new Map();
     const actionButtonElements = new Map();
+    // Container element, plugin instance, name and order of each module, used for reordering
+    const moduleEntries = new Map();
 
     const heartbeat = () => {
         clearTimeout(heartbeatTimeout);
@@ -128,10 +133,10 @@
             return;
         }
 
-        if (msg == 'ping') {
+        if (msg.ping) {
             heartbeat();
-            socket.send('"pong"');
-        } else if (msg == 'pong') {
+            socket.send(JSON.stringify({ pong: { server_ts: msg.ping.server_ts } }));
+        } else if (msg.pong) {
             heartbeat();
         } else if (msg.system_info) {
             statusEl.innerText = 'Connected';
@@ -144,6 +149,7 @@
             appContainerEl.classList.remove('hidden');
             authContainerEl.classList.add('hidden');
 
+            moduleEntries.clear();
             const containerEl = document.createElement('div');
             containerEl.classList.add('modules');
             for (const pluginInstance of msg.system_info.plugin_instances) {
@@ -153,6 +159,12 @@
                     const moduleContainerEl = document.createElement('div');
                     moduleContainerEl.classList.add('module-container');
                     containerEl.appendChild(moduleContainerEl);
+                    moduleEntries.set(moduleIdentifier, {
+                        el: moduleContainerEl,
+                        pluginInstance: pluginInstance.name,
+                        name: module.name,
+                        order: module.order,
+                    });
 
                     const moduleInfoEl = document.createElement('div');
                     moduleInfoEl.classList.add('module-info');
@@ -214,6 +226,11 @@
                 const update = notification.module_active_actions_update;
                 const moduleIdentifier = `${update.plugin_instance}::${update.module}`;
                 handleModuleActiveActionsUpdate(moduleIdentifier, update.new_active_actions);
+            } else if (notification.module_order_update) {
+                const update = notification.module_order_update;
+                const moduleIdentifier = `${update.plugin_instance}::${update.module}`;
+                moduleEntries.get(moduleIdentifier).order = update.new_order;
+                handleModuleOrderUpdate();
             }
         }
     };
@@ -222,9 +239,48 @@
         console.log('socket error', event);
     };
 
+    // NOTE: Make sure to adjust the close codes in the server accordingly
+    const CLOSE_CODE_REASONS = {
+        1008: SOCKET_DISCONNECT_REASON_MESSAGE_TOO_LARGE,
+        4000: SOCKET_DISCONNECT_REASON_IDLE,
+        4001: SOCKET_DISCONNECT_REASON_AUTH,
+        4002: SOCKET_DISCONNECT_REASON_AUTH_TIMEOUT,
+        4003: SOCKET_DISCONNECT_REASON_SHUTDOWN,
+    };
+
+    // The server sends close reasons as JSON of the form `{ code, retryable }`. The close code
+    // identifies the reason as well, e.g. when a proxy dropped the reason. Connections that were
+    // lost without a close code of the server are retryable.
+    const parseCloseReason = (event) => {
+        try {
+            const { code, retryable } = JSON.parse(event.reason);
+            return { code, retryable: retryable === true };
+        } catch (e) {
+            const code = CLOSE_CODE_REASONS[event.code];
+            if (code === undefined) {
+                return { code: event.reason, retryable: true };
+            }
+            return { code, retryable: code === SOCKET_DISCONNECT_REASON_SHUTDOWN };
+        }
+    };
+
     const handleSocketClose = (event) => {
         console.log('socket close', event);
-        handleDisconnect(event.reason);
+        const { code, retryable } = parseCloseReason(event);
+        handleDisconnect(code, retryable);
+    };
+
+    // Same order as in the system info of the server
+    const handleModuleOrderUpdate = () => {
+        const entries = [...moduleEntries.values()].sort(
+            (a, b) =>
+                a.pluginInstance.localeCompare(b.pluginInstance) ||
+                a.order - b.order ||
+                a.name.localeCompare(b.name)
+        );
+        for (const entry of entries) {
+            entry.el.parentElement.appendChild(entry.el);
+        }
     };
 
     const handleModuleActiveActionsUpdate = (moduleIdentifier, new_active_actions) => {
@@ -242,10 +298,10 @@
 
     const disconnect = (reconnectOverwrite = null) => {
         socket.close();
-        handleDisconnect('', reconnectOverwrite);
+        handleDisconnect('', true, reconnectOverwrite);
     };
 
-    const handleDisconnect = (reason, reconnectOverwrite = null) => {
+    const handleDisconnect = (reason, retryable = true, reconnectOverwrite = null) => {
         socket.removeEventListener('open', handleSocketOpen);
         socket.removeEventListener('error', handleSocketError);
         socket.removeEventListener('close', handleSocketClose);
@@ -265,6 +321,8 @@
             }
         } else if (reason === SOCKET_DISCONNECT_REASON_AUTH_TIMEOUT) {
             statusText = 'Socket authentication timed out';
+        } else if (reason === SOCKET_DISCONNECT_REASON_IDLE) {
+            statusText = 'Disconnected due to inactivity';
         } else {
             if (initialConnect) {
                 statusText = 'Connection failed';
@@ -274,11 +332,8 @@
         }
         statusEl.innerText = statusText;
 
-        if (
-            reconnectOverwrite === false ||
-            reason === SOCKET_DISCONNECT_REASON_AUTH ||
-            reason === SOCKET_DISCONNECT_REASON_AUTH_TIMEOUT
-        ) {
+        const shouldReconnect = reconnectOverwrite != null ? reconnectOverwrite : retryable;
+        if (!shouldReconnect) {
             passwordInputEl.disabled = false;
             passwordRememberCheckboxEl.disabled = false;
             passwordSendButtonEl.disabled = false;
@@ -286,11 +341,6 @@
             authContainerEl.classList.remove('hidden');
         }
 
-        const shouldReconnect =
-            reconnectOverwrite != null
-                ? reconnectOverwrite
-                : reason !== SOCKET_DISCONNECT_REASON_AUTH &&
-                  reason !== SOCKET_DISCONNECT_REASON_AUTH_TIMEOUT;
         if (shouldReconnect) {
             if (reconnecting) {
                 reconnecting = false;